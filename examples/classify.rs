@@ -0,0 +1,89 @@
+//! Guesses which of several classes a text belongs to, by the model that codes it in the
+//! fewest bits. Every class is given by a file to train on.
+//!
+//! ```text
+//! cargo run --example classify -- <text> <class file>...
+//! ```
+use huffman_markov::{Builder, Encoder, EscapeMode};
+use std::{env, error::Error, fs, io};
+
+const DEPTH: usize = 3;
+
+struct Class {
+    name: String,
+    encoder: Encoder,
+}
+
+impl Class {
+    fn new(name: &str, training: &[u8]) -> Result<Self, Box<dyn Error>> {
+        // escapes let every class code any text, bytes it has not seen are just expensive.
+        let builder = Builder::new().depth(DEPTH).escape(EscapeMode::Literal);
+        let (encoder, _) = builder.coder_from(&builder.train(training)?)?;
+        Ok(Class {
+            name: name.into(),
+            encoder,
+        })
+    }
+
+    // bits per byte of the text under the model of the class.
+    fn cost(&self, text: &[u8]) -> Result<f64, Box<dyn Error>> {
+        let mut writer = self.encoder.writer(io::sink());
+        io::Write::write_all(&mut writer, text)?;
+        let (_, stats) = writer.finish()?;
+        Ok(stats.output_bits as f64 / text.len().max(1) as f64)
+    }
+}
+
+// the class with the lowest cost, along with the costs of all classes.
+fn classify<'a>(classes: &'a [Class], text: &[u8]) -> Result<(&'a str, Vec<f64>), Box<dyn Error>> {
+    let costs = classes
+        .iter()
+        .map(|class| class.cost(text))
+        .collect::<Result<Vec<_>, _>>()?;
+    let best = (0..classes.len())
+        .min_by(|a, b| costs[*a].total_cmp(&costs[*b]))
+        .ok_or("no classes given")?;
+    Ok((&classes[best].name, costs))
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let (text, classes) = match args.next() {
+        Some(path) => (
+            fs::read(path)?,
+            args.map(|path| Class::new(&path, &fs::read(&path)?))
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => (
+            b"fn main() { let value = compute(); println!(\"{value}\"); }".to_vec(),
+            vec![
+                Class::new("rust", include_bytes!("../src/markov.rs"))?,
+                Class::new("english", include_bytes!("../README.md"))?,
+            ],
+        ),
+    };
+    let (best, costs) = classify(&classes, &text)?;
+    for (class, cost) in classes.iter().zip(costs) {
+        println!("{}: {cost:.2} bits per byte", class.name);
+    }
+    println!("{best}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let classes = [
+            Class::new("rust", include_bytes!("../src/markov.rs")).unwrap(),
+            Class::new("english", include_bytes!("../README.md")).unwrap(),
+        ];
+        let rust = b"impl Iterator for Iter { fn next(&mut self) -> Option<Self::Item> { None } }";
+        assert_eq!(classify(&classes, rust).unwrap().0, "rust");
+        let english = b"I had the idea that it would be interesting to combine the two of them.";
+        assert_eq!(classify(&classes, english).unwrap().0, "english");
+        assert!(classify(&[], english).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! Samples text from a model trained on a file, or the readme without an argument.
+//!
+//! ```text
+//! cargo run --example generate -- <file> [length] [seed]
+//! ```
+use huffman_markov::Builder;
+use std::{env, error::Error, fs};
+
+const DEPTH: usize = 4;
+
+// xorshift64*, enough to pick successors by their weight.
+fn next(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545f4914f6cdd1d)
+}
+
+fn run(training: &[u8], len: usize, seed: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let markov = Builder::new().depth(DEPTH).train(training)?;
+    let index = markov.context_index();
+    let mut state = seed | 1;
+    let mut output = training[..DEPTH - 1].to_vec();
+    while output.len() < len {
+        let context = &output[output.len() + 1 - DEPTH..];
+        // the end of the training data has no successor, starts over from its beginning.
+        match index.sample(context, next(&mut state) as usize) {
+            Some(byte) => output.push(byte),
+            None => output.extend_from_slice(&training[..DEPTH - 1]),
+        }
+    }
+    output.truncate(len);
+    Ok(output)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let training = match args.next() {
+        Some(path) => fs::read(path)?,
+        None => include_bytes!("../README.md").to_vec(),
+    };
+    let len = args
+        .next()
+        .map(|len| len.parse())
+        .transpose()?
+        .unwrap_or(500);
+    let seed = args
+        .next()
+        .map(|seed| seed.parse())
+        .transpose()?
+        .unwrap_or(1);
+    println!("{}", String::from_utf8_lossy(&run(&training, len, seed)?));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let training = include_bytes!("../README.md");
+        let text = run(training, 1000, 7).unwrap();
+        assert_eq!(text.len(), 1000);
+        assert_eq!(text, run(training, 1000, 7).unwrap());
+
+        // every window of the output occurs in the training data.
+        let known = |window: &[u8]| training.windows(DEPTH).any(|other| other == window);
+        assert!(text.windows(DEPTH).filter(|window| !known(window)).count() < 10);
+    }
+}
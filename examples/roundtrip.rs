@@ -0,0 +1,41 @@
+//! Compresses a file, or the model source without an argument, and checks that it decompresses to
+//! the same bytes.
+//!
+//! ```text
+//! cargo run --example roundtrip -- <file>
+//! ```
+use huffman_markov::{container::Codec, decompress_bytes, Builder};
+use std::{env, error::Error, fs};
+
+fn run(data: &[u8], codec: Codec) -> Result<usize, Box<dyn Error>> {
+    let builder = Builder::new().depth(4).codec(codec);
+    let compressed = builder.compress(data)?;
+    let decompressed = decompress_bytes(&compressed)?;
+    assert_eq!(decompressed, data, "roundtrip changed the data");
+    Ok(compressed.len())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let data = match env::args().nth(1) {
+        Some(path) => fs::read(path)?,
+        None => include_bytes!("../src/markov.rs").to_vec(),
+    };
+    for codec in [Codec::Huffman, Codec::Range] {
+        let size = run(&data, codec)?;
+        println!("{codec}: {} to {size} bytes", data.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        for codec in [Codec::Huffman, Codec::Range] {
+            run(include_bytes!("../README.md"), codec).unwrap();
+            run(b"", codec).unwrap();
+        }
+    }
+}
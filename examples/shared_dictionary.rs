@@ -0,0 +1,83 @@
+//! Trains a model on one file and compresses many others with it, the way a dictionary shared
+//! between sender and receiver works. Only the coded bytes travel, the model is saved once.
+//!
+//! ```text
+//! cargo run --example shared_dictionary -- <training file> <file>...
+//! ```
+use huffman_markov::{markov::ExportFormat, Builder, EscapeMode, Markov};
+use std::{env, error::Error, fs};
+
+const DEPTH: usize = 3;
+
+// compresses every file with a model trained on `training` and returns the compressed sizes.
+fn run(training: &[u8], files: &[Vec<u8>]) -> Result<Vec<usize>, Box<dyn Error>> {
+    // bytes the training data lacks are escaped rather than failing the whole file.
+    let builder = Builder::new().depth(DEPTH).escape(EscapeMode::Literal);
+    let mut model = vec![];
+    builder
+        .train(training)?
+        .save(&mut model, ExportFormat::Compact)?;
+    println!("model: {} bytes", model.len());
+
+    // the receiver loads the same model and builds the same codes from it.
+    let markov = Markov::load(&model[..])?;
+    let (encoder, decoder) = builder.coder_from(&markov)?;
+
+    let mut sizes = vec![];
+    for data in files {
+        // the first bytes are the context of the first code, they are sent as they are.
+        let context = &data[..data.len().min(DEPTH - 1)];
+        let coded = if data.len() < DEPTH {
+            vec![]
+        } else {
+            encoder.encode_all(data)?
+        };
+        let decoded = decoder.decode_all(context, &coded, data.len() - context.len())?;
+        assert_eq!(
+            [context, &decoded].concat(),
+            *data,
+            "roundtrip changed the data"
+        );
+        sizes.push(context.len() + coded.len());
+    }
+    Ok(sizes)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut paths = env::args().skip(1);
+    let (training, files) = match paths.next() {
+        Some(path) => (
+            fs::read(path)?,
+            paths.map(fs::read).collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => (
+            include_bytes!("../src/markov.rs").to_vec(),
+            vec![
+                include_bytes!("../src/huffman.rs").to_vec(),
+                include_bytes!("../src/builder.rs").to_vec(),
+                include_bytes!("../src/container.rs").to_vec(),
+            ],
+        ),
+    };
+    for (data, size) in files.iter().zip(run(&training, &files)?) {
+        println!("{} to {size} bytes", data.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_dictionary() {
+        let files = vec![
+            include_bytes!("../src/huffman.rs").to_vec(),
+            b"\xff\xfe unseen bytes".to_vec(),
+            b"a".to_vec(),
+            vec![],
+        ];
+        let sizes = run(include_bytes!("../src/markov.rs"), &files).unwrap();
+        assert!(sizes[0] < files[0].len() / 2);
+    }
+}
@@ -0,0 +1,9 @@
+#![no_main]
+
+use huffman_markov::{container::decompress_recover, decompress_with_limit};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decompress_with_limit(data, 1 << 20);
+    let _ = decompress_recover(data, 1 << 20);
+});
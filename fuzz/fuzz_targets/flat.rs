@@ -0,0 +1,8 @@
+#![no_main]
+
+use huffman_markov::Decoder;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Decoder::from_flat(data);
+});
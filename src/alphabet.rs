@@ -0,0 +1,130 @@
+use std::fmt;
+
+/// Set of bytes that can occur in the data, numbered densely in ascending order.
+///
+/// Code tables list the symbols of every context by their id in the alphabet, and smoothing
+/// only gives codes to bytes in it. Bytes outside of the alphabet can only be coded as escapes.
+/// The default alphabet holds all 256 bytes, where ids and bytes are the same.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AlphabetMap {
+    bits: [u64; 4],
+}
+
+impl AlphabetMap {
+    pub fn new(symbols: impl IntoIterator<Item = u8>) -> Self {
+        let mut alphabet = AlphabetMap { bits: [0; 4] };
+        for byte in symbols {
+            alphabet.bits[usize::from(byte / 64)] |= 1 << (byte % 64);
+        }
+        alphabet
+    }
+
+    /// Alphabet of all bytes occurring in `data`.
+    pub fn from_data(data: &[u8]) -> Self {
+        Self::new(data.iter().copied())
+    }
+
+    pub(crate) fn from_words(bits: [u64; 4]) -> Self {
+        AlphabetMap { bits }
+    }
+
+    // the bit set, the lowest bit of the first word is the byte zero.
+    pub(crate) fn words(&self) -> [u64; 4] {
+        self.bits
+    }
+
+    pub fn full() -> Self {
+        AlphabetMap {
+            bits: [u64::MAX; 4],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == [0; 4]
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.bits == [u64::MAX; 4]
+    }
+
+    pub fn contains(&self, byte: u8) -> bool {
+        self.bits[usize::from(byte / 64)] & (1 << (byte % 64)) != 0
+    }
+
+    /// Dense id of `byte`, its rank among the bytes of the alphabet.
+    pub fn id(&self, byte: u8) -> Option<u8> {
+        if !self.contains(byte) {
+            return None;
+        }
+        let word = usize::from(byte / 64);
+        let below = self.bits[word] & ((1 << (byte % 64)) - 1);
+        let rank: u32 = self.bits[..word].iter().map(|word| word.count_ones()).sum();
+        Some((rank + below.count_ones()) as u8)
+    }
+
+    /// Byte with the dense id `id`.
+    pub fn byte(&self, id: u8) -> Option<u8> {
+        self.symbols().nth(usize::from(id))
+    }
+
+    /// Bytes of the alphabet in ascending order, which is the order of their ids.
+    pub fn symbols(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(|byte| self.contains(*byte))
+    }
+}
+
+impl Default for AlphabetMap {
+    fn default() -> Self {
+        Self::full()
+    }
+}
+
+impl fmt::Debug for AlphabetMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.symbols()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use test_strategy::proptest;
+
+    #[test]
+    fn test_genome() {
+        let alphabet = AlphabetMap::from_data(b"GATTACA");
+        assert_eq!(alphabet.len(), 4);
+        assert_eq!(alphabet.symbols().collect::<Vec<_>>(), b"ACGT");
+        assert_eq!(alphabet.id(b'G'), Some(2));
+        assert_eq!(alphabet.byte(3), Some(b'T'));
+        assert_eq!(alphabet.id(b'N'), None);
+        assert_eq!(alphabet.byte(4), None);
+    }
+
+    #[test]
+    fn test_full() {
+        let alphabet = AlphabetMap::default();
+        assert!(alphabet.is_full() && !alphabet.is_empty());
+        assert_eq!(alphabet.len(), 256);
+        assert!((0..=u8::MAX).all(|byte| alphabet.id(byte) == Some(byte)));
+        assert!(AlphabetMap::new([]).is_empty());
+    }
+
+    #[proptest]
+    fn test_ids(data: Vec<u8>) {
+        let alphabet = AlphabetMap::from_data(&data);
+        for (id, byte) in alphabet.symbols().enumerate() {
+            prop_assert_eq!(alphabet.id(byte), Some(id as u8));
+            prop_assert_eq!(alphabet.byte(id as u8), Some(byte));
+        }
+        prop_assert!(data.iter().all(|byte| alphabet.contains(*byte)));
+    }
+}
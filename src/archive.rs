@@ -0,0 +1,268 @@
+//! Several inputs compressed into one file, see [`ArchiveWriter`].
+//!
+//! An archive starts with the magic `HMAR`. Every entry follows as the length of its name as a
+//! little-endian `u16`, the name in UTF-8, the length of its compressed data as a little-endian
+//! `u64` and the data, compressed on its own like with [`Builder::compress`]. A name of zero
+//! bytes ends the archive. With [`Builder::external_model`] all entries are coded with the same
+//! model, so the per-entry results tell which inputs it suits poorly.
+use crate::{
+    builder::Builder,
+    container::{self, Progress},
+    error::Error,
+    huffman::Coder,
+};
+use std::io::Write;
+
+pub const ARCHIVE_MAGIC: [u8; 4] = *b"HMAR";
+
+/// Bytes of an archive outside of its entries, the magic and the end marker.
+pub const ARCHIVE_OVERHEAD: u64 = 6;
+
+/// How a single entry of an archive was compressed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EntryStats {
+    pub name: String,
+    pub original_bytes: u64,
+    /// Bytes of the entry in the archive, with its name and length.
+    pub compressed_bytes: u64,
+    /// Bytes written as literals.
+    pub escapes: u64,
+    /// No block was coded, the entry was stored as it is because coding would have expanded it.
+    pub stored: bool,
+}
+
+impl EntryStats {
+    /// Compressed size as a fraction of the original one, `None` for an empty entry.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.original_bytes > 0).then(|| self.compressed_bytes as f64 / self.original_bytes as f64)
+    }
+}
+
+/// What [`ArchiveWriter::finish`] wrote.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveSummary {
+    /// The entries in the order they were added.
+    pub per_entry: Vec<EntryStats>,
+    /// Sum of the original bytes of the entries.
+    pub original_bytes: u64,
+    /// Sum of the compressed bytes of the entries, the archive is [`ARCHIVE_OVERHEAD`] longer.
+    pub compressed_bytes: u64,
+    pub escapes: u64,
+}
+
+/// Writes inputs into an archive one by one, compressing each with the same builder.
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    builder: Builder,
+    per_entry: Vec<EntryStats>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W, builder: Builder) -> Result<Self, Error> {
+        builder.validate()?;
+        writer.write_all(&ARCHIVE_MAGIC)?;
+        Ok(ArchiveWriter {
+            writer,
+            builder,
+            per_entry: vec![],
+        })
+    }
+
+    /// Compresses `data` into the archive as the entry `name`, which must not be empty.
+    pub fn add(&mut self, name: &str, data: &[u8]) -> Result<&EntryStats, Error> {
+        self.add_with_progress(name, data, |_| {})
+    }
+
+    /// Adds an entry like [`ArchiveWriter::add`], passing the progress of compressing it to
+    /// `progress` like [`Builder::compress_with_progress`].
+    pub fn add_with_progress(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        progress: impl FnMut(Progress),
+    ) -> Result<&EntryStats, Error> {
+        let name_len = u16::try_from(name.len())
+            .ok()
+            .filter(|len| *len > 0)
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "archive entry names must be 1 to {} bytes",
+                    u16::MAX
+                ))
+            })?;
+        let (compressed, stats) = self.builder.compress_with_progress(data, progress)?;
+        self.writer.write_all(&name_len.to_le_bytes())?;
+        self.writer.write_all(name.as_bytes())?;
+        self.writer
+            .write_all(&(compressed.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&compressed)?;
+        self.per_entry.push(EntryStats {
+            name: name.into(),
+            original_bytes: data.len() as u64,
+            compressed_bytes: (2 + name.len() + 8 + compressed.len()) as u64,
+            escapes: stats.writer.escapes,
+            stored: stats.coded == 0,
+        });
+        Ok(self.per_entry.last().unwrap())
+    }
+
+    /// Ends the archive and flushes the writer.
+    pub fn finish(mut self) -> Result<ArchiveSummary, Error> {
+        self.writer.write_all(&[0, 0])?;
+        self.writer.flush()?;
+        let per_entry = self.per_entry;
+        Ok(ArchiveSummary {
+            original_bytes: per_entry.iter().map(|entry| entry.original_bytes).sum(),
+            compressed_bytes: per_entry.iter().map(|entry| entry.compressed_bytes).sum(),
+            escapes: per_entry.iter().map(|entry| entry.escapes).sum(),
+            per_entry,
+        })
+    }
+}
+
+/// The names and decompressed data of the entries of an archive, in order. Entries coded with
+/// an external model need it as `model`.
+pub fn read_archive(
+    mut data: &[u8],
+    model: Option<&Coder>,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    if take(&mut data, 4)? != ARCHIVE_MAGIC {
+        return Err(Error::Format("not an archive"));
+    }
+    let mut entries = vec![];
+    loop {
+        let name_len = u16::from_le_bytes(take(&mut data, 2)?.try_into().unwrap());
+        if name_len == 0 {
+            return Ok(entries);
+        }
+        let name = std::str::from_utf8(take(&mut data, name_len.into())?)
+            .map_err(|_| Error::Format("archive entry name is not UTF-8"))?;
+        let len = u64::from_le_bytes(take(&mut data, 8)?.try_into().unwrap());
+        let len = usize::try_from(len).map_err(|_| Error::Truncated)?;
+        let entry = container::decompress_model(
+            take(&mut data, len)?,
+            container::DEFAULT_MAX_LENGTH,
+            &Default::default(),
+            model,
+        )?;
+        entries.push((name.into(), entry));
+    }
+}
+
+// the next `len` bytes of `data`.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if data.len() < len {
+        return Err(Error::Truncated);
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::huffman::EscapeMode;
+
+    // text that compresses well next to random bytes that are stored.
+    fn inputs() -> Vec<(String, Vec<u8>)> {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let random = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        vec![
+            ("text".into(), include_bytes!("archive.rs").to_vec()),
+            ("random".into(), random),
+            ("empty".into(), vec![]),
+        ]
+    }
+
+    #[test]
+    fn test_per_entry() {
+        let mut archive = vec![];
+        let mut writer = ArchiveWriter::new(&mut archive, Builder::new().depth(2)).unwrap();
+        for (name, data) in inputs() {
+            writer.add(&name, &data).unwrap();
+        }
+        let summary = writer.finish().unwrap();
+
+        let [text, random, empty] = &summary.per_entry[..] else {
+            panic!("{:?}", summary.per_entry);
+        };
+        assert_eq!(text.name, "text");
+        assert!(text.ratio().unwrap() < 0.8);
+        assert!(!text.stored);
+        assert!(random.ratio().unwrap() > 1.0);
+        assert!(random.stored);
+        assert_eq!(random.escapes, 0);
+        assert_eq!(empty.ratio(), None);
+        assert!(empty.stored);
+
+        let sum = |field: fn(&EntryStats) -> u64| summary.per_entry.iter().map(field).sum::<u64>();
+        assert_eq!(summary.original_bytes, sum(|entry| entry.original_bytes));
+        assert_eq!(
+            summary.compressed_bytes,
+            sum(|entry| entry.compressed_bytes)
+        );
+        assert_eq!(summary.escapes, sum(|entry| entry.escapes));
+        assert_eq!(
+            archive.len() as u64,
+            summary.compressed_bytes + ARCHIVE_OVERHEAD
+        );
+        assert_eq!(read_archive(&archive, None).unwrap(), inputs());
+    }
+
+    #[test]
+    fn test_shared_model() {
+        let model = Builder::new().depth(2).escape(EscapeMode::Literal);
+        let coder = model
+            .build_coder(&model.train(b"the quick brown fox the").unwrap())
+            .unwrap();
+        let builder = Builder::new()
+            .depth(2)
+            .escape(EscapeMode::Literal)
+            .external_model(coder.clone());
+        let mut archive = vec![];
+        let mut writer = ArchiveWriter::new(&mut archive, builder).unwrap();
+        writer
+            .add("fox", &b"the quick brown fox ".repeat(20))
+            .unwrap();
+        writer
+            .add("numbered", &b"the quick brown fox 7 ".repeat(20))
+            .unwrap();
+        writer.add("digits", &b"0123456789".repeat(40)).unwrap();
+        let summary = writer.finish().unwrap();
+        let [fox, numbered, digits] = &summary.per_entry[..] else {
+            panic!("{:?}", summary.per_entry);
+        };
+        assert_eq!(fox.escapes, 0);
+        assert!(numbered.escapes > 0);
+        assert!(!fox.stored && !numbered.stored);
+        assert!(fox.ratio() < numbered.ratio());
+        // bytes the model has never seen are all escapes, which expand the entry.
+        assert!(digits.stored);
+        assert!(numbered.ratio() < digits.ratio());
+
+        let entries = read_archive(&archive, Some(&coder)).unwrap();
+        assert_eq!(entries[2].1, b"0123456789".repeat(40));
+        assert!(read_archive(&archive, None).is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut writer = ArchiveWriter::new(vec![], Builder::new()).unwrap();
+        assert!(matches!(writer.add("", b"data"), Err(Error::Config(_))));
+        assert!(writer.finish().unwrap().per_entry.is_empty());
+
+        assert!(matches!(read_archive(b"HMKV", None), Err(Error::Format(_))));
+        assert!(matches!(
+            read_archive(b"HMAR\x01", None),
+            Err(Error::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,782 @@
+use crate::{
+    alphabet::AlphabetMap,
+    container::{self, BlockStats, Codec, Progress},
+    error::Error,
+    filter::BuiltinFilter,
+    huffman::{CodeOptions, Coder, Decoder, Encoder, EscapeMode, LazyDecoder, MAX_CODE_LENGTH},
+    markov::{
+        check_depth, Limited, Markov, MarkovWriter, Sampling, SamplingWriter, SequenceWriter,
+        TrainLimits, TrainStats, TrainSummary, WeightPolicy, Weighted, DEFAULT_WEIGHT,
+    },
+    range::RangeEncoder,
+    util::{ByteMapper, CancellationToken},
+};
+use std::{
+    io::{copy, Read},
+    sync::Arc,
+};
+
+pub const DEFAULT_DEPTH: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Builder {
+    pub(crate) depth: usize,
+    pub(crate) prune_below: usize,
+    pub(crate) top_successors: Option<usize>,
+    pub(crate) limits: TrainLimits,
+    pub(crate) options: CodeOptions,
+    pub(crate) codec: Codec,
+    pub(crate) block_size: usize,
+    pub(crate) sync_interval: Option<usize>,
+    pub(crate) restrict_alphabet: bool,
+    pub(crate) sampling: Option<Sampling>,
+    pub(crate) byte_map: Option<ByteMapper>,
+    pub(crate) lossy_byte_map: bool,
+    pub(crate) filter: Option<BuiltinFilter>,
+    pub(crate) progress_interval: u64,
+    pub(crate) cancel: Option<CancellationToken>,
+    pub(crate) increment: usize,
+    pub(crate) model: Option<Arc<Coder>>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            depth: DEFAULT_DEPTH,
+            prune_below: 0,
+            top_successors: None,
+            limits: TrainLimits::default(),
+            options: CodeOptions::default(),
+            codec: Codec::default(),
+            block_size: container::DEFAULT_BLOCK_SIZE,
+            sync_interval: None,
+            restrict_alphabet: false,
+            sampling: None,
+            byte_map: None,
+            lossy_byte_map: false,
+            filter: None,
+            progress_interval: container::DEFAULT_PROGRESS_INTERVAL,
+            cancel: None,
+            increment: DEFAULT_WEIGHT,
+            model: None,
+        }
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    pub fn smoothing(mut self, smoothing: usize) -> Self {
+        self.options.smoothing = smoothing;
+        self
+    }
+
+    pub fn prune_below(mut self, threshold: usize) -> Self {
+        self.prune_below = threshold;
+        self
+    }
+
+    pub fn top_successors(mut self, k: usize) -> Self {
+        self.top_successors = Some(k);
+        self
+    }
+
+    /// Weight every window adds while training, 1 by default, see [`MarkovWriter::with_weight`].
+    /// Thresholds such as [`Builder::prune_below`] compare against the weights it results in.
+    /// [`Builder::train_weighted`] takes its weights from its function instead.
+    pub fn increment(mut self, increment: usize) -> Self {
+        self.increment = increment;
+        self
+    }
+
+    /// What adding weights does once they overflow, both while training and when building the
+    /// codes, [`WeightPolicy::Saturate`] by default.
+    pub fn weight_policy(mut self, policy: WeightPolicy) -> Self {
+        self.options.weight_policy = policy;
+        self
+    }
+
+    pub fn limits(mut self, limits: TrainLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn max_code_length(mut self, length: u8) -> Self {
+        self.options.max_code_length = length;
+        self
+    }
+
+    pub fn escape(mut self, escape: EscapeMode) -> Self {
+        self.options.escape = escape;
+        self
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn block_size(mut self, size: usize) -> Self {
+        self.block_size = size;
+        self
+    }
+
+    /// Stores block checksums and puts a sync marker in front of the first block after every
+    /// `interval` bytes of output, for [`container::decompress_recover`] to resume at.
+    pub fn sync_interval(mut self, interval: usize) -> Self {
+        self.sync_interval = Some(interval);
+        self
+    }
+
+    /// Restricts the codes to the bytes the model was trained on, see [`AlphabetMap`]. This
+    /// shrinks the tables of small alphabets, most of all with smoothing.
+    pub fn restrict_alphabet(mut self, restrict: bool) -> Self {
+        self.restrict_alphabet = restrict;
+        self
+    }
+
+    /// Gives every context an end of stream symbol, which [`huffman::HuffmanWriter::finish`] writes
+    /// so the data can be decoded without knowing its length, see
+    /// [`Coder::decode_until_eof`]. Only the huffman codec supports it.
+    ///
+    /// [`huffman::HuffmanWriter::finish`]: crate::huffman::HuffmanWriter::finish
+    pub fn eof(mut self, eof: bool) -> Self {
+        self.options.eof = eof;
+        self
+    }
+
+    /// Trains only on the chunks of the input selected by `sampling`, see [`SamplingWriter`].
+    pub fn sample(mut self, sampling: Sampling) -> Self {
+        self.sampling = Some(sampling);
+        self
+    }
+
+    /// Replaces the bytes of the input by `mapper` before compressing. The mapper has to be a
+    /// bijection, it is stored with the data and undone when decompressing.
+    pub fn byte_map(mut self, mapper: ByteMapper) -> Self {
+        self.byte_map = Some(mapper);
+        self.lossy_byte_map = false;
+        self
+    }
+
+    /// Replaces the bytes of the input by any `mapper` before compressing, such as
+    /// [`ByteMapper::ascii_lowercase`]. Nothing is stored, decompressing yields the mapped data.
+    pub fn lossy_byte_map(mut self, mapper: ByteMapper) -> Self {
+        self.byte_map = Some(mapper);
+        self.lossy_byte_map = true;
+        self
+    }
+
+    /// Filters the input before compressing, such as with deltas for numeric data. The filter is
+    /// stored with the data and inverted when decompressing.
+    pub fn filter(mut self, filter: BuiltinFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Number of input bytes between calls of the callback of
+    /// [`Builder::compress_with_progress`].
+    pub fn progress_interval(mut self, bytes: u64) -> Self {
+        self.progress_interval = bytes;
+        self
+    }
+
+    /// Checks `token` while training, building trees and coding, failing with
+    /// [`Error::Cancelled`] once it is set.
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    // fails if the token of the builder was cancelled.
+    pub(crate) fn check_cancelled(&self) -> Result<(), Error> {
+        self.cancel
+            .as_ref()
+            .map_or(Ok(()), CancellationToken::check)
+    }
+
+    // markov writer with the increment of the builder, checking its token.
+    fn writer<S: SequenceWriter>(&self, writer: S) -> MarkovWriter<S> {
+        self.writer_with_weight(writer, self.increment)
+    }
+
+    fn writer_with_weight<S: SequenceWriter>(&self, writer: S, weight: usize) -> MarkovWriter<S> {
+        let writer = MarkovWriter::with_weight(writer, weight);
+        match &self.cancel {
+            Some(token) => writer.with_cancellation(token.clone()),
+            None => writer,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        check_depth(self.depth)?;
+
+        if self.increment == 0 {
+            return Err(Error::Config("increment must be at least 1".into()));
+        }
+
+        if self.block_size == 0 || u32::try_from(self.block_size).is_err() {
+            return Err(Error::Config(format!(
+                "block size {} must be between 1 and {}",
+                self.block_size,
+                u32::MAX
+            )));
+        }
+
+        if self.options.eof && self.codec == Codec::Range {
+            return Err(Error::Config(
+                "end symbols are not supported by the range codec".into(),
+            ));
+        }
+
+        // every context may need to hold all 256 bytes, plus the escape and end symbols.
+        let minimum = match self.options.escape {
+            EscapeMode::None if !self.options.eof => 8,
+            _ => 9,
+        };
+        if !(minimum..=MAX_CODE_LENGTH).contains(&self.options.max_code_length) {
+            return Err(Error::Config(format!(
+                "max code length {} must be between {minimum} and {MAX_CODE_LENGTH} with {} escapes",
+                self.options.max_code_length, self.options.escape
+            )));
+        }
+
+        if let Some(filter) = &self.filter {
+            if filter.parameter() == 0 || u32::try_from(filter.parameter()).is_err() {
+                return Err(Error::Config(format!(
+                    "filter parameter {} must be between 1 and {}",
+                    filter.parameter(),
+                    u32::MAX
+                )));
+            }
+        }
+
+        if let Some(model) = &self.model {
+            if self.codec != Codec::Huffman {
+                return Err(Error::Config(
+                    "external models are only supported by the huffman codec".into(),
+                ));
+            }
+            if model.depth() != self.depth {
+                return Err(Error::ModelMismatch {
+                    expected: self.depth,
+                    found: model.depth(),
+                });
+            }
+        }
+
+        if let Some(mapper) = &self.byte_map {
+            if !self.lossy_byte_map && !mapper.is_bijection() {
+                return Err(Error::Config(
+                    "byte map is not a bijection, use a lossy byte map to compress with it".into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn build_markov(&self) -> Result<Markov, Error> {
+        self.validate()?;
+        Ok(Markov::new(self.depth).with_weight_policy(self.options.weight_policy))
+    }
+
+    pub fn train(&self, data: &[u8]) -> Result<Markov, Error> {
+        Ok(self.train_counted(data)?.0)
+    }
+
+    // trains on `data`, also returning the number of windows trained on.
+    pub(crate) fn train_counted(&self, data: &[u8]) -> Result<(Markov, u64), Error> {
+        // without limits to check or windows to skip, the whole input can be inserted in bulk.
+        if self.limits != TrainLimits::default() || self.sampling.is_some() {
+            return self.train_reader_counted(data);
+        }
+
+        let mut markov = self.build_markov()?;
+        match &self.cancel {
+            Some(token) => markov.insert_run_cancellable(data, token)?,
+            None => markov.insert_run(data),
+        }
+        if self.increment != DEFAULT_WEIGHT {
+            markov.scale_weights(self.increment)?;
+        }
+        self.finish_model(&mut markov);
+        let windows = (data.len() + 1).saturating_sub(self.depth) as u64;
+        Ok((markov, windows))
+    }
+
+    /// Trains a model, inserting every window with the weight `weight_fn` returns for its index.
+    pub fn train_weighted(
+        &self,
+        data: &[u8],
+        weight_fn: impl FnMut(u64) -> usize,
+    ) -> Result<Markov, Error> {
+        let mut markov = self.build_markov()?;
+        let sampled = SamplingWriter::new(
+            Limited::new(&mut markov, self.limits),
+            self.sampling.unwrap_or_default(),
+        );
+        self.writer(Weighted::new(sampled, weight_fn))
+            .try_write(data)?;
+        self.finish_model(&mut markov);
+        Ok(markov)
+    }
+
+    pub fn train_reader<R: Read>(&self, reader: R) -> Result<Markov, Error> {
+        Ok(self.train_reader_counted(reader)?.0)
+    }
+
+    /// Trains like [`Builder::train_reader`], also returning the number of windows trained on,
+    /// which is the number of input bytes used with [`Builder::sample`].
+    pub fn train_reader_counted<R: Read>(&self, reader: R) -> Result<(Markov, u64), Error> {
+        let (markov, stats) = self.train_reader_stats(reader)?;
+        Ok((markov, stats.trained()))
+    }
+
+    /// Trains like [`Builder::train_reader`], also returning what was trained on.
+    pub fn train_reader_stats<R: Read>(
+        &self,
+        mut reader: R,
+    ) -> Result<(Markov, TrainStats), Error> {
+        let mut markov = self.build_markov()?;
+        let mut writer = self.writer(SamplingWriter::new(
+            Limited::new(&mut markov, self.limits),
+            self.sampling.unwrap_or_default(),
+        ));
+        copy(&mut reader, &mut writer)?;
+        let stats = writer.stats();
+        self.finish_model(&mut markov);
+        Ok((markov, stats))
+    }
+
+    /// Trains like [`Markov::train_sources`], with the increment of every window multiplied by
+    /// the weight of its source, and the limits, sampling and pruning of the builder.
+    pub fn train_sources<R: Read>(
+        &self,
+        sources: impl IntoIterator<Item = (R, usize)>,
+    ) -> Result<(Markov, TrainSummary), Error> {
+        let mut markov = self.build_markov()?;
+        let mut summary = TrainSummary::default();
+        for (mut reader, weight) in sources {
+            let sampled = SamplingWriter::new(
+                Limited::new(&mut markov, self.limits),
+                self.sampling.unwrap_or_default(),
+            );
+            let mut writer =
+                self.writer_with_weight(sampled, self.increment.saturating_mul(weight));
+            summary.bytes.push(copy(&mut reader, &mut writer)?);
+            summary.stats += writer.stats();
+        }
+        self.finish_model(&mut markov);
+        Ok((markov, summary))
+    }
+
+    /// Codes every block with `coder` instead of a model trained on the block, so that blocks
+    /// carry no tables. The header stores its [`Coder::content_hash`], decompressing needs the
+    /// same model, see [`container::decompress_with_model`]. The training and code options of
+    /// the builder do not apply, the codec has to be huffman and the depth that of `coder`.
+    pub fn external_model(mut self, coder: impl Into<Arc<Coder>>) -> Self {
+        self.model = Some(coder.into());
+        self
+    }
+
+    fn finish_model(&self, markov: &mut Markov) {
+        if self.prune_below > 1 {
+            markov.prune(self.prune_below);
+        }
+        if let Some(k) = self.top_successors {
+            markov.cap_successors(k);
+        }
+    }
+
+    pub fn coder_from(&self, markov: &Markov) -> Result<(Encoder, Decoder), Error> {
+        let decoder = self.build_coder(markov)?.decoder();
+        Ok((decoder.encoder(), decoder))
+    }
+
+    fn check_model(&self, markov: &Markov) -> Result<(), Error> {
+        self.validate()?;
+        if markov.len() != self.depth {
+            return Err(Error::ModelMismatch {
+                expected: self.depth,
+                found: markov.len(),
+            });
+        }
+        Ok(())
+    }
+
+    // code options for a model, with its alphabet if restricted.
+    fn code_options(&self, markov: &Markov) -> CodeOptions {
+        let mut options = self.options;
+        if self.restrict_alphabet {
+            options.alphabet = AlphabetMap::new(markov.alphabet());
+        }
+        options
+    }
+
+    pub fn build_coder(&self, markov: &Markov) -> Result<Coder, Error> {
+        self.check_model(markov)?;
+        let options = self.code_options(markov);
+        let token = self.cancel.clone().unwrap_or_default();
+        Ok(Decoder::with_options_cancellable(markov, &options, &token)?.into())
+    }
+
+    /// Decoder building the trees of [`Builder::build_coder`] only for the contexts it decodes
+    /// in, see [`Decoder::lazy`].
+    pub fn build_lazy_decoder<'a>(&self, markov: &'a Markov) -> Result<LazyDecoder<'a>, Error> {
+        self.check_model(markov)?;
+        Ok(LazyDecoder::new(markov, &self.code_options(markov)))
+    }
+
+    pub fn build_range_encoder(&self, markov: &Markov) -> Result<RangeEncoder, Error> {
+        self.check_model(markov)?;
+        self.check_cancelled()?;
+        Ok(RangeEncoder::with_options(
+            markov,
+            &self.code_options(markov),
+        ))
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(self.compress_with_stats(data)?.0)
+    }
+
+    pub fn compress_with_stats(&self, data: &[u8]) -> Result<(Vec<u8>, BlockStats), Error> {
+        self.compress_with_progress(data, |_| {})
+    }
+
+    /// Compresses like [`Builder::compress_with_stats`], passing the progress to `progress` at
+    /// most once per [`Builder::progress_interval`] bytes.
+    pub fn compress_with_progress(
+        &self,
+        data: &[u8],
+        mut progress: impl FnMut(Progress),
+    ) -> Result<(Vec<u8>, BlockStats), Error> {
+        // pruned windows can only be encoded through escapes.
+        if self.prune_below > 1 && self.options.escape == EscapeMode::None {
+            return Err(Error::Config(
+                "pruning the model for compression requires an escape mode".into(),
+            ));
+        }
+        // so can the windows that were not sampled.
+        if self.sampling.is_some() && self.options.escape == EscapeMode::None {
+            return Err(Error::Config(
+                "sampling the input for compression requires an escape mode".into(),
+            ));
+        }
+        container::compress_blocks_with_progress(data, self, &mut progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decompress_bytes, huffman::WeightedItem, MAX_SUPPORTED_DEPTH};
+
+    #[test]
+    fn test_top_successors() {
+        let data = include_bytes!("markov.rs");
+        let builder = Builder::new().depth(3).top_successors(4);
+        let markov = builder.train(data).unwrap();
+        assert!(markov.has_escapes());
+        assert!(markov.iter_prefix().all(|(_, items)| items.len() <= 4));
+
+        let compressed = builder.compress(data).unwrap();
+        assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_train_weighted() {
+        let data = include_bytes!("builder.rs");
+        let builder = Builder::new().depth(3).top_successors(8);
+        assert_eq!(
+            builder.train_weighted(data, |_| 1).unwrap(),
+            builder.train(data).unwrap()
+        );
+
+        let doubled = builder.train_weighted(data, |_| 2).unwrap();
+        let (sources, summary) = builder.train_sources([(&data[..], 2)]).unwrap();
+        assert_eq!(sources, doubled);
+        assert_eq!(summary.bytes, [data.len() as u64]);
+        let markov = builder.train(data).unwrap();
+        assert!(doubled
+            .iter()
+            .zip(markov.iter())
+            .all(|(a, b)| a.0 == b.0 && a.1 == 2 * b.1));
+    }
+
+    #[test]
+    fn test_weight_policy() {
+        let builder = Builder::new().depth(2).weight_policy(WeightPolicy::Error);
+        let mut markov = builder.build_markov().unwrap();
+        assert_eq!(markov.weight_policy(), WeightPolicy::Error);
+        markov.insert(b"ab", usize::MAX / 2 + 1).unwrap();
+        markov.insert(b"ac", usize::MAX / 2 + 1).unwrap();
+        markov.insert(b"ad", 1).unwrap();
+        assert!(matches!(
+            markov.insert(b"ab", usize::MAX),
+            Err(Error::WeightOverflow)
+        ));
+
+        // the weights of the context only overflow when its tree is built.
+        assert!(matches!(
+            builder.build_coder(&markov),
+            Err(Error::WeightOverflow)
+        ));
+        let builder = builder.weight_policy(WeightPolicy::Scale);
+        assert!(builder.build_coder(&markov).is_ok());
+        let builder = builder.weight_policy(WeightPolicy::Saturate);
+        assert!(builder.build_coder(&markov).is_ok());
+    }
+
+    #[test]
+    fn test_increment() {
+        let data = include_bytes!("builder.rs");
+        let builder = Builder::new().depth(3).top_successors(8);
+        let mut expected = builder.train(data).unwrap();
+        expected.scale_weights(16).unwrap();
+
+        // the bulk and the windowed path agree, escape weights scale as well.
+        let builder = builder.increment(16);
+        assert_eq!(builder.train(data).unwrap(), expected);
+        assert_eq!(builder.train_reader(&data[..]).unwrap(), expected);
+        assert!(expected.has_escapes());
+
+        assert!(matches!(
+            builder.increment(0).train(data),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_increment_overflow() {
+        let increment = usize::MAX / 2 + 1;
+        let builder = Builder::new().depth(1).increment(increment);
+
+        // the bulk and the windowed path apply the weight policy alike.
+        let builder = builder.weight_policy(WeightPolicy::Error);
+        assert!(matches!(builder.train(b"aab"), Err(Error::WeightOverflow)));
+        assert!(matches!(
+            builder.train_reader(&b"aab"[..]),
+            Err(Error::WeightOverflow)
+        ));
+        assert!(builder.train(b"ab").is_ok());
+
+        let builder = builder.weight_policy(WeightPolicy::Scale);
+        let markov = builder.train(b"aab").unwrap();
+        assert_eq!(markov, builder.train_reader(&b"aab"[..]).unwrap());
+        let weights: Vec<_> = markov.iter().map(|(_, weight)| weight).collect();
+        assert_eq!(weights, [increment, increment]);
+
+        let builder = builder.weight_policy(WeightPolicy::Saturate);
+        let markov = builder.train(b"aab").unwrap();
+        assert_eq!(markov, builder.train_reader(&b"aab"[..]).unwrap());
+        let weights: Vec<_> = markov.iter().map(|(_, weight)| weight).collect();
+        assert_eq!(weights, [usize::MAX, increment]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let data: Vec<u8> = (0..=255).collect();
+        let limited = |limits| Builder::new().depth(2).limits(limits).train(&data);
+        assert!(limited(TrainLimits::default()).is_ok());
+        assert!(limited(TrainLimits {
+            max_sequences: Some(255),
+            max_memory: None,
+        })
+        .is_ok());
+        assert!(matches!(
+            limited(TrainLimits {
+                max_sequences: Some(254),
+                max_memory: None,
+            }),
+            Err(Error::LimitExceeded {
+                kind: "sequence",
+                limit: 254
+            })
+        ));
+        assert!(matches!(
+            limited(TrainLimits {
+                max_sequences: None,
+                max_memory: Some(1024),
+            }),
+            Err(Error::LimitExceeded { kind: "memory", .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_configurations() {
+        assert!(matches!(
+            Builder::new().depth(0).build_markov(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            Builder::new().depth(MAX_SUPPORTED_DEPTH + 1).train(b""),
+            Err(Error::Config(_))
+        ));
+        assert!(Builder::new().depth(MAX_SUPPORTED_DEPTH).train(b"").is_ok());
+        assert!(matches!(
+            Builder::new().max_code_length(16).build_markov(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            Builder::new()
+                .max_code_length(8)
+                .escape(EscapeMode::Literal)
+                .build_markov(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            Builder::new().depth(3).coder_from(&Markov::new(2)),
+            Err(Error::ModelMismatch {
+                expected: 3,
+                found: 2
+            })
+        ));
+        assert!(matches!(
+            Builder::new().prune_below(2).compress(b"hello"),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_prune_with_escapes() {
+        let data = b"the quick brown fox jumps over the lazy dog, the end.";
+        let builder = Builder::new()
+            .depth(3)
+            .prune_below(2)
+            .escape(EscapeMode::Literal);
+        let markov = builder.train(data).unwrap();
+        assert!(markov.iter().all(|(_, weight)| weight >= 2));
+
+        let compressed = builder.compress(data).unwrap();
+        assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let builder = Builder::new().depth(2).smoothing(1);
+        let markov = builder.train(b"abab").unwrap();
+        let (encoder, decoder) = builder.coder_from(&markov).unwrap();
+        assert_eq!(decoder.trees.len(), 2);
+
+        // every byte is encodable in trained contexts.
+        assert_eq!(encoder.prefixes[&b"a"[..]].len(), 256);
+        let encoded = encoder.encode_all(b"aab").unwrap();
+        assert_eq!(decoder.decode_all(b"a", &encoded, 2).unwrap(), b"ab");
+    }
+
+    // a synthetic genome, where every context can be followed by any of the four bases.
+    fn genome(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                b"ACGT"[(state % 4) as usize]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_restrict_alphabet() {
+        let data = genome(4096);
+        let builder = Builder::new().depth(4).smoothing(1);
+        let markov = builder.train(&data).unwrap();
+        assert_eq!(markov.alphabet(), b"ACGT");
+
+        let tables = |builder: &Builder| {
+            let coder = builder.build_coder(&markov).unwrap();
+            let mut tables = vec![];
+            coder.write_tables(&mut tables).unwrap();
+            (coder, tables)
+        };
+        let (full, full_tables) = tables(&builder);
+        let (restricted, restricted_tables) = tables(&builder.clone().restrict_alphabet(true));
+        assert_eq!(full.tree(b"ACG").unwrap().iter().count(), 256);
+        assert_eq!(restricted.tree(b"ACG").unwrap().iter().count(), 4);
+        assert!(restricted_tables.len() * 10 < full_tables.len());
+        assert_eq!(
+            Coder::read_tables(&mut &restricted_tables[..]).unwrap(),
+            restricted
+        );
+
+        // bytes outside of the alphabet are escaped.
+        let builder = builder.restrict_alphabet(true).escape(EscapeMode::Literal);
+        let coder = builder.build_coder(&markov).unwrap();
+        let input = [&data[..64], b"NNACGT"].concat();
+        let encoded = coder.encode_all(&input).unwrap();
+        assert_eq!(
+            coder.decode_all(&input[..3], &encoded, 67).unwrap(),
+            input[3..]
+        );
+
+        // rebuilt contexts keep the encoder and the decoder on the same alphabet.
+        let items = [WeightedItem::new(b'A', 3), WeightedItem::new(b'N', 2)];
+        let mut decoder = coder.decoder();
+        let mut encoder = decoder.encoder();
+        decoder.rebuild_context(b"ACG", &items).unwrap();
+        encoder.rebuild_context(b"ACG", &items).unwrap();
+        assert_eq!(encoder, decoder.encoder());
+        let input = b"ACGANANACGTACGACGN";
+        let encoded = encoder.encode_all(input).unwrap();
+        assert_eq!(
+            decoder
+                .decode_all(b"ACG", &encoded, input.len() - 3)
+                .unwrap(),
+            input[3..]
+        );
+
+        for codec in [Codec::Huffman, Codec::Range] {
+            let builder = Builder::new().depth(4).smoothing(1).codec(codec);
+            let full = builder.compress(&data).unwrap();
+            let restricted = builder.restrict_alphabet(true).compress(&data).unwrap();
+            assert!(restricted.len() < full.len());
+            assert_eq!(decompress_bytes(&full).unwrap(), data);
+            assert_eq!(decompress_bytes(&restricted).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_eof() {
+        let data = genome(4096);
+        let builder = Builder::new().depth(4).eof(true);
+        let coder = builder.build_coder(&builder.train(&data).unwrap()).unwrap();
+        let encoded = coder.encode_all(&data).unwrap();
+        assert_eq!(
+            coder.decode_until_eof(&data[..3], &encoded).unwrap(),
+            data[3..]
+        );
+        assert_eq!(
+            decompress_bytes(&builder.compress(&data).unwrap()).unwrap(),
+            data
+        );
+
+        assert!(matches!(
+            builder.codec(Codec::Range).validate(),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_code_length() {
+        let mut data = vec![];
+        for byte in 0..40u8 {
+            data.extend(std::iter::repeat_n([0, byte], 1 << (byte / 3)).flatten());
+        }
+        let builder = Builder::new().depth(2).max_code_length(10);
+        let markov = builder.train(&data).unwrap();
+        let (encoder, _) = builder.coder_from(&markov).unwrap();
+        assert!(encoder.prefixes[&[0][..]]
+            .values()
+            .all(|code| code.len() <= 10));
+    }
+}
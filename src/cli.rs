@@ -0,0 +1,7 @@
+//! Helpers shared by the subcommands of the command line tool.
+#[cfg(feature = "compare")]
+pub mod compare;
+pub mod output;
+pub mod render;
+pub mod report;
+pub mod watch;
@@ -0,0 +1,48 @@
+//! General purpose codecs that `compare` measures the models against, see [`Reference`].
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    fmt,
+    io::{Result as IoResult, Write},
+    str::FromStr,
+};
+
+/// Codec compressing at its default level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reference {
+    Gzip,
+    Zstd,
+}
+
+impl Reference {
+    pub fn compress(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+        match self {
+            Reference::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Reference::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+}
+
+impl FromStr for Reference {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "gzip" => Ok(Reference::Gzip),
+            "zstd" => Ok(Reference::Zstd),
+            other => Err(format!("unknown codec {other:?}, expected gzip or zstd")),
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reference::Gzip => write!(f, "gzip"),
+            Reference::Zstd => write!(f, "zstd"),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::{anyhow, Result};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{stdout, BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+/// Where a subcommand writes its output, either standard output or a file.
+///
+/// Files are written to a `.tmp` sibling that is renamed over the destination once writing
+/// succeeded, so that interrupted runs never leave truncated outputs behind. Existing files and
+/// terminals are only written to when forced.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutputTarget {
+    Stdout { force: bool },
+    File { path: PathBuf, force: bool },
+}
+
+impl OutputTarget {
+    pub fn new(path: Option<&Path>, force: bool) -> Self {
+        match path {
+            Some(path) => OutputTarget::File {
+                path: path.into(),
+                force,
+            },
+            None => OutputTarget::Stdout { force },
+        }
+    }
+
+    /// Runs `write` against the target, cleaning up the temporary file if it fails.
+    ///
+    /// Binary output is refused on a terminal unless forced.
+    pub fn write_with<F>(&self, binary: bool, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<()>,
+    {
+        match self {
+            OutputTarget::Stdout { force } => {
+                let stdout = stdout();
+                if binary && !force && stdout.is_terminal() {
+                    return Err(anyhow!(
+                        "refusing to write binary output to a terminal, use --output or --force"
+                    ));
+                }
+                let mut writer = BufWriter::new(stdout.lock());
+                write(&mut writer)?;
+                writer.flush()?;
+                Ok(())
+            }
+            OutputTarget::File { path, force } => {
+                if !force && path.exists() {
+                    return Err(anyhow!(
+                        "refusing to overwrite {}, use --force",
+                        path.display()
+                    ));
+                }
+
+                let temporary = temporary_path(path);
+                let result = write_file(&temporary, write)
+                    .and_then(|()| fs::rename(&temporary, path).map_err(Into::into));
+                if result.is_err() {
+                    let _ = fs::remove_file(&temporary);
+                }
+                result
+            }
+        }
+    }
+}
+
+fn write_file<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(&mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|error| error.into_error())?
+        .sync_all()?;
+    Ok(())
+}
+
+/// Sibling of `path` that output is written to before it is renamed into place.
+pub fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
@@ -0,0 +1,91 @@
+//! Text renderings of binary data for the terminal.
+use std::fmt::Write;
+
+// bytes per line of a hexdump, in two groups of eight.
+const LINE: usize = 16;
+
+/// Renders `bytes` like `hexdump -C`, one line of sixteen bytes each: the offset in hex, the
+/// bytes in two groups of eight and the printable ASCII ones between bars, with dots for all
+/// others. Lines are aligned even when the last one is short, `start` is the offset of the
+/// first byte.
+pub fn hexdump(bytes: &[u8], start: u64) -> String {
+    let mut output = String::new();
+    for (index, line) in bytes.chunks(LINE).enumerate() {
+        let offset = start + (index * LINE) as u64;
+        write!(output, "{offset:08x} ").unwrap();
+        for position in 0..LINE {
+            if position % 8 == 0 {
+                output.push(' ');
+            }
+            match line.get(position) {
+                Some(byte) => write!(output, "{byte:02x} ").unwrap(),
+                None => output.push_str("   "),
+            }
+        }
+        output.push_str(" |");
+        output.extend(line.iter().map(|byte| printable(*byte)));
+        output.push_str("|\n");
+    }
+    output
+}
+
+// the byte itself if it is printable ASCII, a dot otherwise.
+fn printable(byte: u8) -> char {
+    match byte {
+        b' '..=b'~' => char::from(byte),
+        _ => '.',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hexdump() {
+        assert_eq!(
+            hexdump(b"the quick brown fox\n\x00\xff", 0),
+            "00000000  74 68 65 20 71 75 69 63  6b 20 62 72 6f 77 6e 20  |the quick brown |\n\
+             00000010  66 6f 78 0a 00 ff                                 |fox...|\n"
+        );
+        assert_eq!(hexdump(b"", 0), "");
+    }
+
+    #[test]
+    fn test_hexdump_alignment() {
+        // the sidebar starts in the same column however long the line is.
+        for len in 1..=2 * LINE {
+            let bytes: Vec<u8> = (0..len as u8).map(|byte| b'a' + byte % 26).collect();
+            let dump = hexdump(&bytes, 0);
+            assert_eq!(dump.lines().count(), len.div_ceil(LINE));
+            for line in dump.lines() {
+                assert_eq!(line.find('|'), Some(60), "{line:?}");
+                assert!(line.ends_with('|'));
+            }
+        }
+    }
+
+    #[test]
+    fn test_hexdump_escaping() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let sidebar: String = hexdump(&bytes, 0)
+            .lines()
+            .map(|line| line[61..line.len() - 1].to_string())
+            .collect();
+        assert_eq!(sidebar.chars().count(), 256);
+        assert!(sidebar.chars().all(|c| c.is_ascii_graphic() || c == ' '));
+        assert_eq!(
+            &sidebar[32..127],
+            std::str::from_utf8(&bytes[32..127]).unwrap()
+        );
+        assert_eq!(sidebar[..32], *".".repeat(32));
+        assert_eq!(sidebar[127..], *".".repeat(129));
+    }
+
+    #[test]
+    fn test_hexdump_offset() {
+        let dump = hexdump(&[0; 20], 0xfff0);
+        let offsets: Vec<&str> = dump.lines().map(|line| &line[..8]).collect();
+        assert_eq!(offsets, ["0000fff0", "00010000"]);
+    }
+}
@@ -0,0 +1,73 @@
+//! Results of the subcommands, printed once a command is done, as text or with `--json` as a
+//! single JSON document.
+use serde_json::Value;
+use std::io::{stderr, stdout, Result as IoResult, Write};
+
+/// Result of a subcommand.
+pub trait Report {
+    /// Writes the result for people to read.
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()>;
+
+    /// The result as a JSON document.
+    fn to_json(&self) -> Value;
+}
+
+/// What a subcommand returns, a report and where it goes.
+pub struct Outcome {
+    report: Option<Box<dyn Report>>,
+    // the text goes to standard error, next to the data the command writes.
+    summary: bool,
+    // standard output carries binary data, so the JSON document goes to standard error.
+    binary_stdout: bool,
+}
+
+impl Outcome {
+    /// Outcome of a command that has nothing to report, like one that prints a script.
+    pub fn none() -> Self {
+        Outcome {
+            report: None,
+            summary: false,
+            binary_stdout: false,
+        }
+    }
+
+    /// Report that is the output of the command, printed on standard output.
+    pub fn listing(report: impl Report + 'static) -> Self {
+        Outcome {
+            report: Some(Box::new(report)),
+            summary: false,
+            binary_stdout: false,
+        }
+    }
+
+    /// Report of a command that writes data, whose text goes to standard error. The JSON
+    /// document does too if the data went to standard output.
+    pub fn summary(report: impl Report + 'static, binary_stdout: bool) -> Self {
+        Outcome {
+            report: Some(Box::new(report)),
+            summary: true,
+            binary_stdout,
+        }
+    }
+
+    /// Report of a failed command, printed on standard error either way.
+    pub fn error(report: impl Report + 'static) -> Self {
+        Outcome {
+            report: Some(Box::new(report)),
+            summary: true,
+            binary_stdout: true,
+        }
+    }
+
+    pub fn print(&self, json: bool) -> IoResult<()> {
+        let Some(report) = &self.report else {
+            return Ok(());
+        };
+        match (json, self.summary) {
+            (true, _) if self.binary_stdout => writeln!(stderr(), "{}", report.to_json()),
+            (true, _) => writeln!(stdout(), "{}", report.to_json()),
+            (false, true) => report.write_text(&mut stderr().lock()),
+            (false, false) => report.write_text(&mut stdout().lock()),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Watching a directory for files that are written to, for `train --watch`.
+use anyhow::{anyhow, Result};
+use huffman_markov::{util::CancellationToken, Error};
+use notify::{
+    event::{EventKind, ModifyKind, RenameMode},
+    RecursiveMode, Watcher,
+};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+// how often the loop checks for cancellation while nothing changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// files below `dir` and its subdirectories, in ascending order.
+fn scan(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Calls `update` with the files below `dir`, and then with the ones that are created or
+/// written to, at most once every `debounce`, until `cancel` is cancelled.
+///
+/// Removed and renamed files are logged and ignored, as are the paths `ignore` returns true
+/// for. Changes that are pending when the watch is cancelled are passed on before it returns
+/// [`Error::Cancelled`].
+pub fn watch(
+    dir: &Path,
+    debounce: Duration,
+    cancel: &CancellationToken,
+    ignore: impl Fn(&Path) -> bool,
+    mut update: impl FnMut(Vec<PathBuf>) -> Result<()>,
+) -> Result<()> {
+    let (sender, events) = channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(dir, RecursiveMode::Recursive)?;
+    // files written to while they are scanned are passed on again.
+    update(scan(dir)?)?;
+
+    let mut changed = BTreeSet::new();
+    let mut deadline = None;
+    loop {
+        if cancel.is_cancelled() {
+            if !changed.is_empty() {
+                update(changed.into_iter().collect())?;
+            }
+            return Err(Error::Cancelled.into());
+        }
+
+        let timeout = deadline.map_or(POLL_INTERVAL, |deadline: Instant| {
+            deadline.saturating_duration_since(Instant::now())
+        });
+        match events.recv_timeout(timeout.min(POLL_INTERVAL)) {
+            Ok(Ok(event)) => {
+                let paths = event.paths.into_iter().filter(|path| !ignore(path));
+                match event.kind {
+                    EventKind::Create(_)
+                    | EventKind::Modify(
+                        ModifyKind::Any | ModifyKind::Data(_) | ModifyKind::Other,
+                    ) => {
+                        changed.extend(paths.filter(|path| path.is_file()));
+                    }
+                    EventKind::Remove(_) => {
+                        for path in paths {
+                            eprintln!("ignoring removal of {}", path.display());
+                        }
+                    }
+                    // renames within the directory are also reported as the move from and to.
+                    EventKind::Modify(ModifyKind::Name(RenameMode::From | RenameMode::To)) => {}
+                    EventKind::Modify(ModifyKind::Name(_)) => {
+                        let paths: Vec<_> = paths.map(|path| path.display().to_string()).collect();
+                        if !paths.is_empty() {
+                            eprintln!("ignoring rename of {}", paths.join(" to "));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Err(error)) => eprintln!("warning: {error}"),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("stopped watching {}", dir.display()))
+            }
+        }
+
+        if changed.is_empty() {
+            continue;
+        }
+        let due = *deadline.get_or_insert_with(|| Instant::now() + debounce);
+        if Instant::now() >= due {
+            update(std::mem::take(&mut changed).into_iter().collect())?;
+            deadline = None;
+        }
+    }
+}
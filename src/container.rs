@@ -0,0 +1,2220 @@
+use crate::{
+    builder::Builder,
+    error::{Error, UnsupportedFeature},
+    filter::{BuiltinFilter, Filter},
+    huffman::{Coder, WriterStats},
+    range::RangeDecoder,
+    util::{ByteMapper, CancellationToken, HashingWriter},
+};
+use bitstream_io::{BigEndian, BitReader, BitWrite, BitWriter};
+use std::{
+    borrow::Cow,
+    fmt,
+    io::{Read, Write},
+    ops::Range,
+    str::FromStr,
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+pub const MAGIC: [u8; 4] = *b"HMKV";
+pub const VERSION: u16 = 1;
+
+/// Deepest model supported, deeper contexts are too sparse for modeling bytes. The formats store
+/// the depth in a byte, but reject the ones above this.
+pub const MAX_SUPPORTED_DEPTH: usize = 16;
+
+/// Default number of input bytes per block.
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 20;
+
+/// Entropy coder used for the payload, stored as a byte in the header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Codec {
+    #[default]
+    Huffman,
+    Range,
+}
+
+impl Codec {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Codec::Huffman),
+            1 => Some(Codec::Range),
+            _ => None,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Codec::Huffman => 0,
+            Codec::Range => 1,
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "huffman" => Ok(Codec::Huffman),
+            "range" => Ok(Codec::Range),
+            other => Err(format!(
+                "unknown codec {other:?}, expected huffman or range"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Huffman => write!(f, "huffman"),
+            Codec::Range => write!(f, "range"),
+        }
+    }
+}
+
+/// Largest uncompressed length [`decompress_bytes`] accepts, 1 GiB.
+pub const DEFAULT_MAX_LENGTH: u64 = 1 << 30;
+
+// set when the input was shorter than the depth and is stored verbatim.
+const FLAG_LITERAL: u16 = 1 << 0;
+
+// set when blocks carry checksums and are preceded by sync markers.
+const FLAG_SYNC: u16 = 1 << 1;
+
+// set when blocks are coded with a model given to the decompressor and carry no tables, the
+// content hash of its code tables as a little-endian `u64` follows the header, byte map and
+// filter.
+const FLAG_EXTERNAL: u16 = 1 << 2;
+
+// set when the input was mapped by a bijective byte map, whose table follows the header.
+const FLAG_MAPPED: u16 = 1 << 3;
+
+// set when the input was filtered, the filter id and its parameter as a little-endian `u32`
+// follow the header and byte map.
+const FLAG_FILTERED: u16 = 1 << 4;
+
+const KNOWN_FLAGS: u16 = FLAG_LITERAL | FLAG_SYNC | FLAG_EXTERNAL | FLAG_MAPPED | FLAG_FILTERED;
+
+/// Start of a sync marker, followed by the block number as a little-endian `u32`. Block kinds
+/// never start with this byte.
+pub const SYNC_MAGIC: [u8; 3] = [0xa5, 0x5a, 0xc3];
+
+// block kinds, stored in the first byte of every block header.
+const BLOCK_CODED: u8 = 0;
+const BLOCK_STORED: u8 = 1;
+
+/// How many blocks were entropy coded and how many were stored because coding would have
+/// expanded them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockStats {
+    pub coded: usize,
+    pub stored: usize,
+    /// Combined writer counters of the huffman coded blocks.
+    pub writer: WriterStats,
+    /// Bytes the models of the blocks were trained on, fewer than the input when sampling.
+    pub trained_bytes: u64,
+    /// [`Markov::content_hash`](crate::Markov::content_hash) of the model of a single block,
+    /// or the hashes of the models of several blocks combined in order. `None` if the input was
+    /// too short for a model or coded with an external one.
+    pub model_fingerprint: Option<u64>,
+}
+
+/// Default number of input bytes between progress reports, see [`Builder::progress_interval`].
+pub const DEFAULT_PROGRESS_INTERVAL: u64 = 1 << 16;
+
+/// Step of compressing a block, as reported by [`compress_with_progress`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Training,
+    BuildingTrees,
+    Encoding,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Training => write!(f, "training"),
+            Phase::BuildingTrees => write!(f, "building trees"),
+            Phase::Encoding => write!(f, "encoding"),
+        }
+    }
+}
+
+/// Progress of compressing, reported to the callback of [`compress_with_progress`].
+///
+/// Blocks go through all phases in turn, so `bytes_done` counts the input up to the end of the
+/// current block for training and building trees, and the bytes encoded so far for encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    pub bytes_done: u64,
+    pub bytes_total: Option<u64>,
+}
+
+// passes progress on, at most once per interval of bytes and always for the last byte.
+struct Reporter<'a> {
+    callback: &'a mut dyn FnMut(Progress),
+    interval: u64,
+    total: u64,
+    next: u64,
+    last: Option<(Phase, u64)>,
+}
+
+impl<'a> Reporter<'a> {
+    fn new(callback: &'a mut dyn FnMut(Progress), interval: u64, total: u64) -> Self {
+        Reporter {
+            callback,
+            interval,
+            total,
+            next: 0,
+            last: None,
+        }
+    }
+
+    fn report(&mut self, phase: Phase, bytes_done: u64) {
+        if (bytes_done < self.next && bytes_done != self.total)
+            || self.last == Some((phase, bytes_done))
+        {
+            return;
+        }
+        self.next = bytes_done.saturating_add(self.interval);
+        self.last = Some((phase, bytes_done));
+        (self.callback)(Progress {
+            phase,
+            bytes_done,
+            bytes_total: Some(self.total),
+        });
+    }
+}
+
+/// Metadata at the start of a compressed file, readable without touching the payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Header {
+    pub version: u16,
+    flags: u16,
+    pub depth: usize,
+    pub codec: Codec,
+    /// Uncompressed length in bytes.
+    pub length: u64,
+    /// Uncompressed bytes per block.
+    pub block_size: usize,
+    /// Byte map applied before compressing, undone after decompressing.
+    pub byte_map: Option<ByteMapper>,
+    /// Filter applied before compressing, inverted after decompressing.
+    pub filter: Option<BuiltinFilter>,
+    /// [`Coder::content_hash`] of the external model the blocks were coded with, which has to
+    /// be given to [`decompress_with_model`]. `None` if every block carries its own tables.
+    pub model_fingerprint: Option<u64>,
+}
+
+impl Header {
+    pub(crate) fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let depth: u8 = self
+            .depth
+            .try_into()
+            .map_err(|_| Error::Format("depth too large"))?;
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&self.version.to_le_bytes())?;
+        writer.write_all(&self.flags.to_le_bytes())?;
+        writer.write_all(&[depth, self.codec.to_byte()])?;
+        writer.write_all(&self.length.to_le_bytes())?;
+        let block_size: u32 = self
+            .block_size
+            .try_into()
+            .map_err(|_| Error::Format("block size too large"))?;
+        writer.write_all(&block_size.to_le_bytes())?;
+        if let Some(mapper) = &self.byte_map {
+            writer.write_all(mapper.table())?;
+        }
+        if let Some(filter) = &self.filter {
+            let parameter: u32 = filter
+                .parameter()
+                .try_into()
+                .map_err(|_| Error::Format("filter parameter too large"))?;
+            writer.write_all(&[filter.id()])?;
+            writer.write_all(&parameter.to_le_bytes())?;
+        }
+        if let Some(fingerprint) = self.model_fingerprint {
+            writer.write_all(&fingerprint.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads the header, leaving `reader` at the start of the payload.
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::Format("bad magic"));
+        }
+
+        let mut version = [0; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version == 0 {
+            return Err(Error::Format("zero version"));
+        }
+        if version > VERSION {
+            return Err(UnsupportedFeature::Version {
+                found: version,
+                supported: VERSION,
+            }
+            .into());
+        }
+
+        let mut flags = [0; 2];
+        reader.read_exact(&mut flags)?;
+        let flags = u16::from_le_bytes(flags);
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(UnsupportedFeature::Flags(flags & !KNOWN_FLAGS).into());
+        }
+        let mut depth_codec = [0; 2];
+        reader.read_exact(&mut depth_codec)?;
+        let [depth, codec] = depth_codec;
+        let codec = Codec::from_byte(codec).ok_or(UnsupportedFeature::Codec(codec))?;
+
+        let mut length = [0; 8];
+        reader.read_exact(&mut length)?;
+        let mut block_size = [0; 4];
+        reader.read_exact(&mut block_size)?;
+
+        let mut byte_map = None;
+        if flags & FLAG_MAPPED != 0 {
+            let mut table = [0; 256];
+            reader.read_exact(&mut table)?;
+            let mapper = ByteMapper::new(table);
+            if !mapper.is_bijection() {
+                return Err(Error::Format("byte map is not a bijection"));
+            }
+            byte_map = Some(mapper);
+        }
+
+        let mut filter = None;
+        if flags & FLAG_FILTERED != 0 {
+            let mut parts = [0; 5];
+            reader.read_exact(&mut parts)?;
+            let parameter = u32::from_le_bytes(parts[1..].try_into().unwrap());
+            filter = match BuiltinFilter::from_parts(parts[0], parameter) {
+                None if parameter == 0 => return Err(Error::Format("zero filter parameter")),
+                None => return Err(UnsupportedFeature::Filter(parts[0]).into()),
+                filter => filter,
+            };
+        }
+
+        let mut model_fingerprint = None;
+        if flags & FLAG_EXTERNAL != 0 {
+            let mut fingerprint = [0; 8];
+            reader.read_exact(&mut fingerprint)?;
+            model_fingerprint = Some(u64::from_le_bytes(fingerprint));
+        }
+
+        let header = Header {
+            version,
+            flags,
+            depth: depth.into(),
+            codec,
+            length: u64::from_le_bytes(length),
+            block_size: u32::from_le_bytes(block_size) as usize,
+            byte_map,
+            filter,
+            model_fingerprint,
+        };
+
+        if header.depth == 0 {
+            return Err(Error::Format("zero depth"));
+        }
+        if header.depth > MAX_SUPPORTED_DEPTH {
+            return Err(Error::Format("depth too large"));
+        }
+        if header.block_size == 0 {
+            return Err(Error::Format("zero block size"));
+        }
+        if header.model_fingerprint.is_some() && header.codec != Codec::Huffman {
+            return Err(Error::Format("external model without huffman codec"));
+        }
+
+        Ok(header)
+    }
+
+    /// Whether the input was shorter than the depth and is stored verbatim.
+    pub fn literal(&self) -> bool {
+        self.flags & FLAG_LITERAL != 0
+    }
+
+    /// Whether blocks carry checksums and are preceded by sync markers.
+    pub fn sync(&self) -> bool {
+        self.flags & FLAG_SYNC != 0
+    }
+
+    /// Name of the algorithm of the block checksums, if there are any.
+    pub fn checksum(&self) -> Option<&'static str> {
+        self.sync().then_some("xxh3-64, low 32 bits")
+    }
+
+    /// Whether this build can decompress data with this header, see [`check_compatibility`].
+    pub fn is_supported(&self) -> bool {
+        check_compatibility(self).is_ok()
+    }
+}
+
+/// Checks that this build knows the version and every flag of `header`, naming the first
+/// feature it does not. Codecs and filters this build does not know fail when reading the
+/// header already, blocks and tables of unknown kinds when decompressing.
+pub fn check_compatibility(header: &Header) -> Result<(), UnsupportedFeature> {
+    if header.version > VERSION {
+        return Err(UnsupportedFeature::Version {
+            found: header.version,
+            supported: VERSION,
+        });
+    }
+    if header.flags & !KNOWN_FLAGS != 0 {
+        return Err(UnsupportedFeature::Flags(header.flags & !KNOWN_FLAGS));
+    }
+    Ok(())
+}
+
+pub fn compress_bytes(data: &[u8], depth: usize) -> Result<Vec<u8>, Error> {
+    compress_with(data, &Builder::new().depth(depth))
+}
+
+pub fn compress_with(data: &[u8], builder: &Builder) -> Result<Vec<u8>, Error> {
+    Ok(compress_blocks(data, builder)?.0)
+}
+
+/// Compresses `data` like [`compress_bytes`], calling `progress` at most once every
+/// [`DEFAULT_PROGRESS_INTERVAL`] bytes. The callback runs on the calling thread.
+pub fn compress_with_progress(
+    data: &[u8],
+    depth: usize,
+    mut progress: impl FnMut(Progress),
+) -> Result<Vec<u8>, Error> {
+    Ok(compress_blocks_with_progress(data, &Builder::new().depth(depth), &mut progress)?.0)
+}
+
+/// Compresses `data` like [`compress_with`], also reporting how the blocks were written.
+pub fn compress_blocks(data: &[u8], builder: &Builder) -> Result<(Vec<u8>, BlockStats), Error> {
+    compress_blocks_with_progress(data, builder, &mut |_| {})
+}
+
+pub(crate) fn compress_blocks_with_progress(
+    data: &[u8],
+    builder: &Builder,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<(Vec<u8>, BlockStats), Error> {
+    builder.validate()?;
+    let mut reporter = Reporter::new(progress, builder.progress_interval, data.len() as u64);
+    let depth = builder.depth;
+    let (mut header, data) = prepare(data, builder);
+    let mut output = vec![];
+    let mut stats = BlockStats::default();
+
+    // inputs shorter than the depth have no windows to model, store them as-is.
+    if data.len() < depth {
+        header.flags |= FLAG_LITERAL;
+        header.write(&mut output)?;
+        output.extend_from_slice(&data);
+        reporter.report(Phase::Encoding, data.len() as u64);
+        return Ok((output, stats));
+    }
+
+    if builder.sync_interval.is_some() {
+        header.flags |= FLAG_SYNC;
+    }
+    header.write(&mut output)?;
+
+    // markers go in front of the first block after every interval of output bytes.
+    let mut next_marker = output.len();
+    let mut offset = 0;
+    for (index, block) in data.chunks(builder.block_size).enumerate() {
+        if let Some(interval) = builder.sync_interval {
+            if output.len() >= next_marker {
+                output.extend_from_slice(&SYNC_MAGIC);
+                output.extend_from_slice(&(index as u32).to_le_bytes());
+                next_marker = output.len() + interval;
+            }
+        }
+
+        builder.check_cancelled()?;
+        let (coded, sum) = encode_block(block, builder, &mut stats, &mut reporter, offset)?;
+        offset += block.len() as u64;
+        reporter.report(Phase::Encoding, offset);
+        let length = block.len() as u32;
+        match coded {
+            // the coded block header is four bytes larger than the stored one.
+            Some((coded, writer)) if coded.len() + 4 < block.len() => {
+                output.push(BLOCK_CODED);
+                output.extend_from_slice(&length.to_le_bytes());
+                output.extend_from_slice(&(coded.len() as u32).to_le_bytes());
+                if header.sync() {
+                    output.extend_from_slice(&sum.to_le_bytes());
+                }
+                output.extend_from_slice(&coded);
+                stats.coded += 1;
+                stats.writer += writer;
+            }
+            _ => {
+                output.push(BLOCK_STORED);
+                output.extend_from_slice(&length.to_le_bytes());
+                if header.sync() {
+                    output.extend_from_slice(&sum.to_le_bytes());
+                }
+                output.extend_from_slice(block);
+                stats.stored += 1;
+            }
+        }
+    }
+    Ok((output, stats))
+}
+
+// header of the compressed `data` and the data that is coded, after its filter and byte map.
+// the flags of literal inputs and of sync markers are left to the caller.
+pub(crate) fn prepare<'a>(data: &'a [u8], builder: &Builder) -> (Header, Cow<'a, [u8]>) {
+    let mut header = Header {
+        version: VERSION,
+        flags: 0,
+        depth: builder.depth,
+        codec: builder.codec,
+        length: data.len() as u64,
+        block_size: builder.block_size,
+        byte_map: None,
+        filter: builder.filter,
+        model_fingerprint: None,
+    };
+    if let Some(model) = &builder.model {
+        header.flags |= FLAG_EXTERNAL;
+        header.model_fingerprint = Some(model.content_hash());
+    }
+
+    // filters and byte maps apply in that order, decompressing undoes them in reverse.
+    let data = match (builder.filter, builder.byte_map) {
+        (None, None) => Cow::Borrowed(data),
+        (filter, mapper) => {
+            let mut data = match filter {
+                Some(filter) => {
+                    header.flags |= FLAG_FILTERED;
+                    filter.encode(data)
+                }
+                None => data.to_vec(),
+            };
+            if let Some(mapper) = mapper {
+                if !builder.lossy_byte_map {
+                    header.flags |= FLAG_MAPPED;
+                    header.byte_map = Some(mapper);
+                }
+                mapper.map_slice(&mut data);
+            }
+            Cow::Owned(data)
+        }
+    };
+    (header, data)
+}
+
+/// Upper bound on the length of [`compress_with`] for any `input_len` bytes. Blocks that do
+/// not get smaller are stored, so this is `input_len` plus the header and a few bytes per block,
+/// however the input is chosen.
+pub fn max_compressed_len(input_len: u64, builder: &Builder) -> u64 {
+    // magic, version, flags, depth, codec, length and block size.
+    let mut header = 22;
+    if builder.byte_map.is_some() && !builder.lossy_byte_map {
+        header += 256;
+    }
+    if builder.filter.is_some() {
+        header += 5;
+    }
+    if builder.model.is_some() {
+        header += 8;
+    }
+    // block type and length, checksums and one marker at most with sync.
+    let mut block = 5;
+    if builder.sync_interval.is_some() {
+        block += 4 + SYNC_MAGIC.len() as u64 + 4;
+    }
+    let blocks = input_len.div_ceil(builder.block_size.max(1) as u64);
+    input_len
+        .saturating_add(header)
+        .saturating_add(blocks.saturating_mul(block))
+}
+
+fn checksum(block: &[u8]) -> u32 {
+    xxh3_64(block) as u32
+}
+
+// payload and writer counters of a block if it could be coded, and its checksum.
+type EncodedBlock = (Option<(Vec<u8>, WriterStats)>, u32);
+
+// codes a block into a scratch buffer along with its checksum, blocks shorter than the depth
+// cannot be coded. huffman blocks are hashed in the same pass that codes them.
+fn encode_block(
+    block: &[u8],
+    builder: &Builder,
+    stats: &mut BlockStats,
+    reporter: &mut Reporter,
+    offset: u64,
+) -> Result<EncodedBlock, Error> {
+    let depth = builder.depth;
+    if block.len() < depth {
+        return Ok((None, checksum(block)));
+    }
+
+    let end = offset + block.len() as u64;
+    let context = &block[..depth - 1];
+    let mut output = vec![];
+    // external models are known to the decompressor, only the blocks of their own carry tables.
+    let trained;
+    let coder = match &builder.model {
+        Some(model) => model,
+        None => {
+            let (markov, bytes) = builder.train_counted(block)?;
+            stats.trained_bytes += bytes;
+            let hash = markov.content_hash();
+            stats.model_fingerprint = Some(match stats.model_fingerprint {
+                Some(previous) => xxh3_64(&[previous.to_le_bytes(), hash.to_le_bytes()].concat()),
+                None => hash,
+            });
+            reporter.report(Phase::Training, end);
+            if builder.codec == Codec::Range {
+                let encoder = builder.build_range_encoder(&markov)?;
+                reporter.report(Phase::BuildingTrees, end);
+                output.extend_from_slice(context);
+                encoder.write_tables(&mut output)?;
+                output.append(&mut encoder.encode_all(block)?);
+                return Ok((Some((output, WriterStats::default())), checksum(block)));
+            }
+            trained = builder.build_coder(&markov)?;
+            reporter.report(Phase::BuildingTrees, end);
+            trained.write_tables(&mut output)?;
+            &trained
+        }
+    };
+    write_context(coder, context, &mut output)?;
+    let mut writer = coder.writer(output);
+    if let Some(token) = &builder.cancel {
+        writer = writer.with_cancellation(token.clone());
+    }
+    let mut writer = HashingWriter::new(writer);
+    let mut done = offset;
+    let step = builder.progress_interval.clamp(1, block.len() as u64) as usize;
+    for chunk in block.chunks(step) {
+        writer.write_all(chunk)?;
+        done += chunk.len() as u64;
+        reporter.report(Phase::Encoding, done);
+    }
+    let sum = writer.digest() as u32;
+    Ok((Some(writer.into_inner().0.finish()?), sum))
+}
+
+// codes the initial context of a huffman block with the order-0 tree, padded to a byte.
+pub(crate) fn write_context(
+    coder: &Coder,
+    context: &[u8],
+    output: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let mut writer = BitWriter::endian(output, BigEndian);
+    for byte in context {
+        coder.write_order0(&mut writer, *byte)?;
+    }
+    writer.byte_align()?;
+    Ok(())
+}
+
+pub fn decompress_bytes(data: &[u8]) -> Result<Vec<u8>, Error> {
+    decompress_with_limit(data, DEFAULT_MAX_LENGTH)
+}
+
+/// Decompresses `data`, rejecting it before allocating if the declared uncompressed length is
+/// larger than `max_length`.
+pub fn decompress_with_limit(data: &[u8], max_length: u64) -> Result<Vec<u8>, Error> {
+    decompress_cancellable(data, max_length, &CancellationToken::new())
+}
+
+/// Decompresses like [`decompress_with_limit`], checking `token` before every block and failing
+/// with [`Error::Cancelled`] once it is set.
+pub fn decompress_cancellable(
+    data: &[u8],
+    max_length: u64,
+    token: &CancellationToken,
+) -> Result<Vec<u8>, Error> {
+    decompress_model(data, max_length, token, None)
+}
+
+/// Decompresses data compressed with [`Builder::external_model`], which `coder` has to be the
+/// model of. A different model fails with [`Error::ModelFingerprintMismatch`] before any block
+/// is decoded. Data without an external model decompresses like with [`decompress_bytes`].
+pub fn decompress_with_model(data: &[u8], coder: &Coder) -> Result<Vec<u8>, Error> {
+    decompress_model(
+        data,
+        DEFAULT_MAX_LENGTH,
+        &CancellationToken::new(),
+        Some(coder),
+    )
+}
+
+pub(crate) fn decompress_model(
+    mut data: &[u8],
+    max_length: u64,
+    token: &CancellationToken,
+    model: Option<&Coder>,
+) -> Result<Vec<u8>, Error> {
+    let start = data.len();
+    let header = Header::read(&mut data)?;
+    let model = external_model(&header, model)?;
+    let header_bits = 8 * (start - data.len()) as u64;
+    let mut output = decompress_with_limit_header(header, data, max_length, token, model)
+        .map_err(|error| error.offset_by(header_bits, 0))?;
+    restore(&header, &mut output);
+    Ok(output)
+}
+
+// the model to decode the blocks with, checked against the fingerprint of the header. data
+// without an external model ignores the given one.
+fn external_model<'a>(
+    header: &Header,
+    model: Option<&'a Coder>,
+) -> Result<Option<&'a Coder>, Error> {
+    let Some(expected) = header.model_fingerprint else {
+        return Ok(None);
+    };
+    let model = model.ok_or(Error::Format(
+        "data was coded with an external model, which was not given",
+    ))?;
+    let found = model.content_hash();
+    if found != expected {
+        return Err(Error::ModelFingerprintMismatch { expected, found });
+    }
+    Ok(Some(model))
+}
+
+// undoes the byte map and filter of the header, if there are any.
+fn restore(header: &Header, data: &mut Vec<u8>) {
+    if let Some(inverse) = header.byte_map.and_then(|mapper| mapper.inverse()) {
+        inverse.map_slice(data);
+    }
+    if let Some(filter) = header.filter {
+        *data = filter.decode(data);
+    }
+}
+
+fn decompress_with_limit_header(
+    header: Header,
+    mut data: &[u8],
+    max_length: u64,
+    token: &CancellationToken,
+    model: Option<&Coder>,
+) -> Result<Vec<u8>, Error> {
+    let length = checked_length(&header, max_length)?;
+
+    if header.literal() {
+        if data.len() < length {
+            return Err(Error::Truncated);
+        }
+        return Ok(data[..length].to_vec());
+    }
+
+    let start = data.len();
+    let mut output = vec![];
+    let mut index = 0;
+    while output.len() < length {
+        token.check()?;
+        let offset = 8 * (start - data.len()) as u64;
+        let mut block = read_block(&header, &mut data, index, length - output.len(), model)
+            .map_err(|error| error.offset_by(offset, output.len() as u64))?;
+        output.append(&mut block);
+        index += 1;
+    }
+    Ok(output)
+}
+
+/// Limits on what [`decompress_stream`] may use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DecodeLimits {
+    /// Most bytes to write. Data declaring a larger length fails with [`Error::LimitExceeded`]
+    /// before anything is written.
+    pub max_output: Option<u64>,
+    /// Most bytes of compressed and decompressed data to hold at once, not counting the code
+    /// tables. Blocks are held one at a time, but data with a filter or without blocks is held
+    /// whole.
+    pub max_memory: Option<usize>,
+}
+
+/// Counters of [`decompress_stream`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub blocks: u64,
+    /// Most bytes of compressed and decompressed data held at once.
+    pub peak_memory: usize,
+}
+
+/// Decompresses from `reader` into `writer` a block at a time, so that memory use does not
+/// grow with the length of the data. `writer` receives every block once it is decoded and
+/// checked, data is only written up to the first error.
+pub fn decompress_stream<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    limits: &DecodeLimits,
+) -> Result<DecodeStats, Error> {
+    decompress_stream_cancellable(reader, writer, limits, &CancellationToken::new())
+}
+
+/// Decompresses like [`decompress_stream`], checking `token` before every block.
+pub fn decompress_stream_cancellable<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    limits: &DecodeLimits,
+    token: &CancellationToken,
+) -> Result<DecodeStats, Error> {
+    decompress_stream_with_model(reader, writer, limits, token, None)
+}
+
+/// Decompresses like [`decompress_stream_cancellable`], decoding data compressed with
+/// [`Builder::external_model`] with `model`, see [`decompress_with_model`].
+pub fn decompress_stream_with_model<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    limits: &DecodeLimits,
+    token: &CancellationToken,
+    model: Option<&Coder>,
+) -> Result<DecodeStats, Error> {
+    let mut reader = CountingReader {
+        inner: reader,
+        bytes: 0,
+    };
+    let header = Header::read(&mut reader)?;
+    let model = external_model(&header, model)?;
+    if let Some(max_output) = limits.max_output.filter(|max| header.length > *max) {
+        return Err(Error::LimitExceeded {
+            kind: "output",
+            limit: max_output.try_into().unwrap_or(usize::MAX),
+        });
+    }
+    let length = checked_length(&header, u64::MAX)?;
+    let max_memory = limits.max_memory.unwrap_or(usize::MAX);
+    let memory_exceeded = || Error::LimitExceeded {
+        kind: "memory",
+        limit: max_memory,
+    };
+    let mut stats = DecodeStats {
+        output_bytes: length as u64,
+        ..Default::default()
+    };
+
+    // filters work on all of the output.
+    if header.filter.is_some() {
+        let available = max_memory.checked_sub(length).ok_or_else(memory_exceeded)?;
+        let mut data = vec![];
+        (&mut reader)
+            .take((available as u64).saturating_add(1))
+            .read_to_end(&mut data)?;
+        if data.len() > available {
+            return Err(memory_exceeded());
+        }
+        let header_bits = 8 * (reader.bytes - data.len() as u64);
+        let mut output = decompress_with_limit_header(header, &data, u64::MAX, token, model)
+            .map_err(|error| error.offset_by(header_bits, 0))?;
+        restore(&header, &mut output);
+        writer.write_all(&output)?;
+        stats.input_bytes = reader.bytes;
+        stats.peak_memory = data.len() + output.len();
+        return Ok(stats);
+    }
+
+    let inverse = header.byte_map.and_then(|mapper| mapper.inverse());
+    let mut done = 0;
+    if header.literal() {
+        let mut chunk = vec![0; length.min(1 << 16).min(max_memory.max(1))];
+        while done < length {
+            let size = (length - done).min(chunk.len());
+            let chunk = &mut chunk[..size];
+            reader.read_exact(chunk)?;
+            if let Some(inverse) = &inverse {
+                inverse.map_slice(chunk);
+            }
+            writer.write_all(chunk)?;
+            done += chunk.len();
+        }
+        stats.input_bytes = reader.bytes;
+        stats.peak_memory = chunk.len();
+        return Ok(stats);
+    }
+
+    let mut frame = vec![];
+    while done < length {
+        token.check()?;
+        let offset = 8 * reader.bytes;
+        let remaining = length - done;
+        let mut block = read_frame(&header, &mut reader, &mut frame, remaining, max_memory)
+            .and_then(|()| {
+                let index = stats.blocks as usize;
+                read_block(&header, &mut &frame[..], index, remaining, model)
+            })
+            .map_err(|error| error.offset_by(offset, done as u64))?;
+        stats.peak_memory = stats.peak_memory.max(frame.len() + block.len());
+        if let Some(inverse) = &inverse {
+            inverse.map_slice(&mut block);
+        }
+        writer.write_all(&block)?;
+        done += block.len();
+        stats.blocks += 1;
+    }
+    stats.input_bytes = reader.bytes;
+    Ok(stats)
+}
+
+/// Decompresses only the first `len` bytes from `reader`, returned with the header of the data.
+///
+/// Nothing past the blocks these bytes are in is read, so errors in later blocks go unnoticed,
+/// and a block that is only decoded in part is not checked against its checksum. Filters work
+/// on all of the data, filtered data is decoded whole before it is cut.
+pub fn decompress_prefix<R: Read>(reader: R, len: usize) -> Result<(Header, Vec<u8>), Error> {
+    decompress_prefix_with_model(reader, len, None)
+}
+
+/// Decompresses the start of the data like [`decompress_prefix`], with `model` for data
+/// compressed with an external one, see [`decompress_with_model`].
+pub fn decompress_prefix_with_model<R: Read>(
+    reader: R,
+    len: usize,
+    model: Option<&Coder>,
+) -> Result<(Header, Vec<u8>), Error> {
+    let mut reader = CountingReader {
+        inner: reader,
+        bytes: 0,
+    };
+    let header = Header::read(&mut reader)?;
+    let model = external_model(&header, model)?;
+    let length = checked_length(&header, DEFAULT_MAX_LENGTH)?;
+    let wanted = len.min(length);
+    let header_bits = 8 * reader.bytes;
+
+    if header.filter.is_some() {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        let token = CancellationToken::new();
+        let mut output =
+            decompress_with_limit_header(header, &data, DEFAULT_MAX_LENGTH, &token, model)
+                .map_err(|error| error.offset_by(header_bits, 0))?;
+        restore(&header, &mut output);
+        output.truncate(wanted);
+        return Ok((header, output));
+    }
+
+    let mut output = if header.literal() {
+        let mut output = vec![0; wanted];
+        reader.read_exact(&mut output)?;
+        output
+    } else {
+        let mut output = vec![];
+        let mut frame = vec![];
+        let mut index = 0;
+        while output.len() < wanted {
+            let offset = 8 * reader.bytes;
+            let remaining = length - output.len();
+            let mut block = read_frame(&header, &mut reader, &mut frame, remaining, usize::MAX)
+                .and_then(|()| {
+                    let wanted = wanted - output.len();
+                    read_block_prefix(&header, &mut &frame[..], index, remaining, wanted, model)
+                })
+                .map_err(|error| error.offset_by(offset, output.len() as u64))?;
+            output.append(&mut block);
+            index += 1;
+        }
+        output
+    };
+    if let Some(inverse) = header.byte_map.and_then(|mapper| mapper.inverse()) {
+        inverse.map_slice(&mut output);
+    }
+    Ok((header, output))
+}
+
+// reader that counts the bytes read from it, for the positions of decode errors.
+struct CountingReader<R> {
+    inner: R,
+    bytes: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes += read as u64;
+        Ok(read)
+    }
+}
+
+// reads the next block into `frame` as it is stored, for read_block to check and decode.
+fn read_frame<R: Read>(
+    header: &Header,
+    reader: &mut R,
+    frame: &mut Vec<u8>,
+    remaining: usize,
+    max_memory: usize,
+) -> Result<(), Error> {
+    frame.clear();
+    let mut read = |len: usize, frame: &mut Vec<u8>| {
+        let start = frame.len();
+        frame.resize(start + len, 0);
+        reader.read_exact(&mut frame[start..])
+    };
+
+    read(1, frame)?;
+    if frame[0] == SYNC_MAGIC[0] && header.sync() {
+        read(SYNC_MAGIC.len() - 1 + 4 + 1, frame)?;
+    }
+    let kind = frame[frame.len() - 1];
+    read(4, frame)?;
+    let block_length = u32::from_le_bytes(frame[frame.len() - 4..].try_into().unwrap()) as usize;
+    let size = match kind {
+        BLOCK_STORED => block_length,
+        BLOCK_CODED => {
+            read(4, frame)?;
+            u32::from_le_bytes(frame[frame.len() - 4..].try_into().unwrap()) as usize
+        }
+        kind => return Err(UnsupportedFeature::BlockKind(kind).into()),
+    };
+    if header.sync() {
+        read(4, frame)?;
+    }
+
+    // the output of blocks longer than the rest of the data is never allocated.
+    if frame.len() + size + block_length.min(remaining) > max_memory {
+        return Err(Error::LimitExceeded {
+            kind: "memory",
+            limit: max_memory,
+        });
+    }
+    let start = frame.len();
+    reader.take(size as u64).read_to_end(frame)?;
+    if frame.len() - start < size {
+        return Err(Error::Truncated);
+    }
+    Ok(())
+}
+
+/// Output of [`decompress_recover`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recovered {
+    /// Decompressed data, with the bytes of lost blocks set to zero.
+    pub data: Vec<u8>,
+    /// Ranges of the uncompressed data that could not be recovered, in ascending order.
+    pub lost: Vec<Range<u64>>,
+}
+
+/// Decompresses `data`, skipping ahead to the next sync marker whenever a block is damaged.
+///
+/// Damage is only contained for data compressed with a sync interval, which stores checksums
+/// and markers. Otherwise everything from the first damaged block onwards is lost. The header
+/// has to be intact.
+pub fn decompress_recover(data: &[u8], max_length: u64) -> Result<Recovered, Error> {
+    decompress_recover_with_model(data, max_length, None)
+}
+
+/// Recovers the data like [`decompress_recover`], with `model` for data compressed with an
+/// external one, see [`decompress_with_model`]. The model is checked against the header before
+/// anything is recovered.
+pub fn decompress_recover_with_model(
+    mut data: &[u8],
+    max_length: u64,
+    model: Option<&Coder>,
+) -> Result<Recovered, Error> {
+    let header = Header::read(&mut data)?;
+    let model = external_model(&header, model)?;
+    if header.literal() {
+        let token = CancellationToken::new();
+        let mut output = decompress_with_limit_header(header, data, max_length, &token, model)?;
+        restore(&header, &mut output);
+        return Ok(Recovered {
+            data: output,
+            lost: vec![],
+        });
+    }
+    let length = checked_length(&header, max_length)?;
+    let block_size = header.block_size;
+
+    let mut recovered = Recovered::default();
+    let mut index = 0;
+    while recovered.data.len() < length {
+        let remaining = length - recovered.data.len();
+        let mut rest = data;
+        if let Ok(mut block) = read_block(&header, &mut rest, index, remaining, model) {
+            recovered.data.append(&mut block);
+            data = rest;
+            index += 1;
+            continue;
+        }
+
+        // resume at the next marker of a later block, or give up on the rest.
+        let start = recovered.data.len();
+        let resume = header
+            .sync()
+            .then(|| next_marker(data, index, length, block_size));
+        let (offset, next) = match resume.flatten() {
+            Some((offset, next)) => (offset, next),
+            None => (data.len(), length.div_ceil(block_size)),
+        };
+        let end = (next * block_size).min(length);
+        recovered.data.resize(end, 0);
+        recovered.lost.push(start as u64..end as u64);
+        data = &data[offset..];
+        index = next;
+    }
+
+    // filters spread damage, deltas to all later bytes and transposing to every row.
+    if let (Some(filter), Some(first)) = (header.filter, recovered.lost.first()) {
+        let start = match filter {
+            BuiltinFilter::Delta(_) => first.start,
+            BuiltinFilter::Transpose(_) => 0,
+        };
+        recovered.lost.clear();
+        recovered.lost.push(start..length as u64);
+    }
+
+    // lost bytes stay zero rather than whatever zero maps back to.
+    restore(&header, &mut recovered.data);
+    for range in &recovered.lost {
+        recovered.data[range.start as usize..range.end as usize].fill(0);
+    }
+    Ok(recovered)
+}
+
+// finds the next marker of a block after `index`, returning its offset and block number.
+fn next_marker(
+    data: &[u8],
+    index: usize,
+    length: usize,
+    block_size: usize,
+) -> Option<(usize, usize)> {
+    (1..data.len()).find_map(|offset| {
+        let marker = data[offset..].get(..SYNC_MAGIC.len() + 4)?;
+        if marker[..SYNC_MAGIC.len()] != SYNC_MAGIC {
+            return None;
+        }
+        let next = u32::from_le_bytes(marker[SYNC_MAGIC.len()..].try_into().unwrap()) as usize;
+        (next > index && next.checked_mul(block_size)? < length).then_some((offset, next))
+    })
+}
+
+fn checked_length(header: &Header, max_length: u64) -> Result<usize, Error> {
+    if header.length > max_length {
+        return Err(Error::Format("declared length exceeds limit"));
+    }
+    header
+        .length
+        .try_into()
+        .map_err(|_| Error::Format("length too large"))
+}
+
+// reads the block with the given index, which is the next one in `data`.
+fn read_block(
+    header: &Header,
+    data: &mut &[u8],
+    index: usize,
+    remaining: usize,
+    model: Option<&Coder>,
+) -> Result<Vec<u8>, Error> {
+    read_block_prefix(header, data, index, remaining, usize::MAX, model)
+}
+
+// reads the block like `read_block`, but only decodes its first `wanted` bytes. the checksum is
+// only checked if that is all of the block.
+fn read_block_prefix(
+    header: &Header,
+    data: &mut &[u8],
+    index: usize,
+    remaining: usize,
+    wanted: usize,
+    model: Option<&Coder>,
+) -> Result<Vec<u8>, Error> {
+    let start = data.len();
+    let mut kind = [0; 1];
+    data.read_exact(&mut kind)?;
+    if kind[0] == SYNC_MAGIC[0] && header.sync() {
+        let mut marker = [0; SYNC_MAGIC.len() - 1];
+        data.read_exact(&mut marker)?;
+        if marker != SYNC_MAGIC[1..] || read_u32(data)? != index {
+            return Err(Error::Format("invalid sync marker"));
+        }
+        data.read_exact(&mut kind)?;
+    }
+
+    let block_length = read_u32(data)?;
+    // every block but the last one has the size from the header.
+    if block_length != header.block_size.min(remaining) {
+        return Err(Error::Format("invalid block length"));
+    }
+
+    let size = match kind[0] {
+        BLOCK_STORED => block_length,
+        BLOCK_CODED => read_u32(data)?,
+        kind => return Err(UnsupportedFeature::BlockKind(kind).into()),
+    };
+    let sum = if header.sync() {
+        Some(read_u32(data)?)
+    } else {
+        None
+    };
+    if data.len() < size {
+        return Err(Error::Truncated);
+    }
+    let offset = 8 * (start - data.len()) as u64;
+    let (block, rest) = data.split_at(size);
+    *data = rest;
+
+    let wanted = wanted.min(block_length);
+    let block = match kind[0] {
+        BLOCK_STORED => block[..wanted].to_vec(),
+        _ => decode_block(header, block, block_length, wanted, model)
+            .map_err(|error| error.offset_by(offset, 0))?,
+    };
+    let whole = wanted == block_length;
+    if whole && sum.is_some_and(|sum| sum != checksum(&block) as usize) {
+        return Err(Error::ChecksumMismatch { block: index });
+    }
+    Ok(block)
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<usize, Error> {
+    let mut bytes = [0; 4];
+    data.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes) as usize)
+}
+
+// decodes the first `wanted` bytes of a coded payload of `length` bytes, with the tables of the
+// payload unless there is an external model.
+fn decode_block(
+    header: &Header,
+    mut data: &[u8],
+    length: usize,
+    wanted: usize,
+    model: Option<&Coder>,
+) -> Result<Vec<u8>, Error> {
+    if length < header.depth {
+        return Err(Error::Format("coded payload shorter than depth"));
+    }
+    // the initial context is always decoded.
+    let length = wanted.max(header.depth - 1).min(length);
+
+    let start = data.len();
+    let mut output = vec![0; header.depth - 1];
+    // huffman blocks code their initial context after the tables, range blocks store it first.
+    let coded_context = header.codec == Codec::Huffman;
+    if !coded_context {
+        data.read_exact(&mut output)?;
+    }
+    let decoded = match header.codec {
+        Codec::Huffman => {
+            let read;
+            let coder = match model {
+                Some(model) => model,
+                None => {
+                    read = Coder::read_tables(&mut data)?;
+                    &read
+                }
+            };
+            if coder.depth() != header.depth {
+                return Err(Error::Format("model depth does not match header"));
+            }
+            if coded_context {
+                // the reader takes whole bytes from the data, so it ends after the padding.
+                let mut reader = BitReader::endian(&mut data, BigEndian);
+                for byte in &mut output {
+                    *byte = coder.read_order0(&mut reader)?;
+                }
+            }
+            let offset = 8 * (start - data.len()) as u64;
+            coder
+                .decode_all(&output, data, length - output.len())
+                .map_err(|error| error.offset_by(offset, output.len() as u64))?
+        }
+        Codec::Range => {
+            let decoder = RangeDecoder::read_tables(&mut data)?;
+            if decoder.depth() != header.depth {
+                return Err(Error::Format("model depth does not match header"));
+            }
+            decoder.decode_all(&output, data, length - output.len())?
+        }
+    };
+    output.extend_from_slice(&decoded);
+    output.truncate(wanted);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::{cell::RefCell, rc::Rc};
+    use test_strategy::proptest;
+
+    fn codec() -> impl Strategy<Value = Codec> {
+        prop_oneof![Just(Codec::Huffman), Just(Codec::Range)]
+    }
+
+    fn roundtrip(data: &[u8], depth: usize) -> Vec<u8> {
+        let compressed = compress_bytes(data, depth).unwrap();
+        let decompressed = decompress_bytes(&compressed).unwrap();
+        assert_eq!(decompressed, data);
+        compressed
+    }
+
+    #[test]
+    fn test_short_inputs() {
+        for depth in 1..=5 {
+            for len in [0, 1, depth - 1] {
+                let data: Vec<u8> = (0..len as u8).collect();
+                let compressed = roundtrip(&data, depth);
+                let header = Header::read(&mut &compressed[..]).unwrap();
+                assert_eq!(header.literal(), len < depth);
+            }
+        }
+    }
+
+    #[test]
+    fn test_repeated_byte() {
+        // a single context with a single successor, whose code is empty.
+        let single = |depth| {
+            [
+                Builder::new().depth(depth),
+                Builder::new().depth(depth).codec(Codec::Range),
+            ]
+        };
+        // a second symbol, or a code for every byte.
+        let other = |depth| {
+            [
+                Builder::new().depth(depth).eof(true),
+                Builder::new()
+                    .depth(depth)
+                    .escape(crate::EscapeMode::Literal),
+                Builder::new().depth(depth).smoothing(1),
+            ]
+        };
+        for depth in 1..=4 {
+            for len in [0, 1, depth - 1, depth, depth + 1, 1000] {
+                let data = vec![b'x'; len];
+                for builder in single(depth) {
+                    let compressed = compress_with(&data, &builder).unwrap();
+                    assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+                    // the header and one block with its tables.
+                    assert!(compressed.len() <= 22 + 32, "{} bytes", compressed.len());
+                }
+                for builder in other(depth) {
+                    let compressed = compress_with(&data, &builder).unwrap();
+                    assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+                }
+            }
+
+            let data = vec![b'x'; 10 << 20];
+            let blocks = data.len().div_ceil(DEFAULT_BLOCK_SIZE);
+            for builder in single(depth) {
+                let compressed = compress_with(&data, &builder).unwrap();
+                assert!(
+                    compressed.len() <= 22 + 32 * blocks,
+                    "{} bytes",
+                    compressed.len()
+                );
+                assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_input() {
+        for depth in 1..=5 {
+            let compressed = roundtrip(&[], depth);
+            assert_eq!(compressed.len(), 22);
+        }
+    }
+
+    #[test]
+    fn test_truncated_literal() {
+        let compressed = compress_bytes(b"abc", 4).unwrap();
+        let result = decompress_bytes(&compressed[..compressed.len() - 1]);
+        assert!(matches!(result, Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn test_length_limit() {
+        let compressed = compress_bytes(b"abracadabra", 3).unwrap();
+        assert!(decompress_with_limit(&compressed, 11).is_ok());
+        assert!(matches!(
+            decompress_with_limit(&compressed, 10),
+            Err(Error::Format(_))
+        ));
+
+        // a single symbol costs no bits, so a tiny payload can claim a huge length.
+        let mut compressed = compress_bytes(b"aaaa", 2).unwrap();
+        compressed[10..18].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            decompress_bytes(&compressed),
+            Err(Error::Format(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_failed_position() {
+        let data = b"the quick brown fox jumps over the lazy dog. ".repeat(40);
+        let compressed = compress_bytes(&data, 3).unwrap();
+        let mut rest = &compressed[..];
+        let header = Header::read(&mut rest).unwrap();
+        assert!(!header.sync() && header.codec == Codec::Huffman);
+        assert_eq!(rest[0], BLOCK_CODED);
+
+        // a single coded block of kind, length and size, then the tables, context and codes.
+        let block = compressed.len() - rest.len();
+        let mut codes = &rest[9..];
+        let coder = Coder::read_tables(&mut codes).unwrap();
+        let mut context = [0; 2];
+        let mut reader = BitReader::endian(&mut codes, BigEndian);
+        for byte in &mut context {
+            *byte = coder.read_order0(&mut reader).unwrap();
+        }
+        assert_eq!(&context, b"th");
+        let context = &context[..];
+        let start = compressed.len() - codes.len();
+
+        // the block loses its last byte, which the codes of its end were in.
+        let mut truncated = compressed[..compressed.len() - 1].to_vec();
+        let size = read_u32(&mut &truncated[block + 5..]).unwrap() as u32;
+        truncated[block + 5..block + 9].copy_from_slice(&(size - 1).to_le_bytes());
+
+        let expected = coder
+            .decode_all(context, &codes[..codes.len() - 1], data.len() - 2)
+            .unwrap_err()
+            .offset_by(8 * start as u64, 2);
+        let actual = decompress_bytes(&truncated).unwrap_err();
+        assert!(matches!(actual, Error::DecodeFailed { .. }));
+        assert_eq!(format!("{actual:?}"), format!("{expected:?}"));
+    }
+
+    #[test]
+    fn test_truncated_prefixes() {
+        let compressed = compress_bytes(b"the quick brown fox jumps over the lazy dog", 3).unwrap();
+        for len in 0..compressed.len() {
+            assert!(matches!(
+                decompress_bytes(&compressed[..len]),
+                Err(Error::Truncated | Error::Format(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_invalid_tables() {
+        let compressed = compress_bytes(&b"ab".repeat(50), 1).unwrap();
+        // header, a coded block of 100 bytes, then depth 1, no flags, a single empty context
+        // with two symbols.
+        assert_eq!(compressed[22], BLOCK_CODED);
+        assert_eq!(&compressed[31..39], [1, 0, 1, 0, 1, b'a', b'b', 0x11]);
+
+        let mut duplicate = compressed.clone();
+        duplicate[37] = b'a';
+        assert!(matches!(
+            decompress_bytes(&duplicate),
+            Err(Error::Format(_))
+        ));
+
+        let mut oversubscribed = compressed.clone();
+        oversubscribed[38] = 0x12;
+        assert!(matches!(
+            decompress_bytes(&oversubscribed),
+            Err(Error::Format(_))
+        ));
+
+        let mut flags = compressed.clone();
+        flags[32] = 2;
+        assert!(matches!(decompress_bytes(&flags), Err(Error::Format(_))));
+
+        let mut depth = compressed.clone();
+        depth[31] = 2;
+        assert!(decompress_bytes(&depth).is_err());
+
+        let mut codec = compressed.clone();
+        codec[9] = 2;
+        assert!(matches!(
+            decompress_bytes(&codec),
+            Err(Error::Unsupported(UnsupportedFeature::Codec(2)))
+        ));
+
+        let mut kind = compressed.clone();
+        kind[22] = 2;
+        assert!(matches!(
+            decompress_bytes(&kind),
+            Err(Error::Unsupported(UnsupportedFeature::BlockKind(2)))
+        ));
+
+        let mut length = compressed.clone();
+        length[23..27].copy_from_slice(&99u32.to_le_bytes());
+        assert!(matches!(decompress_bytes(&length), Err(Error::Format(_))));
+
+        let mut block_size = compressed;
+        block_size[18..22].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(
+            decompress_bytes(&block_size),
+            Err(Error::Format(_))
+        ));
+    }
+
+    #[test]
+    fn test_header() {
+        let builder = Builder::new()
+            .depth(3)
+            .codec(Codec::Range)
+            .block_size(512)
+            .sync_interval(1024);
+        let compressed = compress_with(&b"abracadabra ".repeat(100), &builder).unwrap();
+        let mut reader = &compressed[..];
+        let header = Header::read(&mut reader).unwrap();
+        assert_eq!(reader.len(), compressed.len() - 22);
+        assert_eq!(
+            (header.version, header.depth, header.codec, header.length),
+            (VERSION, 3, Codec::Range, 1200)
+        );
+        assert_eq!(header.block_size, 512);
+        assert!(header.checksum().is_some() && !header.literal());
+
+        // nothing past the header is needed.
+        assert_eq!(Header::read(&compressed[..22]).unwrap(), header);
+        for len in 0..22 {
+            assert!(matches!(
+                Header::read(&compressed[..len]),
+                Err(Error::Truncated)
+            ));
+        }
+
+        // depths past the supported one fit in the header, but are rejected.
+        let builder = Builder::new().depth(MAX_SUPPORTED_DEPTH);
+        let mut compressed = compress_with(&b"abracadabra ".repeat(10), &builder).unwrap();
+        assert_eq!(Header::read(&compressed[..]).unwrap().depth, 16);
+        compressed[8] = 17;
+        assert!(matches!(
+            Header::read(&compressed[..]),
+            Err(Error::Format("depth too large"))
+        ));
+    }
+
+    #[test]
+    fn test_byte_map() {
+        let data = b"Abracadabra ABRACADABRA ".repeat(50);
+        let reversed = ByteMapper::new(std::array::from_fn(|byte| !(byte as u8)));
+        // the short input is stored as a literal.
+        for input in [&data[..], b"Ab"] {
+            let builder = Builder::new().depth(3).byte_map(reversed);
+            let compressed = compress_with(input, &builder).unwrap();
+            let header = Header::read(&compressed[..]).unwrap();
+            assert_eq!(header.byte_map, Some(reversed));
+            assert_eq!(decompress_bytes(&compressed).unwrap(), input);
+            assert_eq!(
+                decompress_recover(&compressed, u64::MAX).unwrap().data,
+                input
+            );
+        }
+
+        let lowercase = ByteMapper::ascii_lowercase();
+        assert!(matches!(
+            compress_with(&data, &Builder::new().byte_map(lowercase)),
+            Err(Error::Config(_))
+        ));
+        let compressed = compress_with(&data, &Builder::new().lossy_byte_map(lowercase)).unwrap();
+        assert_eq!(Header::read(&compressed[..]).unwrap().byte_map, None);
+        assert_eq!(
+            decompress_bytes(&compressed).unwrap(),
+            data.to_ascii_lowercase()
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        // 16-bit samples of a sawtooth wave, with a period that is not a power of two.
+        let data: Vec<u8> = (0..50_000u32)
+            .flat_map(|index| ((index * 7 % 50_000) as u16).to_le_bytes())
+            .collect();
+        let plain = compress_with(&data, &Builder::new()).unwrap();
+        for filter in ["delta:2", "transpose:2"] {
+            let filter: BuiltinFilter = filter.parse().unwrap();
+            let builder = Builder::new().filter(filter);
+            let compressed = compress_with(&data, &builder).unwrap();
+            assert_eq!(Header::read(&compressed[..]).unwrap().filter, Some(filter));
+            assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+            // unfiltered, the block does not compress at all and is stored.
+            assert!(compressed.len() * 4 < plain.len(), "{filter}");
+        }
+    }
+
+    #[test]
+    fn test_progress() {
+        let data = include_bytes!("markov.rs").repeat(4);
+        let reports = Rc::new(RefCell::new(vec![]));
+        let builder = Builder::new().block_size(1 << 16).progress_interval(10_000);
+        let sink = reports.clone();
+        let (compressed, _) = builder
+            .compress_with_progress(&data, move |progress| sink.borrow_mut().push(progress))
+            .unwrap();
+        assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+
+        let reports = reports.take();
+        let total = data.len() as u64;
+        assert!(reports
+            .iter()
+            .all(|report| report.bytes_total == Some(total)));
+        assert_eq!(reports.last().unwrap().bytes_done, total);
+        for pair in reports.windows(2) {
+            let (first, second) = (pair[0].bytes_done, pair[1].bytes_done);
+            assert!(second >= first + 10_000 || second == total);
+        }
+        for phase in [Phase::Training, Phase::BuildingTrees, Phase::Encoding] {
+            assert!(reports.iter().any(|report| report.phase == phase));
+        }
+
+        let mut count = 0;
+        let compressed = compress_with_progress(b"ab", 3, |_| count += 1).unwrap();
+        assert_eq!(decompress_bytes(&compressed).unwrap(), b"ab");
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let data = include_bytes!("markov.rs").repeat(4);
+        let token = CancellationToken::new();
+        let builder = Builder::new()
+            .block_size(1 << 14)
+            .cancellation(token.clone());
+        let compressed = builder.compress(&data).unwrap();
+        assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+
+        // compresses until the timer thread cancels, which stops the run in progress.
+        let timer = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                token.cancel();
+            })
+        };
+        let error = loop {
+            if let Err(error) = builder.compress(&data) {
+                break error;
+            }
+        };
+        timer.join().unwrap();
+        assert!(matches!(error, Error::Cancelled));
+        assert!(matches!(
+            decompress_cancellable(&compressed, DEFAULT_MAX_LENGTH, &token),
+            Err(Error::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_unknown_flags() {
+        let mut compressed = compress_bytes(b"abracadabra", 3).unwrap();
+        compressed[6] |= 1 << 7;
+        assert!(matches!(
+            decompress_bytes(&compressed),
+            Err(Error::Unsupported(UnsupportedFeature::Flags(0x80)))
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_features() {
+        let data = b"abracadabra ".repeat(40);
+        let compressed = compress_bytes(&data, 3).unwrap();
+        let unsupported = |offset: usize, byte: u8| {
+            let mut compressed = compressed.clone();
+            compressed[offset] = byte;
+            match decompress_bytes(&compressed) {
+                Err(Error::Unsupported(feature)) => feature,
+                result => panic!("{result:?}"),
+            }
+        };
+        // the codec follows magic, version, flags and depth, the first block the header.
+        assert_eq!(unsupported(9, 7), UnsupportedFeature::Codec(7));
+        assert_eq!(unsupported(22, 7), UnsupportedFeature::BlockKind(7));
+        // the tables start with the depth and their flags, after the block lengths.
+        assert_eq!(compressed[22], BLOCK_CODED);
+        assert_eq!(compressed[31], 3);
+        assert_eq!(
+            unsupported(32, 1 << 6),
+            UnsupportedFeature::TableFlags(1 << 6)
+        );
+
+        let filter = "delta:1".parse().unwrap();
+        let mut filtered = compress_with(&data, &Builder::new().filter(filter)).unwrap();
+        filtered[22] = 7;
+        assert!(matches!(
+            decompress_bytes(&filtered),
+            Err(Error::Unsupported(UnsupportedFeature::Filter(7)))
+        ));
+    }
+
+    #[test]
+    fn test_check_compatibility() {
+        let compressed = compress_bytes(b"abracadabra", 3).unwrap();
+        let mut header = Header::read(&compressed[..]).unwrap();
+        assert!(header.is_supported());
+
+        header.flags |= 1 << 9;
+        assert_eq!(
+            check_compatibility(&header),
+            Err(UnsupportedFeature::Flags(1 << 9))
+        );
+        header.version = VERSION + 1;
+        assert_eq!(
+            check_compatibility(&header),
+            Err(UnsupportedFeature::Version {
+                found: VERSION + 1,
+                supported: VERSION
+            })
+        );
+        assert!(!header.is_supported());
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut compressed = compress_bytes(b"abracadabra", 3).unwrap();
+        compressed[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            decompress_bytes(&compressed),
+            Err(Error::Unsupported(UnsupportedFeature::Version {
+                found,
+                supported: VERSION
+            })) if found == VERSION + 1
+        ));
+
+        // there is no version before the first one.
+        compressed[4..6].copy_from_slice(&0u16.to_le_bytes());
+        assert!(matches!(
+            decompress_bytes(&compressed),
+            Err(Error::Format("zero version"))
+        ));
+    }
+
+    #[test]
+    fn test_external_model() {
+        let data = include_bytes!("markov.rs");
+        let builder = Builder::new().depth(3).block_size(4096);
+        let coder = builder.build_coder(&builder.train(data).unwrap()).unwrap();
+        let external = builder.clone().external_model(coder.clone());
+        let (compressed, stats) = compress_blocks(data, &external).unwrap();
+        assert!(stats.coded > 0);
+        assert_eq!(stats.model_fingerprint, None);
+        assert!(compressed.len() as u64 <= max_compressed_len(data.len() as u64, &external));
+        // the blocks carry no tables.
+        assert!(compressed.len() < compress_with(data, &builder).unwrap().len());
+        assert_eq!(decompress_with_model(&compressed, &coder).unwrap(), data);
+
+        // the fingerprint follows the header.
+        let header = Header::read(&compressed[..]).unwrap();
+        assert_eq!(header.model_fingerprint, Some(coder.content_hash()));
+        assert_eq!(Header::read(&compressed[..30]).unwrap(), header);
+        assert!(matches!(
+            Header::read(&compressed[..29]),
+            Err(Error::Truncated)
+        ));
+
+        // other models are rejected before any block is read.
+        let other = builder
+            .build_coder(&builder.train(b"abracadabra").unwrap())
+            .unwrap();
+        assert!(matches!(
+            decompress_with_model(&compressed[..30], &other),
+            Err(Error::ModelFingerprintMismatch { expected, found })
+                if expected == coder.content_hash() && found == other.content_hash()
+        ));
+        assert!(matches!(
+            decompress_bytes(&compressed),
+            Err(Error::Format(_))
+        ));
+        // data with tables of its own does not need the model.
+        let own = compress_with(data, &builder).unwrap();
+        assert_eq!(decompress_with_model(&own, &other).unwrap(), data);
+
+        // every other way of decoding takes the model as well, and checks it first.
+        let token = CancellationToken::new();
+        let limits = DecodeLimits::default();
+        let mut output = vec![];
+        let stats = decompress_stream_with_model(
+            &compressed[..],
+            &mut output,
+            &limits,
+            &token,
+            Some(&coder),
+        )
+        .unwrap();
+        assert_eq!(output, data);
+        assert_eq!(stats.blocks as usize, data.len().div_ceil(4096));
+        assert!(matches!(
+            decompress_stream_with_model(&compressed[..30], vec![], &limits, &token, Some(&other)),
+            Err(Error::ModelFingerprintMismatch { .. })
+        ));
+        assert!(matches!(
+            decompress_stream(&compressed[..], vec![], &limits),
+            Err(Error::Format(_))
+        ));
+
+        let (_, prefix) = decompress_prefix_with_model(&compressed[..], 100, Some(&coder)).unwrap();
+        assert_eq!(prefix, data[..100]);
+        assert!(matches!(
+            decompress_prefix_with_model(&compressed[..30], 100, Some(&other)),
+            Err(Error::ModelFingerprintMismatch { .. })
+        ));
+        assert!(matches!(
+            decompress_prefix(&compressed[..], 10),
+            Err(Error::Format(_))
+        ));
+
+        let recovered = decompress_recover_with_model(&compressed, u64::MAX, Some(&coder)).unwrap();
+        assert_eq!(
+            (recovered.data.as_slice(), recovered.lost),
+            (&data[..], vec![])
+        );
+        assert!(matches!(
+            decompress_recover_with_model(&compressed[..30], u64::MAX, Some(&other)),
+            Err(Error::ModelFingerprintMismatch { .. })
+        ));
+        assert!(matches!(
+            decompress_recover(&compressed, u64::MAX),
+            Err(Error::Format(_))
+        ));
+
+        // the model has to fit the codec and depth.
+        assert!(matches!(
+            compress_with(data, &external.clone().codec(Codec::Range)),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            compress_with(data, &external.depth(4)),
+            Err(Error::ModelMismatch {
+                expected: 4,
+                found: 3
+            })
+        ));
+    }
+
+    #[proptest]
+    fn test_header_truncated(#[strategy(1usize..4)] depth: usize, data: Vec<u8>, len: usize) {
+        let compressed = compress_bytes(&data, depth).unwrap();
+        let header = Header::read(&compressed[..]).unwrap();
+        prop_assert_eq!(header.length, data.len() as u64);
+        prop_assert_eq!(header.literal(), data.len() < depth);
+
+        let len = len % 22;
+        prop_assert!(matches!(
+            Header::read(&compressed[..len]),
+            Err(Error::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_blocks() {
+        let data = include_bytes!("markov.rs");
+        let builder = Builder::new().depth(3).block_size(4096);
+        let (compressed, stats) = compress_blocks(data, &builder).unwrap();
+        assert_eq!(stats.coded + stats.stored, data.len().div_ceil(4096));
+        assert!(stats.coded > 0);
+        assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_stored_blocks() {
+        // random bytes, like already compressed image data, cannot be modeled.
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let data: Vec<u8> = (0..1 << 16)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let builder = Builder::new().depth(2).block_size(4096);
+        let (compressed, stats) = compress_blocks(&data, &builder).unwrap();
+        assert_eq!((stats.coded, stats.stored), (0, 16));
+
+        // the header and five bytes per block.
+        assert_eq!(compressed.len(), data.len() + 22 + 16 * 5);
+        assert_eq!(
+            compressed.len() as u64,
+            max_compressed_len(data.len() as u64, &builder)
+        );
+        assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+    }
+
+    fn sync_builder() -> Builder {
+        Builder::new().depth(3).block_size(512).sync_interval(1024)
+    }
+
+    // input and output of the sync builder, shared between the test cases.
+    fn sync_compressed() -> (&'static [u8], &'static [u8]) {
+        static COMPRESSED: std::sync::OnceLock<Vec<u8>> = std::sync::OnceLock::new();
+        let data = &include_bytes!("markov.rs")[..16384];
+        let compressed = COMPRESSED.get_or_init(|| compress_with(data, &sync_builder()).unwrap());
+        (data, compressed)
+    }
+
+    #[test]
+    fn test_sync_markers() {
+        let data = include_bytes!("markov.rs");
+        let compressed = compress_with(data, &sync_builder()).unwrap();
+        assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+        assert_eq!(
+            decompress_recover(&compressed, DEFAULT_MAX_LENGTH).unwrap(),
+            Recovered {
+                data: data.to_vec(),
+                lost: vec![],
+            }
+        );
+
+        // the first block always has a marker, later ones once the interval has passed.
+        assert_eq!(&compressed[22..25], SYNC_MAGIC);
+        let markers = compressed.windows(3).filter(|w| *w == SYNC_MAGIC).count();
+        assert!(markers > 1 && markers < data.len().div_ceil(512));
+
+        // coded blocks are hashed while coding them, to the same checksum.
+        let builder = Builder::new()
+            .depth(3)
+            .block_size(4096)
+            .sync_interval(1 << 20);
+        let text = b"abracadabra ".repeat(1000);
+        let coded = compress_with(&text, &builder).unwrap();
+        assert_eq!(coded[29], BLOCK_CODED);
+        assert_eq!(coded[38..42], checksum(&text[..4096]).to_le_bytes());
+
+        // without recovery, any damage is an error.
+        let mut damaged = compressed.clone();
+        damaged[compressed.len() / 2] ^= 0x10;
+        assert!(decompress_bytes(&damaged).is_err());
+
+        // stored blocks decode fine and are only caught by their checksum.
+        let data: Vec<u8> = (0..=255).collect();
+        let builder = Builder::new().depth(1).block_size(128).sync_interval(1);
+        let (mut compressed, stats) = compress_blocks(&data, &builder).unwrap();
+        assert_eq!(stats.stored, 2);
+        *compressed.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decompress_bytes(&compressed),
+            Err(Error::ChecksumMismatch { block: 1 })
+        ));
+    }
+
+    #[proptest]
+    fn test_recover(#[strategy(22usize..)] position: usize, #[strategy(0u8..8)] bit: u8) {
+        let (data, compressed) = sync_compressed();
+        let mut damaged = compressed.to_vec();
+        let position = 22 + position % (compressed.len() - 22);
+        damaged[position] ^= 1 << bit;
+
+        let recovered = decompress_recover(&damaged, DEFAULT_MAX_LENGTH).unwrap();
+        prop_assert_eq!(recovered.data.len(), data.len());
+        prop_assert!(recovered.lost.len() <= 1);
+
+        // everything outside the lost range decodes correctly, including the tail.
+        let mut expected = data.to_vec();
+        for range in &recovered.lost {
+            prop_assert_eq!(range.start % 512, 0);
+            prop_assert!(
+                (range.end as usize).is_multiple_of(512) || range.end as usize == data.len()
+            );
+            prop_assert!(range.end - range.start <= 2048);
+            expected[range.start as usize..range.end as usize].fill(0);
+        }
+        prop_assert_eq!(recovered.data, expected);
+    }
+
+    #[test]
+    fn test_recover_without_markers() {
+        // without checksums, only errors are noticed.
+        let data = include_bytes!("markov.rs");
+        let mut compressed = compress_with(data, &Builder::new().depth(3).block_size(512)).unwrap();
+        compressed.truncate(compressed.len() / 2);
+
+        let recovered = decompress_recover(&compressed, DEFAULT_MAX_LENGTH).unwrap();
+        let start = recovered.lost[0].start as usize;
+        assert_eq!(recovered.lost, vec![start as u64..data.len() as u64]);
+        assert_eq!(recovered.data[..start], data[..start]);
+    }
+
+    #[test]
+    fn test_recover_filtered() {
+        let data = include_bytes!("markov.rs");
+        let builder = Builder::new()
+            .depth(3)
+            .block_size(512)
+            .sync_interval(1)
+            .filter("delta:1".parse().unwrap());
+        let mut compressed = compress_with(data, &builder).unwrap();
+        let middle = compressed.len() / 2;
+        compressed[middle] ^= 1;
+
+        // the damage spreads through the deltas to the end.
+        let recovered = decompress_recover(&compressed, DEFAULT_MAX_LENGTH).unwrap();
+        let start = recovered.lost[0].start as usize;
+        assert_eq!(recovered.lost, vec![start as u64..data.len() as u64]);
+        assert_eq!(recovered.data[..start], data[..start]);
+        assert!(recovered.data[start..].iter().all(|byte| *byte == 0));
+    }
+
+    #[proptest]
+    fn test_decompress_mutated(
+        #[strategy(1usize..4)] depth: usize,
+        #[strategy(codec())] codec: Codec,
+        #[strategy(proptest::collection::vec(any::<u8>(), 1..64))] data: Vec<u8>,
+        index: usize,
+        byte: u8,
+    ) {
+        let builder = Builder::new().depth(depth).codec(codec);
+        let mut compressed = compress_with(&data, &builder).unwrap();
+        let index = index % compressed.len();
+        compressed[index] = byte;
+        let _ = decompress_with_limit(&compressed, 1 << 16);
+    }
+
+    #[proptest]
+    fn test_decompress_garbage(data: Vec<u8>) {
+        let mut input = MAGIC.to_vec();
+        input.extend_from_slice(&VERSION.to_le_bytes());
+        input.extend_from_slice(&data);
+        let _ = decompress_with_limit(&input, 1 << 16);
+    }
+
+    #[proptest]
+    fn test_decompress_prefix(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(codec())] codec: Codec,
+        #[strategy(proptest::collection::vec(0u8..8, 0..512))] data: Vec<u8>,
+        #[strategy(1usize..128)] block_size: usize,
+        #[strategy(0usize..600)] len: usize,
+        filter: bool,
+    ) {
+        let mut builder = Builder::new()
+            .depth(depth)
+            .codec(codec)
+            .block_size(block_size);
+        if filter {
+            builder = builder.filter("delta:1".parse().unwrap());
+        }
+        let compressed = compress_with(&data, &builder).unwrap();
+        let (header, prefix) = decompress_prefix(&compressed[..], len).unwrap();
+        prop_assert_eq!(header, Header::read(&compressed[..]).unwrap());
+        prop_assert_eq!(&prefix[..], &data[..len.min(data.len())]);
+    }
+
+    #[test]
+    fn test_decompress_prefix_rest_unread() {
+        let data = b"the quick brown fox jumps over the lazy dog. ".repeat(40);
+        let builder = Builder::new().depth(3).block_size(256).sync_interval(256);
+        let compressed = compress_with(&data, &builder).unwrap();
+
+        // the data is cut off in a later block, which the prefix is not in.
+        let truncated = &compressed[..compressed.len() / 2];
+        assert!(decompress_bytes(truncated).is_err());
+        for len in [0, 1, 100, 256, 257] {
+            let (_, prefix) = decompress_prefix(truncated, len).unwrap();
+            assert_eq!(prefix, data[..len]);
+        }
+        assert!(decompress_prefix(truncated, data.len()).is_err());
+    }
+
+    #[proptest]
+    fn test_compress_roundtrip(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(codec())] codec: Codec,
+        data: Vec<u8>,
+    ) {
+        let compressed = compress_with(&data, &Builder::new().depth(depth).codec(codec)).unwrap();
+        prop_assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+    }
+
+    #[proptest]
+    fn test_coded_context(
+        #[strategy(2usize..5)] depth: usize,
+        #[strategy(proptest::collection::vec(128u8.., #depth - 1))] context: Vec<u8>,
+        #[strategy(proptest::collection::vec(0u8..4, 64..256))] body: Vec<u8>,
+    ) {
+        // the opening bytes are no context anywhere else in the block.
+        let data = [context, body].concat();
+        let compressed = compress_with(&data, &Builder::new().depth(depth)).unwrap();
+        prop_assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+    }
+
+    #[proptest]
+    fn test_max_compressed_len(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(codec())] codec: Codec,
+        #[strategy(1usize..256)] block_size: usize,
+        #[strategy(proptest::option::of(1usize..256))] sync_interval: Option<usize>,
+        mapped: bool,
+        filtered: bool,
+        data: Vec<u8>,
+    ) {
+        let mut builder = Builder::new()
+            .depth(depth)
+            .codec(codec)
+            .block_size(block_size);
+        if let Some(interval) = sync_interval {
+            builder = builder.sync_interval(interval);
+        }
+        if mapped {
+            builder = builder.byte_map(ByteMapper::new(std::array::from_fn(|byte| !(byte as u8))));
+        }
+        if filtered {
+            builder = builder.filter("delta:1".parse().unwrap());
+        }
+        let compressed = compress_with(&data, &builder).unwrap();
+        prop_assert!(compressed.len() as u64 <= max_compressed_len(data.len() as u64, &builder));
+    }
+
+    #[proptest]
+    fn test_decompress_stream(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(codec())] codec: Codec,
+        #[strategy(1usize..256)] block_size: usize,
+        sync: bool,
+        mapped: bool,
+        filtered: bool,
+        data: Vec<u8>,
+    ) {
+        let mut builder = Builder::new()
+            .depth(depth)
+            .codec(codec)
+            .block_size(block_size);
+        if sync {
+            builder = builder.sync_interval(64);
+        }
+        if mapped {
+            builder = builder.byte_map(ByteMapper::new(std::array::from_fn(|byte| !(byte as u8))));
+        }
+        if filtered {
+            builder = builder.filter("delta:1".parse().unwrap());
+        }
+        let compressed = compress_with(&data, &builder).unwrap();
+        let mut output = vec![];
+        let stats =
+            decompress_stream(&compressed[..], &mut output, &DecodeLimits::default()).unwrap();
+        prop_assert_eq!(&output, &data);
+        prop_assert_eq!(stats.input_bytes, compressed.len() as u64);
+        prop_assert_eq!(stats.output_bytes, data.len() as u64);
+    }
+
+    // reads `frame` over and over, `count` times.
+    struct Repeated<'a> {
+        frame: &'a [u8],
+        offset: usize,
+        count: usize,
+    }
+
+    impl Read for Repeated<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.count == 0 {
+                return Ok(0);
+            }
+            let read = (&self.frame[self.offset..]).read(buf)?;
+            self.offset += read;
+            if self.offset == self.frame.len() {
+                self.offset = 0;
+                self.count -= 1;
+            }
+            Ok(read)
+        }
+    }
+
+    // checks the bytes written to it against `expected` repeated, without keeping them.
+    struct Sink<'a> {
+        expected: &'a [u8],
+        bytes: usize,
+    }
+
+    impl Write for Sink<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            for byte in buf {
+                assert_eq!(*byte, self.expected[self.bytes % self.expected.len()]);
+                self.bytes += 1;
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_decompress_stream_memory() {
+        // a long stream of the same coded block, which is far larger than what is held of it.
+        let block = &include_bytes!("markov.rs")[..1 << 16];
+        let builder = Builder::new().depth(3).block_size(block.len());
+        let (compressed, stats) = compress_blocks(block, &builder).unwrap();
+        assert_eq!(stats.coded, 1);
+        let count = 128;
+        let header = Header {
+            length: (count * block.len()) as u64,
+            ..Header::read(&compressed[..]).unwrap()
+        };
+        let mut input = vec![];
+        header.write(&mut input).unwrap();
+        let reader = input.chain(Repeated {
+            frame: &compressed[input.len()..],
+            offset: 0,
+            count,
+        });
+
+        let mut sink = Sink {
+            expected: block,
+            bytes: 0,
+        };
+        let limits = DecodeLimits {
+            max_memory: Some(2 * block.len()),
+            ..Default::default()
+        };
+        let stats = decompress_stream(reader, &mut sink, &limits).unwrap();
+        assert_eq!(sink.bytes, count * block.len());
+        assert_eq!(stats.blocks, count as u64);
+        assert_eq!(stats.output_bytes, header.length);
+        assert_eq!(
+            stats.peak_memory,
+            compressed.len() - input.len() + block.len()
+        );
+    }
+
+    #[test]
+    fn test_decompress_stream_limits() {
+        let data = b"abracadabra ".repeat(100);
+        let compressed = compress_bytes(&data, 3).unwrap();
+        let decompress = |limits| {
+            let mut output = vec![];
+            let result = decompress_stream(&compressed[..], &mut output, &limits);
+            (result, output)
+        };
+
+        let (result, output) = decompress(DecodeLimits {
+            max_output: Some(data.len() as u64 - 1),
+            ..Default::default()
+        });
+        assert!(matches!(
+            result,
+            Err(Error::LimitExceeded { kind: "output", limit }) if limit == data.len() - 1
+        ));
+        assert!(output.is_empty());
+        let (result, _) = decompress(DecodeLimits {
+            max_output: Some(data.len() as u64),
+            ..Default::default()
+        });
+        assert!(result.is_ok());
+
+        let (result, output) = decompress(DecodeLimits {
+            max_memory: Some(data.len()),
+            ..Default::default()
+        });
+        assert!(matches!(
+            result,
+            Err(Error::LimitExceeded { kind: "memory", .. })
+        ));
+        assert!(output.is_empty());
+    }
+
+    #[proptest]
+    fn test_compress_deterministic(#[strategy(1usize..5)] depth: usize, data: Vec<u8>) {
+        prop_assert_eq!(
+            compress_bytes(&data, depth).unwrap(),
+            compress_bytes(&data, depth).unwrap()
+        );
+    }
+}
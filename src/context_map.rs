@@ -0,0 +1,304 @@
+//! Map from the contexts of a model to the tables coding their symbols.
+//!
+//! Contexts of depths up to 3 are at most two bytes, so once enough of them are in the map they
+//! index an array of all possible contexts directly instead of being searched for in a sorted
+//! map. Sparse and deeper contexts stay in the sorted map. Either way the contexts iterate in
+//! ascending order.
+//!
+//! This is the storage of [`Coder`] and the range coder tables. [`Encoder`] and [`Decoder`]
+//! keep their contexts in sorted maps.
+//!
+//! [`Coder`]: crate::huffman::Coder
+//! [`Encoder`]: crate::huffman::Encoder
+//! [`Decoder`]: crate::huffman::Decoder
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+// longest context that is stored in an array, whose size is 256 to this power.
+const MAX_DENSE_WIDTH: usize = 2;
+
+// contexts move to an array once they occupy at least one in this many of its slots.
+const DENSE_OCCUPANCY: usize = 64;
+
+type Entry<T> = Option<Box<(Arc<[u8]>, T)>>;
+
+#[derive(Clone, Debug)]
+enum Storage<T> {
+    // slot `i` holds the context whose bytes are `i` in big-endian order, which sorts like the
+    // contexts. entries are boxed to keep empty slots small.
+    Dense {
+        width: usize,
+        slots: Box<[Entry<T>]>,
+    },
+    Sorted(BTreeMap<Arc<[u8]>, T>),
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ContextMap<T> {
+    storage: Storage<T>,
+    len: usize,
+    // length of the contexts if they can move to an array.
+    dense_width: Option<usize>,
+}
+
+impl<T> ContextMap<T> {
+    /// Map of the contexts of a model of `depth`, which are `depth - 1` bytes long.
+    pub fn new(depth: usize) -> Self {
+        let mut map = ContextMap {
+            dense_width: depth
+                .checked_sub(1)
+                .filter(|width| *width <= MAX_DENSE_WIDTH),
+            ..Default::default()
+        };
+        map.densify();
+        map
+    }
+
+    // moves the contexts to an array once they are dense enough.
+    fn densify(&mut self) {
+        let Some(width) = self.dense_width else {
+            return;
+        };
+        let len = 1 << (8 * width);
+        if !matches!(self.storage, Storage::Sorted(_)) || self.len < len / DENSE_OCCUPANCY {
+            return;
+        }
+        let mut slots: Box<[Entry<T>]> = std::iter::repeat_with(|| None).take(len).collect();
+        let Storage::Sorted(map) =
+            std::mem::replace(&mut self.storage, Storage::Sorted(BTreeMap::new()))
+        else {
+            unreachable!();
+        };
+        for (context, value) in map {
+            let index = slot(width, &context).expect("context length does not match depth");
+            slots[index] = Some(Box::new((context, value)));
+        }
+        self.storage = Storage::Dense { width, slots };
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, context: &[u8]) -> Option<&T> {
+        match &self.storage {
+            Storage::Dense { width, slots } => {
+                let entry = slots[slot(*width, context)?].as_ref()?;
+                Some(&entry.1)
+            }
+            Storage::Sorted(map) => map.get(context),
+        }
+    }
+
+    pub fn contains_key(&self, context: &[u8]) -> bool {
+        self.get(context).is_some()
+    }
+
+    /// Inserts `value` for `context`, which has to be as long as the contexts of the depth.
+    pub fn insert(&mut self, context: Arc<[u8]>, value: T) -> Option<T> {
+        let previous = match &mut self.storage {
+            Storage::Dense { width, slots } => {
+                let index = slot(*width, &context).expect("context length does not match depth");
+                slots[index]
+                    .replace(Box::new((context, value)))
+                    .map(|entry| entry.1)
+            }
+            Storage::Sorted(map) => map.insert(context, value),
+        };
+        if previous.is_none() {
+            self.len += 1;
+            self.densify();
+        }
+        previous
+    }
+
+    pub fn remove(&mut self, context: &[u8]) -> Option<T> {
+        let removed = match &mut self.storage {
+            Storage::Dense { width, slots } => {
+                let entry = slots[slot(*width, context)?].take()?;
+                Some(entry.1)
+            }
+            Storage::Sorted(map) => map.remove(context),
+        };
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// Contexts and their values, in ascending order of the contexts.
+    pub fn iter(&self) -> Iter<'_, T> {
+        let inner = match &self.storage {
+            Storage::Dense { slots, .. } => IterInner::Dense(slots.iter()),
+            Storage::Sorted(map) => IterInner::Sorted(map.iter()),
+        };
+        Iter {
+            inner,
+            remaining: self.len,
+        }
+    }
+}
+
+pub(crate) struct Iter<'a, T> {
+    inner: IterInner<'a, T>,
+    remaining: usize,
+}
+
+enum IterInner<'a, T> {
+    Dense(std::slice::Iter<'a, Entry<T>>),
+    Sorted(std::collections::btree_map::Iter<'a, Arc<[u8]>, T>),
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (&'a Arc<[u8]>, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = match &mut self.inner {
+            IterInner::Dense(slots) => slots
+                .find_map(Option::as_ref)
+                .map(|entry| (&entry.0, &entry.1)),
+            IterInner::Sorted(entries) => entries.next(),
+        }?;
+        self.remaining -= 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T> ExactSizeIterator for Iter<'_, T> {}
+
+// index of the slot of `context` in a dense map, `None` if it has the wrong length.
+fn slot(width: usize, context: &[u8]) -> Option<usize> {
+    if context.len() != width {
+        return None;
+    }
+    Some(
+        context
+            .iter()
+            .fold(0, |index, byte| (index << 8) | usize::from(*byte)),
+    )
+}
+
+impl<T> Default for ContextMap<T> {
+    fn default() -> Self {
+        ContextMap {
+            storage: Storage::Sorted(BTreeMap::new()),
+            len: 0,
+            dense_width: None,
+        }
+    }
+}
+
+impl<T> Extend<(Arc<[u8]>, T)> for ContextMap<T> {
+    fn extend<I: IntoIterator<Item = (Arc<[u8]>, T)>>(&mut self, iter: I) {
+        for (context, value) in iter {
+            self.insert(context, value);
+        }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a ContextMap<T> {
+    type Item = (&'a Arc<[u8]>, &'a T);
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// equality and hashing only depend on the contents, like for the map it replaces.
+impl<T: PartialEq> PartialEq for ContextMap<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Eq> Eq for ContextMap<T> {}
+
+impl<T: Hash> Hash for ContextMap<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+        for (context, value) in self.iter() {
+            context.hash(state);
+            value.hash(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use test_strategy::proptest;
+
+    #[proptest]
+    fn test_like_btree_map(
+        #[strategy(1usize..6)] depth: usize,
+        #[strategy(proptest::collection::vec((any::<[u8; 4]>(), any::<u16>(), any::<bool>()), 0..64))]
+        operations: Vec<([u8; 4], u16, bool)>,
+    ) {
+        let mut map = ContextMap::new(depth);
+        let mut expected = BTreeMap::new();
+        for (bytes, value, insert) in operations {
+            // bytes below 4, so that contexts repeat.
+            let context: Arc<[u8]> = bytes[..depth - 1].iter().map(|byte| byte % 4).collect();
+            if insert {
+                prop_assert_eq!(
+                    map.insert(context.clone(), value),
+                    expected.insert(context.clone(), value)
+                );
+            } else {
+                prop_assert_eq!(map.remove(&context), expected.remove(&context));
+            }
+            prop_assert_eq!(map.get(&context), expected.get(&context));
+            prop_assert_eq!(map.len(), expected.len());
+        }
+        prop_assert!(map.iter().eq(expected.iter()));
+        prop_assert!(map.get(&[0; 5]).is_none());
+    }
+
+    #[test]
+    fn test_storage() {
+        assert!(matches!(
+            ContextMap::<()>::new(1).storage,
+            Storage::Dense { width: 0, ref slots } if slots.len() == 1
+        ));
+        assert!(matches!(
+            ContextMap::<()>::new(4).storage,
+            Storage::Sorted(_)
+        ));
+
+        // the array of all two byte contexts is only allocated once enough of them are used.
+        let mut map = ContextMap::new(3);
+        let threshold = (1 << 16) / DENSE_OCCUPANCY;
+        for index in 0..threshold as u16 {
+            assert!(matches!(map.storage, Storage::Sorted(_)));
+            map.insert(index.to_be_bytes()[..].into(), index);
+        }
+        assert!(matches!(
+            map.storage,
+            Storage::Dense { width: 2, ref slots } if slots.len() == 1 << 16
+        ));
+        assert_eq!(map.len(), threshold);
+        assert!(map.iter().map(|(_, value)| *value).eq(0..threshold as u16));
+        assert_eq!(map.get(&[0, 7]), Some(&7));
+
+        // maps of the same contents are equal, however they are stored.
+        let mut dense = ContextMap::new(2);
+        for byte in 0..4 {
+            dense.insert([byte][..].into(), 1);
+        }
+        assert!(matches!(dense.storage, Storage::Dense { .. }));
+        let mut sorted = ContextMap::default();
+        for byte in 0..4 {
+            sorted.insert([byte][..].into(), 1);
+        }
+        assert_eq!(dense, sorted);
+    }
+}
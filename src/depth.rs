@@ -0,0 +1,242 @@
+//! Choosing the depth of a model from a sample of the data, see [`suggest_depth`].
+//!
+//! Deeper models predict better but have more contexts, whose code tables are stored with every
+//! block. The best depth is the one where the codes shrink less than the tables grow, which
+//! depends on the data.
+use crate::{
+    builder::Builder,
+    container::{prepare, write_context, Codec, MAX_SUPPORTED_DEPTH, SYNC_MAGIC},
+    error::Error,
+};
+
+// kind and length of a stored block, coded ones also have the length of their codes.
+const STORED_BLOCK: u64 = 5;
+const CODED_BLOCK: u64 = STORED_BLOCK + 4;
+// checksum of a block and the marker in front of the first one, with sync markers.
+const SYNC_BLOCK: u64 = 4;
+const SYNC_MARKER: u64 = SYNC_MAGIC.len() as u64 + 4;
+
+/// Estimated compressed size of a sample with a model of one depth.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DepthEstimate {
+    pub depth: usize,
+    /// Contexts of the model.
+    pub contexts: usize,
+    /// Bytes of the code tables.
+    pub table_bytes: u64,
+    /// Bytes of the codes of the sample, without its first `depth - 1` bytes.
+    pub code_bytes: u64,
+    /// Compressed size of the sample as a single block, with the header, the tables, the coded
+    /// initial context and the codes, or with the sample stored if that is smaller.
+    pub total_bytes: u64,
+}
+
+/// Outcome of [`suggest_depth`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DepthSuggestion {
+    /// Depth of the smallest estimate, the lowest one if several are as small.
+    pub depth: usize,
+    /// Estimates for every depth from 1 up to the maximum, in ascending order.
+    pub estimates: Vec<DepthEstimate>,
+}
+
+/// Picks the depth up to `max_depth` that compresses `sample` the smallest, with the default
+/// options of the huffman codec. See [`suggest_depth_with`].
+pub fn suggest_depth(sample: &[u8], max_depth: usize) -> DepthSuggestion {
+    suggest_depth_with(sample, max_depth, &Builder::new())
+        .expect("the default options can code any sample")
+}
+
+/// Picks the depth up to `max_depth` that compresses `sample` the smallest with the options of
+/// `builder`, whose depth is ignored.
+///
+/// The sample is filtered and trained on at every depth as compressing it would. Sizes are
+/// exact for a sample that is compressed as a single block, for larger inputs the sample should
+/// be about as large as a block. Depths larger than the sample are not considered. Models given
+/// with [`Builder::external_model`] have a depth of their own, they are rejected.
+pub fn suggest_depth_with(
+    sample: &[u8],
+    max_depth: usize,
+    builder: &Builder,
+) -> Result<DepthSuggestion, Error> {
+    if builder.model.is_some() {
+        return Err(Error::Config(
+            "the depth of an external model cannot be chosen".into(),
+        ));
+    }
+    let max_depth = max_depth
+        .clamp(1, MAX_SUPPORTED_DEPTH)
+        .min(sample.len().max(1));
+    let estimates = (1..=max_depth)
+        .map(|depth| estimate(&builder.clone().depth(depth), sample))
+        .collect::<Result<Vec<_>, _>>()?;
+    let best = estimates
+        .iter()
+        .min_by_key(|estimate| estimate.total_bytes)
+        .expect("there is at least one depth");
+    Ok(DepthSuggestion {
+        depth: best.depth,
+        estimates,
+    })
+}
+
+fn estimate(builder: &Builder, sample: &[u8]) -> Result<DepthEstimate, Error> {
+    builder.validate()?;
+    let depth = builder.depth;
+    let (header, sample) = prepare(sample, builder);
+    let mut header_bytes = vec![];
+    header.write(&mut header_bytes)?;
+    let header_bytes = header_bytes.len() as u64;
+    let mut estimate = DepthEstimate {
+        depth,
+        contexts: 0,
+        table_bytes: 0,
+        code_bytes: 0,
+        total_bytes: header_bytes + sample.len() as u64,
+    };
+    // inputs shorter than the depth are stored as they are.
+    if sample.len() < depth {
+        return Ok(estimate);
+    }
+
+    let markov = builder.train(&sample)?;
+    let initial = &sample[..depth - 1];
+    let mut tables = vec![];
+    let mut context = vec![];
+    let codes = match builder.codec {
+        Codec::Huffman => {
+            let coder = builder.build_coder(&markov)?;
+            coder.write_tables(&mut tables)?;
+            // the initial context is coded with the order-0 tree.
+            write_context(&coder, initial, &mut context)?;
+            coder.encode_all(&sample)?
+        }
+        Codec::Range => {
+            let encoder = builder.build_range_encoder(&markov)?;
+            encoder.write_tables(&mut tables)?;
+            context.extend_from_slice(initial);
+            encoder.encode_all(&sample)?
+        }
+    };
+
+    let mut coded = CODED_BLOCK + context.len() as u64 + tables.len() as u64 + codes.len() as u64;
+    let mut stored = STORED_BLOCK + sample.len() as u64;
+    if builder.sync_interval.is_some() {
+        coded += SYNC_MARKER + SYNC_BLOCK;
+        stored += SYNC_MARKER + SYNC_BLOCK;
+    }
+    estimate.contexts = markov.iter_prefix().count();
+    estimate.table_bytes = tables.len() as u64;
+    estimate.code_bytes = codes.len() as u64;
+    estimate.total_bytes = header_bytes + coded.min(stored);
+    Ok(estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compress_with,
+        filter::{BuiltinFilter, Delta},
+        huffman::EscapeMode,
+        Builder, Markov,
+    };
+    use test_strategy::proptest;
+
+    // bytes below 8 from a source where each byte is one of two that depend on the `order`
+    // bytes before it.
+    fn markov_source(order: usize, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; order];
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        while data.len() < len {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let context = &data[data.len() - order..];
+            let hash = context
+                .iter()
+                .fold(0u32, |hash, byte| hash.wrapping_mul(31) + u32::from(*byte));
+            let byte = (hash.wrapping_mul(2_654_435_761) >> 29) as u8 ^ (state & 1) as u8;
+            data.push(byte);
+        }
+        data
+    }
+
+    #[test]
+    fn test_estimate_exact() {
+        // a sample of a single block compresses to exactly the estimate.
+        let data = markov_source(2, 20_000);
+        let suggestion = suggest_depth(&data, 4);
+        for estimate in &suggestion.estimates {
+            let compressed = compress_with(&data, &Builder::new().depth(estimate.depth)).unwrap();
+            assert_eq!(estimate.total_bytes, compressed.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_estimate_exact_options() {
+        // the estimates follow the codec, escapes, end symbols, code lengths and headers of the
+        // builder.
+        let data = markov_source(2, 20_000);
+        let builders = [
+            Builder::new()
+                .escape(EscapeMode::Literal)
+                .eof(true)
+                .max_code_length(10)
+                .top_successors(3),
+            Builder::new().codec(Codec::Range),
+            Builder::new()
+                .filter(BuiltinFilter::Delta(Delta { stride: 1 }))
+                .sync_interval(1 << 10),
+        ];
+        for builder in builders {
+            let suggestion = suggest_depth_with(&data, 4, &builder).unwrap();
+            for estimate in &suggestion.estimates {
+                let compressed = compress_with(&data, &builder.clone().depth(estimate.depth));
+                assert_eq!(estimate.total_bytes, compressed.unwrap().len() as u64);
+            }
+        }
+
+        let filtered = Builder::new().filter(BuiltinFilter::Delta(Delta { stride: 1 }));
+        let suggestion = suggest_depth_with(b"", 4, &filtered).unwrap();
+        assert_eq!(suggestion.estimates[0].total_bytes, 22 + 5);
+        let model = Builder::new()
+            .depth(1)
+            .build_coder(&Markov::new(1))
+            .unwrap();
+        assert!(matches!(
+            suggest_depth_with(&data, 4, &Builder::new().external_model(model)),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[proptest]
+    fn test_estimate_exact_small(#[strategy(1usize..5)] max_depth: usize, data: Vec<u8>) {
+        for estimate in suggest_depth(&data, max_depth).estimates {
+            let compressed = compress_with(&data, &Builder::new().depth(estimate.depth)).unwrap();
+            assert_eq!(estimate.total_bytes, compressed.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_suggest_depth() {
+        // with the whole context a byte costs one bit, with less of it three, and more of it
+        // only adds contexts.
+        for order in 1..=3 {
+            let data = markov_source(order, 200_000);
+            let suggestion = suggest_depth(&data, 6);
+            assert_eq!(suggestion.depth, order + 1, "{:?}", suggestion.estimates);
+            assert_eq!(suggestion.estimates.len(), 6);
+        }
+
+        assert_eq!(suggest_depth(b"", 4).depth, 1);
+        assert_eq!(suggest_depth(b"", 4).estimates[0].total_bytes, 22);
+        // too short to code, the sample is stored at every depth.
+        let suggestion = suggest_depth(b"ab", 4);
+        assert_eq!(suggestion.depth, 1);
+        assert!(suggestion
+            .estimates
+            .iter()
+            .all(|estimate| estimate.total_bytes == 22 + 5 + 2));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    huffman::{CodeTableError, ModelShapeChanged},
+    markov::SequenceLengthError,
+};
+use std::io::{Error as IoError, ErrorKind};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("input of {len} bytes is shorter than model depth {depth}")]
+    InputTooShort { len: usize, depth: usize },
+
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("invalid format: {0}")]
+    Format(&'static str),
+
+    // not a source, the message names the feature already.
+    #[error("unsupported {0}")]
+    Unsupported(UnsupportedFeature),
+
+    #[error("checksum mismatch in block {block}")]
+    ChecksumMismatch { block: usize },
+
+    #[error("model depth {found} does not match the expected depth {expected}")]
+    ModelMismatch { expected: usize, found: usize },
+
+    /// The model given to decode data coded with an external model is not the one it was coded
+    /// with, by the fingerprint in the header.
+    #[error(
+        "model fingerprint {found:016x} does not match the expected fingerprint {expected:016x}"
+    )]
+    ModelFingerprintMismatch { expected: u64, found: u64 },
+
+    #[error("{kind} limit of {limit} exceeded")]
+    LimitExceeded { kind: &'static str, limit: usize },
+
+    #[error("unexpected end of input")]
+    Truncated,
+
+    /// Failure to decode a coded stream, at the start of the code that could not be decoded.
+    /// Positions count from the start of the stream, in the container from the start of the
+    /// compressed data and of the output before the filters of the header are undone.
+    #[error("decode failed at output byte {bytes_produced} (bit {bit_offset}), context = {context:02x?}")]
+    DecodeFailed {
+        bit_offset: u64,
+        bytes_produced: u64,
+        /// Bytes before the one that failed to decode.
+        context: Vec<u8>,
+        source: Box<Error>,
+    },
+
+    #[error("cancelled")]
+    Cancelled,
+
+    /// Weights added up to more than fit, with [`WeightPolicy::Error`].
+    ///
+    /// [`WeightPolicy::Error`]: crate::markov::WeightPolicy::Error
+    #[error("weights overflow")]
+    WeightOverflow,
+
+    /// A thread panicked while holding the lock of a shared model.
+    #[error("model lock poisoned")]
+    Poisoned,
+
+    #[error(transparent)]
+    SequenceLength(#[from] SequenceLengthError),
+
+    #[error(transparent)]
+    CodeTable(#[from] CodeTableError),
+
+    #[error(transparent)]
+    ModelShape(#[from] ModelShapeChanged),
+
+    #[cfg(feature = "serde_json")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Io(IoError),
+}
+
+/// Part of the format this build does not know, which a newer one wrote.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum UnsupportedFeature {
+    /// Version of the container, a model file or the flat decoder layout that is newer than
+    /// the ones this build reads.
+    #[error("format version {found}, expected at most {supported}")]
+    Version { found: u16, supported: u16 },
+
+    /// Flags of the container header that are not known.
+    #[error("header flags {0:#06x}")]
+    Flags(u16),
+
+    #[error("codec {0}")]
+    Codec(u8),
+
+    #[error("filter {0}")]
+    Filter(u8),
+
+    #[error("block kind {0}")]
+    BlockKind(u8),
+
+    /// Flags of the code tables that are not known, which select the escape scheme, the
+    /// alphabet and end symbols.
+    #[error("table flags {0:#x}")]
+    TableFlags(u64),
+}
+
+impl Error {
+    // moves the position of a decode failure past the data before the stream it happened in.
+    pub(crate) fn offset_by(self, bits: u64, bytes: u64) -> Self {
+        match self {
+            Error::DecodeFailed {
+                bit_offset,
+                bytes_produced,
+                context,
+                source,
+            } => Error::DecodeFailed {
+                bit_offset: bit_offset + bits,
+                bytes_produced: bytes_produced + bytes,
+                context,
+                source,
+            },
+            error => error,
+        }
+    }
+}
+
+impl From<UnsupportedFeature> for Error {
+    fn from(feature: UnsupportedFeature) -> Self {
+        Error::Unsupported(feature)
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(error: IoError) -> Self {
+        // errors raised inside of the io adapters carry our own error type.
+        if error.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *error.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+
+        if error.kind() == ErrorKind::UnexpectedEof {
+            return Error::Truncated;
+        }
+
+        Error::Io(error)
+    }
+}
+
+impl From<Error> for IoError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(error) => error,
+            Error::Truncated => IoError::new(ErrorKind::UnexpectedEof, error),
+            Error::DecodeFailed { ref source, .. } if matches!(**source, Error::Truncated) => {
+                IoError::new(ErrorKind::UnexpectedEof, error)
+            }
+            Error::LimitExceeded { .. }
+            | Error::Cancelled
+            | Error::Poisoned
+            | Error::WeightOverflow => IoError::other(error),
+            error => IoError::new(ErrorKind::InvalidData, error),
+        }
+    }
+}
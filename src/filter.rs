@@ -0,0 +1,218 @@
+//! Reversible transforms applied to the input before compressing, see [`Builder::filter`].
+//!
+//! Filters keep the length of the data. They help when the bytes are structured in a way an
+//! order-n model does not see, such as slowly changing numbers or rows of fixed width.
+//!
+//! [`Builder::filter`]: crate::Builder::filter
+use std::{fmt, str::FromStr};
+
+pub trait Filter {
+    fn encode(&self, data: &[u8]) -> Vec<u8>;
+    fn decode(&self, data: &[u8]) -> Vec<u8>;
+}
+
+/// Replaces every byte by its difference to the byte `stride` positions before it, so that
+/// series of records of `stride` bytes that change slowly turn into runs of small values. A
+/// stride of zero leaves the data as it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Delta {
+    pub stride: usize,
+}
+
+impl Filter for Delta {
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut output = data.to_vec();
+        if self.stride == 0 {
+            return output;
+        }
+        for index in self.stride..data.len() {
+            output[index] = data[index].wrapping_sub(data[index - self.stride]);
+        }
+        output
+    }
+
+    fn decode(&self, data: &[u8]) -> Vec<u8> {
+        let mut output = data.to_vec();
+        if self.stride == 0 {
+            return output;
+        }
+        for index in self.stride..data.len() {
+            output[index] = output[index].wrapping_add(output[index - self.stride]);
+        }
+        output
+    }
+}
+
+/// Writes rows of `cols` bytes column by column, which puts the bytes of every field of fixed
+/// width records next to each other. Bytes after the last full row are kept as they are, and
+/// zero columns leave the data as it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Transpose {
+    pub cols: usize,
+}
+
+impl Transpose {
+    fn rows(&self, data: &[u8]) -> usize {
+        data.len().checked_div(self.cols).unwrap_or(0)
+    }
+}
+
+impl Filter for Transpose {
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let rows = self.rows(data);
+        let mut output = Vec::with_capacity(data.len());
+        for col in 0..self.cols.min(data.len()) {
+            output.extend((0..rows).map(|row| data[row * self.cols + col]));
+        }
+        output.extend_from_slice(&data[rows * self.cols..]);
+        output
+    }
+
+    fn decode(&self, data: &[u8]) -> Vec<u8> {
+        let rows = self.rows(data);
+        let mut output = data.to_vec();
+        for col in 0..self.cols.min(data.len()) {
+            for row in 0..rows {
+                output[row * self.cols + col] = data[col * rows + row];
+            }
+        }
+        output
+    }
+}
+
+/// Filter of the container format, stored in the header as its id and parameter.
+///
+/// Parses from and displays as `delta:<stride>` or `transpose:<cols>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BuiltinFilter {
+    Delta(Delta),
+    Transpose(Transpose),
+}
+
+impl BuiltinFilter {
+    pub(crate) fn from_parts(id: u8, parameter: u32) -> Option<Self> {
+        let parameter = parameter as usize;
+        match id {
+            _ if parameter == 0 => None,
+            0 => Some(BuiltinFilter::Delta(Delta { stride: parameter })),
+            1 => Some(BuiltinFilter::Transpose(Transpose { cols: parameter })),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            BuiltinFilter::Delta(_) => 0,
+            BuiltinFilter::Transpose(_) => 1,
+        }
+    }
+
+    /// Stride or number of columns.
+    pub fn parameter(&self) -> usize {
+        match self {
+            BuiltinFilter::Delta(delta) => delta.stride,
+            BuiltinFilter::Transpose(transpose) => transpose.cols,
+        }
+    }
+}
+
+impl Filter for BuiltinFilter {
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            BuiltinFilter::Delta(delta) => delta.encode(data),
+            BuiltinFilter::Transpose(transpose) => transpose.encode(data),
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            BuiltinFilter::Delta(delta) => delta.decode(data),
+            BuiltinFilter::Transpose(transpose) => transpose.decode(data),
+        }
+    }
+}
+
+impl FromStr for BuiltinFilter {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (name, parameter) = input
+            .split_once(':')
+            .ok_or_else(|| format!("filter {input:?} has no parameter, expected name:number"))?;
+        let parameter: u32 = parameter
+            .parse()
+            .map_err(|_| format!("invalid filter parameter {parameter:?}"))?;
+        if parameter == 0 {
+            return Err(format!("filter parameter of {name:?} must be at least 1"));
+        }
+        let id = match name {
+            "delta" => 0,
+            "transpose" => 1,
+            other => {
+                return Err(format!(
+                    "unknown filter {other:?}, expected delta or transpose"
+                ))
+            }
+        };
+        Ok(Self::from_parts(id, parameter).expect("parameter is at least 1"))
+    }
+}
+
+impl fmt::Display for BuiltinFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuiltinFilter::Delta(delta) => write!(f, "delta:{}", delta.stride),
+            BuiltinFilter::Transpose(transpose) => write!(f, "transpose:{}", transpose.cols),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use test_strategy::proptest;
+
+    #[proptest]
+    fn test_roundtrip(#[strategy(1u32..20)] parameter: u32, transpose: bool, data: Vec<u8>) {
+        let filter = BuiltinFilter::from_parts(transpose.into(), parameter).unwrap();
+        let encoded = filter.encode(&data);
+        prop_assert_eq!(encoded.len(), data.len());
+        prop_assert_eq!(filter.decode(&encoded), data);
+        prop_assert_eq!(filter.to_string().parse::<BuiltinFilter>().unwrap(), filter);
+    }
+
+    #[test]
+    fn test_delta() {
+        let delta = Delta { stride: 2 };
+        assert_eq!(delta.encode(&[1, 10, 2, 12, 3, 14]), [1, 10, 1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn test_transpose() {
+        let transpose = Transpose { cols: 3 };
+        assert_eq!(transpose.encode(b"abcABC12"), *b"aAbBcC12");
+        assert_eq!(transpose.decode(b"aAbBcC12"), *b"abcABC12");
+    }
+
+    #[test]
+    fn test_zero_parameter() {
+        let data = b"abcABC12";
+        for filter in [&Delta { stride: 0 } as &dyn Filter, &Transpose { cols: 0 }] {
+            assert_eq!(filter.encode(data), data);
+            assert_eq!(filter.decode(data), data);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "delta:4".parse(),
+            Ok(BuiltinFilter::Delta(Delta { stride: 4 }))
+        );
+        assert!("delta".parse::<BuiltinFilter>().is_err());
+        assert!("delta:0".parse::<BuiltinFilter>().is_err());
+        assert!("transpose:0".parse::<BuiltinFilter>().is_err());
+        assert!("rotate:1".parse::<BuiltinFilter>().is_err());
+    }
+}
@@ -0,0 +1,317 @@
+//! Flat layout of a [`Decoder`] for shipping models to other machines, which loads faster than
+//! the code tables of [`Decoder::write_tables`] at the cost of size.
+//!
+//! Everything is little-endian and at a fixed offset:
+//!
+//! | offset | size | content |
+//! |---|---|---|
+//! | 0 | 4 | magic `HMFL` |
+//! | 4 | 2 | version, 1 |
+//! | 6 | 2 | flags, 1 for literal escapes, 2 for end symbols and 4 for an order-0 tree |
+//! | 8 | 1 | depth, at most 9 |
+//! | 9 | 3 | zero |
+//! | 12 | 4 | number of contexts |
+//! | 16 | 4 | number of symbols |
+//! | 20 | 32 | alphabet as a bit set, four `u64` |
+//! | 52 | 16 per context | contexts in ascending order |
+//! | | 16 | the order-0 tree if its flag is set |
+//! | | 2 per symbol | symbols of all contexts |
+//!
+//! Every context is its bytes padded with zeros to 8, the index of its first symbol as `u32`,
+//! its number of symbols as `u16` and two zero bytes. The symbols of a context follow those of
+//! the one before it, in the order of [`Decoder::iter_codes`]. Each is a `u16` holding the
+//! symbol in the low 9 bits, bytes as themselves, 256 for the escape and 257 for the end, and
+//! its code length above them. The order-0 tree is stored like a context of zero bytes, with an
+//! escape but never an end, and is not counted in the number of contexts.
+use crate::{
+    alphabet::AlphabetMap,
+    error::{Error, UnsupportedFeature},
+    huffman::{symbol_index, tree_codes, Decoder, EscapeMode, HuffmanNode, Symbol},
+};
+
+const MAGIC: [u8; 4] = *b"HMFL";
+const VERSION: u16 = 1;
+const FLAG_ESCAPE: u16 = 1;
+const FLAG_EOF: u16 = 2;
+const FLAG_ORDER0: u16 = 4;
+const HEADER_SIZE: usize = 52;
+const CONTEXT_SIZE: usize = 16;
+const KEY_SIZE: usize = 8;
+
+/// Largest depth the flat layout holds, whose contexts fill the 8 bytes of a key.
+pub const MAX_FLAT_DEPTH: usize = KEY_SIZE + 1;
+
+impl Decoder {
+    /// The decoder in the flat layout, see the [module documentation](self).
+    pub fn to_flat(&self) -> Result<Vec<u8>, Error> {
+        if self.depth > MAX_FLAT_DEPTH {
+            return Err(Error::Config(format!(
+                "depth {} exceeds the maximum of {MAX_FLAT_DEPTH} for the flat layout",
+                self.depth
+            )));
+        }
+
+        let order0 = self.depth > 1 && self.order0().is_some();
+        // contexts of deeper models are never empty, so the one of the order-0 tree stands out.
+        let order0_codes = self.order0().filter(|_| order0).into_iter();
+        let codes = self
+            .iter_codes()
+            .chain(order0_codes.flat_map(|node| tree_codes(&[], node)));
+        let mut flat = Vec::with_capacity(HEADER_SIZE + CONTEXT_SIZE * (self.trees.len() + 1));
+        let mut entries = vec![];
+        let mut flags = 0;
+        if self.escape == EscapeMode::Literal {
+            flags |= FLAG_ESCAPE;
+        }
+        if self.eof {
+            flags |= FLAG_EOF;
+        }
+        if order0 {
+            flags |= FLAG_ORDER0;
+        }
+        flat.extend_from_slice(&MAGIC);
+        flat.extend_from_slice(&VERSION.to_le_bytes());
+        flat.extend_from_slice(&flags.to_le_bytes());
+        flat.extend_from_slice(&[self.depth as u8, 0, 0, 0]);
+        flat.extend_from_slice(&(self.trees.len() as u32).to_le_bytes());
+        // the number of symbols is known once every tree is written.
+        flat.extend_from_slice(&[0; 4]);
+        for word in self.alphabet.words() {
+            flat.extend_from_slice(&word.to_le_bytes());
+        }
+
+        // a context starts with its first code, its count is updated as its codes follow.
+        let mut symbols = 0u32;
+        let mut previous = None;
+        let mut count_offset = 0;
+        for code in codes {
+            if previous != Some(code.context) {
+                previous = Some(code.context);
+                let mut key = [0; KEY_SIZE];
+                key[..code.context.len()].copy_from_slice(code.context);
+                flat.extend_from_slice(&key);
+                flat.extend_from_slice(&symbols.to_le_bytes());
+                count_offset = flat.len();
+                flat.extend_from_slice(&[0; 4]);
+            }
+            let count = &mut flat[count_offset..count_offset + 2];
+            let next = u16::from_le_bytes([count[0], count[1]]) + 1;
+            count.copy_from_slice(&next.to_le_bytes());
+            let entry = symbol_index(code.symbol) as u16 | u16::from(code.length) << 9;
+            entries.extend_from_slice(&entry.to_le_bytes());
+            symbols += 1;
+        }
+        flat[16..20].copy_from_slice(&symbols.to_le_bytes());
+        flat.append(&mut entries);
+        Ok(flat)
+    }
+
+    /// Reads a decoder in the flat layout, rejecting anything [`Decoder::to_flat`] would not
+    /// have written.
+    pub fn from_flat(flat: &[u8]) -> Result<Decoder, Error> {
+        let header = flat.get(..HEADER_SIZE).ok_or(Error::Truncated)?;
+        if header[..4] != MAGIC {
+            return Err(Error::Format("bad magic"));
+        }
+        let version = u16_at(header, 4);
+        if version != VERSION {
+            return Err(UnsupportedFeature::Version {
+                found: version,
+                supported: VERSION,
+            }
+            .into());
+        }
+        let flags = u16_at(header, 6);
+        if flags & !(FLAG_ESCAPE | FLAG_EOF | FLAG_ORDER0) != 0 {
+            return Err(Error::Format("unknown flags"));
+        }
+        let depth = usize::from(header[8]);
+        if !(1..=MAX_FLAT_DEPTH).contains(&depth) {
+            return Err(Error::Format("invalid depth"));
+        }
+        let order0 = flags & FLAG_ORDER0 != 0;
+        if order0 && depth == 1 {
+            return Err(Error::Format("order-0 tree in a model of depth 1"));
+        }
+        if header[9..12] != [0; 3] {
+            return Err(Error::Format("reserved bytes are not zero"));
+        }
+        let contexts = u32_at(header, 12) as usize;
+        let symbols = u32_at(header, 16) as usize;
+        let alphabet =
+            AlphabetMap::from_words(std::array::from_fn(|index| u64_at(header, 20 + 8 * index)));
+
+        // sizes come from the input, so they are checked before anything is allocated for them.
+        let records = contexts + usize::from(order0);
+        let expected = records
+            .checked_mul(CONTEXT_SIZE)
+            .and_then(|size| size.checked_add(symbols.checked_mul(2)?))
+            .and_then(|size| size.checked_add(HEADER_SIZE));
+        match expected {
+            Some(expected) if flat.len() < expected => return Err(Error::Truncated),
+            Some(expected) if flat.len() == expected => {}
+            _ => return Err(Error::Format("size does not match the counts")),
+        }
+        let (table, entries) = flat[HEADER_SIZE..].split_at(records * CONTEXT_SIZE);
+
+        let mut decoder = Decoder {
+            depth,
+            escape: match flags & FLAG_ESCAPE {
+                0 => EscapeMode::None,
+                _ => EscapeMode::Literal,
+            },
+            alphabet,
+            eof: flags & FLAG_EOF != 0,
+            ..Default::default()
+        };
+        let mut lengths = Vec::with_capacity(258);
+        let mut next = 0;
+        let mut previous: Option<&[u8]> = None;
+        for (index, record) in table.chunks_exact(CONTEXT_SIZE).enumerate() {
+            let is_order0 = index == contexts;
+            let width = if is_order0 { 0 } else { depth - 1 };
+            let (context, padding) = record[..KEY_SIZE].split_at(width);
+            if padding.iter().any(|byte| *byte != 0) || record[14..] != [0, 0] {
+                return Err(Error::Format("padding bytes are not zero"));
+            }
+            if !is_order0 && previous.is_some_and(|previous| previous >= context) {
+                return Err(Error::Format("contexts are not in ascending order"));
+            }
+            previous = Some(context);
+
+            let start = u32_at(record, KEY_SIZE) as usize;
+            let count = usize::from(u16_at(record, KEY_SIZE + 4));
+            if start != next || count == 0 || count > symbols - start {
+                return Err(Error::Format("invalid symbol range"));
+            }
+            next += count;
+
+            // the order-0 tree always has an escape and never an end.
+            let (escape, eof) = match is_order0 {
+                true => (true, false),
+                false => (decoder.escape == EscapeMode::Literal, decoder.eof),
+            };
+            lengths.clear();
+            for entry in entries[2 * start..2 * next].chunks_exact(2) {
+                let entry = u16_at(entry, 0);
+                let symbol = match entry & 0x1ff {
+                    index @ 0..=255 if alphabet.contains(index as u8) => Symbol::Byte(index as u8),
+                    256 if escape => Symbol::Escape,
+                    257 if eof => Symbol::Eof,
+                    _ => return Err(Error::Format("invalid symbol")),
+                };
+                lengths.push((symbol, (entry >> 9) as u8));
+            }
+            let has = |wanted| lengths.iter().any(|(symbol, _)| *symbol == wanted);
+            if has(Symbol::Escape) != escape || has(Symbol::Eof) != eof {
+                return Err(Error::Format(
+                    "escape or end symbols do not match the flags",
+                ));
+            }
+            let node =
+                HuffmanNode::from_lengths(&lengths).ok_or(Error::Format("invalid code lengths"))?;
+            if is_order0 {
+                decoder.order0 = Some(node);
+            } else {
+                decoder.trees.insert(context.into(), node);
+            }
+        }
+        if next != symbols {
+            return Err(Error::Format("symbols left over"));
+        }
+        Ok(decoder)
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{huffman::CodeOptions, Markov};
+    use proptest::prelude::*;
+    use test_strategy::proptest;
+
+    fn decoder(depth: usize, data: &[u8], escape: bool, eof: bool) -> Decoder {
+        let mut markov = Markov::new(depth);
+        markov.writer().write(data);
+        let options = CodeOptions {
+            escape: if escape {
+                EscapeMode::Literal
+            } else {
+                EscapeMode::None
+            },
+            eof,
+            alphabet: AlphabetMap::from_data(data),
+            ..Default::default()
+        };
+        Decoder::with_options(&markov, &options)
+    }
+
+    #[proptest]
+    fn test_roundtrip(
+        #[strategy(1usize..=MAX_FLAT_DEPTH)] depth: usize,
+        data: Vec<u8>,
+        escape: bool,
+        eof: bool,
+    ) {
+        let decoder = decoder(depth, &data, escape, eof);
+        let flat = decoder.to_flat().unwrap();
+        prop_assert_eq!(Decoder::from_flat(&flat).unwrap(), decoder);
+    }
+
+    #[proptest]
+    fn test_mutated(data: Vec<u8>, index: usize, byte: u8, len: usize) {
+        let mut flat = decoder(3, &data, true, false).to_flat().unwrap();
+        let index = index % flat.len();
+        flat[index] = byte;
+        let _ = Decoder::from_flat(&flat);
+        let _ = Decoder::from_flat(&flat[..len % flat.len()]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let decoder = decoder(3, b"abracadabra", false, false);
+        let flat = decoder.to_flat().unwrap();
+        // the order-0 record has the five bytes and the escape.
+        assert_eq!(
+            flat.len(),
+            HEADER_SIZE + 7 * CONTEXT_SIZE + 2 * 7 + CONTEXT_SIZE + 2 * 6
+        );
+
+        let mutate = |offset: usize, value: u8| {
+            let mut flat = flat.clone();
+            flat[offset] = value;
+            Decoder::from_flat(&flat)
+        };
+        // the first context "ab" turns into "bb", which sorts after the second one "ac".
+        assert!(matches!(
+            mutate(52, b'b'),
+            Err(Error::Format("contexts are not in ascending order"))
+        ));
+        assert!(matches!(mutate(6, 8), Err(Error::Format("unknown flags"))));
+        assert!(matches!(mutate(8, 10), Err(Error::Format("invalid depth"))));
+        assert!(matches!(mutate(12, 8), Err(Error::Truncated)));
+        assert!(matches!(
+            Decoder::from_flat(&[&flat[..], &[0]].concat()),
+            Err(Error::Format(_))
+        ));
+        assert!(matches!(
+            mutate(flat.len() - 1, 0xff),
+            Err(Error::Format(_))
+        ));
+
+        let deep = Decoder::new(&Markov::new(MAX_FLAT_DEPTH + 1));
+        assert!(matches!(deep.to_flat(), Err(Error::Config(_))));
+    }
+}
@@ -1,38 +1,403 @@
-use crate::{markov::Markov, util::buffered_windows};
-use bitstream_io::{BigEndian, BitWrite, BitWriter, Endianness};
+use crate::{
+    alphabet::AlphabetMap,
+    container::MAX_SUPPORTED_DEPTH,
+    context_map::ContextMap,
+    error::{Error, UnsupportedFeature},
+    markov::{Markov, WeightPolicy},
+    util::{buffered_windows, read_varint, write_varint, CancellationToken},
+};
+use bitstream_io::{
+    BigEndian, BitRead, BitReader, BitWrite, BitWriter, Endianness, Numeric, Primitive,
+    SignedNumeric,
+};
 use bitvec::prelude::*;
 use std::{
-    borrow::Borrow,
     cmp::Reverse,
-    collections::{BinaryHeap, HashMap},
-    io::{Result as IoResult, Write},
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    io::{Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write},
+    iter::FusedIterator,
+    ops::AddAssign,
+    str::FromStr,
+    string::FromUtf8Error,
+    sync::{Arc, Mutex, OnceLock, PoisonError},
 };
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub enum Node {
+pub const MAX_CODE_LENGTH: u8 = 15;
+
+/// Most misses reported by a single validation.
+pub const MAX_REPORTED_MISSES: usize = 100;
+
+// set in the table flags when every context carries an escape symbol.
+const TABLES_FLAG_ESCAPE: u64 = 1 << 0;
+
+// set in the table flags when symbols are numbered within an alphabet listed in the header.
+const TABLES_FLAG_ALPHABET: u64 = 1 << 1;
+
+// set in the table flags when every context carries an end of stream symbol.
+const TABLES_FLAG_EOF: u64 = 1 << 2;
+
+// set in the table flags when an order-0 table follows the header.
+const TABLES_FLAG_ORDER0: u64 = 1 << 3;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum EscapeMode {
+    /// Bytes the model has no code for cannot be encoded.
+    #[default]
+    None,
+    /// Every context gets an escape code which is followed by the byte verbatim, and bytes in
+    /// contexts the model has never seen are written verbatim without an escape code.
+    Literal,
+}
+
+impl FromStr for EscapeMode {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "none" => Ok(EscapeMode::None),
+            "literal" => Ok(EscapeMode::Literal),
+            other => Err(format!(
+                "unknown escape mode {other:?}, expected none or literal"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EscapeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EscapeMode::None => write!(f, "none"),
+            EscapeMode::Literal => write!(f, "literal"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct CodeOptions {
+    pub smoothing: usize,
+    pub max_code_length: u8,
+    pub escape: EscapeMode,
+    pub alphabet: AlphabetMap,
+    pub eof: bool,
+    pub weight_policy: WeightPolicy,
+}
+
+impl CodeOptions {
+    // capped models can only code the removed successors through escapes.
+    pub(crate) fn escape_mode(&self, markov: &Markov) -> EscapeMode {
+        match self.escape {
+            EscapeMode::None if markov.has_escapes() => EscapeMode::Literal,
+            escape => escape,
+        }
+    }
+
+    // builds the tree of the weights of a single context.
+    fn weights_tree(&self, weights: &[WeightedItem<Symbol>]) -> Result<Option<HuffmanNode>, Error> {
+        HuffmanNode::new(
+            weights.iter().copied(),
+            self.max_code_length,
+            self.weight_policy,
+        )
+    }
+
+    // builds the tree for a single context, with an escape symbol of the given weight.
+    fn tree(
+        &self,
+        items: &[WeightedItem],
+        escape: Option<usize>,
+    ) -> Result<Option<HuffmanNode>, Error> {
+        HuffmanNode::new(
+            self.weights(items, escape),
+            self.max_code_length,
+            self.weight_policy,
+        )
+    }
+
+    // weights of the tree of the context `prefix` of `markov`, whose escape weight depends on
+    // the model.
+    fn context_weights(
+        &self,
+        markov: &Markov,
+        escape: EscapeMode,
+        prefix: &[u8],
+        items: &[WeightedItem],
+    ) -> Vec<WeightedItem<Symbol>> {
+        let escape = (escape == EscapeMode::Literal)
+            .then(|| markov.escape_weight(prefix).unwrap_or(0).max(1));
+        self.weights(items, escape).collect()
+    }
+
+    // weights of the symbols of a single context, in ascending order of the symbols.
+    pub(crate) fn weights<'a>(
+        &self,
+        items: &[WeightedItem],
+        escape: Option<usize>,
+    ) -> impl Iterator<Item = WeightedItem<Symbol>> + 'a {
+        let smoothing = self.smoothing;
+        let mut weights = [None; 256];
+        for item in items {
+            weights[usize::from(item.item)] = Some(item.weight);
+        }
+
+        // smoothing gives every byte of the alphabet a code, in addition to the observed ones.
+        // bytes outside of it are left to escapes.
+        let alphabet = self.alphabet;
+        let items = (0..=u8::MAX).filter_map(move |byte| {
+            if !alphabet.contains(byte) {
+                return None;
+            }
+            let weight = match weights[usize::from(byte)] {
+                Some(weight) => weight.saturating_add(smoothing),
+                None if smoothing > 0 => smoothing,
+                None => return None,
+            };
+            Some(WeightedItem::new(Symbol::Byte(byte), weight))
+        });
+        let escape = escape.map(|weight| WeightedItem::new(Symbol::Escape, weight));
+        // the end of the stream occurs once, it gets the smallest weight in every context.
+        let eof = self.eof.then_some(WeightedItem::new(Symbol::Eof, 1));
+        items.chain(escape).chain(eof)
+    }
+}
+
+impl Default for CodeOptions {
+    fn default() -> Self {
+        CodeOptions {
+            smoothing: 0,
+            max_code_length: MAX_CODE_LENGTH,
+            escape: EscapeMode::None,
+            alphabet: AlphabetMap::default(),
+            eof: false,
+            weight_policy: WeightPolicy::default(),
+        }
+    }
+}
+
+/// Position in the input that the model has no code for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncodeMiss {
+    pub offset: usize,
+    pub context: Box<[u8]>,
+    pub byte: u8,
+}
+
+/// How much of some data a model covers, see [`Encoder::coverage`]. Each window of `depth` bytes
+/// counts once, as covered, missing its context or missing its last byte after the context.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Coverage {
+    pub windows: u64,
+    pub missing_context: u64,
+    pub missing_symbol: u64,
+}
+
+impl Coverage {
+    /// Windows with a code of their own.
+    pub fn covered(&self) -> u64 {
+        self.windows - self.missing_context - self.missing_symbol
+    }
+
+    /// Fraction of the windows with a code of their own, 1 if there are none.
+    pub fn covered_fraction(&self) -> f64 {
+        if self.windows == 0 {
+            return 1.0;
+        }
+        self.covered() as f64 / self.windows as f64
+    }
+}
+
+/// Shape of the trees of a [`Decoder`], see [`Decoder::tree_shape`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TreeShape {
+    /// Contexts with a tree.
+    pub trees: usize,
+    /// Symbols of all trees, including escapes and ends.
+    pub symbols: usize,
+    /// Length of the longest code of any tree.
+    pub max_depth: usize,
+    /// Context of the first tree with a code of `max_depth`, if there are any trees.
+    pub deepest: Option<Box<[u8]>>,
+}
+
+/// Reason [`Encoder::encode_window`] has no code for a window.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    #[error("window of {len} bytes does not match the depth {depth}")]
+    WindowLength { len: usize, depth: usize },
+    #[error("context {context:02x?} does not occur in the model")]
+    MissingContext { context: Box<[u8]> },
+    #[error("byte {byte:#04x} never follows the context {context:02x?}")]
+    MissingByte { context: Box<[u8]>, byte: u8 },
+}
+
+/// Reason [`Decoder::decode_one`] decodes no byte.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+pub enum SymbolError {
+    #[error("context of {len} bytes does not match the depth {depth}")]
+    ContextLength { len: usize, depth: usize },
+    /// The context has no tree, and the model has no escapes to code its bytes with.
+    #[error("context {context:02x?} does not occur in the model")]
+    UnknownContext { context: Box<[u8]> },
+    /// The bits ended inside of a code, after `consumed` of its bits.
+    #[error("bits ran out after {consumed} bits of a code")]
+    OutOfBits { consumed: usize },
+    /// The code is the end of the stream, see [`Symbol::Eof`].
+    #[error("end of the stream")]
+    EndOfStream,
+    #[error("invalid code: {0}")]
+    Format(&'static str),
+}
+
+impl From<SymbolError> for Error {
+    fn from(error: SymbolError) -> Self {
+        match error {
+            SymbolError::ContextLength { .. } => {
+                Error::Format("context length does not match model depth")
+            }
+            SymbolError::UnknownContext { .. } => Error::Format("context missing from model"),
+            SymbolError::OutOfBits { .. } => Error::Truncated,
+            SymbolError::EndOfStream => Error::Format("end of stream before the declared length"),
+            SymbolError::Format(message) => Error::Format(message),
+        }
+    }
+}
+
+/// Error of [`Decoder::decode_to_string`].
+#[derive(thiserror::Error, Debug)]
+pub enum DecodeError {
+    #[error(transparent)]
+    Decode(#[from] Error),
+    /// The data decoded, but not to UTF-8. The error holds the bytes, for
+    /// [`String::from_utf8_lossy`] or [`Decoder::decode_to_string_lossy`].
+    #[error("decoded bytes are not valid UTF-8: {0}")]
+    Utf8(#[from] FromUtf8Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Symbol {
+    Byte(u8),
+    Escape,
+    /// End of the stream, written once by [`HuffmanWriter::finish`].
+    Eof,
+}
+
+/// Huffman tree for a single context.
+///
+/// Codes are read starting at the root, where a `0` bit selects the `left` and a `1` bit the
+/// `right` child, until a leaf is reached. Codes are written to the stream in this same
+/// root-to-leaf order.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HuffmanNode {
     Leaf(u8),
-    Node { left: Box<Node>, right: Box<Node> },
+    Escape,
+    Eof,
+    Node {
+        left: Box<HuffmanNode>,
+        right: Box<HuffmanNode>,
+    },
 }
 
+#[deprecated(note = "renamed to `HuffmanNode`")]
+pub type Node = HuffmanNode;
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct WeightedNode {
     weight: usize,
-    node: Node,
+    node: HuffmanNode,
 }
 
+/// Item along with its weight, such as a successor byte and how often it occurred.
+///
+/// Items are ordered by their weight first and by the item itself second. The Huffman trees
+/// rely on this to break ties between equal weights the same way every time, it will not change.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct WeightedItem<T = u8> {
     pub weight: usize,
     pub item: T,
 }
 
-impl Node {
-    fn new(items: impl Iterator<Item = WeightedItem>) -> Option<Self> {
+impl<T> WeightedItem<T> {
+    pub fn new(item: T, weight: usize) -> Self {
+        WeightedItem { weight, item }
+    }
+}
+
+impl<T: Copy> WeightedItem<T> {
+    /// Probabilities of the items, their weights divided by the combined weight. Items are
+    /// kept in order, all probabilities are zero if the combined weight is.
+    pub fn normalize(items: &[WeightedItem<T>]) -> Vec<(T, f64)> {
+        let total = items
+            .iter()
+            .fold(0usize, |total, item| total.saturating_add(item.weight));
+        items
+            .iter()
+            .map(|item| match total {
+                0 => (item.item, 0.0),
+                total => (item.item, item.weight as f64 / total as f64),
+            })
+            .collect()
+    }
+}
+
+impl<T> From<(T, usize)> for WeightedItem<T> {
+    fn from((item, weight): (T, usize)) -> Self {
+        WeightedItem::new(item, weight)
+    }
+}
+
+impl HuffmanNode {
+    fn new(
+        items: impl Iterator<Item = WeightedItem<Symbol>>,
+        max_length: u8,
+        policy: WeightPolicy,
+    ) -> Result<Option<Self>, Error> {
+        let mut items: Vec<WeightedItem<Symbol>> = items.collect();
+        loop {
+            let tree = match Self::huffman(items.iter().copied(), policy) {
+                Ok(tree) => tree,
+                Err(Error::WeightOverflow) if policy == WeightPolicy::Scale => {
+                    for item in &mut items {
+                        item.weight = item.weight.div_ceil(2);
+                    }
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            let Some(tree) = tree else {
+                return Ok(None);
+            };
+            let lengths = tree.lengths();
+            if lengths.iter().all(|(_, length)| *length <= max_length) {
+                return Ok(Self::from_lengths(&lengths));
+            }
+
+            // flatten the distribution until the longest code fits.
+            for item in &mut items {
+                item.weight = 1 + item.weight / 2;
+            }
+        }
+    }
+
+    fn from_symbol(symbol: Symbol) -> Self {
+        match symbol {
+            Symbol::Byte(byte) => HuffmanNode::Leaf(byte),
+            Symbol::Escape => HuffmanNode::Escape,
+            Symbol::Eof => HuffmanNode::Eof,
+        }
+    }
+
+    // builds the tree, failing on weights that overflow unless they saturate.
+    fn huffman(
+        items: impl Iterator<Item = WeightedItem<Symbol>>,
+        policy: WeightPolicy,
+    ) -> Result<Option<Self>, Error> {
         let mut heap: BinaryHeap<Reverse<WeightedNode>> = items
             .map(|item| {
                 Reverse(WeightedNode {
                     weight: item.weight,
-                    node: Node::Leaf(item.item),
+                    node: HuffmanNode::from_symbol(item.item),
                 })
             })
             .collect();
@@ -40,10 +405,16 @@ impl Node {
         while heap.len() > 1 {
             let left = heap.pop().unwrap().0;
             let right = heap.pop().unwrap().0;
+            let weight = match policy {
+                WeightPolicy::Saturate => left.weight.saturating_add(right.weight),
+                _ => left
+                    .weight
+                    .checked_add(right.weight)
+                    .ok_or(Error::WeightOverflow)?,
+            };
             let node = WeightedNode {
-                // FIXME: saturating add?
-                weight: left.weight.saturating_add(right.weight),
-                node: Node::Node {
+                weight,
+                node: HuffmanNode::Node {
                     left: left.node.into(),
                     right: right.node.into(),
                 },
@@ -51,148 +422,5447 @@ impl Node {
             heap.push(Reverse(node));
         }
 
-        let root = heap.pop()?.0;
+        Ok(heap.pop().map(|root| root.0.node))
+    }
+
+    pub fn left(&self) -> Option<&HuffmanNode> {
+        match self {
+            HuffmanNode::Node { left, .. } => Some(left),
+            _ => None,
+        }
+    }
+
+    pub fn right(&self) -> Option<&HuffmanNode> {
+        match self {
+            HuffmanNode::Node { right, .. } => Some(right),
+            _ => None,
+        }
+    }
+
+    pub fn leaf(&self) -> Option<u8> {
+        match self {
+            HuffmanNode::Leaf(byte) => Some(*byte),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(&self) -> Option<Symbol> {
+        match self {
+            HuffmanNode::Leaf(byte) => Some(Symbol::Byte(*byte)),
+            HuffmanNode::Escape => Some(Symbol::Escape),
+            HuffmanNode::Eof => Some(Symbol::Eof),
+            HuffmanNode::Node { .. } => None,
+        }
+    }
+
+    /// Length of the longest code in this tree, see [`HuffmanNode::max_depth`].
+    pub fn depth(&self) -> usize {
+        self.max_depth()
+    }
+
+    /// Length of the longest code in this tree, zero for a single leaf.
+    pub fn max_depth(&self) -> usize {
+        self.symbols().map(|(_, depth)| depth).max().unwrap_or(0)
+    }
+
+    /// Number of leaves of this tree, including the escape and the end.
+    pub fn symbol_count(&self) -> usize {
+        self.symbols().count()
+    }
+
+    /// Sum of the code lengths of the bytes in `weights`, each times its weight, which is the
+    /// number of bits their codes take. Bytes without a leaf do not count, neither do escapes
+    /// and ends.
+    pub fn weighted_path_length(&self, weights: &[(u8, u64)]) -> u128 {
+        let mut table = [0u128; 256];
+        for (byte, weight) in weights {
+            table[usize::from(*byte)] += u128::from(*weight);
+        }
+        self.symbols()
+            .filter_map(|(node, depth)| Some(table[usize::from(node.leaf()?)] * depth as u128))
+            .sum()
+    }
+
+    // leaves with the length of their code, left to right. trees can be as deep as they have
+    // symbols, so they are walked with a stack instead of recursing.
+    fn symbols(&self) -> impl Iterator<Item = (&HuffmanNode, usize)> {
+        let mut stack = vec![(self, 0)];
+        std::iter::from_fn(move || loop {
+            let (node, depth) = stack.pop()?;
+            match node {
+                HuffmanNode::Node { left, right } => {
+                    stack.push((right, depth + 1));
+                    stack.push((left, depth + 1));
+                }
+                leaf => return Some((leaf, depth)),
+            }
+        })
+    }
+
+    /// Iterates over the codes of this tree in ascending bit-string order, which is left to
+    /// right through the tree. Each code is in root-to-leaf order.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (BitBox, Symbol)> + '_> {
+        Box::new(
+            self.codes(Default::default())
+                .map(|(bits, symbol)| (bits.into_boxed_bitslice(), symbol)),
+        )
+    }
+
+    pub(crate) fn lengths(&self) -> Vec<(Symbol, u8)> {
+        self.symbol_lengths().collect()
+    }
+
+    // symbols of the tree and the lengths of their codes in ascending order of the symbols,
+    // without allocating.
+    pub(crate) fn symbol_lengths(&self) -> impl Iterator<Item = (Symbol, u8)> + Clone {
+        self.code_lengths()
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, length)| Some((index_symbol(index), length?)))
+    }
 
-        Some(root.node)
+    // lengths of the codes of the tree indexed by `symbol_index`, none for symbols it lacks.
+    fn code_lengths(&self) -> [Option<u8>; 258] {
+        let mut lengths = [None; 258];
+        self.fill_lengths(0, &mut lengths);
+        lengths
     }
 
-    fn iter(&self, mut prefix: BitVec) -> Box<dyn Iterator<Item = (BitVec, u8)> + '_> {
+    fn fill_lengths(&self, length: u8, lengths: &mut [Option<u8>; 258]) {
         match self {
-            Self::Leaf(byte) => {
-                prefix.reverse();
-                Box::new(std::iter::once((prefix, *byte)))
+            Self::Leaf(byte) => lengths[usize::from(*byte)] = Some(length),
+            Self::Escape => lengths[256] = Some(length),
+            Self::Eof => lengths[257] = Some(length),
+            Self::Node { left, right } => {
+                left.fill_lengths(length + 1, lengths);
+                right.fill_lengths(length + 1, lengths);
+            }
+        }
+    }
+
+    pub(crate) fn from_lengths(lengths: &[(Symbol, u8)]) -> Option<Self> {
+        let mut lengths = lengths.to_vec();
+        lengths.sort_by_key(|(symbol, length)| (*length, *symbol));
+        if let [(symbol, 0)] = lengths[..] {
+            return Some(HuffmanNode::from_symbol(symbol));
+        }
+
+        // assign canonical codes, these are increasing when read as bit strings.
+        let mut codes = Vec::with_capacity(lengths.len());
+        let mut seen = [false; 258];
+        let mut code = 0u32;
+        let mut previous = 0;
+        for (symbol, length) in lengths {
+            let index = symbol_index(symbol);
+            if !(1..=MAX_CODE_LENGTH).contains(&length) || seen[index] {
+                return None;
+            }
+            seen[index] = true;
+            if !codes.is_empty() {
+                code += 1;
             }
+            code <<= length - previous;
+            if code >> length != 0 {
+                return None;
+            }
+            codes.push((code, length, symbol));
+            previous = length;
+        }
+
+        Self::from_codes(&codes, 0)
+    }
+
+    fn from_codes(codes: &[(u32, u8, Symbol)], depth: u8) -> Option<Self> {
+        match codes {
+            [] => None,
+            [(_, length, symbol)] if *length == depth => Some(HuffmanNode::from_symbol(*symbol)),
+            _ if codes.iter().any(|(_, length, _)| *length <= depth) => None,
+            _ => {
+                let split = codes
+                    .partition_point(|(code, length, _)| (code >> (length - depth - 1)) & 1 == 0);
+                Some(HuffmanNode::Node {
+                    left: Self::from_codes(&codes[..split], depth + 1)?.into(),
+                    right: Self::from_codes(&codes[split..], depth + 1)?.into(),
+                })
+            }
+        }
+    }
+
+    fn codes(&self, mut prefix: BitVec) -> Box<dyn Iterator<Item = (BitVec, Symbol)> + '_> {
+        match self {
+            Self::Leaf(byte) => Box::new(std::iter::once((prefix, Symbol::Byte(*byte)))),
+            Self::Escape => Box::new(std::iter::once((prefix, Symbol::Escape))),
+            Self::Eof => Box::new(std::iter::once((prefix, Symbol::Eof))),
             Self::Node { left, right } => {
                 prefix.push(false);
-                let left = left.iter(prefix.clone());
+                let left = left.codes(prefix.clone());
                 prefix.pop();
                 prefix.push(true);
-                let right = right.iter(prefix);
+                let right = right.codes(prefix);
                 Box::new(left.chain(right))
             }
         }
     }
 
-    fn encoding(&self) -> HashMap<u8, BitBox> {
-        self.iter(Default::default())
-            .map(|(bits, byte)| (byte, bits.into()))
-            .collect()
+    // pushes the code of `symbol` onto `code`, false if the tree has no such symbol.
+    fn find_code(&self, symbol: Symbol, code: &mut BitVec) -> bool {
+        match self {
+            Self::Leaf(byte) => symbol == Symbol::Byte(*byte),
+            Self::Escape => symbol == Symbol::Escape,
+            Self::Eof => symbol == Symbol::Eof,
+            Self::Node { left, right } => {
+                for (bit, child) in [(false, left), (true, right)] {
+                    code.push(bit);
+                    if child.find_code(symbol, code) {
+                        return true;
+                    }
+                    code.pop();
+                }
+                false
+            }
+        }
+    }
+
+    fn encoding(&self) -> BTreeMap<Symbol, BitBox> {
+        self.iter().map(|(bits, symbol)| (symbol, bits)).collect()
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Default)]
-pub struct Decoder {
-    pub depth: usize,
-    pub trees: HashMap<Box<[u8]>, Node>,
+// position of a symbol in tables indexed by every byte, followed by the escape and the end.
+pub(crate) fn symbol_index(symbol: Symbol) -> usize {
+    match symbol {
+        Symbol::Byte(byte) => usize::from(byte),
+        Symbol::Escape => 256,
+        Symbol::Eof => 257,
+    }
 }
 
-impl Decoder {
-    pub fn new(markov: &Markov) -> Self {
-        let mut huffman = Decoder {
-            depth: markov.len(),
-            trees: Default::default(),
+// symbol at a position of `symbol_index`.
+fn index_symbol(index: usize) -> Symbol {
+    match index {
+        256 => Symbol::Escape,
+        257 => Symbol::Eof,
+        byte => Symbol::Byte(byte as u8),
+    }
+}
+
+/// Difference between a model and the trees of a [`Decoder`] that [`Decoder::refresh`] cannot
+/// update them for, the decoder has to be built from the model again.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ModelShapeChanged {
+    #[error("model depth {found} does not match the decoder depth {expected}")]
+    Depth { expected: usize, found: usize },
+
+    /// The model has escapes, but the decoder was built without them.
+    #[error("model escapes do not match the escape mode {0} of the decoder")]
+    Escape(EscapeMode),
+
+    /// Only one of the model and the decoder has the context.
+    #[error("context {0:02x?} was added or removed")]
+    Context(Box<[u8]>),
+
+    #[error("symbols of context {0:02x?} changed")]
+    Symbols(Box<[u8]>),
+}
+
+/// Trees [`Decoder::refresh`] built again and the ones it kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RefreshReport {
+    /// Contexts whose weights changed.
+    pub rebuilt: usize,
+    /// Contexts whose weights did not change, their trees keep their codes.
+    pub untouched: usize,
+}
+
+/// Code tables rejected by [`Encoder::from_codes`] and [`Decoder::from_codes`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CodeTableError {
+    #[error("depth must be at least 1")]
+    Depth,
+
+    #[error("context {context:02x?} is not {expected} bytes long")]
+    ContextLength { context: Box<[u8]>, expected: usize },
+
+    #[error("context {0:02x?} is listed more than once")]
+    DuplicateContext(Box<[u8]>),
+
+    #[error("context {0:02x?} has no codes")]
+    EmptyContext(Box<[u8]>),
+
+    #[error("byte {byte:#04x} has more than one code in context {context:02x?}")]
+    DuplicateByte { context: Box<[u8]>, byte: u8 },
+
+    #[error(
+        "bytes {:#04x} and {:#04x} have the same code {:?} in context {context:02x?}",
+        .bytes.0, .bytes.1, bit_string(.code)
+    )]
+    DuplicateCode {
+        context: Box<[u8]>,
+        code: BitBox,
+        bytes: (u8, u8),
+    },
+
+    /// The code of one byte starts with the code of another one, each given with its byte.
+    #[error(
+        "code {:?} of byte {:#04x} is a prefix of code {:?} of byte {:#04x} in context {context:02x?}",
+        bit_string(&.prefix.1), .prefix.0, bit_string(&.code.1), .code.0
+    )]
+    NotPrefixFree {
+        context: Box<[u8]>,
+        prefix: (u8, BitBox),
+        code: (u8, BitBox),
+    },
+
+    /// Bits that no code of the context starts with, which the trees of a [`Decoder`] cannot
+    /// represent.
+    #[error("no code in context {context:02x?} starts with {:?}", bit_string(.missing))]
+    Incomplete { context: Box<[u8]>, missing: BitBox },
+}
+
+// checks the codes of a context, sorted so that every code is right before the ones that start
+// with it.
+fn sorted_codes(
+    depth: usize,
+    context: &[u8],
+    codes: Vec<(u8, BitBox)>,
+) -> Result<Vec<(BitBox, u8)>, CodeTableError> {
+    if context.len() + 1 != depth {
+        return Err(CodeTableError::ContextLength {
+            context: context.into(),
+            expected: depth - 1,
+        });
+    }
+    if codes.is_empty() {
+        return Err(CodeTableError::EmptyContext(context.into()));
+    }
+
+    let mut seen = [false; 256];
+    let mut sorted = Vec::with_capacity(codes.len());
+    for (byte, code) in codes {
+        if std::mem::replace(&mut seen[usize::from(byte)], true) {
+            return Err(CodeTableError::DuplicateByte {
+                context: context.into(),
+                byte,
+            });
+        }
+        sorted.push((code, byte));
+    }
+    sorted.sort_unstable();
+    for pair in sorted.windows(2) {
+        let [(prefix, first), (code, second)] = pair else {
+            unreachable!()
         };
-        for (prefix, items) in markov.iter_prefix() {
-            huffman
-                .trees
-                .insert(prefix.into(), Node::new(items.into_iter()).unwrap());
+        if prefix == code {
+            return Err(CodeTableError::DuplicateCode {
+                context: context.into(),
+                code: code.clone(),
+                bytes: (*first, *second),
+            });
+        }
+        if code.starts_with(prefix) {
+            return Err(CodeTableError::NotPrefixFree {
+                context: context.into(),
+                prefix: (*first, prefix.clone()),
+                code: (*second, code.clone()),
+            });
         }
-        huffman
     }
+    Ok(sorted)
+}
 
-    pub fn encoder(&self) -> Encoder {
-        Encoder::new(self)
+// builds the subtree of the sorted, prefix-free `codes` that start with `prefix`.
+fn tree_from_codes(
+    context: &[u8],
+    codes: &[(BitBox, u8)],
+    prefix: &mut BitVec,
+) -> Result<HuffmanNode, CodeTableError> {
+    match codes {
+        [] => Err(CodeTableError::Incomplete {
+            context: context.into(),
+            missing: prefix.clone().into_boxed_bitslice(),
+        }),
+        [(code, byte)] if code.len() == prefix.len() => Ok(HuffmanNode::Leaf(*byte)),
+        // the codes are longer than the prefix, or one of them would be a prefix of the others.
+        _ => {
+            let level = prefix.len();
+            let split = codes.partition_point(|(code, _)| !code[level]);
+            let mut child = |codes, bit| {
+                prefix.push(bit);
+                let node = tree_from_codes(context, codes, prefix);
+                prefix.pop();
+                node.map(Box::new)
+            };
+            Ok(HuffmanNode::Node {
+                left: child(&codes[..split], false)?,
+                right: child(&codes[split..], true)?,
+            })
+        }
     }
+}
 
-    fn decoder(&self, prefix: &[u8]) -> () {}
+/// Code as a string of `0` and `1` in the order the bits are written.
+pub(crate) fn bit_string(code: &BitSlice) -> String {
+    code.iter()
+        .map(|bit| if *bit { '1' } else { '0' })
+        .collect()
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Default)]
-pub struct Encoder {
-    pub depth: usize,
-    pub prefixes: HashMap<Box<[u8]>, HashMap<u8, BitBox>>,
+/// Code of a symbol in the tree of a context, see [`Decoder::iter_codes`]. Entries borrow the
+/// tree, the code itself is only looked up by [`CodeEntry::code`].
+#[derive(Clone, Copy)]
+pub struct CodeEntry<'a> {
+    /// Context of the tree, the `depth - 1` bytes preceding the symbol.
+    pub context: &'a [u8],
+    pub symbol: Symbol,
+    /// Length of the code in bits.
+    pub length: u8,
+    tree: &'a HuffmanNode,
 }
 
-impl Encoder {
-    fn new(decoder: &Decoder) -> Self {
-        Encoder {
-            depth: decoder.depth,
-            prefixes: decoder
-                .trees
-                .iter()
-                .map(|(prefix, node)| (prefix.clone(), node.encoding()))
-                .collect(),
-        }
+impl CodeEntry<'_> {
+    /// Code of the symbol in root-to-leaf order.
+    pub fn code(&self) -> BitBox {
+        let mut code = BitVec::with_capacity(self.length.into());
+        self.tree.find_code(self.symbol, &mut code);
+        code.into_boxed_bitslice()
     }
+}
 
-    fn encode(&self, prefix: &[u8], byte: u8) -> Option<&BitSlice> {
-        Some(self.prefixes.get(prefix)?.get(&byte)?.as_bitslice())
+impl fmt::Debug for CodeEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CodeEntry")
+            .field("context", &self.context)
+            .field("symbol", &self.symbol)
+            .field("code", &bit_string(&self.code()))
+            .finish()
     }
+}
 
-    pub fn writer<W: Write>(&self, writer: W) -> Writer<&Self, W> {
-        Writer::new(self, writer)
+impl PartialEq for CodeEntry<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.context == other.context && self.symbol == other.symbol && self.code() == other.code()
     }
 }
 
-pub struct Writer<H: Borrow<Encoder>, W: Write, E: Endianness = BigEndian> {
-    buffer: Vec<u8>,
-    encoder: H,
-    writer: BitWriter<W, E>,
-}
+impl Eq for CodeEntry<'_> {}
 
-impl<H: Borrow<Encoder>, W: Write> Writer<H, W> {
-    fn new(encoder: H, writer: W) -> Self {
-        Self {
-            buffer: vec![],
-            encoder,
-            writer: BitWriter::new(writer),
-        }
+impl Hash for CodeEntry<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.context.hash(state);
+        self.symbol.hash(state);
+        self.code().hash(state);
     }
 }
 
-impl<H: Borrow<Encoder>, W: Write, E: Endianness> Write for Writer<H, W, E> {
-    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        let encoder = self.encoder.borrow();
-        buffered_windows(encoder.depth, &mut self.buffer, buf, |window| {
-            let prefix = &window[0..window.len() - 1];
-            let byte = window[window.len() - 1];
-            let slice = encoder.encode(prefix, byte).unwrap();
-            for bit in slice.iter() {
-                self.writer.write_bit(*bit);
-            }
-            Ok(()) as IoResult<()>
+// codes of the tree of `context`, in ascending order of their symbols.
+pub(crate) fn tree_codes<'a>(
+    context: &'a [u8],
+    node: &'a HuffmanNode,
+) -> impl Iterator<Item = CodeEntry<'a>> + Clone {
+    node.symbol_lengths()
+        .map(move |(symbol, length)| CodeEntry {
+            context,
+            symbol,
+            length,
+            tree: node,
         })
-        .unwrap();
-        Ok(buf.len())
+}
+
+// codes of trees that are in ascending order of their contexts, in the canonical order that
+// tables are written and hashed in.
+fn canonical_codes<'a>(
+    trees: impl Iterator<Item = (&'a [u8], &'a HuffmanNode)>,
+) -> impl Iterator<Item = CodeEntry<'a>> {
+    trees.flat_map(|(context, node)| tree_codes(context, node))
+}
+
+// hash of the code tables, see `Decoder::content_hash`. the trees are in ascending order of
+// their contexts.
+fn tables_hash<'a>(
+    depth: usize,
+    escape: EscapeMode,
+    alphabet: &AlphabetMap,
+    eof: bool,
+    trees: impl Iterator<Item = (&'a [u8], &'a HuffmanNode)>,
+    order0: Option<&HuffmanNode>,
+) -> u64 {
+    let mut hasher = Xxh3::new();
+    hasher.update(&(depth as u64).to_le_bytes());
+    if escape == EscapeMode::Literal {
+        hasher.update(&[1]);
+    }
+    if !alphabet.is_full() {
+        hasher.update(&[2]);
+        hasher.update(&alphabet.symbols().collect::<Vec<_>>());
+    }
+    if eof {
+        hasher.update(&[3]);
+    }
+    let mut contexts = 0u64;
+    for (context, node) in trees {
+        hasher.update(context);
+        hash_codes(&mut hasher, tree_codes(context, node));
+        contexts += 1;
+    }
+    // models without an order-0 tree hash as they did before there was one. the tree follows
+    // its tag and the number of contexts, so it cannot be taken for another context.
+    if let Some(node) = order0 {
+        hasher.update(&[4]);
+        hasher.update(&contexts.to_le_bytes());
+        hash_codes(&mut hasher, tree_codes(&[], node));
     }
+    hasher.digest()
+}
 
-    fn flush(&mut self) -> IoResult<()> {
-        for _ in 0..7 {
-            self.writer.write_bit(false);
+// number of bytes in a tree, then every symbol with the length of its code.
+fn hash_codes<'a>(hasher: &mut Xxh3, codes: impl Iterator<Item = CodeEntry<'a>> + Clone) {
+    let bytes = codes
+        .clone()
+        .filter(|entry| matches!(entry.symbol, Symbol::Byte(_)))
+        .count();
+    hasher.update(&(bytes as u16).to_le_bytes());
+    for entry in codes {
+        match entry.symbol {
+            Symbol::Byte(byte) => hasher.update(&[byte, entry.length]),
+            Symbol::Escape | Symbol::Eof => hasher.update(&[entry.length]),
         }
-        self.writer.flush()?;
-        Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use proptest::prelude::*;
-    use std::collections::BTreeMap;
-    use test_strategy::proptest;
+/// Huffman trees of a model for decoding, which an [`Encoder`] is derived from. [`Coder`] holds
+/// the same trees and covers both directions without keeping a separate [`Encoder`] in sync.
+///
+/// Equality and hashing compare the trees. Cloning copies every tree, use [`Decoder::shared`]
+/// to hand one decoder to several readers.
+#[derive(Clone, Debug, Default)]
+pub struct Decoder {
+    pub depth: usize,
+    pub escape: EscapeMode,
+    /// Maps every context, the `depth - 1` bytes preceding a symbol, to the tree its symbol is
+    /// coded with. Contexts the model has not seen have no tree.
+    ///
+    /// The context bytes are shared with the [`Encoder`] built from this decoder.
+    pub trees: BTreeMap<Arc<[u8]>, HuffmanNode>,
+    /// Bytes the trees can have codes for, the tables number symbols within it.
+    pub alphabet: AlphabetMap,
+    /// Whether every tree has a [`Symbol::Eof`], which ends decoding where it occurs.
+    pub eof: bool,
+    // order-0 tree of models deeper than 1, see `Decoder::order0`.
+    pub(crate) order0: Option<HuffmanNode>,
+    // what the trees were built from, for `Decoder::refresh`. decoders that were not built from
+    // a model have none.
+    pub(crate) source: Option<TreeSource>,
+}
 
-    #[proptest]
-    fn test_node(#[filter(!#items.is_empty())] items: BTreeMap<u8, usize>) {
-        let node = Node::new(items.iter().map(|(item, weight)| WeightedItem {
-            item: *item,
-            weight: *weight,
-        }))
-        .unwrap();
-        let encoder = node.encoding();
+// options and weights the trees of a decoder were built with.
+#[derive(Clone, Debug)]
+pub(crate) struct TreeSource {
+    options: CodeOptions,
+    // digest of the weights of every tree, in ascending order of the contexts.
+    digests: Vec<u64>,
+}
+
+// digest of the weights of a context, which decide its tree.
+fn weights_digest(weights: &[WeightedItem<Symbol>]) -> u64 {
+    let mut hasher = Xxh3::new();
+    for item in weights {
+        let symbol: u16 = match item.item {
+            Symbol::Byte(byte) => byte.into(),
+            Symbol::Escape => 256,
+            Symbol::Eof => 257,
+        };
+        hasher.update(&symbol.to_le_bytes());
+        hasher.update(&(item.weight as u64).to_le_bytes());
+    }
+    hasher.digest()
+}
+
+// whether `tree` has a leaf for every symbol of `weights` and no others.
+fn same_symbols(tree: &HuffmanNode, weights: &[WeightedItem<Symbol>]) -> bool {
+    let mut leaves = 0;
+    let found = tree.symbols().all(|(leaf, _)| {
+        leaves += 1;
+        leaf.symbol().is_some_and(|symbol| {
+            weights
+                .binary_search_by_key(&symbol, |item| item.item)
+                .is_ok()
+        })
+    });
+    found && leaves == weights.len()
+}
 
-        for (byte, weight) in items.iter() {
-            prop_assert!(encoder.get(byte).is_some());
+impl PartialEq for Decoder {
+    fn eq(&self, other: &Self) -> bool {
+        self.depth == other.depth
+            && self.escape == other.escape
+            && self.trees == other.trees
+            && self.alphabet == other.alphabet
+            && self.eof == other.eof
+            && self.order0 == other.order0
+    }
+}
+
+impl Eq for Decoder {}
+
+impl Hash for Decoder {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.depth.hash(state);
+        self.escape.hash(state);
+        self.trees.hash(state);
+        self.alphabet.hash(state);
+        self.eof.hash(state);
+        self.order0.hash(state);
+    }
+}
+
+impl Decoder {
+    pub fn new(markov: &Markov) -> Self {
+        Self::with_options(markov, &CodeOptions::default())
+    }
+
+    /// Builds the decoder like [`Decoder::new`], checking `token` before every context.
+    pub fn with_cancellation(markov: &Markov, token: &CancellationToken) -> Result<Self, Error> {
+        Self::with_options_cancellable(markov, &CodeOptions::default(), token)
+    }
+
+    pub(crate) fn with_options(markov: &Markov, options: &CodeOptions) -> Self {
+        Self::with_options_cancellable(markov, options, &CancellationToken::new())
+            .expect("fresh tokens are not cancelled")
+    }
+
+    pub(crate) fn with_options_cancellable(
+        markov: &Markov,
+        options: &CodeOptions,
+        token: &CancellationToken,
+    ) -> Result<Self, Error> {
+        let escape = options.escape_mode(markov);
+        let build = |(prefix, items): (Vec<u8>, Vec<WeightedItem>)| {
+            if token.is_cancelled() {
+                return None;
+            }
+            let weights = options.context_weights(markov, escape, &prefix, &items);
+            let digest = weights_digest(&weights);
+            options
+                .weights_tree(&weights)
+                .transpose()
+                .map(|tree| Ok((Arc::from(prefix), tree?, digest)))
+        };
+
+        // every tree only depends on its own context.
+        #[cfg(feature = "rayon")]
+        let built: Vec<_> = {
+            use rayon::prelude::*;
+            let contexts: Vec<_> = markov.iter_prefix().collect();
+            contexts
+                .into_par_iter()
+                .filter_map(build)
+                .collect::<Result<_, Error>>()?
+        };
+        #[cfg(not(feature = "rayon"))]
+        let built: Vec<_> = markov
+            .iter_prefix()
+            .filter_map(build)
+            .collect::<Result<_, Error>>()?;
+
+        // contexts skipped after cancelling are missing, the decoder is incomplete then.
+        token.check()?;
+        let mut digests = Vec::with_capacity(built.len());
+        let trees = built
+            .into_iter()
+            .map(|(prefix, tree, digest)| {
+                digests.push(digest);
+                (prefix, tree)
+            })
+            .collect();
+        Ok(Decoder {
+            depth: markov.len(),
+            escape,
+            trees,
+            alphabet: options.alphabet,
+            eof: options.eof,
+            order0: order0_tree(markov, options)?,
+            source: Some(TreeSource {
+                options: *options,
+                digests,
+            }),
+        })
+    }
+
+    /// Updates the trees to the weights of `markov`, which has to have the contexts and the
+    /// symbols of every context that the trees were built from. Only the trees whose weights
+    /// changed are built again, the others keep their codes, and so does the order-0 tree if
+    /// none changed.
+    ///
+    /// Trees are built with the options this decoder was built with. Decoders that were not
+    /// built from a model, such as ones read from tables, use the default options with their
+    /// escape mode, alphabet and end symbols, and build every tree on their first refresh.
+    ///
+    /// On errors the decoder is unchanged. If the shape of the model changed, with
+    /// [`ModelShapeChanged`] in [`Error::ModelShape`], the decoder has to be built again.
+    pub fn refresh(&mut self, markov: &Markov) -> Result<RefreshReport, Error> {
+        if markov.len() != self.depth {
+            return Err(ModelShapeChanged::Depth {
+                expected: self.depth,
+                found: markov.len(),
+            }
+            .into());
+        }
+        let options = match &self.source {
+            Some(source) => source.options,
+            None => CodeOptions {
+                escape: self.escape,
+                alphabet: self.alphabet,
+                eof: self.eof,
+                ..Default::default()
+            },
+        };
+        if options.escape_mode(markov) != self.escape {
+            return Err(ModelShapeChanged::Escape(self.escape).into());
+        }
+
+        // the shape is checked and the changed trees are built before the decoder is touched.
+        let previous = self.source.as_ref().map(|source| &source.digests);
+        let mut trees = self.trees.iter();
+        let mut digests = Vec::with_capacity(self.trees.len());
+        let mut rebuilt = vec![];
+        for (prefix, items) in markov.iter_prefix() {
+            let weights = options.context_weights(markov, self.escape, &prefix, &items);
+            if weights.is_empty() {
+                continue;
+            }
+            let tree = match trees.next() {
+                Some((context, tree)) if context[..] == prefix[..] => tree,
+                // the smaller of the two contexts is the one the other side is missing.
+                Some((context, _)) if context[..] < prefix[..] => {
+                    return Err(ModelShapeChanged::Context(context[..].into()).into())
+                }
+                _ => return Err(ModelShapeChanged::Context(prefix.into()).into()),
+            };
+            if !same_symbols(tree, &weights) {
+                return Err(ModelShapeChanged::Symbols(prefix.into()).into());
+            }
+            let digest = weights_digest(&weights);
+            if previous.and_then(|previous| previous.get(digests.len())) != Some(&digest) {
+                let tree = options
+                    .weights_tree(&weights)?
+                    .expect("weights are not empty");
+                rebuilt.push((digests.len(), tree));
+            }
+            digests.push(digest);
+        }
+        if let Some((context, _)) = trees.next() {
+            return Err(ModelShapeChanged::Context(context[..].into()).into());
+        }
+        let order0 = match rebuilt.is_empty() || self.order0.is_none() {
+            true => None,
+            false => Some(order0_tree(markov, &options)?),
+        };
+
+        let report = RefreshReport {
+            rebuilt: rebuilt.len(),
+            untouched: digests.len() - rebuilt.len(),
+        };
+        let mut rebuilt = rebuilt.into_iter().peekable();
+        for (index, tree) in self.trees.values_mut().enumerate() {
+            if let Some((_, rebuilt)) = rebuilt.next_if(|(rebuilt, _)| *rebuilt == index) {
+                *tree = rebuilt;
+            }
+        }
+        if let Some(order0) = order0 {
+            self.order0 = order0;
+        }
+        self.source = Some(TreeSource { options, digests });
+        Ok(report)
+    }
+
+    /// Tree of the bytes following any context, summed over the contexts of the model.
+    ///
+    /// With literal escapes, escaped bytes and bytes in contexts without a tree are coded with
+    /// it, and the container codes the first `depth - 1` bytes of a block with it. It has an
+    /// escape of its own, after which the byte follows as a literal.
+    ///
+    /// For a model of depth 1 this is the tree of its only context, which is not backed off
+    /// from. Decoders from [`Decoder::from_probabilities`] or [`Decoder::from_codes`] and tables
+    /// written without one have none, bytes fall back to literals right away then.
+    pub fn order0(&self) -> Option<&HuffmanNode> {
+        match self.depth {
+            1 => self.trees.get(&[][..]),
+            _ => self.order0.as_ref(),
+        }
+    }
+
+    /// Decoder that only builds the tree of a context once it is coded in, for large models of
+    /// which little is used. It borrows the model, which cannot change while it is in use.
+    pub fn lazy(markov: &Markov) -> LazyDecoder<'_> {
+        LazyDecoder::new(markov, &CodeOptions::default())
+    }
+
+    /// Builds a decoder from per-context probability distributions.
+    ///
+    /// Probabilities must be finite and non-negative, and sum to 1 within a tolerance of
+    /// `1e-3` in every context. They are quantized to integer weights by multiplying with
+    /// `scale` and rounding, symbols with a probability of zero are left out and any others
+    /// that would round to zero get a weight of 1, so a larger `scale` keeps the code lengths
+    /// closer to the distribution.
+    pub fn from_probabilities(
+        depth: usize,
+        contexts: impl IntoIterator<Item = (Box<[u8]>, Vec<(u8, f64)>)>,
+        scale: u32,
+    ) -> Result<Self, Error> {
+        if depth == 0 || scale == 0 {
+            return Err(Error::Config("depth and scale must be at least 1".into()));
+        }
+
+        let mut decoder = Decoder {
+            depth,
+            escape: EscapeMode::None,
+            trees: Default::default(),
+            alphabet: AlphabetMap::default(),
+            eof: false,
+            order0: None,
+            source: None,
+        };
+        let options = CodeOptions::default();
+        for (context, probabilities) in contexts {
+            decoder.check_context(&context)?;
+            if decoder.trees.contains_key(&context[..]) {
+                return Err(Error::Config(format!("duplicate context {context:02x?}")));
+            }
+
+            let mut seen = [false; 256];
+            let mut sum = 0.0;
+            let mut items = Vec::with_capacity(probabilities.len());
+            for (byte, probability) in probabilities {
+                if !probability.is_finite() || probability < 0.0 {
+                    return Err(Error::Config(format!(
+                        "invalid probability {probability} in context {context:02x?}"
+                    )));
+                }
+                if std::mem::replace(&mut seen[usize::from(byte)], true) {
+                    return Err(Error::Config(format!(
+                        "duplicate symbol {byte:#04x} in context {context:02x?}"
+                    )));
+                }
+                sum += probability;
+                if probability > 0.0 {
+                    items.push(WeightedItem::new(
+                        byte,
+                        ((probability * f64::from(scale)).round() as usize).max(1),
+                    ));
+                }
+            }
+
+            if (sum - 1.0).abs() > 1e-3 {
+                return Err(Error::Config(format!(
+                    "probabilities in context {context:02x?} sum to {sum}"
+                )));
+            }
+
+            if let Some(node) = options.tree(&items, None)? {
+                decoder.trees.insert(context.into(), node);
+            }
+        }
+        Ok(decoder)
+    }
+
+    /// Builds a decoder from externally specified codes of every context, such as the tables of
+    /// a published format, without a model. The codes of a context have to be prefix-free and
+    /// complete, every sequence of bits has to start with one of them. A context with a single
+    /// byte can give it the empty code.
+    ///
+    /// The code tables of the container only store code lengths and assign canonical codes
+    /// from them, so the decoder only writes tables that read back the same if the codes are
+    /// canonical and at most [`MAX_CODE_LENGTH`] bits long.
+    pub fn from_codes(
+        depth: usize,
+        codes: impl IntoIterator<Item = (Box<[u8]>, Vec<(u8, BitBox)>)>,
+    ) -> Result<Self, CodeTableError> {
+        if depth == 0 {
+            return Err(CodeTableError::Depth);
+        }
+
+        let mut decoder = Decoder {
+            depth,
+            ..Default::default()
+        };
+        for (context, codes) in codes {
+            let codes = sorted_codes(depth, &context, codes)?;
+            let tree = tree_from_codes(&context, &codes, &mut BitVec::new())?;
+            if decoder.trees.contains_key(&context[..]) {
+                return Err(CodeTableError::DuplicateContext(context));
+            }
+            decoder.trees.insert(context.into(), tree);
+        }
+        Ok(decoder)
+    }
+
+    fn check_context(&self, prefix: &[u8]) -> Result<(), Error> {
+        if prefix.len() + 1 != self.depth {
+            return Err(Error::Format("context length does not match model depth"));
+        }
+        Ok(())
+    }
+
+    /// Replaces the tree of a single context with one built from `items`, leaving all other
+    /// contexts untouched. Without any items, the context is removed.
+    ///
+    /// Trees are built without smoothing and with the default maximum code length.
+    pub fn rebuild_context(&mut self, prefix: &[u8], items: &[WeightedItem]) -> Result<(), Error> {
+        self.check_context(prefix)?;
+        match rebuild_tree(self.escape, self.alphabet, self.eof, items) {
+            Some(node) => self.trees.insert(prefix.into(), node),
+            None => self.trees.remove(prefix),
+        };
+        Ok(())
+    }
+
+    /// Rebuilds the trees of the `changed` contexts from their current weights in `markov`.
+    pub fn update_from(
+        &mut self,
+        markov: &Markov,
+        changed: impl IntoIterator<Item = Box<[u8]>>,
+    ) -> Result<(), Error> {
+        if markov.len() != self.depth {
+            return Err(Error::ModelMismatch {
+                expected: self.depth,
+                found: markov.len(),
+            });
+        }
+
+        let options = CodeOptions {
+            escape: self.escape,
+            alphabet: self.alphabet,
+            eof: self.eof,
+            ..Default::default()
+        };
+        for prefix in changed {
+            self.check_context(&prefix)?;
+            let escape = (self.escape == EscapeMode::Literal)
+                .then(|| markov.escape_weight(&prefix).unwrap_or(0).max(1));
+            match options.tree(&markov.successors(&prefix)?, escape)? {
+                Some(node) => self.trees.insert(prefix.into(), node),
+                None => self.trees.remove(&prefix[..]),
+            };
+        }
+        Ok(())
+    }
+
+    pub fn encoder(&self) -> Encoder {
+        Encoder::new(self)
+    }
+
+    /// Counts the trees and their symbols and finds the deepest one, such as to tell whether
+    /// the code lengths need to be limited. The order-0 tree is not included.
+    pub fn tree_shape(&self) -> TreeShape {
+        let mut shape = TreeShape::default();
+        for (context, tree) in &self.trees {
+            let depth = tree.max_depth();
+            if shape.deepest.is_none() || depth > shape.max_depth {
+                shape.max_depth = depth;
+                shape.deepest = Some(context[..].into());
+            }
+            shape.trees += 1;
+            shape.symbols += tree.symbol_count();
+        }
+        shape
+    }
+
+    pub fn shared(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    /// Stable hash of the code tables.
+    ///
+    /// This is XXH3-64 (seed 0) over the depth as a little-endian `u64` (followed by a single
+    /// `1` byte if literal escapes are enabled, a `2` byte and the bytes of the alphabet if
+    /// it is restricted, and a `3` byte with end symbols), followed by every context in
+    /// ascending order, each as its raw bytes, its byte symbol count as a little-endian `u16`, a
+    /// `(symbol, code length)` byte pair per byte symbol in ascending order and finally the
+    /// escape and end code lengths as a single byte each if the context has them. The order-0
+    /// tree of a deeper model follows after a `4` byte and the number of contexts as a
+    /// little-endian `u64`, like a context without bytes. Models without one hash as they did
+    /// before order-0 trees existed.
+    pub fn content_hash(&self) -> u64 {
+        tables_hash(
+            self.depth,
+            self.escape,
+            &self.alphabet,
+            self.eof,
+            self.trees
+                .iter()
+                .map(|(context, node)| (&context[..], node)),
+            self.order0.as_ref(),
+        )
+    }
+
+    /// Codes of every context in canonical order, ascending by context and then by symbol. The
+    /// order-0 tree of a deeper model is not included, see [`Decoder::order0`].
+    ///
+    /// Code tables and the [flat layout](crate::flat) are written and hashed in this order.
+    pub fn iter_codes(&self) -> impl Iterator<Item = CodeEntry<'_>> {
+        canonical_codes(
+            self.trees
+                .iter()
+                .map(|(context, node)| (&context[..], node)),
+        )
+    }
+
+    pub fn write_tables<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        write_tables(
+            writer,
+            self.depth,
+            self.escape,
+            &self.alphabet,
+            self.eof,
+            self.order0.as_ref(),
+            self.trees
+                .iter()
+                .map(|(context, node)| (&context[..], node)),
+        )
+    }
+
+    pub fn read_tables<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let TableHeader {
+            depth,
+            escape,
+            alphabet,
+            eof,
+            order0,
+            count,
+        } = read_table_header(reader)?;
+        if order0 && depth == 1 {
+            return Err(Error::Format("order-0 table in a model of depth 1"));
+        }
+        let mut decoder = Decoder {
+            depth,
+            escape,
+            trees: Default::default(),
+            alphabet,
+            eof,
+            order0: None,
+            source: None,
+        };
+        if order0 {
+            decoder.order0 = Some(read_tree(reader, &alphabet, true, false)?);
+        }
+        let mut context = vec![0; depth - 1];
+        for index in 0..count {
+            read_context(reader, index == 0, &mut context)?;
+            let node = read_tree(reader, &alphabet, escape == EscapeMode::Literal, eof)?;
+            decoder.trees.insert(context.clone().into(), node);
+        }
+
+        Ok(decoder)
+    }
+
+    /// Newest version of the container format this build reads, see
+    /// [`container::check_compatibility`](crate::container::check_compatibility).
+    pub fn format_version() -> u16 {
+        crate::container::VERSION
+    }
+
+    pub fn reader<R: Read>(&self, reader: R, context: &[u8], len: u64) -> Reader<&Self, R> {
+        Reader::new(self, reader, context, len)
+    }
+
+    pub fn decode_all(&self, context: &[u8], data: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        decode_all(self, self.depth, context, data, len)
+    }
+
+    /// Decodes `len` bytes written by [`Encoder::encode_into`] from the start of `bits`, after
+    /// the same `initial_context`. Also returns the number of bits their codes take, after
+    /// which whatever follows them in `bits` starts.
+    pub fn decode_from<T: BitStore, O: BitOrder>(
+        &self,
+        bits: &BitSlice<T, O>,
+        initial_context: &[u8],
+        len: usize,
+    ) -> Result<(Vec<u8>, usize), Error> {
+        self.check_context(initial_context)?;
+
+        let mut input = BitVec::<u8, Msb0>::with_capacity(bits.len());
+        input.extend_from_bitslice(bits);
+        input.set_uninitialized(false);
+        let mut context = initial_context.to_vec();
+        // every code but the one of a single leaf takes a bit.
+        let mut output = Vec::with_capacity(len.min(bits.len()));
+        let consumed = decode_symbols(
+            self,
+            &mut context,
+            input.as_raw_slice(),
+            bits.len() as u64,
+            len,
+            &mut output,
+        )?;
+        Ok((output, consumed as usize))
+    }
+
+    /// Decodes the single byte following `prefix` from the next bits of `bits`, taking no more
+    /// bits than its code has and without allocating. This is the step every decoding of the
+    /// decoder takes, for interleaving codes with other data.
+    ///
+    /// Unknown contexts are an error only if the model has no escapes. The end of the stream,
+    /// with end symbols, is [`SymbolError::EndOfStream`].
+    pub fn decode_one<I: Iterator<Item = bool>>(
+        &self,
+        prefix: &[u8],
+        bits: &mut I,
+    ) -> Result<u8, SymbolError> {
+        if prefix.len() + 1 != self.depth {
+            return Err(SymbolError::ContextLength {
+                len: prefix.len(),
+                depth: self.depth,
+            });
+        }
+        let tree = self.trees.get(prefix);
+        if tree.is_none() && self.escape == EscapeMode::None && !self.eof {
+            return Err(SymbolError::UnknownContext {
+                context: prefix.into(),
+            });
+        }
+
+        let mut consumed = 0;
+        let decoded = decode_symbol(
+            tree,
+            self.escape,
+            self.eof,
+            self.order0.as_ref(),
+            &mut BitIter(bits.inspect(|_| consumed += 1)),
+        );
+        match decoded {
+            Ok(Some(byte)) => Ok(byte),
+            Ok(None) => Err(SymbolError::EndOfStream),
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
+                Err(SymbolError::OutOfBits { consumed })
+            }
+            Err(error) => match Error::from(error) {
+                Error::Format(message) => Err(SymbolError::Format(message)),
+                error => unreachable!("bits from an iterator fail with {error}"),
+            },
+        }
+    }
+
+    /// Decodes the single byte following `prefix` from the start of `bits` like
+    /// [`Decoder::decode_one`], also returning the number of bits its code takes.
+    pub fn decode_one_bits<T: BitStore, O: BitOrder>(
+        &self,
+        prefix: &[u8],
+        bits: &BitSlice<T, O>,
+    ) -> Result<(u8, usize), SymbolError> {
+        let mut iter = bits.iter().by_vals();
+        let byte = self.decode_one(prefix, &mut iter)?;
+        Ok((byte, bits.len() - iter.len()))
+    }
+
+    /// Session for decoding many independent buffers into reused output, see [`DecodeSession`].
+    pub fn session(&self) -> DecodeSession<&Self> {
+        DecodeSession::new(self, self.depth)
+    }
+
+    /// Decodes `len` bytes following `context` like [`Decoder::decode_all`], into a string that
+    /// starts with `context`. Characters split between the context and the decoded bytes are
+    /// validated whole, so this is the inverse of [`Encoder::encode_str`].
+    pub fn decode_to_string(
+        &self,
+        context: &[u8],
+        data: &[u8],
+        len: usize,
+    ) -> Result<String, DecodeError> {
+        Ok(String::from_utf8(
+            self.decode_with_context(context, data, len)?,
+        )?)
+    }
+
+    /// Like [`Decoder::decode_to_string`], replacing invalid UTF-8 by the replacement character.
+    pub fn decode_to_string_lossy(
+        &self,
+        context: &[u8],
+        data: &[u8],
+        len: usize,
+    ) -> Result<String, Error> {
+        let bytes = self.decode_with_context(context, data, len)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn decode_with_context(
+        &self,
+        context: &[u8],
+        data: &[u8],
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut bytes = context.to_vec();
+        bytes.extend(self.decode_all(context, data, len)?);
+        Ok(bytes)
+    }
+
+    /// Decodes the bytes following `context` up to the end symbol, for data written with
+    /// [`Decoder::eof`] set and no known length.
+    pub fn decode_until_eof(&self, context: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        decode_until_eof(self, self.eof, self.depth, context, data)
+    }
+
+    /// Decodes `len` bytes starting at a [`Checkpoint`] recorded by the [`HuffmanWriter`], seeking
+    /// `reader` to it. Bit offsets count from the start of the reader.
+    pub fn decode_from_checkpoint<R: Read + Seek>(
+        &self,
+        reader: R,
+        checkpoint: &Checkpoint,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let mut reader = self.resume(
+            reader,
+            &DecodeCheckpoint {
+                bit_offset: checkpoint.bit_offset,
+                bytes_produced: 0,
+                remaining: len as u64,
+                context: checkpoint.context.clone(),
+            },
+        )?;
+        let mut output = Vec::with_capacity(len.min(1 << 20));
+        reader.read_to_end(&mut output)?;
+        if output.len() < len {
+            return Err(Error::Format("end of stream before the declared length"));
+        }
+        Ok(output)
+    }
+
+    /// Continues decoding where [`Reader::checkpoint`] was taken, seeking `reader` to it. The
+    /// reader has to hold the same stream, bit offsets count from its start.
+    pub fn resume<R: Read + Seek>(
+        &self,
+        mut reader: R,
+        checkpoint: &DecodeCheckpoint,
+    ) -> Result<Reader<&Self, R>, Error> {
+        if checkpoint.remaining > 0 {
+            self.check_context(&checkpoint.context)?;
+        }
+
+        reader.seek(SeekFrom::Start(checkpoint.bit_offset / 8))?;
+        let mut bits = BitReader::new(reader);
+        bits.skip((checkpoint.bit_offset % 8) as u32)?;
+        Ok(Reader {
+            context: checkpoint.context.to_vec(),
+            decoder: self,
+            reader: CountingReader::new(bits, checkpoint.bit_offset),
+            remaining: checkpoint.remaining,
+            produced: checkpoint.bytes_produced,
+        })
+    }
+
+    /// Decodes `len` bytes following `context` from an iterator of bits, in the order they are
+    /// written (see [`HuffmanWriter`]).
+    ///
+    /// Yields an error and stops if the bits run out early, no bits past the last byte are
+    /// consumed. With end symbols, the iterator also ends at the end of the stream.
+    pub fn decode_iter<I: IntoIterator<Item = bool>>(
+        &self,
+        bits: I,
+        context: &[u8],
+        len: u64,
+    ) -> DecodeIter<'_, I::IntoIter> {
+        let error = (len > 0 && context.len() + 1 != self.depth)
+            .then_some(Error::Format("context length does not match model depth"));
+        DecodeIter {
+            decoder: self,
+            bits: bits.into_iter(),
+            context: context.into(),
+            remaining: len,
+            error,
+        }
+    }
+}
+
+/// Iterator over the bytes decoded from an iterator of bits, see [`Decoder::decode_iter`].
+pub struct DecodeIter<'a, I> {
+    decoder: &'a Decoder,
+    bits: I,
+    context: Vec<u8>,
+    remaining: u64,
+    error: Option<Error>,
+}
+
+impl<I: Iterator<Item = bool>> Iterator for DecodeIter<'_, I> {
+    type Item = Result<u8, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(error) = self.error.take() {
+            self.remaining = 0;
+            return Some(Err(error));
+        }
+        if self.remaining == 0 {
+            return None;
+        }
+
+        match self.decoder.decode_one(&self.context, &mut self.bits) {
+            Ok(byte) => {
+                shift_context(&mut self.context, byte);
+                self.remaining -= 1;
+                Some(Ok(byte))
+            }
+            Err(SymbolError::EndOfStream) => {
+                self.remaining = 0;
+                None
+            }
+            Err(error) => {
+                self.remaining = 0;
+                Some(Err(error.into()))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let upper = usize::try_from(self.remaining).ok();
+        match &self.error {
+            Some(_) => (1, Some(1)),
+            None => (0, upper),
+        }
+    }
+}
+
+impl<I: Iterator<Item = bool>> FusedIterator for DecodeIter<'_, I> {}
+
+/// Code tables of a model for encoding, derived from a [`Decoder`]. [`Coder`] holds the same
+/// trees and covers both directions without keeping the two in sync.
+///
+/// Equality and hashing compare the code tables. Cloning copies every code, use
+/// [`Encoder::shared`] to hand one encoder to several writers.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub struct Encoder {
+    pub depth: usize,
+    pub escape: EscapeMode,
+    /// Bytes the codes can be given to, see [`Decoder::alphabet`].
+    pub alphabet: AlphabetMap,
+    pub eof: bool,
+    pub prefixes: BTreeMap<Arc<[u8]>, BTreeMap<u8, BitBox>>,
+    pub escapes: BTreeMap<Arc<[u8]>, BitBox>,
+    pub eofs: BTreeMap<Arc<[u8]>, BitBox>,
+    // codes of the order-0 tree, see `Decoder::order0`.
+    pub(crate) order0: Option<Codes>,
+}
+
+impl Encoder {
+    fn new(decoder: &Decoder) -> Self {
+        let mut encoder = Encoder {
+            depth: decoder.depth,
+            escape: decoder.escape,
+            alphabet: decoder.alphabet,
+            eof: decoder.eof,
+            prefixes: Default::default(),
+            escapes: Default::default(),
+            eofs: Default::default(),
+            order0: decoder.order0.as_ref().map(Codes::new),
+        };
+
+        #[cfg(feature = "rayon")]
+        let codes: Vec<_> = {
+            use rayon::prelude::*;
+            decoder
+                .trees
+                .par_iter()
+                .map(|(prefix, node)| (prefix.clone(), Codes::new(node)))
+                .collect()
+        };
+        #[cfg(not(feature = "rayon"))]
+        let codes = decoder
+            .trees
+            .iter()
+            .map(|(prefix, node)| (prefix.clone(), Codes::new(node)));
+
+        for (prefix, codes) in codes {
+            encoder.insert_codes(prefix, codes);
+        }
+        encoder
+    }
+
+    /// Builds an encoder from externally specified codes of every context, like
+    /// [`Decoder::from_codes`]. The codes only have to be prefix-free, bytes without a code
+    /// cannot be encoded.
+    pub fn from_codes(
+        depth: usize,
+        codes: impl IntoIterator<Item = (Box<[u8]>, Vec<(u8, BitBox)>)>,
+    ) -> Result<Self, CodeTableError> {
+        if depth == 0 {
+            return Err(CodeTableError::Depth);
+        }
+
+        let mut encoder = Encoder {
+            depth,
+            ..Default::default()
+        };
+        for (context, codes) in codes {
+            let codes = sorted_codes(depth, &context, codes)?;
+            if encoder.prefixes.contains_key(&context[..]) {
+                return Err(CodeTableError::DuplicateContext(context));
+            }
+            let codes = codes.into_iter().map(|(code, byte)| (byte, code)).collect();
+            encoder.prefixes.insert(context.into(), codes);
+        }
+        Ok(encoder)
+    }
+
+    fn insert_codes(&mut self, prefix: Arc<[u8]>, codes: Codes) {
+        match codes.escape {
+            Some(code) => self.escapes.insert(prefix.clone(), code),
+            None => self.escapes.remove(&prefix),
+        };
+        match codes.eof {
+            Some(code) => self.eofs.insert(prefix.clone(), code),
+            None => self.eofs.remove(&prefix),
+        };
+        self.prefixes.insert(prefix, codes.bytes);
+    }
+
+    /// Replaces the codes of a single context, see [`Decoder::rebuild_context`].
+    pub fn rebuild_context(&mut self, prefix: &[u8], items: &[WeightedItem]) -> Result<(), Error> {
+        if prefix.len() + 1 != self.depth {
+            return Err(Error::Format("context length does not match model depth"));
+        }
+
+        match rebuild_tree(self.escape, self.alphabet, self.eof, items) {
+            Some(node) => {
+                let prefix = match self.prefixes.get_key_value(prefix) {
+                    Some((prefix, _)) => prefix.clone(),
+                    None => prefix.into(),
+                };
+                self.insert_codes(prefix, Codes::new(&node))
+            }
+            None => {
+                self.prefixes.remove(prefix);
+                self.escapes.remove(prefix);
+                self.eofs.remove(prefix);
+            }
+        }
+        Ok(())
+    }
+
+    fn encode(&self, prefix: &[u8], byte: u8) -> Option<&BitSlice> {
+        Some(self.prefixes.get(prefix)?.get(&byte)?.as_bitslice())
+    }
+
+    /// Code of the last byte of `window` after the bytes before it, which are its context.
+    /// Escapes are not considered, bytes without a code of their own are missing.
+    pub fn encode_window(&self, window: &[u8]) -> Result<&BitSlice, EncodeError> {
+        if window.len() != self.depth {
+            return Err(EncodeError::WindowLength {
+                len: window.len(),
+                depth: self.depth,
+            });
+        }
+        let (byte, prefix) = window.split_last().unwrap();
+        let codes = self
+            .prefixes
+            .get(prefix)
+            .ok_or_else(|| EncodeError::MissingContext {
+                context: prefix.into(),
+            })?;
+        match codes.get(byte) {
+            Some(code) => Ok(code),
+            None => Err(EncodeError::MissingByte {
+                context: prefix.into(),
+                byte: *byte,
+            }),
+        }
+    }
+
+    /// Whether the model has codes after `prefix`, which is `depth - 1` bytes long.
+    pub fn has_context(&self, prefix: &[u8]) -> bool {
+        self.prefixes.contains_key(prefix)
+    }
+
+    /// Number of contexts with codes.
+    pub fn context_count(&self) -> usize {
+        self.prefixes.len()
+    }
+
+    /// Counts the windows of `data` the model has no code for, in a single pass without
+    /// allocating. Unlike [`Encoder::validate`], escapes are not considered, so this tells how
+    /// well the model fits data it was not trained on.
+    pub fn coverage(&self, data: &[u8]) -> Coverage {
+        coverage(self.depth, data, |prefix, byte| {
+            Some(self.prefixes.get(prefix)?.contains_key(&byte))
+        })
+    }
+
+    /// Length of the code [`Encoder::encode_window`] returns, without the reason if there is
+    /// none.
+    pub fn code_len(&self, window: &[u8]) -> Option<u8> {
+        if window.len() != self.depth {
+            return None;
+        }
+        let (byte, prefix) = window.split_last()?;
+        Some(self.prefixes.get(prefix)?.get(byte)?.len() as u8)
+    }
+
+    /// Upper bound on the length of [`Encoder::encode_all`] for any `input_len` bytes, from the
+    /// longest code of any context. Bytes without a code count as escaped.
+    pub fn max_compressed_len(&self, input_len: u64) -> u64 {
+        let longest = |codes: &mut dyn Iterator<Item = &BitBox>| {
+            codes.map(|code| code.len() as u64).max().unwrap_or(0)
+        };
+        // unknown contexts have no codes, with end symbols a single bit marks their literals
+        // and the end.
+        let unknown = u64::from(self.eof && self.escape == EscapeMode::Literal);
+        let mut symbol = longest(&mut self.prefixes.values().flat_map(BTreeMap::values));
+        if self.escape == EscapeMode::Literal {
+            let backoff = match &self.order0 {
+                Some(order0) => {
+                    longest(&mut order0.bytes.values()).max(longest(&mut order0.escape.iter()) + 8)
+                }
+                None => 8,
+            };
+            symbol = symbol.max(longest(&mut self.escapes.values()).max(unknown) + backoff);
+        }
+        let eof = longest(&mut self.eofs.values()).max(unknown);
+        let symbols = input_len.saturating_sub(self.depth.saturating_sub(1) as u64);
+        symbols
+            .saturating_mul(symbol)
+            .saturating_add(eof)
+            .div_ceil(8)
+    }
+
+    /// Codes of the bytes of `text` after its first `depth - 1`, which are the context to decode
+    /// them with. Missing bytes are escaped as the [`HuffmanWriter`] does, so
+    /// [`BitVec::into_vec`] gives the data [`Decoder::decode_to_string`] reads.
+    pub fn encode_str(&self, text: &str) -> Result<BitVec<u8, Msb0>, EncodeError> {
+        let text = text.as_bytes();
+        let mut bits = BitVec::new();
+        if text.is_empty() {
+            return Ok(bits);
+        }
+        if text.len() < self.depth {
+            return Err(EncodeError::WindowLength {
+                len: text.len(),
+                depth: self.depth,
+            });
+        }
+
+        let mut bytes = vec![];
+        let mut writer = BitWriter::endian(&mut bytes, BigEndian);
+        let mut len = 0;
+        for window in text.windows(self.depth) {
+            // without escapes, tell which code is missing before writing fails.
+            if self.escape == EscapeMode::None {
+                self.encode_window(window)?;
+            }
+            let (byte, prefix) = window.split_last().unwrap();
+            let emitted = self
+                .write_symbol(&mut writer, prefix, *byte)
+                .expect("writing to memory does not fail");
+            len += emitted.bits() as usize;
+        }
+        writer
+            .byte_align()
+            .expect("writing to memory does not fail");
+        bits.extend_from_bitslice(&bytes.view_bits::<Msb0>()[..len]);
+        Ok(bits)
+    }
+
+    /// Code of any symbol in the context `prefix`, if it has one.
+    pub fn encode_symbol(&self, prefix: &[u8], symbol: Symbol) -> Option<&BitSlice> {
+        let code = match symbol {
+            Symbol::Byte(byte) => return self.encode(prefix, byte),
+            Symbol::Escape => self.escapes.get(prefix)?,
+            Symbol::Eof => self.eofs.get(prefix)?,
+        };
+        Some(code.as_bitslice())
+    }
+
+    pub fn writer<W: Write>(&self, writer: W) -> HuffmanWriter<&Self, W> {
+        HuffmanWriter::new(self, writer)
+    }
+
+    /// Writer over a type-erased output, so chains of adapters don't leak into its type.
+    ///
+    /// The writer can sit between other adapters, `&mut HuffmanWriter` is [`Write`] through
+    /// the blanket impl of the standard library. Unwrap the outer adapters to
+    /// [`HuffmanWriter::finish`] it:
+    ///
+    /// ```
+    /// use huffman_markov::Markov;
+    /// use std::{fs::File, io::{BufWriter, Write}};
+    ///
+    /// let mut markov = Markov::new(2);
+    /// markov.writer().write(b"abracadabra");
+    /// let encoder = markov.encoder();
+    ///
+    /// let path = std::env::temp_dir().join("huffman-markov-boxed-writer");
+    /// let file = BufWriter::new(File::create(&path)?);
+    /// let mut output = BufWriter::new(encoder.boxed_writer(Box::new(file)));
+    /// output.write_all(b"abra")?;
+    /// std::io::copy(&mut &b"cadabra"[..], &mut output)?;
+    ///
+    /// let writer = output.into_inner().map_err(|error| error.into_error())?;
+    /// let (mut file, stats) = writer.finish()?;
+    /// file.flush()?;
+    /// drop(file);
+    /// assert_eq!(stats.input_bytes, 11);
+    /// assert_eq!(std::fs::read(&path)?, encoder.encode_all(b"abracadabra")?);
+    /// # std::fs::remove_file(&path)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn boxed_writer(&self, writer: Box<dyn Write>) -> HuffmanWriter<&Self, Box<dyn Write>> {
+        HuffmanWriter::new(self, writer)
+    }
+
+    /// Moves the encoder behind an [`Arc`], which writers can share without copying the codes.
+    ///
+    /// ```
+    /// use huffman_markov::{huffman::HuffmanWriter, Markov};
+    /// use std::{io::Write, thread};
+    ///
+    /// let mut markov = Markov::new(2);
+    /// markov.writer().write(b"abracadabra");
+    /// let encoder = markov.encoder().shared();
+    ///
+    /// let threads: Vec<_> = ["abra", "cadabra"]
+    ///     .into_iter()
+    ///     .map(|input| {
+    ///         let mut writer = HuffmanWriter::new(encoder.clone(), vec![]);
+    ///         thread::spawn(move || {
+    ///             writer.write_all(input.as_bytes()).unwrap();
+    ///             writer.finish().unwrap().0
+    ///         })
+    ///     })
+    ///     .collect();
+    /// for thread in threads {
+    ///     assert!(!thread.join().unwrap().is_empty());
+    /// }
+    /// ```
+    pub fn shared(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    pub fn encode_all(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        encode_all(self, data)
+    }
+
+    /// Appends the codes of every byte of `data` to `out`, for embedding them in another bit
+    /// stream. There is no padding and no end symbol, the field ends after
+    /// [`WriterStats::output_bits`]. `initial_context` is the `depth - 1` bytes the first byte
+    /// is coded after, which [`Decoder::decode_from`] needs again.
+    pub fn encode_into<T: BitStore, O: BitOrder>(
+        &self,
+        data: &[u8],
+        initial_context: &[u8],
+        out: &mut BitVec<T, O>,
+    ) -> Result<WriterStats, Error> {
+        if initial_context.len() + 1 != self.depth {
+            return Err(Error::Format("context length does not match model depth"));
+        }
+
+        let mut context = initial_context.to_vec();
+        let mut bytes = vec![];
+        let mut writer = BitWriter::endian(&mut bytes, BigEndian);
+        let mut stats = WriterStats {
+            input_bytes: (initial_context.len() + data.len()) as u64,
+            ..Default::default()
+        };
+        for byte in data {
+            stats.record(self.write_symbol(&mut writer, &context, *byte)?);
+            shift_context(&mut context, *byte);
+        }
+        writer.byte_align()?;
+        out.extend_from_bitslice(&bytes.view_bits::<Msb0>()[..stats.output_bits as usize]);
+        Ok(stats)
+    }
+
+    /// Session for encoding many independent buffers into reused output, see [`EncodeSession`].
+    pub fn session(&self) -> EncodeSession<&Self> {
+        EncodeSession::new(self)
+    }
+
+    /// Checks that every byte of `data` can be encoded, returning the first
+    /// [`MAX_REPORTED_MISSES`] positions that cannot.
+    pub fn validate(&self, data: &[u8]) -> Result<(), Vec<EncodeMiss>> {
+        validate(self.depth, self.escape, data, |window| {
+            self.code_len(window).is_some()
+        })
+    }
+
+    /// Number of bytes in `data` that cannot be encoded.
+    pub fn miss_count(&self, data: &[u8]) -> usize {
+        misses(self.depth, self.escape, data, |window| {
+            self.code_len(window).is_some()
+        })
+        .count()
+    }
+}
+
+/// What was written for a single symbol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Emitted {
+    /// Length of the codes written, of the escape and of the order-0 code of an escaped byte
+    /// together.
+    pub code_length: u8,
+    /// Whether the byte had no code in its context, so it was escaped.
+    pub escape: bool,
+    /// Whether the byte followed the codes as an 8-bit literal.
+    pub literal: bool,
+}
+
+impl Emitted {
+    pub fn bits(&self) -> u64 {
+        u64::from(self.code_length) + if self.literal { 8 } else { 0 }
+    }
+}
+
+/// Writes the codes of symbols, given the context they appear in.
+pub trait EncodeSymbol {
+    fn depth(&self) -> usize;
+    fn write_symbol<B: BitWrite>(
+        &self,
+        writer: &mut B,
+        prefix: &[u8],
+        byte: u8,
+    ) -> IoResult<Emitted>;
+
+    /// Writes the end of the stream after the context `prefix`, if the codes have one.
+    fn write_eof<B: BitWrite>(&self, _writer: &mut B, _prefix: &[u8]) -> IoResult<Emitted> {
+        Ok(Emitted::default())
+    }
+}
+
+/// Reads symbols from their codes, given the context they appear in. `None` is the end of the
+/// stream.
+pub trait DecodeSymbol {
+    fn decode_symbol<R: BitRead>(&self, prefix: &[u8], reader: &mut R) -> IoResult<Option<u8>>;
+}
+
+impl<T: EncodeSymbol + ?Sized> EncodeSymbol for &T {
+    fn depth(&self) -> usize {
+        (**self).depth()
+    }
+
+    fn write_symbol<B: BitWrite>(
+        &self,
+        writer: &mut B,
+        prefix: &[u8],
+        byte: u8,
+    ) -> IoResult<Emitted> {
+        (**self).write_symbol(writer, prefix, byte)
+    }
+
+    fn write_eof<B: BitWrite>(&self, writer: &mut B, prefix: &[u8]) -> IoResult<Emitted> {
+        (**self).write_eof(writer, prefix)
+    }
+}
+
+impl<T: EncodeSymbol + ?Sized> EncodeSymbol for Arc<T> {
+    fn depth(&self) -> usize {
+        (**self).depth()
+    }
+
+    fn write_symbol<B: BitWrite>(
+        &self,
+        writer: &mut B,
+        prefix: &[u8],
+        byte: u8,
+    ) -> IoResult<Emitted> {
+        (**self).write_symbol(writer, prefix, byte)
+    }
+
+    fn write_eof<B: BitWrite>(&self, writer: &mut B, prefix: &[u8]) -> IoResult<Emitted> {
+        (**self).write_eof(writer, prefix)
+    }
+}
+
+impl<T: DecodeSymbol + ?Sized> DecodeSymbol for &T {
+    fn decode_symbol<R: BitRead>(&self, prefix: &[u8], reader: &mut R) -> IoResult<Option<u8>> {
+        (**self).decode_symbol(prefix, reader)
+    }
+}
+
+impl<T: DecodeSymbol + ?Sized> DecodeSymbol for Arc<T> {
+    fn decode_symbol<R: BitRead>(&self, prefix: &[u8], reader: &mut R) -> IoResult<Option<u8>> {
+        (**self).decode_symbol(prefix, reader)
+    }
+}
+
+impl EncodeSymbol for Encoder {
+    fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn write_symbol<B: BitWrite>(
+        &self,
+        writer: &mut B,
+        prefix: &[u8],
+        byte: u8,
+    ) -> IoResult<Emitted> {
+        write_symbol(
+            writer,
+            self.encode(prefix, byte),
+            self.escape,
+            self.escapes.get(prefix).map(|code| code.as_bitslice()),
+            self.eof,
+            self.order0.as_ref(),
+            byte,
+        )
+    }
+
+    fn write_eof<B: BitWrite>(&self, writer: &mut B, prefix: &[u8]) -> IoResult<Emitted> {
+        write_eof(
+            writer,
+            self.eof,
+            self.escape,
+            self.prefixes.contains_key(prefix),
+            self.eofs.get(prefix).map(|code| code.as_bitslice()),
+        )
+    }
+}
+
+impl DecodeSymbol for Decoder {
+    fn decode_symbol<R: BitRead>(&self, prefix: &[u8], reader: &mut R) -> IoResult<Option<u8>> {
+        decode_symbol(
+            self.trees.get(prefix),
+            self.escape,
+            self.eof,
+            self.order0.as_ref(),
+            reader,
+        )
+    }
+}
+
+// code tables of a single context.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Codes {
+    pub(crate) bytes: BTreeMap<u8, BitBox>,
+    pub(crate) escape: Option<BitBox>,
+    pub(crate) eof: Option<BitBox>,
+}
+
+impl Codes {
+    fn new(node: &HuffmanNode) -> Self {
+        let mut codes = Codes::default();
+        for (symbol, code) in node.encoding() {
+            match symbol {
+                Symbol::Byte(byte) => {
+                    codes.bytes.insert(byte, code);
+                }
+                Symbol::Escape => codes.escape = Some(code),
+                Symbol::Eof => codes.eof = Some(code),
+            }
+        }
+        codes
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Context {
+    tree: HuffmanNode,
+    codes: OnceLock<Codes>,
+}
+
+impl Context {
+    fn new(tree: HuffmanNode) -> Self {
+        Context {
+            tree,
+            codes: OnceLock::new(),
+        }
+    }
+
+    fn codes(&self) -> &Codes {
+        self.codes.get_or_init(|| Codes::new(&self.tree))
+    }
+}
+
+/// Huffman trees of every context, used for both encoding and decoding.
+///
+/// The code tables of a context are built from its tree the first time a symbol is encoded in
+/// it, so a coder that only decodes costs no more than a [`Decoder`]. Equality and hashing
+/// compare the trees only.
+#[derive(Clone, Debug, Default)]
+pub struct Coder {
+    depth: usize,
+    escape: EscapeMode,
+    alphabet: AlphabetMap,
+    eof: bool,
+    contexts: ContextMap<Context>,
+    order0: Option<Context>,
+}
+
+impl PartialEq for Coder {
+    fn eq(&self, other: &Self) -> bool {
+        self.depth == other.depth
+            && self.escape == other.escape
+            && self.alphabet == other.alphabet
+            && self.eof == other.eof
+            && self.trees().eq(other.trees())
+            && self.order0_tree() == other.order0_tree()
+    }
+}
+
+impl Eq for Coder {}
+
+impl Hash for Coder {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.depth.hash(state);
+        self.escape.hash(state);
+        self.alphabet.hash(state);
+        self.eof.hash(state);
+        self.contexts.len().hash(state);
+        for (prefix, tree) in self.trees() {
+            prefix.hash(state);
+            tree.hash(state);
+        }
+        self.order0_tree().hash(state);
+    }
+}
+
+impl Coder {
+    pub fn new(markov: &Markov) -> Self {
+        Decoder::new(markov).into()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_options(markov: &Markov, options: &CodeOptions) -> Self {
+        Decoder::with_options(markov, options).into()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn escape(&self) -> EscapeMode {
+        self.escape
+    }
+
+    pub fn alphabet(&self) -> &AlphabetMap {
+        &self.alphabet
+    }
+
+    /// Whether every tree has a [`Symbol::Eof`], see [`Decoder::eof`].
+    pub fn eof(&self) -> bool {
+        self.eof
+    }
+
+    pub fn tree(&self, prefix: &[u8]) -> Option<&HuffmanNode> {
+        Some(&self.contexts.get(prefix)?.tree)
+    }
+
+    /// Tree bytes without a code in their context fall back to, see [`Decoder::order0`].
+    pub fn order0(&self) -> Option<&HuffmanNode> {
+        match self.depth {
+            1 => self.tree(&[]),
+            _ => self.order0_tree(),
+        }
+    }
+
+    fn order0_tree(&self) -> Option<&HuffmanNode> {
+        Some(&self.order0.as_ref()?.tree)
+    }
+
+    /// Writes `byte` with the order-0 codes, escaped to a literal if it has none or there are
+    /// no order-0 codes.
+    pub(crate) fn write_order0<B: BitWrite>(&self, writer: &mut B, byte: u8) -> IoResult<Emitted> {
+        write_backoff(writer, self.order0.as_ref().map(Context::codes), byte)
+    }
+
+    /// Reads a byte written by [`Coder::write_order0`].
+    pub(crate) fn read_order0<R: BitRead>(&self, reader: &mut R) -> IoResult<u8> {
+        decode_backoff(self.order0_tree(), reader)
+    }
+
+    pub fn trees(&self) -> impl ExactSizeIterator<Item = (&[u8], &HuffmanNode)> {
+        self.contexts
+            .iter()
+            .map(|(prefix, context)| (&prefix[..], &context.tree))
+    }
+
+    /// Stable hash of the code tables, the [`Decoder::content_hash`] of the same trees.
+    pub fn content_hash(&self) -> u64 {
+        tables_hash(
+            self.depth,
+            self.escape,
+            &self.alphabet,
+            self.eof,
+            self.trees(),
+            self.order0_tree(),
+        )
+    }
+
+    /// Codes of every context in canonical order, like [`Decoder::iter_codes`].
+    pub fn iter_codes(&self) -> impl Iterator<Item = CodeEntry<'_>> {
+        canonical_codes(self.trees())
+    }
+
+    pub fn encode(&self, prefix: &[u8], byte: u8) -> Option<&BitSlice> {
+        let codes = self.contexts.get(prefix)?.codes();
+        Some(codes.bytes.get(&byte)?.as_bitslice())
+    }
+
+    pub fn decode_symbol<R: BitRead>(&self, prefix: &[u8], reader: &mut R) -> IoResult<Option<u8>> {
+        decode_symbol(
+            self.tree(prefix),
+            self.escape,
+            self.eof,
+            self.order0_tree(),
+            reader,
+        )
+    }
+
+    /// Replaces the tree of a single context, see [`Decoder::rebuild_context`].
+    pub fn rebuild_context(&mut self, prefix: &[u8], items: &[WeightedItem]) -> Result<(), Error> {
+        if prefix.len() + 1 != self.depth {
+            return Err(Error::Format("context length does not match model depth"));
+        }
+
+        match rebuild_tree(self.escape, self.alphabet, self.eof, items) {
+            Some(node) => self.contexts.insert(prefix.into(), Context::new(node)),
+            None => self.contexts.remove(prefix),
+        };
+        Ok(())
+    }
+
+    pub fn writer<W: Write>(&self, writer: W) -> HuffmanWriter<&Self, W> {
+        HuffmanWriter::new(self, writer)
+    }
+
+    /// See [`Encoder::boxed_writer`].
+    pub fn boxed_writer(&self, writer: Box<dyn Write>) -> HuffmanWriter<&Self, Box<dyn Write>> {
+        HuffmanWriter::new(self, writer)
+    }
+
+    pub fn reader<R: Read>(&self, reader: R, context: &[u8], len: u64) -> Reader<&Self, R> {
+        Reader::new(self, reader, context, len)
+    }
+
+    pub fn shared(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    pub fn encode_all(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        encode_all(self, data)
+    }
+
+    /// See [`Encoder::validate`].
+    pub fn validate(&self, data: &[u8]) -> Result<(), Vec<EncodeMiss>> {
+        validate(self.depth, self.escape, data, |window| {
+            let (byte, prefix) = window.split_last().unwrap();
+            self.encode(prefix, *byte).is_some()
+        })
+    }
+
+    pub fn miss_count(&self, data: &[u8]) -> usize {
+        misses(self.depth, self.escape, data, |window| {
+            let (byte, prefix) = window.split_last().unwrap();
+            self.encode(prefix, *byte).is_some()
+        })
+        .count()
+    }
+
+    /// See [`Encoder::has_context`].
+    pub fn has_context(&self, prefix: &[u8]) -> bool {
+        self.contexts.contains_key(prefix)
+    }
+
+    /// See [`Encoder::context_count`].
+    pub fn context_count(&self) -> usize {
+        self.contexts.len()
+    }
+
+    /// See [`Encoder::coverage`].
+    pub fn coverage(&self, data: &[u8]) -> Coverage {
+        coverage(self.depth, data, |prefix, byte| {
+            let codes = self.contexts.get(prefix)?.codes();
+            Some(codes.bytes.contains_key(&byte))
+        })
+    }
+
+    pub fn decode_all(&self, context: &[u8], data: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        decode_all(self, self.depth, context, data, len)
+    }
+
+    /// See [`Decoder::decode_until_eof`].
+    pub fn decode_until_eof(&self, context: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        decode_until_eof(self, self.eof, self.depth, context, data)
+    }
+
+    pub fn write_tables<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        write_tables(
+            writer,
+            self.depth,
+            self.escape,
+            &self.alphabet,
+            self.eof,
+            self.order0_tree(),
+            self.trees(),
+        )
+    }
+
+    pub fn read_tables<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Decoder::read_tables(reader)?.into())
+    }
+
+    pub fn decoder(&self) -> Decoder {
+        Decoder {
+            depth: self.depth,
+            escape: self.escape,
+            alphabet: self.alphabet,
+            eof: self.eof,
+            trees: self
+                .contexts
+                .iter()
+                .map(|(prefix, context)| (prefix.clone(), context.tree.clone()))
+                .collect(),
+            order0: self.order0_tree().cloned(),
+            source: None,
+        }
+    }
+
+    pub fn encoder(&self) -> Encoder {
+        let mut encoder = Encoder {
+            depth: self.depth,
+            escape: self.escape,
+            alphabet: self.alphabet,
+            eof: self.eof,
+            prefixes: Default::default(),
+            escapes: Default::default(),
+            eofs: Default::default(),
+            order0: self.order0.as_ref().map(|order0| order0.codes().clone()),
+        };
+        for (prefix, context) in &self.contexts {
+            encoder.insert_codes(prefix.clone(), context.codes().clone());
+        }
+        encoder
+    }
+}
+
+impl From<Decoder> for Coder {
+    fn from(decoder: Decoder) -> Self {
+        Coder {
+            depth: decoder.depth,
+            escape: decoder.escape,
+            alphabet: decoder.alphabet,
+            eof: decoder.eof,
+            contexts: {
+                let mut contexts = ContextMap::new(decoder.depth);
+                contexts.extend(
+                    decoder
+                        .trees
+                        .into_iter()
+                        .map(|(prefix, tree)| (prefix, Context::new(tree))),
+                );
+                contexts
+            },
+            order0: decoder.order0.map(Context::new),
+        }
+    }
+}
+
+impl EncodeSymbol for Coder {
+    fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn write_symbol<B: BitWrite>(
+        &self,
+        writer: &mut B,
+        prefix: &[u8],
+        byte: u8,
+    ) -> IoResult<Emitted> {
+        let codes = self.contexts.get(prefix).map(Context::codes);
+        write_symbol(
+            writer,
+            codes
+                .and_then(|codes| codes.bytes.get(&byte))
+                .map(|code| code.as_bitslice()),
+            self.escape,
+            codes
+                .and_then(|codes| codes.escape.as_ref())
+                .map(|code| code.as_bitslice()),
+            self.eof,
+            self.order0.as_ref().map(Context::codes),
+            byte,
+        )
+    }
+
+    fn write_eof<B: BitWrite>(&self, writer: &mut B, prefix: &[u8]) -> IoResult<Emitted> {
+        let codes = self.contexts.get(prefix).map(Context::codes);
+        write_eof(
+            writer,
+            self.eof,
+            self.escape,
+            codes.is_some(),
+            codes
+                .and_then(|codes| codes.eof.as_ref())
+                .map(|code| code.as_bitslice()),
+        )
+    }
+}
+
+impl DecodeSymbol for Coder {
+    fn decode_symbol<R: BitRead>(&self, prefix: &[u8], reader: &mut R) -> IoResult<Option<u8>> {
+        Coder::decode_symbol(self, prefix, reader)
+    }
+}
+
+// locks the cache of a lazy decoder is split into, so that threads coding in different
+// contexts rarely wait for each other.
+const LAZY_SHARDS: usize = 16;
+
+type LazyShard = Mutex<HashMap<Box<[u8]>, Option<Arc<Context>>>>;
+
+/// Huffman trees of a [`Markov`] model that are built when their context is first coded in,
+/// see [`Decoder::lazy`].
+///
+/// Codes the same as a [`Coder`] of the model. Built trees are cached behind a few locks, so a
+/// single lazy decoder can be shared by writers and readers on several threads.
+pub struct LazyDecoder<'a> {
+    markov: &'a Markov,
+    options: CodeOptions,
+    escape: EscapeMode,
+    shards: Box<[LazyShard]>,
+    order0: OnceLock<Option<Context>>,
+}
+
+impl<'a> LazyDecoder<'a> {
+    pub(crate) fn new(markov: &'a Markov, options: &CodeOptions) -> Self {
+        LazyDecoder {
+            markov,
+            options: *options,
+            escape: options.escape_mode(markov),
+            shards: (0..LAZY_SHARDS).map(|_| Mutex::default()).collect(),
+            order0: OnceLock::new(),
+        }
+    }
+
+    // order-0 tree with its codes, built on first use as it needs all of the model.
+    fn order0(&self) -> Option<&Context> {
+        self.order0
+            .get_or_init(|| {
+                order0_tree(self.markov, &self.options)
+                    .expect("saturating weights do not overflow")
+                    .map(Context::new)
+            })
+            .as_ref()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.markov.len()
+    }
+
+    /// Number of contexts looked up so far, including those the model has no tree for.
+    pub fn built(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).len())
+            .sum()
+    }
+
+    // tree of `prefix` with its codes, built on first use. none if the model has no successors.
+    fn context(&self, prefix: &[u8]) -> Option<Arc<Context>> {
+        let shard = &self.shards[xxh3_64(prefix) as usize % LAZY_SHARDS];
+        if let Some(context) = shard
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(prefix)
+        {
+            return context.clone();
+        }
+
+        // built without holding the lock, of two threads building the same tree one wins.
+        let items = self.markov.successors(prefix).unwrap_or_default();
+        let escape = (self.escape == EscapeMode::Literal)
+            .then(|| self.markov.escape_weight(prefix).unwrap_or(0).max(1));
+        let context = match items.is_empty() {
+            true => None,
+            false => self
+                .options
+                .tree(&items, escape)
+                .expect("saturating weights do not overflow"),
+        };
+        shard
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(prefix.into())
+            .or_insert_with(|| context.map(|tree| Arc::new(Context::new(tree))))
+            .clone()
+    }
+
+    pub fn writer<W: Write>(&self, writer: W) -> HuffmanWriter<&Self, W> {
+        HuffmanWriter::new(self, writer)
+    }
+
+    pub fn reader<R: Read>(&self, reader: R, context: &[u8], len: u64) -> Reader<&Self, R> {
+        Reader::new(self, reader, context, len)
+    }
+
+    pub fn encode_all(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        encode_all(self, data)
+    }
+
+    pub fn decode_all(&self, context: &[u8], data: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        decode_all(self, self.depth(), context, data, len)
+    }
+}
+
+impl EncodeSymbol for LazyDecoder<'_> {
+    fn depth(&self) -> usize {
+        self.markov.len()
+    }
+
+    fn write_symbol<B: BitWrite>(
+        &self,
+        writer: &mut B,
+        prefix: &[u8],
+        byte: u8,
+    ) -> IoResult<Emitted> {
+        let context = self.context(prefix);
+        let codes = context.as_deref().map(Context::codes);
+        write_symbol(
+            writer,
+            codes
+                .and_then(|codes| codes.bytes.get(&byte))
+                .map(|code| code.as_bitslice()),
+            self.escape,
+            codes
+                .and_then(|codes| codes.escape.as_ref())
+                .map(|code| code.as_bitslice()),
+            self.options.eof,
+            self.order0().map(Context::codes),
+            byte,
+        )
+    }
+
+    fn write_eof<B: BitWrite>(&self, writer: &mut B, prefix: &[u8]) -> IoResult<Emitted> {
+        // without end symbols there is nothing to write, nor a tree to build.
+        if !self.options.eof {
+            return Ok(Emitted::default());
+        }
+        let context = self.context(prefix);
+        let codes = context.as_deref().map(Context::codes);
+        write_eof(
+            writer,
+            self.options.eof,
+            self.escape,
+            codes.is_some(),
+            codes
+                .and_then(|codes| codes.eof.as_ref())
+                .map(|code| code.as_bitslice()),
+        )
+    }
+}
+
+impl DecodeSymbol for LazyDecoder<'_> {
+    fn decode_symbol<R: BitRead>(&self, prefix: &[u8], reader: &mut R) -> IoResult<Option<u8>> {
+        let context = self.context(prefix);
+        decode_symbol(
+            context.as_ref().map(|context| &context.tree),
+            self.escape,
+            self.options.eof,
+            self.order0().map(|order0| &order0.tree),
+            reader,
+        )
+    }
+}
+
+// builds the tree of a context for a coder with the given escape mode, alphabet and end
+// symbol, with default options otherwise.
+fn rebuild_tree(
+    escape: EscapeMode,
+    alphabet: AlphabetMap,
+    eof: bool,
+    items: &[WeightedItem],
+) -> Option<HuffmanNode> {
+    // a context without bytes would only have the escape, which the tables cannot store.
+    if items.is_empty() {
+        return None;
+    }
+    let options = CodeOptions {
+        escape,
+        alphabet,
+        eof,
+        ..Default::default()
+    };
+    options
+        .tree(items, (escape == EscapeMode::Literal).then_some(1))
+        .expect("saturating weights do not overflow")
+}
+
+// windows of the input that cannot be encoded, as the offset of their last byte.
+fn misses<'a>(
+    depth: usize,
+    escape: EscapeMode,
+    data: &'a [u8],
+    encodes: impl Fn(&[u8]) -> bool + 'a,
+) -> impl Iterator<Item = (usize, &'a [u8])> + 'a {
+    // escapes can encode anything, there is nothing to miss.
+    let windows = match escape {
+        EscapeMode::None if depth > 0 => data.windows(depth),
+        _ => [].windows(1),
+    };
+    windows
+        .enumerate()
+        .filter(move |(_, window)| !encodes(window))
+        .map(move |(index, window)| (index + depth - 1, window))
+}
+
+// `has_code` tells whether a byte has a code after a context, `None` if the context is missing.
+fn coverage(depth: usize, data: &[u8], has_code: impl Fn(&[u8], u8) -> Option<bool>) -> Coverage {
+    let mut coverage = Coverage::default();
+    if depth == 0 {
+        return coverage;
+    }
+    for window in data.windows(depth) {
+        let (byte, prefix) = window.split_last().unwrap();
+        coverage.windows += 1;
+        match has_code(prefix, *byte) {
+            None => coverage.missing_context += 1,
+            Some(false) => coverage.missing_symbol += 1,
+            Some(true) => {}
+        }
+    }
+    coverage
+}
+
+fn validate(
+    depth: usize,
+    escape: EscapeMode,
+    data: &[u8],
+    encodes: impl Fn(&[u8]) -> bool,
+) -> Result<(), Vec<EncodeMiss>> {
+    let misses: Vec<EncodeMiss> = misses(depth, escape, data, encodes)
+        .take(MAX_REPORTED_MISSES)
+        .map(|(offset, window)| {
+            let (byte, context) = window.split_last().unwrap();
+            EncodeMiss {
+                offset,
+                context: context.into(),
+                byte: *byte,
+            }
+        })
+        .collect();
+
+    if misses.is_empty() {
+        Ok(())
+    } else {
+        Err(misses)
+    }
+}
+
+fn encode_all<H: EncodeSymbol>(encoder: H, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.is_empty() {
+        return Ok(vec![]);
+    }
+
+    if data.len() < encoder.depth() {
+        return Err(Error::InputTooShort {
+            len: data.len(),
+            depth: encoder.depth(),
+        });
+    }
+
+    let mut writer = HuffmanWriter::new(encoder, vec![]);
+    writer.write_all(data)?;
+    Ok(writer.finish()?.0)
+}
+
+fn decode_all<H: DecodeSymbol>(
+    decoder: H,
+    depth: usize,
+    context: &[u8],
+    data: &[u8],
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    if len == 0 {
+        return Ok(vec![]);
+    }
+
+    if context.len() + 1 != depth {
+        return Err(Error::Format("context length does not match model depth"));
+    }
+
+    // every symbol takes at least one bit unless its tree is a single leaf, so the declared
+    // length is only trusted as far as the input could plausibly back it.
+    let mut output = Vec::with_capacity(len.min(data.len().saturating_mul(8)));
+    Reader::new(decoder, data, context, len as u64).read_to_end(&mut output)?;
+    if output.len() < len {
+        return Err(Error::Format("end of stream before the declared length"));
+    }
+    Ok(output)
+}
+
+fn decode_until_eof<H: DecodeSymbol>(
+    decoder: H,
+    eof: bool,
+    depth: usize,
+    context: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    if !eof {
+        return Err(Error::Config(
+            "decoding without a length needs end symbols".into(),
+        ));
+    }
+    if data.is_empty() {
+        return Ok(vec![]);
+    }
+    if context.len() + 1 != depth {
+        return Err(Error::Format("context length does not match model depth"));
+    }
+
+    // every symbol takes at least a bit, as every tree has the end symbol next to the bytes.
+    let mut output = vec![];
+    Reader::new(decoder, data, context, u64::MAX).read_to_end(&mut output)?;
+    Ok(output)
+}
+
+// fields at the start of the huffman and range coder tables.
+pub(crate) struct TableHeader {
+    pub depth: usize,
+    pub escape: EscapeMode,
+    pub alphabet: AlphabetMap,
+    pub eof: bool,
+    pub order0: bool,
+    pub count: u64,
+}
+
+pub(crate) fn write_table_header<W: Write>(
+    writer: &mut W,
+    depth: usize,
+    escape: EscapeMode,
+    alphabet: &AlphabetMap,
+    eof: bool,
+    order0: bool,
+    count: usize,
+) -> Result<(), Error> {
+    let mut flags = match escape {
+        EscapeMode::None => 0,
+        EscapeMode::Literal => TABLES_FLAG_ESCAPE,
+    };
+    if eof {
+        flags |= TABLES_FLAG_EOF;
+    }
+    if order0 {
+        flags |= TABLES_FLAG_ORDER0;
+    }
+    // an empty alphabet has no symbol set, but there are no contexts to number symbols in.
+    let restricted = !alphabet.is_full() && !alphabet.is_empty();
+    if restricted {
+        flags |= TABLES_FLAG_ALPHABET;
+    }
+    write_varint(writer, depth as u64)?;
+    write_varint(writer, flags)?;
+    if restricted {
+        let symbols: Vec<u8> = alphabet.symbols().collect();
+        write_symbol_set(writer, &symbols, &AlphabetMap::full())?;
+    }
+    write_varint(writer, count as u64)?;
+    Ok(())
+}
+
+pub(crate) fn read_table_header<R: Read>(reader: &mut R) -> Result<TableHeader, Error> {
+    let depth: usize = read_varint(reader)?
+        .try_into()
+        .map_err(|_| Error::Format("depth too large"))?;
+    if depth == 0 {
+        return Err(Error::Format("invalid depth"));
+    }
+    if depth > MAX_SUPPORTED_DEPTH {
+        return Err(Error::Format("depth too large"));
+    }
+
+    let flags = read_varint(reader)?;
+    let unknown =
+        flags & !(TABLES_FLAG_ESCAPE | TABLES_FLAG_ALPHABET | TABLES_FLAG_EOF | TABLES_FLAG_ORDER0);
+    if unknown != 0 {
+        return Err(UnsupportedFeature::TableFlags(unknown).into());
+    }
+    let escape = if flags & TABLES_FLAG_ESCAPE != 0 {
+        EscapeMode::Literal
+    } else {
+        EscapeMode::None
+    };
+    let alphabet = if flags & TABLES_FLAG_ALPHABET != 0 {
+        AlphabetMap::new(read_symbol_set(reader, &AlphabetMap::full())?)
+    } else {
+        AlphabetMap::full()
+    };
+    Ok(TableHeader {
+        depth,
+        escape,
+        alphabet,
+        eof: flags & TABLES_FLAG_EOF != 0,
+        order0: flags & TABLES_FLAG_ORDER0 != 0,
+        count: read_varint(reader)?,
+    })
+}
+
+// front-codes the context against the previous one.
+pub(crate) fn write_context<W: Write>(
+    writer: &mut W,
+    previous: &[u8],
+    context: &[u8],
+) -> Result<(), Error> {
+    let shared = context
+        .iter()
+        .zip(previous)
+        .take_while(|(a, b)| a == b)
+        .count();
+    writer.write_all(&[shared as u8])?;
+    writer.write_all(&context[shared..])?;
+    Ok(())
+}
+
+// reads the next context in place of the previous one, which it must sort after.
+pub(crate) fn read_context<R: Read>(
+    reader: &mut R,
+    first: bool,
+    context: &mut [u8],
+) -> Result<(), Error> {
+    let mut shared = [0];
+    reader.read_exact(&mut shared)?;
+    let shared = usize::from(shared[0]);
+    if shared > context.len() || (first && shared != 0) {
+        return Err(Error::Format("invalid shared context length"));
+    }
+    let previous = context.to_vec();
+    reader.read_exact(&mut context[shared..])?;
+    if !first && *context <= previous[..] {
+        return Err(Error::Format("contexts out of order"));
+    }
+    Ok(())
+}
+
+// symbols are stored by their id in the alphabet. sparse sets are listed, dense ones stored as
+// a bitmap with a bit per symbol of the alphabet, once that is no larger than the list. sets
+// are never empty, their length is stored less one.
+pub(crate) fn write_symbol_set<W: Write>(
+    writer: &mut W,
+    symbols: &[u8],
+    alphabet: &AlphabetMap,
+) -> Result<(), Error> {
+    let ids = symbols
+        .iter()
+        .map(|byte| alphabet.id(*byte))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(Error::Format("symbol outside of the alphabet"))?;
+    let count = ids
+        .len()
+        .checked_sub(1)
+        .ok_or(Error::Format("empty symbol set"))?;
+    let bitmap_len = alphabet.len().div_ceil(8);
+    writer.write_all(&[count as u8])?;
+    if ids.len() < bitmap_len {
+        writer.write_all(&ids)?;
+    } else {
+        let mut bitmap = vec![0u8; bitmap_len];
+        for id in ids {
+            bitmap[usize::from(id / 8)] |= 1 << (id % 8);
+        }
+        writer.write_all(&bitmap)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_symbol_set<R: Read>(
+    reader: &mut R,
+    alphabet: &AlphabetMap,
+) -> Result<Vec<u8>, Error> {
+    let mut count = [0];
+    reader.read_exact(&mut count)?;
+    let count = usize::from(count[0]) + 1;
+    let bitmap_len = alphabet.len().div_ceil(8);
+    let ids: Vec<u8> = if count < bitmap_len {
+        let mut ids = vec![0; count];
+        reader.read_exact(&mut ids)?;
+        ids
+    } else {
+        let mut bitmap = vec![0u8; bitmap_len];
+        reader.read_exact(&mut bitmap)?;
+        (0..=u8::MAX)
+            .take(bitmap_len * 8)
+            .filter(|id| bitmap[usize::from(*id / 8)] & (1 << (id % 8)) != 0)
+            .collect()
+    };
+    if ids.len() != count || ids.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(Error::Format("invalid symbol set"));
+    }
+    let symbols: Vec<u8> = alphabet.symbols().collect();
+    ids.into_iter()
+        .map(|id| symbols.get(usize::from(id)).copied())
+        .collect::<Option<_>>()
+        .ok_or(Error::Format("symbol outside of the alphabet"))
+}
+
+fn write_tables<'a, W: Write>(
+    writer: &mut W,
+    depth: usize,
+    escape: EscapeMode,
+    alphabet: &AlphabetMap,
+    eof: bool,
+    order0: Option<&HuffmanNode>,
+    trees: impl ExactSizeIterator<Item = (&'a [u8], &'a HuffmanNode)>,
+) -> Result<(), Error> {
+    write_table_header(
+        writer,
+        depth,
+        escape,
+        alphabet,
+        eof,
+        order0.is_some(),
+        trees.len(),
+    )?;
+    if let Some(node) = order0 {
+        write_tree(writer, tree_codes(&[], node), alphabet, true, false)?;
+    }
+    let mut previous: &[u8] = &[];
+    for (context, node) in trees {
+        write_context(writer, previous, context)?;
+        previous = context;
+        let codes = tree_codes(context, node);
+        write_tree(writer, codes, alphabet, escape == EscapeMode::Literal, eof)?;
+    }
+
+    Ok(())
+}
+
+// writes the symbols of a tree and the lengths of their codes, the escape and end symbols are
+// implied by the flags.
+fn write_tree<'a, W: Write>(
+    writer: &mut W,
+    codes: impl Iterator<Item = CodeEntry<'a>>,
+    alphabet: &AlphabetMap,
+    escape: bool,
+    eof: bool,
+) -> Result<(), Error> {
+    let (mut symbols, mut lengths) = (vec![], vec![]);
+    let (mut has_escape, mut has_eof) = (false, false);
+    for entry in codes {
+        match entry.symbol {
+            Symbol::Byte(byte) => symbols.push(byte),
+            Symbol::Escape => has_escape = true,
+            Symbol::Eof => has_eof = true,
+        }
+        lengths.push(entry.length);
+    }
+    if escape != has_escape {
+        return Err(Error::Format("escape code does not match escape mode"));
+    }
+    if eof != has_eof {
+        return Err(Error::Format("end code does not match the table flags"));
+    }
+    write_symbol_set(writer, &symbols, alphabet)?;
+
+    let packed: Vec<u8> = lengths
+        .chunks(2)
+        .map(|pair| (pair[0] << 4) | pair.get(1).copied().unwrap_or(0))
+        .collect();
+    writer.write_all(&packed)?;
+    Ok(())
+}
+
+fn read_tree<R: Read>(
+    reader: &mut R,
+    alphabet: &AlphabetMap,
+    escape: bool,
+    eof: bool,
+) -> Result<HuffmanNode, Error> {
+    let symbols = read_symbol_set(reader, alphabet)?;
+    let mut symbols: Vec<Symbol> = symbols.into_iter().map(Symbol::Byte).collect();
+    if escape {
+        symbols.push(Symbol::Escape);
+    }
+    if eof {
+        symbols.push(Symbol::Eof);
+    }
+
+    let mut packed = vec![0; symbols.len().div_ceil(2)];
+    reader.read_exact(&mut packed)?;
+    let lengths: Vec<(Symbol, u8)> = symbols
+        .iter()
+        .enumerate()
+        .map(|(index, symbol)| {
+            let pair = packed[index / 2];
+            let length = if index % 2 == 0 {
+                pair >> 4
+            } else {
+                pair & 0xf
+            };
+            (*symbol, length)
+        })
+        .collect();
+
+    HuffmanNode::from_lengths(&lengths).ok_or(Error::Format("invalid code lengths"))
+}
+
+// order-0 tree of a model deeper than 1, from the weights of every byte after any context.
+// the escape is the rarest symbol, it is only needed for bytes that never follow a context.
+fn order0_tree(markov: &Markov, options: &CodeOptions) -> Result<Option<HuffmanNode>, Error> {
+    if markov.len() < 2 {
+        return Ok(None);
+    }
+    let mut weights = [0usize; 256];
+    for (sequence, weight) in markov.iter() {
+        let byte = usize::from(sequence[sequence.len() - 1]);
+        weights[byte] = weights[byte].saturating_add(weight);
+    }
+    let items: Vec<WeightedItem> = (0..=u8::MAX)
+        .filter(|byte| weights[usize::from(*byte)] > 0)
+        .map(|byte| WeightedItem::new(byte, weights[usize::from(byte)]))
+        .collect();
+    if items.is_empty() {
+        return Ok(None);
+    }
+    let options = CodeOptions {
+        eof: false,
+        ..*options
+    };
+    options.tree(&items, Some(1))
+}
+
+// source of the bits of codes and literals, both read MSB-first.
+trait BitSource {
+    fn bit(&mut self) -> IoResult<bool>;
+    fn literal(&mut self) -> IoResult<u8>;
+}
+
+impl<R: BitRead> BitSource for R {
+    fn bit(&mut self) -> IoResult<bool> {
+        self.read_bit()
+    }
+
+    fn literal(&mut self) -> IoResult<u8> {
+        self.read(8)
+    }
+}
+
+// bits taken from an iterator, running out of them is an unexpected end of input.
+struct BitIter<I>(I);
+
+impl<I: Iterator<Item = bool>> BitSource for BitIter<I> {
+    fn bit(&mut self) -> IoResult<bool> {
+        self.0.next().ok_or_else(|| ErrorKind::UnexpectedEof.into())
+    }
+
+    fn literal(&mut self) -> IoResult<u8> {
+        (0..8).try_fold(0, |byte, _| Ok((byte << 1) | u8::from(self.bit()?)))
+    }
+}
+
+// decodes a single symbol, shared by the `Read` adapter and `DecodeIter`. `None` is the end of
+// the stream.
+fn decode_symbol<B: BitSource>(
+    tree: Option<&HuffmanNode>,
+    escape: EscapeMode,
+    eof: bool,
+    order0: Option<&HuffmanNode>,
+    source: &mut B,
+) -> IoResult<Option<u8>> {
+    let mut node = match tree {
+        Some(node) => node,
+        // unknown contexts flag escaped bytes with a 0 bit and the end with a 1 bit.
+        None if escape == EscapeMode::Literal => {
+            if eof && source.bit()? {
+                return Ok(None);
+            }
+            return decode_backoff(order0, source).map(Some);
+        }
+        // without escapes nothing but the end can follow an unknown context.
+        None if eof => return Ok(None),
+        None => return Err(Error::Format("context missing from model").into()),
+    };
+    loop {
+        match node {
+            HuffmanNode::Leaf(byte) => return Ok(Some(*byte)),
+            HuffmanNode::Escape => return decode_backoff(order0, source).map(Some),
+            HuffmanNode::Eof => return Ok(None),
+            HuffmanNode::Node { left, right } => {
+                node = if source.bit()? { right } else { left };
+            }
+        }
+    }
+}
+
+// reads an escaped byte, with the order-0 tree if there is one and as a literal otherwise.
+fn decode_backoff<B: BitSource>(order0: Option<&HuffmanNode>, source: &mut B) -> IoResult<u8> {
+    let Some(mut node) = order0 else {
+        return source.literal();
+    };
+    loop {
+        match node {
+            HuffmanNode::Leaf(byte) => return Ok(*byte),
+            HuffmanNode::Escape => return source.literal(),
+            HuffmanNode::Eof => return Err(Error::Format("end symbol in order-0 table").into()),
+            HuffmanNode::Node { left, right } => {
+                node = if source.bit()? { right } else { left };
+            }
+        }
+    }
+}
+
+// moves the context window past a decoded byte.
+fn shift_context(context: &mut [u8], byte: u8) {
+    if let Some(first) = context.first_mut() {
+        *first = byte;
+        context.rotate_left(1);
+    }
+}
+
+fn write_code<B: BitWrite>(writer: &mut B, code: &BitSlice) -> IoResult<u8> {
+    for bit in code.iter() {
+        writer.write_bit(*bit)?;
+    }
+    Ok(code.len() as u8)
+}
+
+// writes the code of a byte, or the escape code and the byte itself if it has none.
+fn write_symbol<B: BitWrite>(
+    writer: &mut B,
+    code: Option<&BitSlice>,
+    escape: EscapeMode,
+    escape_code: Option<&BitSlice>,
+    eof: bool,
+    order0: Option<&Codes>,
+    byte: u8,
+) -> IoResult<Emitted> {
+    if let Some(code) = code {
+        return Ok(Emitted {
+            code_length: write_code(writer, code)?,
+            ..Default::default()
+        });
+    }
+
+    if escape == EscapeMode::None {
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            "symbol missing from model",
+        ));
+    }
+
+    // unknown contexts have no escape code, the decoder knows to read an escaped byte there.
+    // with end symbols, a 0 bit tells it apart from the end.
+    let code_length = match escape_code {
+        Some(code) => write_code(writer, code)?,
+        None if eof => write_code(writer, bits![0])?,
+        None => 0,
+    };
+    let backoff = write_backoff(writer, order0, byte)?;
+    Ok(Emitted {
+        code_length: code_length + backoff.code_length,
+        escape: true,
+        literal: backoff.literal,
+    })
+}
+
+// writes an escaped byte with the order-0 codes, or as a literal if it has no code there.
+fn write_backoff<B: BitWrite>(
+    writer: &mut B,
+    order0: Option<&Codes>,
+    byte: u8,
+) -> IoResult<Emitted> {
+    let mut code_length = 0;
+    if let Some(order0) = order0 {
+        if let Some(code) = order0.bytes.get(&byte) {
+            return Ok(Emitted {
+                code_length: write_code(writer, code)?,
+                ..Default::default()
+            });
+        }
+        let escape = order0
+            .escape
+            .as_ref()
+            .expect("order-0 tables have an escape");
+        code_length = write_code(writer, escape)?;
+    }
+    writer.write(8, byte)?;
+    Ok(Emitted {
+        code_length,
+        escape: false,
+        literal: true,
+    })
+}
+
+// writes the end of the stream, which is implied in unknown contexts without escapes.
+fn write_eof<B: BitWrite>(
+    writer: &mut B,
+    eof: bool,
+    escape: EscapeMode,
+    known: bool,
+    eof_code: Option<&BitSlice>,
+) -> IoResult<Emitted> {
+    let code_length = match eof_code {
+        _ if !eof => 0,
+        Some(code) => write_code(writer, code)?,
+        None if known => {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "end symbol missing from model",
+            ))
+        }
+        None if escape == EscapeMode::Literal => write_code(writer, bits![1])?,
+        None => 0,
+    };
+    Ok(Emitted {
+        code_length,
+        ..Default::default()
+    })
+}
+
+/// Writes the code of every symbol to the underlying writer.
+///
+/// Codes are emitted root-to-leaf, MSB-first within each output byte: the first bit of the
+/// first code is the most significant bit of the first byte. [`HuffmanWriter::finish`] writes
+/// the end symbol if the codes have one and pads the last byte with zero bits.
+///
+/// The output only depends on the bytes written, not on how they are split into writes or on
+/// flushes in between. It is complete once the writer is finished: flushing passes on the whole
+/// bytes written so far, the bits of the last one are held back until [`HuffmanWriter::finish`].
+pub struct HuffmanWriter<H: EncodeSymbol, W: Write, E: Endianness = BigEndian> {
+    buffer: Vec<u8>,
+    encoder: H,
+    writer: BitWriter<W, E>,
+    stats: WriterStats,
+    checkpoint_interval: Option<u64>,
+    checkpoints: Vec<Checkpoint>,
+    encoded: u64,
+    cancel: Option<CancellationToken>,
+}
+
+#[deprecated(note = "renamed to `HuffmanWriter`")]
+pub type Writer<H, W, E = BigEndian> = HuffmanWriter<H, W, E>;
+
+/// Position in the coded stream that decoding can start from, see
+/// [`Decoder::decode_from_checkpoint`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Checkpoint {
+    /// Offset of the next byte in the uncompressed data, including the initial context.
+    pub offset: u64,
+    /// Offset of its code in the output of the writer, in bits.
+    pub bit_offset: u64,
+    /// The `depth - 1` bytes preceding it.
+    pub context: Box<[u8]>,
+}
+
+/// Counters kept by a [`HuffmanWriter`] while encoding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriterStats {
+    /// Bytes written into the writer, including the initial context.
+    pub input_bytes: u64,
+    /// Bits of codes and literals written, without the padding of the last byte.
+    pub output_bits: u64,
+    /// Bytes written as literals.
+    pub escapes: u64,
+    /// Length of the longest code written.
+    pub max_code_len_seen: u8,
+}
+
+impl WriterStats {
+    fn record(&mut self, emitted: Emitted) {
+        self.output_bits += emitted.bits();
+        self.escapes += u64::from(emitted.escape);
+        self.max_code_len_seen = self.max_code_len_seen.max(emitted.code_length);
+    }
+}
+
+impl AddAssign for WriterStats {
+    fn add_assign(&mut self, other: Self) {
+        self.input_bytes += other.input_bytes;
+        self.output_bits += other.output_bits;
+        self.escapes += other.escapes;
+        self.max_code_len_seen = self.max_code_len_seen.max(other.max_code_len_seen);
+    }
+}
+
+impl<H: EncodeSymbol, W: Write> HuffmanWriter<H, W> {
+    pub fn new(encoder: H, writer: W) -> Self {
+        Self {
+            buffer: vec![],
+            encoder,
+            writer: BitWriter::new(writer),
+            stats: WriterStats::default(),
+            checkpoint_interval: None,
+            checkpoints: vec![],
+            encoded: 0,
+            cancel: None,
+        }
+    }
+}
+
+impl<H: EncodeSymbol, W: Write, E: Endianness> HuffmanWriter<H, W, E> {
+    pub fn stats(&self) -> WriterStats {
+        self.stats
+    }
+
+    /// Records a [`Checkpoint`] at every offset of the uncompressed data that is a multiple of
+    /// `interval`, returned by [`HuffmanWriter::finish_with_checkpoints`].
+    pub fn with_checkpoints(mut self, interval: u64) -> Self {
+        self.checkpoint_interval = Some(interval.max(1));
+        self
+    }
+
+    /// Checks `token` before every symbol, failing with [`Error::Cancelled`] once it is set.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    pub fn finish(self) -> IoResult<(W, WriterStats)> {
+        let (writer, stats, _) = self.finish_with_checkpoints()?;
+        Ok((writer, stats))
+    }
+
+    /// Finishes like [`HuffmanWriter::finish`], also returning the checkpoints in ascending order.
+    pub fn finish_with_checkpoints(mut self) -> IoResult<(W, WriterStats, Vec<Checkpoint>)> {
+        // the buffer holds the context of the next symbol once it is full.
+        if self.buffer.len() + 1 == self.encoder.depth() {
+            let emitted = self.encoder.write_eof(&mut self.writer, &self.buffer)?;
+            self.stats.record(emitted);
+        }
+        self.writer.byte_align()?;
+        Ok((self.writer.into_writer(), self.stats, self.checkpoints))
+    }
+}
+
+impl<H: EncodeSymbol, W: Write, E: Endianness> Write for HuffmanWriter<H, W, E> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let encoder = &self.encoder;
+        let stats = &mut self.stats;
+        let encoded = &mut self.encoded;
+        buffered_windows(encoder.depth(), &mut self.buffer, buf, |window| {
+            let prefix = &window[0..window.len() - 1];
+            let byte = window[window.len() - 1];
+            if let Some(token) = &self.cancel {
+                token.check()?;
+            }
+            let offset = prefix.len() as u64 + *encoded;
+            *encoded += 1;
+            if let Some(interval) = self.checkpoint_interval {
+                if offset.is_multiple_of(interval) {
+                    self.checkpoints.push(Checkpoint {
+                        offset,
+                        bit_offset: stats.output_bits,
+                        context: prefix.into(),
+                    });
+                }
+            }
+            stats.record(encoder.write_symbol(&mut self.writer, prefix, byte)?);
+            Ok::<_, IoError>(())
+        })?;
+        stats.input_bytes += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    // padding here would put zero bits in the middle of the stream, only finishing pads.
+    fn flush(&mut self) -> IoResult<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decoded bytes of a stream of codes, failing with [`Error::DecodeFailed`] at the position of
+/// the first code that does not decode.
+pub struct Reader<H: DecodeSymbol, R: Read, E: Endianness = BigEndian> {
+    context: Vec<u8>,
+    decoder: H,
+    reader: CountingReader<BitReader<R, E>>,
+    remaining: u64,
+    produced: u64,
+}
+
+/// State of a [`Reader`] between two reads, see [`Reader::checkpoint`]. Everything the reader
+/// has consumed is covered by the bit offset, so decoding can resume from it with
+/// [`Decoder::resume`], also in another process.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DecodeCheckpoint {
+    /// Offset of the next code in the stream, in bits.
+    pub bit_offset: u64,
+    /// Bytes decoded before it, not counting the initial context.
+    pub bytes_produced: u64,
+    /// Bytes left to decode.
+    pub remaining: u64,
+    /// The last `depth - 1` bytes decoded, or the initial context.
+    pub context: Box<[u8]>,
+}
+
+impl DecodeCheckpoint {
+    /// Writes the checkpoint as varints followed by the context.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        write_varint(writer, self.bit_offset)?;
+        write_varint(writer, self.bytes_produced)?;
+        write_varint(writer, self.remaining)?;
+        write_varint(writer, self.context.len() as u64)?;
+        writer.write_all(&self.context)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let bit_offset = read_varint(reader)?;
+        let bytes_produced = read_varint(reader)?;
+        let remaining = read_varint(reader)?;
+        let len = read_varint(reader)?;
+        let mut context = vec![];
+        reader.take(len).read_to_end(&mut context)?;
+        if context.len() as u64 != len {
+            return Err(Error::Truncated);
+        }
+        Ok(DecodeCheckpoint {
+            bit_offset,
+            bytes_produced,
+            remaining,
+            context: context.into(),
+        })
+    }
+}
+
+impl<H: DecodeSymbol, R: Read> Reader<H, R> {
+    pub fn new(decoder: H, reader: R, context: &[u8], len: u64) -> Self {
+        Self {
+            context: context.into(),
+            decoder,
+            reader: CountingReader::new(BitReader::new(reader), 0),
+            remaining: len,
+            produced: 0,
+        }
+    }
+}
+
+impl<H: DecodeSymbol, R: Read, E: Endianness> Reader<H, R, E> {
+    /// Position after the bytes read so far, to continue from with [`Decoder::resume`].
+    pub fn checkpoint(&self) -> DecodeCheckpoint {
+        DecodeCheckpoint {
+            bit_offset: self.reader.bits,
+            bytes_produced: self.produced,
+            remaining: self.remaining,
+            context: self.context.clone().into(),
+        }
+    }
+}
+
+impl<H: DecodeSymbol, R: Read, E: Endianness> Read for Reader<H, R, E> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let decoder = &self.decoder;
+        let count = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        for (index, slot) in buf[..count].iter_mut().enumerate() {
+            let start = self.reader.bits;
+            let byte = match decoder.decode_symbol(&self.context, &mut self.reader) {
+                Ok(Some(byte)) => byte,
+                Ok(None) => {
+                    self.remaining = 0;
+                    self.produced += index as u64;
+                    return Ok(index);
+                }
+                Err(error) => {
+                    return Err(Error::DecodeFailed {
+                        bit_offset: start,
+                        bytes_produced: self.produced + index as u64,
+                        context: self.context.clone(),
+                        source: Box::new(error.into()),
+                    }
+                    .into())
+                }
+            };
+            shift_context(&mut self.context, byte);
+            *slot = byte;
+        }
+        self.remaining -= count as u64;
+        self.produced += count as u64;
+        Ok(count)
+    }
+}
+
+/// Encodes many small independent buffers, such as the payloads of a server, without the
+/// allocations of a [`HuffmanWriter`] for every one of them. Every buffer is coded on its own like
+/// [`Encoder::encode_all`], into output the caller can reuse.
+#[derive(Clone, Debug)]
+pub struct EncodeSession<H: EncodeSymbol> {
+    encoder: H,
+    stats: WriterStats,
+}
+
+impl<H: EncodeSymbol> EncodeSession<H> {
+    pub fn new(encoder: H) -> Self {
+        EncodeSession {
+            encoder,
+            stats: WriterStats::default(),
+        }
+    }
+
+    /// Appends the same bytes [`Encoder::encode_all`] returns for `input` to `out`. On errors,
+    /// `out` is left as it was.
+    pub fn compress(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        let start = out.len();
+        let result = self.encode(input, out);
+        if result.is_err() {
+            out.truncate(start);
+        }
+        result
+    }
+
+    /// Statistics of the last buffer compressed.
+    pub fn stats(&self) -> WriterStats {
+        self.stats
+    }
+
+    fn encode(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        self.stats = WriterStats::default();
+        if input.is_empty() {
+            return Ok(());
+        }
+        let depth = self.encoder.depth();
+        if input.len() < depth {
+            return Err(Error::InputTooShort {
+                len: input.len(),
+                depth,
+            });
+        }
+
+        // the whole input is at hand, so the windows need no buffer of their own.
+        let mut writer = BitWriter::endian(out, BigEndian);
+        let mut stats = WriterStats {
+            input_bytes: input.len() as u64,
+            ..Default::default()
+        };
+        for window in input.windows(depth) {
+            let (byte, prefix) = window.split_last().unwrap();
+            stats.record(self.encoder.write_symbol(&mut writer, prefix, *byte)?);
+        }
+        stats.record(
+            self.encoder
+                .write_eof(&mut writer, &input[input.len() + 1 - depth..])?,
+        );
+        writer.byte_align()?;
+        self.stats = stats;
+        Ok(())
+    }
+}
+
+/// Decodes many small independent buffers like [`Decoder::decode_all`], reusing the context
+/// buffer of the session and output the caller can reuse, see [`EncodeSession`].
+#[derive(Clone, Debug)]
+pub struct DecodeSession<H: DecodeSymbol> {
+    decoder: H,
+    depth: usize,
+    context: Vec<u8>,
+}
+
+impl<H: DecodeSymbol> DecodeSession<H> {
+    /// Session of `decoder`, whose contexts are `depth - 1` bytes long.
+    pub fn new(decoder: H, depth: usize) -> Self {
+        DecodeSession {
+            decoder,
+            depth,
+            context: Vec::with_capacity(depth.saturating_sub(1)),
+        }
+    }
+
+    /// Appends the `len` bytes following `context` that `input` decodes to to `out`, the same
+    /// bytes [`Decoder::decode_all`] returns. On errors, `out` is left as it was.
+    pub fn decompress(
+        &mut self,
+        context: &[u8],
+        input: &[u8],
+        len: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let start = out.len();
+        let result = self.decode(context, input, len, out);
+        if result.is_err() {
+            out.truncate(start);
+        }
+        result
+    }
+
+    fn decode(
+        &mut self,
+        context: &[u8],
+        input: &[u8],
+        len: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        if len == 0 {
+            return Ok(());
+        }
+        if context.len() + 1 != self.depth {
+            return Err(Error::Format("context length does not match model depth"));
+        }
+
+        self.context.clear();
+        self.context.extend_from_slice(context);
+        // as in `decode_all`, the declared length is only trusted as far as the input goes.
+        out.reserve(len.min(input.len().saturating_mul(8)));
+        let bits = input.len() as u64 * 8;
+        decode_symbols(&self.decoder, &mut self.context, input, bits, len, out)?;
+        Ok(())
+    }
+}
+
+// decodes `len` bytes following `context` from the first `bits` bits of `input`, appending them
+// to `out`. returns the number of bits their codes take.
+fn decode_symbols<H: DecodeSymbol>(
+    decoder: &H,
+    context: &mut [u8],
+    input: &[u8],
+    bits: u64,
+    len: usize,
+    out: &mut Vec<u8>,
+) -> Result<u64, Error> {
+    let mut reader = CountingReader::new(BitReader::endian(input, BigEndian), 0);
+    for produced in 0..len {
+        let start = reader.bits;
+        let result = match decoder.decode_symbol(context, &mut reader) {
+            // the input may be padded after its last bit.
+            Ok(_) if reader.bits > bits => Err(Error::Truncated),
+            Ok(Some(byte)) => Ok(byte),
+            Ok(None) => return Err(Error::Format("end of stream before the declared length")),
+            Err(error) => Err(error.into()),
+        };
+        match result {
+            Ok(byte) => {
+                shift_context(context, byte);
+                out.push(byte);
+            }
+            Err(source) => {
+                return Err(Error::DecodeFailed {
+                    bit_offset: start,
+                    bytes_produced: produced as u64,
+                    context: context.to_vec(),
+                    source: Box::new(source),
+                })
+            }
+        }
+    }
+    Ok(reader.bits)
+}
+
+// bit reader that counts the bits read from it, for the positions of decode errors. aligning
+// to a byte counts the skipped bits.
+struct CountingReader<B> {
+    inner: B,
+    bits: u64,
+}
+
+impl<B: BitRead> CountingReader<B> {
+    fn new(inner: B, bits: u64) -> Self {
+        CountingReader { inner, bits }
+    }
+
+    fn count<T>(&mut self, bits: u32, result: IoResult<T>) -> IoResult<T> {
+        if result.is_ok() {
+            self.bits += u64::from(bits);
+        }
+        result
+    }
+}
+
+impl<B: BitRead> BitRead for CountingReader<B> {
+    fn read_bit(&mut self) -> IoResult<bool> {
+        let result = self.inner.read_bit();
+        self.count(1, result)
+    }
+
+    fn read<U: Numeric>(&mut self, bits: u32) -> IoResult<U> {
+        let result = self.inner.read(bits);
+        self.count(bits, result)
+    }
+
+    fn read_signed<S: SignedNumeric>(&mut self, bits: u32) -> IoResult<S> {
+        let result = self.inner.read_signed(bits);
+        self.count(bits, result)
+    }
+
+    fn read_to<V: Primitive>(&mut self) -> IoResult<V> {
+        let result = self.inner.read_to();
+        self.count(8 * std::mem::size_of::<V>() as u32, result)
+    }
+
+    fn read_as_to<F: Endianness, V: Primitive>(&mut self) -> IoResult<V> {
+        let result = self.inner.read_as_to::<F, V>();
+        self.count(8 * std::mem::size_of::<V>() as u32, result)
+    }
+
+    fn skip(&mut self, bits: u32) -> IoResult<()> {
+        let result = self.inner.skip(bits);
+        self.count(bits, result)
+    }
+
+    fn byte_aligned(&self) -> bool {
+        self.inner.byte_aligned()
+    }
+
+    fn byte_align(&mut self) {
+        self.inner.byte_align();
+        self.bits = self.bits.next_multiple_of(8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+    use test_strategy::proptest;
+
+    #[proptest]
+    fn test_node(#[filter(!#items.is_empty())] items: BTreeMap<u8, usize>) {
+        let node = HuffmanNode::new(
+            items
+                .iter()
+                .map(|(item, weight)| WeightedItem::new(Symbol::Byte(*item), *weight)),
+            MAX_CODE_LENGTH,
+            WeightPolicy::Saturate,
+        )
+        .unwrap()
+        .unwrap();
+        let encoder = node.encoding();
+
+        for byte in items.keys() {
+            prop_assert!(encoder.contains_key(&Symbol::Byte(*byte)));
+        }
+    }
+
+    fn bits(data: &[u8]) -> impl Iterator<Item = bool> + '_ {
+        data.iter()
+            .flat_map(|byte| (0..8).rev().map(move |bit| byte >> bit & 1 == 1))
+    }
+
+    #[proptest]
+    fn test_decode_iter(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(proptest::collection::vec(0u8..8, 0..256))] data: Vec<u8>,
+        escape: bool,
+        cut: usize,
+    ) {
+        prop_assume!(data.len() >= depth);
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data[..data.len() / 2]);
+        let options = CodeOptions {
+            escape: if escape {
+                EscapeMode::Literal
+            } else {
+                EscapeMode::None
+            },
+            ..Default::default()
+        };
+        let decoder = Decoder::with_options(&markov, &options);
+        let (context, rest) = data.split_at(depth - 1);
+        let Ok(encoded) = decoder.encoder().encode_all(&data) else {
+            return Ok(());
+        };
+
+        let decoded: Result<Vec<u8>, Error> = decoder
+            .decode_iter(bits(&encoded), context, rest.len() as u64)
+            .collect();
+        prop_assert_eq!(
+            decoded.unwrap(),
+            decoder.decode_all(context, &encoded, rest.len()).unwrap()
+        );
+
+        // the bits after the last byte are left alone.
+        let mut padded = bits(&encoded).chain([true; 3]);
+        let count = decoder
+            .decode_iter(padded.by_ref(), context, rest.len() as u64)
+            .count();
+        prop_assert_eq!(count, rest.len());
+        prop_assert!(padded.collect::<Vec<_>>().ends_with(&[true; 3]));
+
+        // running out of bits early is an error, and the end of the iterator.
+        let cut = cut % (encoded.len() * 8 + 1);
+        let mut iter = decoder.decode_iter(bits(&encoded).take(cut), context, rest.len() as u64);
+        let decoded: Vec<Result<u8, Error>> = iter.by_ref().collect();
+        prop_assert!(iter.next().is_none());
+        let (last, init) = decoded.split_last().unwrap();
+        prop_assert!(init
+            .iter()
+            .zip(rest)
+            .all(|(byte, expected)| byte.as_ref().ok() == Some(expected)));
+        match last {
+            Ok(_) => prop_assert_eq!(decoded.len(), rest.len()),
+            Err(error) => prop_assert!(matches!(error, Error::Truncated)),
+        }
+    }
+
+    #[proptest]
+    fn test_decode_one(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(proptest::collection::vec(0u8..8, 0..256))] data: Vec<u8>,
+    ) {
+        prop_assume!(data.len() >= depth);
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data[..data.len() / 2]);
+        // escaped bytes are decoded by the same step.
+        let options = CodeOptions {
+            escape: EscapeMode::Literal,
+            ..Default::default()
+        };
+        let decoder = Decoder::with_options(&markov, &options);
+        let encoded = decoder.encoder().encode_all(&data).unwrap();
+        let (context, rest) = data.split_at(depth - 1);
+        let bits = encoded.view_bits::<Msb0>();
+        let (_, total) = decoder.decode_from(bits, context, rest.len()).unwrap();
+
+        // one byte at a time, each from the bits after the code of the one before.
+        let mut context = context.to_vec();
+        let mut offset = 0;
+        for expected in rest {
+            let (byte, consumed) = decoder.decode_one_bits(&context, &bits[offset..])?;
+            prop_assert_eq!(byte, *expected);
+            offset += consumed;
+            shift_context(&mut context, byte);
+        }
+        prop_assert_eq!(offset, total);
+    }
+
+    #[test]
+    fn test_decode_one_errors() {
+        let mut markov = Markov::new(2);
+        markov.writer().write(b"abacad");
+        let options = CodeOptions {
+            escape: EscapeMode::None,
+            ..Default::default()
+        };
+        let decoder = Decoder::with_options(&markov, &options);
+        let encoder = decoder.encoder();
+        let code = |window: &[u8]| encoder.encode(&window[..1], window[1]).unwrap().to_bitvec();
+
+        // the bits end in the middle of the tree of "a", which has three successors.
+        let longest = [b"ab", b"ac", b"ad"]
+            .into_iter()
+            .map(|window| code(window))
+            .max_by_key(|code| code.len())
+            .unwrap();
+        assert_eq!(longest.len(), 2);
+        assert_eq!(
+            decoder.decode_one(b"a", &mut longest[..1].iter().by_vals()),
+            Err(SymbolError::OutOfBits { consumed: 1 })
+        );
+        assert_eq!(
+            decoder.decode_one(b"a", &mut std::iter::empty()),
+            Err(SymbolError::OutOfBits { consumed: 0 })
+        );
+
+        assert_eq!(
+            decoder.decode_one(b"x", &mut std::iter::repeat(false)),
+            Err(SymbolError::UnknownContext {
+                context: b"x"[..].into()
+            })
+        );
+        assert_eq!(
+            decoder.decode_one(b"ab", &mut std::iter::repeat(false)),
+            Err(SymbolError::ContextLength { len: 2, depth: 2 })
+        );
+
+        // with end symbols, an unknown context only ends the stream.
+        let options = CodeOptions {
+            escape: EscapeMode::None,
+            eof: true,
+            ..Default::default()
+        };
+        let decoder = Decoder::with_options(&markov, &options);
+        assert_eq!(
+            decoder.decode_one(b"x", &mut std::iter::empty()),
+            Err(SymbolError::EndOfStream)
+        );
+    }
+
+    #[test]
+    fn test_decode_one_single_leaf() {
+        // "b" is only ever followed by "a", whose code takes no bits.
+        let mut markov = Markov::new(2);
+        markov.writer().write(b"abab");
+        let decoder = Decoder::new(&markov);
+        assert_eq!(
+            decoder.decode_one_bits(b"b", BitSlice::<u8, Msb0>::empty()),
+            Ok((b'a', 0))
+        );
+        let mut bits = [true, false].into_iter();
+        assert_eq!(decoder.decode_one(b"b", &mut bits), Ok(b'a'));
+        assert_eq!(bits.len(), 2);
+    }
+
+    #[test]
+    fn test_repeated_byte() {
+        for depth in 1..=4 {
+            let data = vec![7; 1000];
+            let mut markov = Markov::new(depth);
+            markov.writer().write(&data);
+            let (context, rest) = data.split_at(depth - 1);
+
+            // a single successor has an empty code, so only the length says where the data ends.
+            let coder = Coder::new(&markov);
+            assert_eq!(coder.decoder().trees[context], HuffmanNode::Leaf(7));
+            let encoded = coder.encode_all(&data).unwrap();
+            assert!(encoded.is_empty());
+            assert_eq!(
+                coder.decode_all(context, &encoded, rest.len()).unwrap(),
+                rest
+            );
+            assert!(coder.decode_all(context, &encoded, 0).unwrap().is_empty());
+
+            // with an end symbol, each byte and the end take a bit.
+            let options = CodeOptions {
+                eof: true,
+                ..Default::default()
+            };
+            let coder = Coder::with_options(&markov, &options);
+            let encoded = coder.encode_all(&data).unwrap();
+            assert_eq!(encoded.len(), (rest.len() + 1).div_ceil(8));
+            assert_eq!(coder.decode_until_eof(context, &encoded).unwrap(), rest);
+        }
+    }
+
+    #[test]
+    fn test_eof_zero_code() {
+        // the last byte has the all-zero code, which the padding would repeat without an end.
+        let data = b"abaaacaaaa";
+        let mut markov = Markov::new(1);
+        markov.writer().write(data);
+        let options = CodeOptions {
+            eof: true,
+            ..Default::default()
+        };
+        let coder = Coder::with_options(&markov, &options);
+        let encoder = coder.encoder();
+        assert!(encoder
+            .encode_symbol(&[], Symbol::Byte(b'a'))
+            .unwrap()
+            .not_any());
+        assert!(encoder.encode_symbol(&[], Symbol::Eof).is_some());
+
+        let encoded = coder.encode_all(data).unwrap();
+        assert_eq!(coder.decode_until_eof(&[], &encoded).unwrap(), data);
+        assert_eq!(
+            coder.decoder().decode_until_eof(&[], &encoded).unwrap(),
+            data
+        );
+        assert_eq!(coder.decode_all(&[], &encoded, data.len()).unwrap(), data);
+        assert!(matches!(
+            coder.decode_all(&[], &encoded, data.len() + 1),
+            Err(Error::Format(_))
+        ));
+
+        let plain = Coder::new(&markov);
+        assert!(matches!(
+            plain.decode_until_eof(&[], &plain.encode_all(data).unwrap()),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[proptest]
+    fn test_str_roundtrip(training: String, text: String, #[strategy(1usize..5)] depth: usize) {
+        let mut markov = Markov::new(depth);
+        markov.train_str(&training);
+        let options = CodeOptions {
+            escape: EscapeMode::Literal,
+            ..Default::default()
+        };
+        let decoder = Decoder::with_options(&markov, &options);
+        let encoder = decoder.encoder();
+
+        match encoder.encode_str(&text) {
+            Ok(bits) => {
+                let context = &text.as_bytes()[..(depth - 1).min(text.len())];
+                let len = text.len() - context.len();
+                if !text.is_empty() {
+                    prop_assert_eq!(
+                        &bits.clone().into_vec(),
+                        &encoder.encode_all(text.as_bytes()).unwrap()
+                    );
+                }
+                let decoded = decoder.decode_to_string(context, &bits.into_vec(), len);
+                prop_assert_eq!(decoded.unwrap(), text);
+            }
+            Err(error) => {
+                prop_assert!(text.len() < depth);
+                prop_assert_eq!(
+                    error,
+                    EncodeError::WindowLength {
+                        len: text.len(),
+                        depth
+                    }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_str() {
+        let mut markov = Markov::new(2);
+        markov.train_str("");
+        assert_eq!(markov, Markov::new(2));
+        markov.train_str("héllo");
+        let decoder = markov.decoder();
+        let encoder = decoder.encoder();
+        assert!(encoder.encode_str("").unwrap().is_empty());
+        assert_eq!(decoder.decode_to_string(b"h", &[], 0).unwrap(), "h");
+        assert_eq!(
+            encoder.encode_str("hx"),
+            Err(EncodeError::MissingByte {
+                context: b"h"[..].into(),
+                byte: b'x'
+            })
+        );
+
+        let data = encoder.encode_all("héllo".as_bytes()).unwrap();
+        assert_eq!(decoder.decode_to_string(b"h", &data, 5).unwrap(), "héllo");
+
+        // the context is the first byte of the é, which the decoded bytes complete.
+        let data = encoder.encode_all(&"héllo".as_bytes()[1..]).unwrap();
+        assert_eq!(decoder.decode_to_string(&[0xc3], &data, 4).unwrap(), "éllo");
+
+        // without its first byte, the rest of the é is not valid UTF-8.
+        let data = encoder.encode_all(&"héllo".as_bytes()[2..]).unwrap();
+        assert!(matches!(
+            decoder.decode_to_string(&[0xa9], &data, 3),
+            Err(DecodeError::Utf8(_))
+        ));
+        assert_eq!(
+            decoder.decode_to_string_lossy(&[0xa9], &data, 3).unwrap(),
+            "\u{fffd}llo"
+        );
+        assert!(matches!(
+            decoder.decode_to_string(b"", &data, 3),
+            Err(DecodeError::Decode(Error::Format(_)))
+        ));
+    }
+
+    #[proptest]
+    fn test_weighted_item_order(a: (u8, usize), b: (u8, usize)) {
+        let (x, y) = (WeightedItem::from(a), WeightedItem::from(b));
+        prop_assert_eq!(x.cmp(&y), (a.1, a.0).cmp(&(b.1, b.0)));
+    }
+
+    #[test]
+    fn test_normalize() {
+        let items: Vec<WeightedItem> = vec![(b'a', 1).into(), (b'b', 3).into()];
+        assert_eq!(
+            WeightedItem::normalize(&items),
+            [(b'a', 0.25), (b'b', 0.75)]
+        );
+        assert_eq!(
+            WeightedItem::normalize(&[WeightedItem::new(b'a', 0)]),
+            [(b'a', 0.0)]
+        );
+        assert!(WeightedItem::<u8>::normalize(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_encode_window() {
+        let mut markov = Markov::new(3);
+        markov.writer().write(b"abracadabra");
+        let encoder = markov.encoder();
+
+        let code = encoder.encode_window(b"abr").unwrap();
+        assert_eq!(Some(code), encoder.encode(b"ab", b'r'));
+        assert_eq!(encoder.code_len(b"abr"), Some(code.len() as u8));
+
+        assert_eq!(
+            encoder.encode_window(b"abc"),
+            Err(EncodeError::MissingByte {
+                context: b"ab"[..].into(),
+                byte: b'c'
+            })
+        );
+        assert_eq!(
+            encoder.encode_window(b"xyz"),
+            Err(EncodeError::MissingContext {
+                context: b"xy"[..].into()
+            })
+        );
+        assert_eq!(
+            encoder.encode_window(b"ab"),
+            Err(EncodeError::WindowLength { len: 2, depth: 3 })
+        );
+        for window in [&b"abc"[..], b"xyz", b"ab", b""] {
+            assert_eq!(encoder.code_len(window), None);
+        }
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let data = include_bytes!("huffman.rs");
+        let mut markov = Markov::new(3);
+        markov.writer().write(data);
+        let token = CancellationToken::new();
+        let decoder = Decoder::with_cancellation(&markov, &token).unwrap();
+        assert_eq!(decoder, Decoder::new(&markov));
+
+        let coder = Coder::from(decoder);
+        let mut writer = coder.writer(vec![]).with_cancellation(token.clone());
+        writer.write_all(&data[..100]).unwrap();
+        token.cancel();
+        let error = writer.write_all(&data[100..200]).unwrap_err();
+        assert!(matches!(Error::from(error), Error::Cancelled));
+        assert!(matches!(
+            Decoder::with_cancellation(&markov, &token),
+            Err(Error::Cancelled)
+        ));
+    }
+
+    #[proptest]
+    fn test_eof_roundtrip(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(proptest::collection::vec(0u8..8, 0..256))] data: Vec<u8>,
+        escape: bool,
+    ) {
+        prop_assume!(data.len() >= depth);
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data[..data.len() / 2]);
+        let options = CodeOptions {
+            escape: if escape {
+                EscapeMode::Literal
+            } else {
+                EscapeMode::None
+            },
+            eof: true,
+            ..Default::default()
+        };
+        let coder = Coder::with_options(&markov, &options);
+        let Ok(encoded) = coder.encode_all(&data) else {
+            return Ok(());
+        };
+        prop_assert_eq!(&encoded, &coder.encoder().encode_all(&data).unwrap());
+
+        let (context, rest) = data.split_at(depth - 1);
+        prop_assert_eq!(&coder.decode_until_eof(context, &encoded).unwrap(), rest);
+        let decoder = coder.decoder();
+        let decoded: Result<Vec<u8>, Error> = decoder
+            .decode_iter(bits(&encoded), context, u64::MAX)
+            .collect();
+        prop_assert_eq!(&decoded.unwrap(), rest);
+
+        let mut tables = vec![];
+        coder.write_tables(&mut tables).unwrap();
+        let read = Coder::read_tables(&mut &tables[..]).unwrap();
+        prop_assert!(read.eof());
+        prop_assert_eq!(&read, &coder);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let data = include_bytes!("huffman.rs");
+        let mut markov = Markov::new(3);
+        markov.writer().write(data);
+        let decoder = markov.decoder();
+        let encoder = decoder.encoder();
+        let mut writer = encoder.writer(vec![]).with_checkpoints(1000);
+        writer.write_all(data).unwrap();
+        let (encoded, _, checkpoints) = writer.finish_with_checkpoints().unwrap();
+        assert_eq!(checkpoints.len(), (data.len() - 1) / 1000);
+        assert_eq!(checkpoints[0].offset, 1000);
+
+        for checkpoint in &checkpoints {
+            let offset = checkpoint.offset as usize;
+            assert_eq!(&checkpoint.context[..], &data[offset - 2..offset]);
+            let len = (data.len() - offset).min(2500);
+            let decoded = decoder
+                .decode_from_checkpoint(std::io::Cursor::new(&encoded), checkpoint, len)
+                .unwrap();
+            assert_eq!(decoded, &data[offset..offset + len]);
+        }
+    }
+
+    #[proptest]
+    fn test_resume(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(proptest::collection::vec(0u8..16, 0..512))] data: Vec<u8>,
+        escape: bool,
+        eof: bool,
+        cut: usize,
+    ) {
+        prop_assume!(data.len() >= depth);
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data[..data.len() / 2]);
+        let options = CodeOptions {
+            escape: if escape {
+                EscapeMode::Literal
+            } else {
+                EscapeMode::None
+            },
+            eof,
+            ..Default::default()
+        };
+        let decoder = Decoder::with_options(&markov, &options);
+        prop_assume!(escape || decoder.encoder().validate(&data).is_ok());
+        let encoded = decoder.encoder().encode_all(&data).unwrap();
+        let mut tables = vec![];
+        decoder.write_tables(&mut tables).unwrap();
+
+        let context = &data[..depth - 1];
+        let len = (data.len() - context.len()) as u64;
+        let mut reader = decoder.reader(&encoded[..], context, len);
+        let mut decoded = vec![0; cut % (len as usize + 1)];
+        reader.read_exact(&mut decoded).unwrap();
+        let mut saved = vec![];
+        reader.checkpoint().write(&mut saved).unwrap();
+        drop(reader);
+
+        // only the tables, the checkpoint and the stream are needed to continue.
+        let decoder = Decoder::read_tables(&mut &tables[..]).unwrap();
+        let checkpoint = DecodeCheckpoint::read(&mut &saved[..]).unwrap();
+        prop_assert_eq!(checkpoint.bytes_produced, decoded.len() as u64);
+        let mut reader = decoder
+            .resume(std::io::Cursor::new(&encoded), &checkpoint)
+            .unwrap();
+        reader.read_to_end(&mut decoded).unwrap();
+        prop_assert_eq!(&decoded[..], &data[depth - 1..]);
+        prop_assert_eq!(reader.checkpoint().remaining, 0);
+    }
+
+    #[proptest]
+    fn test_lazy_decoder(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(proptest::collection::vec(0u8..16, 0..512))] data: Vec<u8>,
+        escape: bool,
+        eof: bool,
+    ) {
+        prop_assume!(data.len() >= depth);
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data[..data.len() / 2]);
+        let options = CodeOptions {
+            escape: if escape {
+                EscapeMode::Literal
+            } else {
+                EscapeMode::None
+            },
+            eof,
+            ..Default::default()
+        };
+        let coder = Coder::with_options(&markov, &options);
+        let lazy = LazyDecoder::new(&markov, &options);
+
+        let encoded = coder.encode_all(&data);
+        prop_assert_eq!(
+            lazy.encode_all(&data).map_err(|error| error.to_string()),
+            encoded.as_ref().map_err(|error| error.to_string()).cloned()
+        );
+        if let Ok(encoded) = encoded {
+            let context = &data[..depth - 1];
+            let len = data.len() - context.len();
+            let decoded = LazyDecoder::new(&markov, &options).decode_all(context, &encoded, len);
+            prop_assert_eq!(&decoded.unwrap()[..], &data[depth - 1..]);
+        }
+    }
+
+    #[test]
+    fn test_lazy_decoder_built() {
+        // a small part of the data only needs the trees of the contexts it has.
+        let data = include_bytes!("huffman.rs");
+        let markov = crate::Builder::new().depth(3).train(data).unwrap();
+        let lazy = Decoder::lazy(&markov);
+        assert_eq!(lazy.built(), 0);
+
+        let part = &data[..200];
+        let encoded = lazy.encode_all(part).unwrap();
+        assert_eq!(encoded, Coder::new(&markov).encode_all(part).unwrap());
+        let contexts: std::collections::HashSet<_> =
+            part.windows(3).map(|window| &window[..2]).collect();
+        assert_eq!(lazy.built(), contexts.len());
+        assert!(lazy.built() * 10 < markov.context_stats().contexts);
+
+        // other threads share what was built.
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut reader = lazy.reader(&encoded[..], &part[..2], 198);
+                    let mut decoded = vec![];
+                    reader.read_to_end(&mut decoded).unwrap();
+                    assert_eq!(decoded, &part[2..]);
+                });
+            }
+        });
+        assert_eq!(lazy.built(), contexts.len());
+    }
+
+    #[proptest]
+    fn test_roundtrip(#[strategy(1usize..5)] depth: usize, data: Vec<u8>) {
+        prop_assume!(data.len() >= depth);
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data);
+        let decoder = markov.decoder();
+        let encoded = decoder.encoder().encode_all(&data).unwrap();
+        let context = &data[..depth - 1];
+        let decoded = decoder
+            .decode_all(context, &encoded, data.len() - context.len())
+            .unwrap();
+        prop_assert_eq!(&decoded[..], &data[depth - 1..]);
+    }
+
+    fn tables_roundtrip(decoder: &Decoder) -> Vec<u8> {
+        let mut tables = vec![];
+        decoder.write_tables(&mut tables).unwrap();
+        let mut reader = &tables[..];
+        assert_eq!(&Decoder::read_tables(&mut reader).unwrap(), decoder);
+        assert!(reader.is_empty());
+        tables
+    }
+
+    #[proptest]
+    fn test_tables_roundtrip(#[strategy(1usize..6)] depth: usize, data: Vec<u8>) {
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data);
+        tables_roundtrip(&markov.decoder());
+    }
+
+    #[proptest]
+    fn test_tables_deterministic(#[strategy(1usize..5)] depth: usize, data: Vec<u8>) {
+        let mut forward = Markov::new(depth);
+        let mut backward = Markov::new(depth);
+        let windows: Vec<&[u8]> = data.windows(depth).collect();
+        for window in &windows {
+            forward.insert(window, 1).unwrap();
+        }
+        for window in windows.iter().rev() {
+            backward.insert(window, 1).unwrap();
+        }
+
+        let tables = tables_roundtrip(&forward.decoder());
+        prop_assert_eq!(&tables, &tables_roundtrip(&forward.decoder()));
+        prop_assert_eq!(&tables, &tables_roundtrip(&backward.decoder()));
+    }
+
+    #[test]
+    fn test_content_hash_vectors() {
+        let mut markov = Markov::new(3);
+        markov.writer().write(b"abracadabra");
+        assert_eq!(markov.decoder().content_hash(), 0x3073ae57e8d9787e);
+        assert_eq!(
+            Coder::from(markov.decoder()).content_hash(),
+            0x3073ae57e8d9787e
+        );
+        assert_eq!(Markov::new(3).decoder().content_hash(), 0x4d922029c1f42e7d);
+
+        // without an order-0 tree, the hash is the one of the tables before there was one.
+        let mut decoder = markov.decoder();
+        decoder.order0 = None;
+        assert_eq!(decoder.content_hash(), 0x3ad5c41d31f58c28);
+        assert_eq!(Coder::from(decoder).content_hash(), 0x3ad5c41d31f58c28);
+    }
+
+    #[test]
+    fn test_tables_shared_prefixes() {
+        let mut markov = Markov::new(8);
+        for suffix in 0..=u8::MAX {
+            markov.insert(&[7, 7, 7, 7, 7, 7, suffix, 1], 3).unwrap();
+            markov.insert(&[7, 7, 7, 7, 7, 7, suffix, 2], 1).unwrap();
+        }
+        let tables = tables_roundtrip(&markov.decoder());
+
+        // every context after the first only stores its last byte.
+        assert!(tables.len() < 256 * (2 + 3 + 1) + 16);
+    }
+
+    #[test]
+    fn test_tables_single_symbol() {
+        let mut markov = Markov::new(3);
+        markov.insert(b"abc", 5).unwrap();
+        markov.insert(b"bcd", 1).unwrap();
+        let decoder = markov.decoder();
+        assert_eq!(decoder.trees[&b"ab"[..]], HuffmanNode::Leaf(b'c'));
+        tables_roundtrip(&decoder);
+    }
+
+    #[test]
+    fn test_tables_dense() {
+        let mut markov = Markov::new(2);
+        for byte in 0..=u8::MAX {
+            markov.insert(&[0, byte], usize::from(byte) + 1).unwrap();
+            markov.insert(&[1, byte / 8], 1).unwrap();
+        }
+        tables_roundtrip(&markov.decoder());
+    }
+
+    #[test]
+    fn test_tables_max_length() {
+        let mut markov = Markov::new(2);
+        markov.insert(&[0, 0], 1).unwrap();
+        for byte in 1..=15 {
+            markov.insert(&[0, byte], 1 << (byte - 1)).unwrap();
+        }
+        let decoder = markov.decoder();
+        let lengths = decoder.trees[&[0u8][..]].lengths();
+        assert_eq!(
+            lengths.iter().map(|(_, length)| *length).max(),
+            Some(MAX_CODE_LENGTH)
+        );
+        tables_roundtrip(&decoder);
+    }
+
+    #[test]
+    fn test_weight_policy() {
+        // the two heavy items overflow once they are combined.
+        let heavy = usize::MAX / 2 + 1;
+        let items = [(b'a', heavy), (b'b', heavy), (b'c', 1)]
+            .map(|(byte, weight)| WeightedItem::new(Symbol::Byte(byte), weight));
+        let build = |policy| HuffmanNode::new(items.iter().copied(), MAX_CODE_LENGTH, policy);
+
+        let saturated = build(WeightPolicy::Saturate).unwrap().unwrap();
+        assert!(matches!(
+            build(WeightPolicy::Error),
+            Err(Error::WeightOverflow)
+        ));
+        // scaling keeps the ratios of the weights, so the codes are those of halved weights.
+        let halved = items.map(|item| WeightedItem::new(item.item, item.weight.div_ceil(2)));
+        let scaled = build(WeightPolicy::Scale).unwrap().unwrap();
+        assert_eq!(
+            Some(scaled.clone()),
+            HuffmanNode::new(halved.into_iter(), MAX_CODE_LENGTH, WeightPolicy::Error).unwrap()
+        );
+        assert_eq!(scaled.lengths().len(), 3);
+        assert_eq!(saturated.lengths().len(), 3);
+    }
+
+    #[test]
+    fn test_length_limit() {
+        let mut fibonacci = vec![1usize, 1];
+        while fibonacci.len() < 40 {
+            fibonacci.push(fibonacci[fibonacci.len() - 1] + fibonacci[fibonacci.len() - 2]);
+        }
+        let items = fibonacci
+            .iter()
+            .enumerate()
+            .map(|(item, weight)| WeightedItem::new(Symbol::Byte(item as u8), *weight));
+        let node = HuffmanNode::new(items, MAX_CODE_LENGTH, WeightPolicy::Saturate)
+            .unwrap()
+            .unwrap();
+        let lengths = node.lengths();
+        assert_eq!(lengths.len(), 40);
+        assert!(lengths.iter().all(|(_, length)| *length <= MAX_CODE_LENGTH));
+        assert_eq!(HuffmanNode::from_lengths(&lengths), Some(node));
+    }
+
+    #[test]
+    fn test_from_lengths_invalid() {
+        // incomplete, oversubscribed, duplicate and zero-length codes.
+        let lengths = |lengths: &[(u8, u8)]| -> Vec<(Symbol, u8)> {
+            lengths
+                .iter()
+                .map(|(byte, length)| (Symbol::Byte(*byte), *length))
+                .collect()
+        };
+        assert_eq!(HuffmanNode::from_lengths(&lengths(&[(0, 1)])), None);
+        assert_eq!(HuffmanNode::from_lengths(&lengths(&[(0, 1), (1, 2)])), None);
+        assert_eq!(
+            HuffmanNode::from_lengths(&lengths(&[(0, 1), (1, 1), (2, 1)])),
+            None
+        );
+        assert_eq!(HuffmanNode::from_lengths(&lengths(&[(0, 1), (0, 1)])), None);
+        assert_eq!(HuffmanNode::from_lengths(&lengths(&[(0, 0), (1, 1)])), None);
+        assert_eq!(
+            HuffmanNode::from_lengths(&[(Symbol::Escape, 1), (Symbol::Escape, 1)]),
+            None
+        );
+        assert_eq!(HuffmanNode::from_lengths(&[]), None);
+    }
+
+    #[proptest]
+    fn test_escape_roundtrip(
+        #[strategy(1usize..5)] depth: usize,
+        training: Vec<u8>,
+        data: Vec<u8>,
+    ) {
+        prop_assume!(data.len() >= depth);
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&training);
+        let options = CodeOptions {
+            escape: EscapeMode::Literal,
+            ..Default::default()
+        };
+        let decoder = Decoder::with_options(&markov, &options);
+        let decoder = Decoder::read_tables(&mut &tables_roundtrip(&decoder)[..]).unwrap();
+
+        let encoded = decoder.encoder().encode_all(&data).unwrap();
+        let context = &data[..depth - 1];
+        let decoded = decoder
+            .decode_all(context, &encoded, data.len() - context.len())
+            .unwrap();
+        prop_assert_eq!(&decoded[..], &data[depth - 1..]);
+    }
+
+    #[test]
+    fn test_order0() {
+        let mut markov = Markov::new(3);
+        markov.writer().write(b"aabaab");
+        let decoder = markov.decoder();
+        let order0 = decoder.order0().unwrap();
+        let symbols: Vec<Symbol> = order0.encoding().into_keys().collect();
+        assert_eq!(
+            symbols,
+            [Symbol::Byte(b'a'), Symbol::Byte(b'b'), Symbol::Escape]
+        );
+        assert_eq!(Coder::from(decoder.clone()).order0(), Some(order0));
+        let decoder = Decoder::read_tables(&mut &tables_roundtrip(&decoder)[..]).unwrap();
+        assert_eq!(decoder.order0(), Some(order0));
+
+        // a model of depth 1 has nothing but the order-0 tree.
+        let mut markov = Markov::new(1);
+        markov.writer().write(b"aab");
+        let decoder = markov.decoder();
+        assert_eq!(decoder.order0(), decoder.trees.get(&[][..]));
+        assert!(decoder.order0.is_none());
+        assert!(Markov::new(3).decoder().order0().is_none());
+    }
+
+    #[proptest]
+    fn test_order0_backoff(
+        #[strategy(2usize..5)] depth: usize,
+        #[strategy(proptest::collection::vec(0u8..4, 1..64))] training: Vec<u8>,
+        #[strategy(proptest::collection::vec(0u8..8, #depth..64))] data: Vec<u8>,
+    ) {
+        // bytes of the training data that are missing from a context back off to the order-0
+        // tree, the others to literals.
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&training);
+        let options = CodeOptions {
+            escape: EscapeMode::Literal,
+            ..Default::default()
+        };
+        let coder = Coder::from(Decoder::with_options(&markov, &options));
+        let mut tables = vec![];
+        coder.write_tables(&mut tables).unwrap();
+        let coder = Coder::read_tables(&mut &tables[..]).unwrap();
+
+        let encoded = coder.encode_all(&data).unwrap();
+        let context = &data[..depth - 1];
+        let decoded = coder
+            .decode_all(context, &encoded, data.len() - context.len())
+            .unwrap();
+        prop_assert_eq!(&decoded[..], &data[depth - 1..]);
+        prop_assert_eq!(
+            &coder
+                .decoder()
+                .decode_all(context, &encoded, decoded.len())
+                .unwrap(),
+            &decoded
+        );
+    }
+
+    #[test]
+    fn test_order0_shorter_than_literals() {
+        let mut markov = Markov::new(2);
+        markov.writer().write(&b"ab".repeat(100));
+        markov.writer().write(b"c");
+        let options = CodeOptions {
+            escape: EscapeMode::Literal,
+            ..Default::default()
+        };
+        let decoder = Decoder::with_options(&markov, &options);
+        // `a` never follows `a`, but is the most common byte.
+        let order0 = decoder.order0().unwrap();
+        let code = &order0.encoding()[&Symbol::Byte(b'a')];
+        assert!(code.len() < 8);
+        let encoded = decoder.encoder().encode_all(b"aaa").unwrap();
+        assert_eq!(decoder.decode_all(b"a", &encoded, 2).unwrap(), b"aa");
+    }
+
+    #[test]
+    fn test_escape_missing() {
+        let mut markov = Markov::new(2);
+        markov.writer().write(b"abab");
+        let encoder = markov.encoder();
+        assert!(encoder.encode_all(b"abc").is_err());
+        assert!(encoder.encode_all(b"cab").is_err());
+    }
+
+    #[test]
+    fn test_node_shape() {
+        let node = HuffmanNode::from_lengths(&[
+            (Symbol::Byte(b'a'), 1),
+            (Symbol::Byte(b'b'), 2),
+            (Symbol::Escape, 3),
+            (Symbol::Byte(b'c'), 3),
+        ])
+        .unwrap();
+
+        assert_eq!(node.depth(), 3);
+        assert_eq!(node.leaf(), None);
+        assert_eq!(node.left().unwrap().leaf(), Some(b'a'));
+        let right = node.right().unwrap();
+        assert_eq!(right.left().unwrap().leaf(), Some(b'b'));
+        assert_eq!(right.right().unwrap().left().unwrap().leaf(), Some(b'c'));
+        assert_eq!(
+            right.right().unwrap().right().unwrap().symbol(),
+            Some(Symbol::Escape)
+        );
+
+        let codes: Vec<(String, Symbol)> = node
+            .iter()
+            .map(|(bits, symbol)| {
+                let bits = bits.iter().map(|bit| if *bit { '1' } else { '0' });
+                (bits.collect(), symbol)
+            })
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                ("0".into(), Symbol::Byte(b'a')),
+                ("10".into(), Symbol::Byte(b'b')),
+                ("110".into(), Symbol::Byte(b'c')),
+                ("111".into(), Symbol::Escape),
+            ]
+        );
+        assert_eq!(HuffmanNode::Leaf(b'x').depth(), 0);
+    }
+
+    #[test]
+    fn test_tree_metrics() {
+        let node = HuffmanNode::from_lengths(&[
+            (Symbol::Byte(b'a'), 1),
+            (Symbol::Byte(b'b'), 2),
+            (Symbol::Escape, 3),
+            (Symbol::Byte(b'c'), 3),
+        ])
+        .unwrap();
+        assert_eq!(node.max_depth(), 3);
+        assert_eq!(node.symbol_count(), 4);
+        // escapes and bytes without a leaf do not count, repeated bytes add up.
+        let weights = [(b'a', 4), (b'c', 1), (b'z', 9), (b'a', 1)];
+        assert_eq!(node.weighted_path_length(&weights), 5 + 3);
+        assert_eq!(
+            HuffmanNode::Leaf(b'x').weighted_path_length(&[(b'x', 7)]),
+            0
+        );
+        assert_eq!(HuffmanNode::Leaf(b'x').symbol_count(), 1);
+
+        // a tree with a code of every length is as deep as it has symbols, less one.
+        let lengths: Vec<(Symbol, u8)> = (1..=255)
+            .map(|length| (Symbol::Byte(length), length))
+            .chain([(Symbol::Byte(0), 255)])
+            .collect();
+        let mut node = HuffmanNode::from_symbol(lengths[255].0);
+        for (symbol, _) in lengths[..255].iter().rev() {
+            node = HuffmanNode::Node {
+                left: HuffmanNode::from_symbol(*symbol).into(),
+                right: node.into(),
+            };
+        }
+        assert_eq!(node.max_depth(), 255);
+        assert_eq!(node.symbol_count(), 256);
+        let weights: Vec<(u8, u64)> = (0..=255).map(|byte| (byte, 1)).collect();
+        let expected: u128 = lengths.iter().map(|(_, length)| u128::from(*length)).sum();
+        assert_eq!(node.weighted_path_length(&weights), expected);
+    }
+
+    #[test]
+    fn test_tree_shape() {
+        let mut markov = Markov::new(2);
+        markov.insert_run(b"aaaaaaaabababcbd");
+        let decoder = Decoder::new(&markov);
+        let shape = decoder.tree_shape();
+        assert_eq!(shape.trees, 3);
+        // a is followed by a and b, b by a, c and d, c by b.
+        assert_eq!(shape.symbols, 2 + 3 + 1);
+        assert_eq!(shape.max_depth, 2);
+        assert_eq!(shape.deepest.as_deref(), Some(&b"b"[..]));
+        assert_eq!(Decoder::default().tree_shape(), TreeShape::default());
+    }
+
+    #[test]
+    fn test_iter_codes() {
+        let mut markov = Markov::new(2);
+        markov.insert_run(b"aaaaaaaabababcbd");
+        let decoder = Decoder::new(&markov);
+        let codes: Vec<_> = decoder
+            .iter_codes()
+            .map(|entry| (entry.context, entry.symbol, bit_string(&entry.code())))
+            .collect();
+        // by context and then by symbol, the single symbol of "c" needs no bits.
+        let expected = [
+            (&b"a"[..], Symbol::Byte(b'a'), "0"),
+            (b"a", Symbol::Byte(b'b'), "1"),
+            (b"b", Symbol::Byte(b'a'), "0"),
+            (b"b", Symbol::Byte(b'c'), "10"),
+            (b"b", Symbol::Byte(b'd'), "11"),
+            (b"c", Symbol::Byte(b'b'), ""),
+        ];
+        assert_eq!(codes, expected.map(|(c, s, code)| (c, s, code.to_string())));
+        assert!(decoder
+            .iter_codes()
+            .all(|entry| entry.code().len() == usize::from(entry.length)));
+        assert!(decoder
+            .iter_codes()
+            .eq(Coder::from(decoder.clone()).iter_codes()));
+    }
+
+    #[test]
+    fn test_refresh() {
+        let mut markov = Markov::new(2);
+        markov.insert_run(b"abacabadabacabaeacad");
+        let original = Decoder::new(&markov);
+        let mut decoder = original.clone();
+        let before: Vec<_> = original.iter_codes().collect();
+        let contexts = original.trees.len();
+
+        // only the context "a" changes, the codes of the others stay bit for bit.
+        markov.insert(b"ae", 6).unwrap();
+        let mut refreshed = original.clone();
+        let report = refreshed.refresh(&markov).unwrap();
+        assert_eq!(
+            report,
+            RefreshReport {
+                rebuilt: 1,
+                untouched: contexts - 1
+            }
+        );
+        assert_eq!(refreshed, Decoder::new(&markov));
+        let after: Vec<_> = refreshed.iter_codes().collect();
+        assert!(!before
+            .iter()
+            .filter(|entry| entry.context == b"a")
+            .eq(after.iter().filter(|entry| entry.context == b"a")));
+        assert!(before
+            .iter()
+            .filter(|entry| entry.context != b"a")
+            .eq(after.iter().filter(|entry| entry.context != b"a")));
+        assert_eq!(
+            refreshed.refresh(&markov).unwrap(),
+            RefreshReport {
+                rebuilt: 0,
+                untouched: contexts
+            }
+        );
+
+        // new contexts and symbols change the shape, which leaves the decoder as it was.
+        let mut changed = markov.clone();
+        changed.insert(b"fa", 1).unwrap();
+        assert!(matches!(
+            decoder.refresh(&changed),
+            Err(Error::ModelShape(ModelShapeChanged::Context(context))) if &context[..] == b"f"
+        ));
+        let mut changed = markov.clone();
+        changed.insert(b"bb", 1).unwrap();
+        assert!(matches!(
+            decoder.refresh(&changed),
+            Err(Error::ModelShape(ModelShapeChanged::Symbols(context))) if &context[..] == b"b"
+        ));
+        assert!(matches!(
+            decoder.refresh(&Markov::new(3)),
+            Err(Error::ModelShape(ModelShapeChanged::Depth {
+                expected: 2,
+                found: 3
+            }))
+        ));
+        assert_eq!(decoder, original);
+    }
+
+    #[proptest]
+    fn test_refresh_tables(
+        #[strategy(1usize..4)] depth: usize,
+        data: Vec<u8>,
+        #[strategy(proptest::collection::vec(any::<(prop::sample::Index, u8)>(), 0..8))]
+        updates: Vec<(prop::sample::Index, u8)>,
+    ) {
+        // decoders read from tables know no weights, refreshing builds every tree.
+        let mut markov = Markov::new(depth);
+        markov.insert_run(&data);
+        let mut tables = vec![];
+        markov.decoder().write_tables(&mut tables)?;
+        let mut decoder = Decoder::read_tables(&mut &tables[..])?;
+
+        let sequences: Vec<_> = markov.iter().map(|(sequence, _)| sequence).collect();
+        for (index, weight) in updates {
+            if !sequences.is_empty() {
+                markov.insert(&sequences[index.index(sequences.len())], weight.into())?;
+            }
+        }
+        let report = decoder.refresh(&markov)?;
+        prop_assert_eq!(report.rebuilt, decoder.trees.len());
+        prop_assert_eq!(&decoder, &Decoder::new(&markov));
+    }
+
+    #[test]
+    fn test_golden_bit_order() {
+        // a: 0, b: 10, c: 11
+        let mut markov = Markov::new(1);
+        markov.writer().write_all(b"aaaabbc").unwrap();
+        let encoder = markov.encoder();
+        assert_eq!(
+            encoder.encode_all(b"aaaabbc").unwrap(),
+            [0b0000_1010, 0b1100_0000]
+        );
+        assert_eq!(encoder.encode_all(b"cab").unwrap(), [0b1101_0000]);
+        assert_eq!(
+            encoder.encode_all(b"bbbbc").unwrap(),
+            [0b1010_1010, 0b1100_0000]
+        );
+
+        let decoder = markov.decoder();
+        assert_eq!(
+            decoder
+                .decode_all(&[], &[0b0000_1010, 0b1100_0000], 7)
+                .unwrap(),
+            b"aaaabbc"
+        );
+    }
+
+    #[test]
+    fn test_golden_escape_bit_order() {
+        // in context "a": a: 0, b: 10, escape: 11, followed by the order-0 code. that is the
+        // same for "c", which is only known as a literal after the order-0 escape.
+        let mut markov = Markov::new(2);
+        markov.writer().write_all(b"aaab").unwrap();
+        let (encoder, _) = crate::Builder::new()
+            .depth(2)
+            .escape(EscapeMode::Literal)
+            .coder_from(&markov)
+            .unwrap();
+        assert_eq!(
+            encoder.encode_all(b"aac").unwrap(),
+            [0b0111_1011, 0b0001_1000]
+        );
+    }
+
+    #[test]
+    fn test_rebuild_context() {
+        let mut markov = Markov::new(2);
+        markov.writer().write_all(b"abracadabra").unwrap();
+        let mut decoder = markov.decoder();
+        let mut encoder = decoder.encoder();
+        let original = decoder.clone();
+
+        let items = [
+            WeightedItem::new(b'x', 5),
+            WeightedItem::new(b'a', 1),
+            WeightedItem::new(b'y', 1),
+        ];
+        decoder.rebuild_context(b"a", &items).unwrap();
+        encoder.rebuild_context(b"a", &items).unwrap();
+        assert_eq!(encoder, decoder.encoder());
+        assert_ne!(decoder.trees[&b"a"[..]], original.trees[&b"a"[..]]);
+        for (context, tree) in &original.trees {
+            if &context[..] != b"a" {
+                assert_eq!(&decoder.trees[context], tree);
+            }
+        }
+
+        decoder.rebuild_context(b"r", &[]).unwrap();
+        encoder.rebuild_context(b"r", &[]).unwrap();
+        assert!(!decoder.trees.contains_key(&b"r"[..]));
+        assert_eq!(encoder, decoder.encoder());
+
+        // with escapes, a context without bytes is removed rather than left with the escape.
+        let mut escaped = Decoder::with_options(
+            &markov,
+            &CodeOptions {
+                escape: EscapeMode::Literal,
+                ..Default::default()
+            },
+        );
+        escaped.rebuild_context(b"r", &[]).unwrap();
+        assert!(!escaped.trees.contains_key(&b"r"[..]));
+        tables_roundtrip(&escaped);
+        assert!(matches!(
+            write_symbol_set(&mut vec![], &[], &AlphabetMap::full()),
+            Err(Error::Format("empty symbol set"))
+        ));
+        assert!(decoder.rebuild_context(b"ab", &items).is_err());
+
+        // updating from the model restores the original trees.
+        decoder
+            .update_from(&markov, [b"a".as_slice().into(), b"r".as_slice().into()])
+            .unwrap();
+        assert_eq!(decoder, original);
+    }
+
+    #[proptest]
+    fn test_coder_matches(#[strategy(1usize..5)] depth: usize, data: Vec<u8>) {
+        let mut markov = Markov::new(depth);
+        markov.writer().write_all(&data).unwrap();
+        let coder = Coder::new(&markov);
+        let decoder = markov.decoder();
+        prop_assert_eq!(&coder.decoder(), &decoder);
+        prop_assert_eq!(coder.encoder(), decoder.encoder());
+        prop_assume!(data.len() >= depth);
+
+        let encoded = coder.encode_all(&data).unwrap();
+        prop_assert_eq!(&encoded, &decoder.encoder().encode_all(&data).unwrap());
+        let context = &data[..depth - 1];
+        let decoded = coder
+            .decode_all(context, &encoded, data.len() - context.len())
+            .unwrap();
+        prop_assert_eq!(&decoded[..], &data[depth - 1..]);
+    }
+
+    #[test]
+    fn test_coder_lazy_codes() {
+        let mut markov = Markov::new(2);
+        markov.writer().write_all(b"abracadabra").unwrap();
+        let coder = Coder::new(&markov);
+        let encoded = markov.encoder().encode_all(b"abracadabra").unwrap();
+
+        coder.decode_all(b"a", &encoded, 10).unwrap();
+        assert!(coder
+            .contexts
+            .iter()
+            .all(|(_, context)| context.codes.get().is_none()));
+
+        assert_eq!(coder.encode(b"r", b'a').map(|code| code.len()), Some(0));
+        assert!(coder.contexts.get(b"r").unwrap().codes.get().is_some());
+        assert!(coder.contexts.get(b"a").unwrap().codes.get().is_none());
+    }
+
+    #[proptest]
+    fn test_writer_stats(
+        #[strategy(1usize..4)] depth: usize,
+        #[strategy(proptest::collection::vec(any::<u8>(), 0..256))] data: Vec<u8>,
+        #[strategy(proptest::collection::vec(any::<u8>(), 0..256))] other: Vec<u8>,
+    ) {
+        let mut markov = Markov::new(depth);
+        markov.writer().write_all(&data).unwrap();
+        let coder = Coder::with_options(
+            &markov,
+            &CodeOptions {
+                escape: EscapeMode::Literal,
+                ..Default::default()
+            },
+        );
+
+        let input = [&data[..], &other[..]].concat();
+        let mut writer = coder.writer(vec![]);
+        writer.write_all(&data).unwrap();
+        writer.write_all(&other).unwrap();
+        let stats = writer.stats();
+        let (output, finished) = writer.finish().unwrap();
+        prop_assert_eq!(stats, finished);
+
+        prop_assert_eq!(stats.input_bytes, input.len() as u64);
+        prop_assert_eq!(stats.output_bits.div_ceil(8), output.len() as u64);
+        prop_assert_eq!(stats.escapes, Coder::new(&markov).miss_count(&input) as u64);
+        prop_assert!(stats.max_code_len_seen <= MAX_CODE_LENGTH);
+    }
+
+    // splits `data` at the offsets in `cuts`, in any order and out of range.
+    fn chunks<'a>(data: &'a [u8], cuts: &[usize]) -> Vec<&'a [u8]> {
+        let mut cuts: Vec<usize> = cuts.iter().map(|cut| cut % (data.len() + 1)).collect();
+        cuts.sort_unstable();
+        let mut start = 0;
+        let mut chunks = vec![];
+        for cut in cuts.into_iter().chain([data.len()]) {
+            chunks.push(&data[start..cut]);
+            start = cut;
+        }
+        chunks
+    }
+
+    #[proptest]
+    fn test_writer_chunked(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(proptest::collection::vec(0u8..16, 0..512))] data: Vec<u8>,
+        #[strategy(proptest::collection::vec(any::<usize>(), 0..32))] cuts: Vec<usize>,
+        flushes: [bool; 32],
+        escape: bool,
+        eof: bool,
+        #[strategy(1u64..64)] interval: u64,
+    ) {
+        // with escapes, the second half has bytes the model has not seen.
+        let mut markov = Markov::new(depth);
+        let training = if escape {
+            &data[..data.len() / 2]
+        } else {
+            &data
+        };
+        markov.writer().write_all(training).unwrap();
+        let options = CodeOptions {
+            escape: if escape {
+                EscapeMode::Literal
+            } else {
+                EscapeMode::None
+            },
+            eof,
+            ..Default::default()
+        };
+        let coder = Coder::with_options(&markov, &options);
+
+        let mut writer = coder.writer(vec![]).with_checkpoints(interval);
+        writer.write_all(&data).unwrap();
+        let whole = writer.finish_with_checkpoints().unwrap();
+
+        let mut writer = coder.writer(vec![]).with_checkpoints(interval);
+        for (chunk, flush) in chunks(&data, &cuts).into_iter().zip(flushes.iter().cycle()) {
+            writer.write_all(chunk).unwrap();
+            if *flush {
+                writer.flush().unwrap();
+            }
+        }
+        prop_assert_eq!(writer.finish_with_checkpoints().unwrap(), whole);
+    }
+
+    #[proptest]
+    fn test_max_compressed_len(
+        #[strategy(1usize..5)] depth: usize,
+        #[strategy(proptest::collection::vec(0u8..16, 0..512))] training: Vec<u8>,
+        #[strategy(0usize..512)] len: usize,
+        escape: bool,
+        eof: bool,
+    ) {
+        let mut markov = Markov::new(depth);
+        markov.writer().write_all(&training).unwrap();
+        let options = CodeOptions {
+            escape: if escape {
+                EscapeMode::Literal
+            } else {
+                EscapeMode::None
+            },
+            eof,
+            ..Default::default()
+        };
+        let encoder = Coder::with_options(&markov, &options).encoder();
+
+        // every byte takes the longest code after the bytes before it, or is escaped if that
+        // is longer.
+        let mut data = training[..training.len().min(depth - 1)].to_vec();
+        data.resize(depth - 1, 0);
+        while data.len() < len {
+            let prefix = &data[data.len() + 1 - depth..];
+            let longest = encoder
+                .prefixes
+                .get(prefix)
+                .into_iter()
+                .flatten()
+                .max_by_key(|(_, code)| code.len());
+            let missing = (0..=255).find(|byte| encoder.encode(prefix, *byte).is_none());
+            let byte = match (longest, missing) {
+                (Some((_, code)), Some(missing)) if escape && code.len() < 9 => missing,
+                (Some((byte, _)), _) => *byte,
+                (None, Some(missing)) if escape => missing,
+                (None, _) => break,
+            };
+            data.push(byte);
+        }
+
+        // inputs shorter than the depth cannot be encoded at all.
+        let bound = encoder.max_compressed_len(data.len() as u64);
+        if data.len() >= depth {
+            let encoded = encoder.encode_all(&data).unwrap();
+            prop_assert!(encoded.len() as u64 <= bound, "{} > {bound}", encoded.len());
+        }
+        prop_assert!(bound <= encoder.max_compressed_len(len as u64));
+    }
+
+    #[test]
+    fn test_writer_adapters() {
+        let data = b"the quick brown fox jumps over the lazy dog";
+        let coder = Coder::new(&crate::Builder::new().depth(3).train(data).unwrap());
+        let expected = coder.encode_all(data).unwrap();
+
+        let mut writer = coder.writer(vec![]);
+        std::io::copy(&mut &data[..], &mut &mut writer).unwrap();
+        assert_eq!(writer.finish().unwrap().0, expected);
+
+        let mut writer = std::io::BufWriter::with_capacity(4, coder.boxed_writer(Box::new(vec![])));
+        for chunk in data.chunks(5) {
+            writer.write_all(chunk).unwrap();
+        }
+        let writer = writer
+            .into_inner()
+            .map_err(|error| error.into_error())
+            .unwrap();
+        let (_, stats) = writer.finish().unwrap();
+        assert_eq!(stats.input_bytes, data.len() as u64);
+        assert_eq!(stats.output_bits.div_ceil(8), expected.len() as u64);
+    }
+
+    #[test]
+    fn test_construction_order() {
+        // trees may be built in parallel, the result must match building them one by one.
+        let builder = crate::Builder::new()
+            .depth(3)
+            .top_successors(6)
+            .escape(EscapeMode::Literal);
+        let markov = builder.train(include_bytes!("markov.rs")).unwrap();
+        let options = &builder.options;
+        let decoder = Decoder::with_options(&markov, options);
+
+        let mut trees = BTreeMap::new();
+        for (prefix, items) in markov.iter_prefix() {
+            let escape = Some(markov.escape_weight(&prefix).unwrap_or(0).max(1));
+            trees.insert(
+                Arc::from(prefix),
+                options.tree(&items, escape).unwrap().unwrap(),
+            );
+        }
+        assert_eq!(decoder.trees, trees);
+
+        let encoder = decoder.encoder();
+        for (prefix, node) in &trees {
+            let codes = Codes::new(node);
+            assert_eq!(encoder.prefixes[prefix], codes.bytes);
+            assert_eq!(encoder.escapes.get(prefix), codes.escape.as_ref());
+        }
+        assert_eq!(encoder.prefixes.len(), trees.len());
+    }
+
+    #[test]
+    fn test_shared_keys() {
+        let mut markov = Markov::new(3);
+        markov.writer().write_all(b"abracadabra").unwrap();
+        let decoder = Decoder::new(&markov);
+        let mut encoder = decoder.encoder();
+        for ((decoded, _), (encoded, _)) in decoder.trees.iter().zip(&encoder.prefixes) {
+            assert!(Arc::ptr_eq(decoded, encoded));
+        }
+
+        // rebuilding a context keeps its key, lookups by slice still work.
+        let key = encoder.prefixes.keys().next().unwrap().clone();
+        let items = [WeightedItem::new(b'x', 1)];
+        encoder.rebuild_context(&key, &items).unwrap();
+        assert!(Arc::ptr_eq(
+            encoder.prefixes.get_key_value(&key[..]).unwrap().0,
+            &key
+        ));
+        assert!(encoder.encode(&key, b'x').is_some());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut markov = Markov::new(2);
+        markov.writer().write_all(b"abracadabra").unwrap();
+        let encoder = markov.encoder();
+        assert_eq!(encoder.validate(b"abracadabra"), Ok(()));
+        assert_eq!(encoder.validate(b"a"), Ok(()));
+        assert_eq!(
+            encoder.validate(b"abxrab"),
+            Err(vec![
+                EncodeMiss {
+                    offset: 2,
+                    context: b"b"[..].into(),
+                    byte: b'x',
+                },
+                EncodeMiss {
+                    offset: 3,
+                    context: b"x"[..].into(),
+                    byte: b'r',
+                },
+            ])
+        );
+        assert_eq!(encoder.miss_count(b"abxrab"), 2);
+        assert_eq!(
+            Coder::new(&markov).validate(b"abxrab"),
+            encoder.validate(b"abxrab")
+        );
+
+        let data = b"xy".repeat(MAX_REPORTED_MISSES);
+        assert_eq!(
+            encoder.validate(&data).unwrap_err().len(),
+            MAX_REPORTED_MISSES
+        );
+        assert_eq!(encoder.miss_count(&data), data.len() - 1);
+
+        let (encoder, _) = crate::Builder::new()
+            .depth(2)
+            .escape(EscapeMode::Literal)
+            .coder_from(&markov)
+            .unwrap();
+        assert_eq!(encoder.validate(b"abxrab"), Ok(()));
+    }
+
+    #[test]
+    fn test_hash_eq() {
+        use std::collections::HashSet;
+
+        let model = |data: &[u8]| {
+            let mut markov = Markov::new(2);
+            markov.writer().write_all(data).unwrap();
+            markov
+        };
+        let markovs = [
+            model(b"abracadabra"),
+            model(b"abracadabra"),
+            model(b"alakazam"),
+        ];
+        let decoders: HashSet<Decoder> = markovs.iter().map(Markov::decoder).collect();
+        let encoders: HashSet<Encoder> = markovs.iter().map(Markov::encoder).collect();
+        // the only interior mutability is the code cache, which hashing ignores.
+        #[allow(clippy::mutable_key_type)]
+        let coders: HashSet<Coder> = markovs.iter().map(Coder::new).collect();
+        assert_eq!(decoders.len(), 2);
+        assert_eq!(encoders.len(), 2);
+        assert_eq!(coders.len(), 2);
+
+        // the lazily built codes do not take part in comparisons.
+        let coder = Coder::new(&markovs[0]);
+        coder.encode_all(b"abracadabra").unwrap();
+        assert!(coders.contains(&coder));
+    }
+
+    #[test]
+    fn test_from_probabilities() {
+        let dyadic = vec![(b'a', 0.5), (b'b', 0.25), (b'c', 0.125), (b'd', 0.125)];
+        let decoder =
+            Decoder::from_probabilities(2, [(b"x"[..].into(), dyadic.clone())], 1 << 16).unwrap();
+        let mut lengths = decoder.trees[&b"x"[..]].lengths();
+        lengths.sort_unstable();
+        assert_eq!(
+            lengths,
+            [
+                (Symbol::Byte(b'a'), 1),
+                (Symbol::Byte(b'b'), 2),
+                (Symbol::Byte(b'c'), 3),
+                (Symbol::Byte(b'd'), 3)
+            ]
+        );
+
+        // the expected code length is within a bit of the entropy.
+        let skewed: Vec<(u8, f64)> = [0.6, 0.2, 0.1, 0.05, 0.03, 0.015, 0.005, 0.0]
+            .into_iter()
+            .enumerate()
+            .map(|(byte, probability)| (byte as u8, probability))
+            .collect();
+        let decoder =
+            Decoder::from_probabilities(1, [(b""[..].into(), skewed.clone())], 1000).unwrap();
+        let lengths: BTreeMap<Symbol, u8> = decoder.trees[&b""[..]].lengths().into_iter().collect();
+        assert_eq!(lengths.len(), 7);
+        let (mut entropy, mut expected) = (0.0, 0.0);
+        for (byte, probability) in skewed.into_iter().filter(|(_, p)| *p > 0.0) {
+            entropy -= probability * probability.log2();
+            expected += probability * f64::from(lengths[&Symbol::Byte(byte)]);
+        }
+        assert!(entropy <= expected && expected < entropy + 1.0);
+
+        let invalid = |probabilities: Vec<(u8, f64)>| {
+            Decoder::from_probabilities(1, [(b""[..].into(), probabilities)], 1000).is_err()
+        };
+        assert!(invalid(vec![(b'a', 0.5), (b'b', 0.4)]));
+        assert!(invalid(vec![(b'a', 1.5), (b'b', -0.5)]));
+        assert!(invalid(vec![(b'a', f64::NAN), (b'b', 1.0)]));
+        assert!(invalid(vec![(b'a', 0.5), (b'a', 0.5)]));
+        assert!(!invalid(vec![(b'a', 0.9995), (b'b', 0.0)]));
+        assert!(Decoder::from_probabilities(2, [(b""[..].into(), dyadic)], 1000).is_err());
+    }
+
+    // contexts with the codes of their bytes, as strings of bits.
+    type CodeStrings<'a> = [(&'a [u8], &'a [(u8, &'a str)])];
+    type ExternalCodes = Vec<(Box<[u8]>, Vec<(u8, BitBox)>)>;
+
+    fn external_codes(contexts: &CodeStrings) -> ExternalCodes {
+        contexts
+            .iter()
+            .map(|(context, codes)| {
+                let codes = codes
+                    .iter()
+                    .map(|(byte, code)| (*byte, code.chars().map(|bit| bit == '1').collect()))
+                    .collect();
+                ((*context).into(), codes)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_from_codes() {
+        // codes that are not canonical, and a context with a single byte of the empty code.
+        let codes = external_codes(&[
+            (b"a", &[(b'a', "11"), (b'b', "0"), (b'c', "10")]),
+            (b"b", &[(b'a', "1"), (b'c', "0")]),
+            (b"c", &[(b'a', "")]),
+        ]);
+        let encoder = Encoder::from_codes(2, codes.clone()).unwrap();
+        let decoder = Decoder::from_codes(2, codes).unwrap();
+        assert_eq!(decoder.encoder(), encoder);
+
+        let data = b"abacaabcab";
+        let encoded = encoder.encode_all(data).unwrap();
+        // 0, 1, 10, nothing for the a after c, 11, 0, 0, nothing again, 0.
+        assert_eq!(encoded, [0b0110_1100, 0]);
+        assert_eq!(
+            decoder.decode_all(b"a", &encoded, data.len() - 1).unwrap(),
+            data[1..]
+        );
+
+        // encoders do not decode, so their codes need not cover every bit string.
+        let partial = external_codes(&[(b"", &[(b'a', "0"), (b'b', "10")])]);
+        assert!(Encoder::from_codes(1, partial.clone()).is_ok());
+        assert_eq!(
+            Decoder::from_codes(1, partial),
+            Err(CodeTableError::Incomplete {
+                context: b""[..].into(),
+                missing: bitbox![1, 1],
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_codes_invalid() {
+        let error = Decoder::from_codes(
+            2,
+            external_codes(&[(b"x", &[(b'a', "01"), (b'b', "1"), (b'c', "011")])]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            CodeTableError::NotPrefixFree {
+                context: b"x"[..].into(),
+                prefix: (b'a', bitbox![0, 1]),
+                code: (b'c', bitbox![0, 1, 1]),
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "code \"01\" of byte 0x61 is a prefix of code \"011\" of byte 0x63 in context [78]"
+        );
+
+        let invalid: [(&CodeStrings, CodeTableError); 6] = [
+            (
+                &[(b"x", &[(b'a', "0"), (b'b', "1"), (b'c', "1")])],
+                CodeTableError::DuplicateCode {
+                    context: b"x"[..].into(),
+                    code: bitbox![1],
+                    bytes: (b'b', b'c'),
+                },
+            ),
+            (
+                &[(b"x", &[(b'a', ""), (b'b', "1")])],
+                CodeTableError::NotPrefixFree {
+                    context: b"x"[..].into(),
+                    prefix: (b'a', bitbox![]),
+                    code: (b'b', bitbox![1]),
+                },
+            ),
+            (
+                &[(b"x", &[(b'a', "0"), (b'a', "1")])],
+                CodeTableError::DuplicateByte {
+                    context: b"x"[..].into(),
+                    byte: b'a',
+                },
+            ),
+            (
+                &[(b"x", &[(b'a', "")]), (b"x", &[(b'b', "")])],
+                CodeTableError::DuplicateContext(b"x"[..].into()),
+            ),
+            (
+                &[(b"xy", &[(b'a', "")])],
+                CodeTableError::ContextLength {
+                    context: b"xy"[..].into(),
+                    expected: 1,
+                },
+            ),
+            (
+                &[(b"x", &[])],
+                CodeTableError::EmptyContext(b"x"[..].into()),
+            ),
+        ];
+        for (contexts, expected) in invalid {
+            let codes = external_codes(contexts);
+            assert_eq!(Encoder::from_codes(2, codes.clone()), Err(expected.clone()));
+            assert_eq!(Decoder::from_codes(2, codes), Err(expected));
+        }
+        assert_eq!(Decoder::from_codes(0, []), Err(CodeTableError::Depth));
+    }
+
+    #[proptest]
+    fn test_from_codes_model(#[strategy(1usize..4)] depth: usize, data: Vec<u8>) {
+        // the codes of a model build the same trees again, all but the order-0 one.
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data);
+        let decoder = Decoder {
+            order0: None,
+            ..markov.decoder()
+        };
+        let encoder = decoder.encoder();
+        let codes = encoder.prefixes.iter().map(|(context, codes)| {
+            let codes = codes.iter().map(|(byte, code)| (*byte, code.clone()));
+            (context[..].into(), codes.collect())
+        });
+        prop_assert_eq!(Decoder::from_codes(depth, codes.clone())?, decoder);
+        prop_assert_eq!(Encoder::from_codes(depth, codes)?, encoder);
+    }
+
+    #[proptest]
+    fn test_sessions(
+        #[strategy(1usize..4)] depth: usize,
+        training: Vec<u8>,
+        #[strategy(proptest::collection::vec(proptest::collection::vec(0u8..4, 0..64), 0..16))]
+        payloads: Vec<Vec<u8>>,
+    ) {
+        // small bytes, so that the payloads share contexts with the training data.
+        let training: Vec<u8> = training.iter().map(|byte| byte % 4).collect();
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&training);
+        let decoder = markov.decoder();
+        let encoder = decoder.encoder();
+        let mut encode = encoder.session();
+        let mut decode = decoder.session();
+        let (mut compressed, mut decompressed) = (vec![], vec![]);
+        for payload in &payloads {
+            compressed.clear();
+            let result = encode.compress(payload, &mut compressed);
+            match encoder.encode_all(payload) {
+                Ok(expected) => {
+                    prop_assert!(result.is_ok());
+                    prop_assert_eq!(&compressed, &expected);
+                }
+                Err(_) => {
+                    prop_assert!(result.is_err());
+                    prop_assert!(compressed.is_empty());
+                    continue;
+                }
+            }
+
+            decompressed.clear();
+            let (context, rest) = payload.split_at(payload.len().min(depth - 1));
+            decode.decompress(context, &compressed, rest.len(), &mut decompressed)?;
+            prop_assert_eq!(&decompressed, rest);
+        }
+    }
+
+    #[proptest]
+    fn test_encode_into(
+        #[strategy(1usize..4)] depth: usize,
+        #[strategy(proptest::collection::vec(0u8..4, 4..64))] training: Vec<u8>,
+        #[strategy(0usize..16)] offset: usize,
+        split: usize,
+    ) {
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&training);
+        let decoder = markov.decoder();
+        let encoder = decoder.encoder();
+
+        // two fields of a protocol, each coded after its own context, with a marker between.
+        let (context, rest) = training.split_at(depth - 1);
+        let split = split % (rest.len() + 1);
+        let (first, second) = rest.split_at(split);
+        let second_context = &training[split..split + depth - 1];
+        let mut bits: BitVec = BitVec::repeat(true, offset);
+        let stats = encoder.encode_into(first, context, &mut bits)?;
+        prop_assert_eq!(bits.len() as u64, offset as u64 + stats.output_bits);
+        bits.extend_from_bitslice(bits![0, 1, 1]);
+        encoder.encode_into(second, second_context, &mut bits)?;
+        let end = bits.len();
+        bits.push(true);
+
+        let (decoded, consumed) = decoder.decode_from(&bits[offset..], context, first.len())?;
+        prop_assert_eq!(&decoded, first);
+        let marker = offset + consumed;
+        prop_assert_eq!(&bits[marker..marker + 3], bits![0, 1, 1]);
+        let (decoded, consumed) =
+            decoder.decode_from(&bits[marker + 3..], second_context, second.len())?;
+        prop_assert_eq!(&decoded, second);
+        prop_assert_eq!(marker + 3 + consumed, end);
+
+        // the codes are the ones of the whole buffer, without the padding.
+        let encoded = encoder.encode_all(&training)?;
+        let mut whole = BitVec::<u8, Msb0>::new();
+        encoder.encode_into(rest, context, &mut whole)?;
+        prop_assert_eq!(&encoded.view_bits::<Msb0>()[..whole.len()], &whole[..]);
+    }
+
+    #[test]
+    fn test_decode_from_truncated() {
+        let mut markov = Markov::new(2);
+        markov.writer().write(b"abracadabra");
+        let decoder = markov.decoder();
+        let mut bits = BitVec::<u8, Msb0>::new();
+        decoder
+            .encoder()
+            .encode_into(b"bracadabra", b"a", &mut bits)
+            .unwrap();
+        assert_eq!(
+            decoder.decode_from(&bits, b"a", 10).unwrap(),
+            (b"bracadabra".to_vec(), bits.len())
+        );
+
+        // the code of the last b is cut off, even though its byte holds more bits. the bytes
+        // after it have a single successor and take no bits.
+        let result = decoder.decode_from(&bits[..bits.len() - 1], b"a", 10);
+        assert!(matches!(
+            result,
+            Err(Error::DecodeFailed { bytes_produced: 7, ref source, .. })
+                if matches!(**source, Error::Truncated)
+        ));
+        assert!(matches!(
+            decoder.decode_from(&bits, b"", 10),
+            Err(Error::Format(_))
+        ));
+        assert!(decoder
+            .encoder()
+            .encode_into(b"abc", b"", &mut bits)
+            .is_err());
+    }
+
+    #[test]
+    fn test_session_output() {
+        let mut markov = Markov::new(2);
+        markov.writer().write(b"abracadabra");
+        let decoder = markov.decoder();
+        let encoder = decoder.encoder();
+
+        // output is appended, and left alone on errors.
+        let mut encode = encoder.session();
+        let mut out = b"header".to_vec();
+        encode.compress(b"abra", &mut out).unwrap();
+        assert_eq!(out[..6], *b"header");
+        assert_eq!(out[6..], encoder.encode_all(b"abra").unwrap());
+        assert_eq!(encode.stats().input_bytes, 4);
+        let len = out.len();
+        assert!(encode.compress(b"abz", &mut out).is_err());
+        assert_eq!(out.len(), len);
+
+        let mut decode = decoder.session();
+        let mut decoded = vec![];
+        decode.decompress(b"a", &out[6..], 3, &mut decoded).unwrap();
+        assert_eq!(decoded, b"bra");
+        assert!(matches!(
+            decode.decompress(b"a", &[], 3, &mut decoded),
+            Err(Error::DecodeFailed { .. })
+        ));
+        assert!(decode.decompress(b"", &out[6..], 3, &mut decoded).is_err());
+        assert_eq!(decoded, b"bra");
+    }
+
+    #[test]
+    fn test_empty_model() {
+        for depth in 1..5 {
+            let decoder = Markov::new(depth).decoder();
+            assert!(decoder.trees.is_empty());
+
+            let encoder = decoder.encoder();
+            assert_eq!(encoder.encode_all(&[]).unwrap(), Vec::<u8>::new());
+            let context = vec![0; depth - 1];
+            assert_eq!(
+                decoder.decode_all(&context, &[], 0).unwrap(),
+                Vec::<u8>::new()
+            );
+            assert_eq!(decoder.decode_all(&[], &[], 0).unwrap(), Vec::<u8>::new());
+        }
+    }
+
+    #[test]
+    fn test_encode_all_too_short() {
+        let mut markov = Markov::new(4);
+        markov.writer().write(b"abcd");
+        let encoder = markov.encoder();
+        assert!(matches!(
+            encoder.encode_all(b"abc"),
+            Err(Error::InputTooShort { len: 3, depth: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut markov = Markov::new(3);
+        markov.writer().write(b"abracadabra");
+        let encoder = markov.encoder();
+        assert_eq!(encoder.context_count(), 7);
+        assert!(encoder.has_context(b"ab") && !encoder.has_context(b"ba"));
+
+        // "bra" and "rac" are covered, "acx" misses the byte and "cxb" and "xbr" the context.
+        let coverage = encoder.coverage(b"bracxbr");
+        assert_eq!(
+            coverage,
+            Coverage {
+                windows: 5,
+                missing_context: 2,
+                missing_symbol: 1,
+            }
+        );
+        assert_eq!(coverage.covered(), 2);
+        assert_eq!(coverage.covered_fraction(), 0.4);
+        assert_eq!(encoder.coverage(b"ab"), Coverage::default());
+        assert_eq!(Coverage::default().covered_fraction(), 1.0);
+    }
+
+    #[proptest]
+    fn test_coverage_misses(#[strategy(1usize..5)] depth: usize, training: Vec<u8>, data: Vec<u8>) {
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&training);
+        let coder = Coder::new(&markov);
+        let encoder = markov.encoder();
+        let coverage = encoder.coverage(&data);
+        prop_assert_eq!(coder.coverage(&data), coverage);
+        prop_assert_eq!(coder.context_count(), encoder.context_count());
+        prop_assert_eq!(
+            coverage.windows,
+            (data.len() + 1).saturating_sub(depth) as u64
+        );
+        prop_assert_eq!(
+            coverage.windows - coverage.covered(),
+            encoder.miss_count(&data) as u64
+        );
+    }
+
+    #[test]
+    fn test_decode_failed() {
+        // "b" and "c" take a bit each after "a", "a" takes none after "b" and nothing can follow
+        // "c", so flipping the first bit of "bab" decodes "c" and fails at the second bit.
+        let mut markov = Markov::new(2);
+        markov.writer().write(b"abac");
+        let decoder = markov.decoder();
+        let mut encoded = decoder.encoder().encode_all(b"abab").unwrap();
+        assert_eq!(decoder.decode_all(b"a", &encoded, 3).unwrap(), b"bab");
+
+        encoded[0] ^= 0x80;
+        let error = decoder.decode_all(b"a", &encoded, 3).unwrap_err();
+        let Error::DecodeFailed {
+            bit_offset,
+            bytes_produced,
+            context,
+            source,
+        } = &error
+        else {
+            panic!("unexpected error {error:?}");
+        };
+        assert_eq!((*bit_offset, *bytes_produced), (1, 1));
+        assert_eq!(context, b"c");
+        assert!(matches!(
+            **source,
+            Error::Format("context missing from model")
+        ));
+        assert_eq!(
+            error.to_string(),
+            "decode failed at output byte 1 (bit 1), context = [63]"
+        );
+
+        // the reader carries the error for callers to downcast.
+        let mut reader = decoder.reader(&encoded[..], b"a", 3);
+        let error = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.get_ref().unwrap().is::<Error>());
+    }
+
+    #[proptest]
+    fn test_decode_failed_truncated(
+        #[strategy(1usize..4)] depth: usize,
+        #[strategy(proptest::collection::vec(0u8..16, 8..256))] data: Vec<u8>,
+        cut: usize,
+    ) {
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data);
+        let encoder = markov.encoder();
+        let encoded = encoder.encode_all(&data).unwrap();
+        // data whose codes are all empty cannot be cut short.
+        prop_assume!(!encoded.is_empty());
+        let cut = cut % encoded.len();
+
+        // the first code that does not fit into the bytes that are left is where it fails.
+        let mut start = 0;
+        let mut expected = None;
+        for (index, window) in data.windows(depth).enumerate() {
+            let end = start + u64::from(encoder.code_len(window).unwrap());
+            if end > 8 * cut as u64 {
+                expected = Some((start, index as u64, &window[..depth - 1]));
+                break;
+            }
+            start = end;
+        }
+        let (bit_offset, bytes_produced, context) = expected.unwrap();
+
+        let context_len = depth - 1;
+        let result = markov.decoder().decode_all(
+            &data[..context_len],
+            &encoded[..cut],
+            data.len() - context_len,
+        );
+        match result {
+            Err(Error::DecodeFailed {
+                bit_offset: actual_offset,
+                bytes_produced: actual_produced,
+                context: actual_context,
+                source,
+            }) => {
+                prop_assert_eq!(
+                    (actual_offset, actual_produced),
+                    (bit_offset, bytes_produced)
+                );
+                prop_assert_eq!(&actual_context[..], context);
+                prop_assert!(matches!(*source, Error::Truncated));
+            }
+            result => prop_assert!(false, "unexpected result {:?}", result),
         }
     }
 }
@@ -0,0 +1,241 @@
+//! Training a model on files that grow, see [`IncrementalTrainer`].
+//!
+//! Files such as logs are appended to while they are trained on. The trainer remembers how far
+//! every file was read and the bytes at its end, so that updates only train on what was added
+//! and windows across the updates are inserted once.
+use crate::{
+    error::Error,
+    markov::{Markov, DEFAULT_WEIGHT},
+};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+// bytes read from a file at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Default)]
+struct FileState {
+    offset: u64,
+    // last bytes of the file, too few to complete a window.
+    tail: Vec<u8>,
+}
+
+/// What [`IncrementalTrainer::update`] trained on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileUpdate {
+    /// Bytes read from the file.
+    pub bytes: u64,
+    /// The file shrank since the last update, so it was read from the start again.
+    pub restarted: bool,
+}
+
+/// Trains a model on files as they grow, reading every byte of a file only once.
+///
+/// Files are told apart by their path. A file that is shorter than what was read from it was
+/// rewritten, it is trained on from the start again without removing what it added before.
+#[derive(Clone, Debug)]
+pub struct IncrementalTrainer {
+    markov: Markov,
+    increment: usize,
+    files: BTreeMap<PathBuf, FileState>,
+}
+
+impl IncrementalTrainer {
+    pub fn new(markov: Markov) -> Self {
+        IncrementalTrainer {
+            markov,
+            increment: DEFAULT_WEIGHT,
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Weight every window adds to the model, 1 by default.
+    pub fn with_increment(mut self, increment: usize) -> Self {
+        self.increment = increment;
+        self
+    }
+
+    pub fn markov(&self) -> &Markov {
+        &self.markov
+    }
+
+    pub fn into_markov(self) -> Markov {
+        self.markov
+    }
+
+    /// Bytes read from the file at `path`, `None` if it was never updated.
+    pub fn offset(&self, path: &Path) -> Option<u64> {
+        self.files.get(path).map(|state| state.offset)
+    }
+
+    /// Files that were updated, in ascending order.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(PathBuf::as_path)
+    }
+
+    /// Trains on the bytes added to the file at `path` since its last update.
+    pub fn update(&mut self, path: &Path) -> Result<FileUpdate, Error> {
+        self.update_reader(path, File::open(path)?)
+    }
+
+    /// Trains on the bytes of `reader` after the ones read in earlier updates of `path`.
+    ///
+    /// If inserting a window fails, the file is left read up to the byte before it, so a later
+    /// update continues there without inserting any window twice.
+    pub fn update_reader<R: Read + Seek>(
+        &mut self,
+        path: &Path,
+        mut reader: R,
+    ) -> Result<FileUpdate, Error> {
+        let state = self.files.entry(path.into()).or_default();
+        let len = reader.seek(SeekFrom::End(0))?;
+        let restarted = len < state.offset;
+        if restarted {
+            *state = FileState::default();
+        }
+        reader.seek(SeekFrom::Start(state.offset))?;
+
+        let mut update = FileUpdate {
+            bytes: 0,
+            restarted,
+        };
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let read = match reader.read(&mut chunk) {
+                Ok(0) => return Ok(update),
+                Ok(read) => read,
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error.into()),
+            };
+            // the offset moves past every byte once its window is in the model.
+            let depth = self.markov.len();
+            for byte in &chunk[..read] {
+                state.tail.push(*byte);
+                if state.tail.len() == depth {
+                    if let Err(error) = self.markov.insert(&state.tail, self.increment) {
+                        state.tail.pop();
+                        return Err(error);
+                    }
+                    state.tail.remove(0);
+                }
+                state.offset += 1;
+                update.bytes += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markov::WeightPolicy;
+    use proptest::prelude::*;
+    use std::io::{Cursor, Write};
+    use test_strategy::proptest;
+
+    #[proptest]
+    fn test_appended(
+        #[strategy(1usize..5)] depth: usize,
+        data: Vec<u8>,
+        #[strategy(proptest::collection::vec(any::<prop::sample::Index>(), 0..4))] splits: Vec<
+            prop::sample::Index,
+        >,
+    ) {
+        // updating as the file grows trains like reading it once.
+        let mut ends: Vec<usize> = splits
+            .iter()
+            .map(|split| split.index(data.len() + 1))
+            .collect();
+        ends.push(data.len());
+        ends.sort();
+        let mut trainer = IncrementalTrainer::new(Markov::new(depth));
+        let path = Path::new("input");
+        let mut previous = 0;
+        for end in ends {
+            let update = trainer
+                .update_reader(path, Cursor::new(&data[..end]))
+                .unwrap();
+            prop_assert_eq!(update.bytes, (end - previous) as u64);
+            prop_assert!(!update.restarted);
+            previous = end;
+        }
+        prop_assert_eq!(trainer.offset(path), Some(data.len() as u64));
+
+        let mut expected = Markov::new(depth);
+        expected.insert_run(&data);
+        prop_assert_eq!(trainer.markov(), &expected);
+    }
+
+    #[test]
+    fn test_files() {
+        // windows do not span files, however their updates interleave.
+        let mut trainer = IncrementalTrainer::new(Markov::new(3)).with_increment(2);
+        let (a, b) = (Path::new("a"), Path::new("b"));
+        trainer.update_reader(a, Cursor::new(b"ab")).unwrap();
+        trainer.update_reader(b, Cursor::new(b"xy")).unwrap();
+        trainer.update_reader(a, Cursor::new(b"abc")).unwrap();
+        trainer.update_reader(b, Cursor::new(b"xyz")).unwrap();
+        let sequences: Vec<_> = trainer.markov().iter().collect();
+        assert_eq!(sequences, [(b"abc".to_vec(), 2), (b"xyz".to_vec(), 2)]);
+        assert_eq!(trainer.paths().collect::<Vec<_>>(), [a, b]);
+        assert_eq!(trainer.offset(Path::new("c")), None);
+
+        // a shorter file was rewritten and is read from the start.
+        let update = trainer.update_reader(a, Cursor::new(b"ab")).unwrap();
+        assert_eq!(
+            update,
+            FileUpdate {
+                bytes: 2,
+                restarted: true
+            }
+        );
+        trainer.update_reader(a, Cursor::new(b"abc")).unwrap();
+        let sequences: Vec<_> = trainer.markov().iter().collect();
+        assert_eq!(sequences, [(b"abc".to_vec(), 4), (b"xyz".to_vec(), 2)]);
+    }
+
+    #[test]
+    fn test_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let mut file = File::create(&path).unwrap();
+        let mut trainer = IncrementalTrainer::new(Markov::new(2));
+        file.write_all(b"ab").unwrap();
+        assert_eq!(trainer.update(&path).unwrap().bytes, 2);
+        assert_eq!(trainer.update(&path).unwrap().bytes, 0);
+        file.write_all(b"c").unwrap();
+        assert_eq!(trainer.update(&path).unwrap().bytes, 1);
+        let sequences: Vec<_> = trainer.markov().iter().collect();
+        assert_eq!(sequences, [(b"ab".to_vec(), 1), (b"bc".to_vec(), 1)]);
+
+        assert!(matches!(
+            trainer.update(&dir.path().join("missing")),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_update_failed() {
+        // the file is read up to the window that fails, retrying does not insert any twice.
+        let mut markov = Markov::new(2).with_weight_policy(WeightPolicy::Error);
+        markov.insert(b"bc", usize::MAX).unwrap();
+        let mut trainer = IncrementalTrainer::new(markov);
+        let path = Path::new("input");
+        for _ in 0..2 {
+            assert!(matches!(
+                trainer.update_reader(path, Cursor::new(b"abcd")),
+                Err(Error::WeightOverflow)
+            ));
+            assert_eq!(trainer.offset(path), Some(2));
+            let sequences: Vec<_> = trainer.markov().iter().collect();
+            assert_eq!(
+                sequences,
+                [(b"ab".to_vec(), 1), (b"bc".to_vec(), usize::MAX)]
+            );
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Code tables of an [`Encoder`] as JSON, for decoders outside of this crate.
+//!
+//! The document holds the `depth`, the `escape` mode, whether there are `eof` symbols, and
+//! `contexts` keyed by the context bytes in lowercase hex. Every context has its byte `codes`,
+//! again keyed by hex, and its `escape` and `eof` codes if it has them. Models deeper than 1 also
+//! have an `order0` object with the `codes` and `escape` code of the order-0 tree, which codes
+//! the initial context and the bytes that escape their context. Codes are strings of `0` and `1`
+//! in the order they are written.
+use crate::{
+    error::Error,
+    huffman::{bit_string, Codes, Encoder, EscapeMode},
+};
+use bitvec::prelude::*;
+use serde_json::{json, Map, Value};
+use std::{collections::BTreeMap, sync::Arc};
+
+impl Encoder {
+    /// Code tables as pretty-printed JSON, see the [module documentation](self).
+    pub fn to_json(&self) -> String {
+        let contexts: Map<String, Value> = self
+            .prefixes
+            .iter()
+            .map(|(prefix, codes)| {
+                let value = codes_value(codes, self.escapes.get(prefix), self.eofs.get(prefix));
+                (hex(prefix), value)
+            })
+            .collect();
+        let mut value = json!({
+            "depth": self.depth,
+            "escape": self.escape.to_string(),
+            "eof": self.eof,
+            "contexts": contexts,
+        });
+        if let Some(order0) = &self.order0 {
+            value["order0"] = codes_value(&order0.bytes, order0.escape.as_ref(), None);
+        }
+        serde_json::to_string_pretty(&value).expect("values always serialize")
+    }
+
+    /// Reads code tables written by [`Encoder::to_json`], rejecting codes that are not
+    /// prefix-free within their context.
+    pub fn from_json(json: &str) -> Result<Encoder, Error> {
+        let value: Value = serde_json::from_str(json)?;
+        let depth = value["depth"]
+            .as_u64()
+            .filter(|depth| (1..=u64::from(u8::MAX)).contains(depth))
+            .ok_or(Error::Format("invalid depth"))? as usize;
+        let escape = value["escape"]
+            .as_str()
+            .and_then(|escape| escape.parse().ok())
+            .ok_or(Error::Format("invalid escape mode"))?;
+        let eof = value["eof"]
+            .as_bool()
+            .ok_or(Error::Format("invalid end flag"))?;
+        let contexts = value["contexts"]
+            .as_object()
+            .ok_or(Error::Format("missing contexts"))?;
+
+        let mut encoder = Encoder {
+            depth,
+            escape,
+            eof,
+            ..Default::default()
+        };
+        for (context, codes) in contexts {
+            let prefix = parse_hex(context)
+                .filter(|prefix| prefix.len() + 1 == depth)
+                .ok_or(Error::Format("invalid context"))?;
+            let codes = parse_codes(codes)?;
+            if codes.escape.is_some() != (escape == EscapeMode::Literal) {
+                return Err(Error::Format("escape code does not match escape mode"));
+            }
+            if codes.eof.is_some() != eof {
+                return Err(Error::Format("end code does not match the end flag"));
+            }
+
+            let prefix: Arc<[u8]> = prefix.into();
+            if let Some(code) = codes.escape {
+                encoder.escapes.insert(prefix.clone(), code);
+            }
+            if let Some(code) = codes.eof {
+                encoder.eofs.insert(prefix.clone(), code);
+            }
+            encoder.prefixes.insert(prefix, codes.bytes);
+        }
+        if let Some(order0) = value.get("order0") {
+            let order0 = parse_codes(order0)?;
+            if depth == 1 || order0.escape.is_none() || order0.eof.is_some() {
+                return Err(Error::Format("invalid order-0 codes"));
+            }
+            encoder.order0 = Some(order0);
+        }
+        Ok(encoder)
+    }
+}
+
+fn codes_value(
+    bytes: &BTreeMap<u8, BitBox>,
+    escape: Option<&BitBox>,
+    eof: Option<&BitBox>,
+) -> Value {
+    let codes: Map<String, Value> = bytes
+        .iter()
+        .map(|(byte, code)| (hex(&[*byte]), bit_string(code).into()))
+        .collect();
+    let mut value = Map::new();
+    value.insert("codes".into(), codes.into());
+    if let Some(code) = escape {
+        value.insert("escape".into(), bit_string(code).into());
+    }
+    if let Some(code) = eof {
+        value.insert("eof".into(), bit_string(code).into());
+    }
+    value.into()
+}
+
+fn parse_codes(value: &Value) -> Result<Codes, Error> {
+    let mut codes = Codes::default();
+    for (byte, code) in value["codes"]
+        .as_object()
+        .ok_or(Error::Format("missing codes"))?
+    {
+        let byte = match parse_hex(byte).as_deref() {
+            Some(&[byte]) => byte,
+            _ => return Err(Error::Format("invalid symbol")),
+        };
+        if codes.bytes.insert(byte, parse_code(code)?).is_some() {
+            return Err(Error::Format("duplicate symbol"));
+        }
+    }
+    codes.escape = value.get("escape").map(parse_code).transpose()?;
+    codes.eof = value.get("eof").map(parse_code).transpose()?;
+
+    // sorted bit strings put every code right before the ones it is a prefix of.
+    let mut all: Vec<&BitBox> = codes
+        .bytes
+        .values()
+        .chain(&codes.escape)
+        .chain(&codes.eof)
+        .collect();
+    all.sort();
+    if all.windows(2).any(|pair| pair[1].starts_with(pair[0])) {
+        return Err(Error::Format("codes are not prefix-free"));
+    }
+    Ok(codes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn parse_hex(input: &str) -> Option<Vec<u8>> {
+    if !input.len().is_multiple_of(2) || !input.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..input.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&input[index..index + 2], 16).ok())
+        .collect()
+}
+
+fn parse_code(value: &Value) -> Result<BitBox, Error> {
+    value
+        .as_str()
+        .and_then(|code| {
+            code.chars()
+                .map(|c| match c {
+                    '0' => Some(false),
+                    '1' => Some(true),
+                    _ => None,
+                })
+                .collect::<Option<BitVec>>()
+        })
+        .map(BitVec::into_boxed_bitslice)
+        .ok_or(Error::Format("invalid code"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        huffman::{CodeOptions, Decoder},
+        Markov,
+    };
+    use proptest::prelude::*;
+    use test_strategy::proptest;
+
+    fn encoder(depth: usize, data: &[u8], escape: EscapeMode, eof: bool) -> Encoder {
+        let mut markov = Markov::new(depth);
+        markov.writer().write(data);
+        let options = CodeOptions {
+            escape,
+            eof,
+            ..Default::default()
+        };
+        Decoder::with_options(&markov, &options).encoder()
+    }
+
+    #[test]
+    fn test_fixture() {
+        let fixture = include_str!("../tests/fixtures/encoder.json");
+        let encoder = encoder(2, b"abracadabra", EscapeMode::Literal, false);
+        assert_eq!(encoder.to_json(), fixture.trim_end());
+        assert_eq!(Encoder::from_json(fixture).unwrap(), encoder);
+    }
+
+    #[proptest]
+    fn test_roundtrip(#[strategy(1usize..5)] depth: usize, data: Vec<u8>, escape: bool, eof: bool) {
+        let escape = if escape {
+            EscapeMode::Literal
+        } else {
+            EscapeMode::None
+        };
+        let encoder = encoder(depth, &data, escape, eof);
+        prop_assert_eq!(Encoder::from_json(&encoder.to_json()).unwrap(), encoder);
+    }
+
+    #[test]
+    fn test_not_prefix_free() {
+        let json = |codes: &str| {
+            format!(
+                r#"{{"depth": 2, "escape": "none", "eof": false, "contexts": {{"61": {{"codes": {codes}}}}}}}"#
+            )
+        };
+        assert!(Encoder::from_json(&json(r#"{"61": "0", "62": "1"}"#)).is_ok());
+        for codes in [
+            r#"{"61": "0", "62": "01"}"#,
+            r#"{"61": "10", "62": "10"}"#,
+            r#"{"61": "", "62": "1"}"#,
+        ] {
+            assert!(matches!(
+                Encoder::from_json(&json(codes)),
+                Err(Error::Format("codes are not prefix-free"))
+            ));
+        }
+        assert!(matches!(
+            Encoder::from_json(&json(r#"{"61": "2"}"#)),
+            Err(Error::Format("invalid code"))
+        ));
+        assert!(matches!(Encoder::from_json("{"), Err(Error::Json(_))));
+    }
+}
@@ -1,8 +1,30 @@
+pub mod alphabet;
+pub mod archive;
+pub mod builder;
+pub mod container;
+mod context_map;
+pub mod depth;
+pub mod error;
+pub mod filter;
+pub mod flat;
 pub mod huffman;
+pub mod incremental;
+#[cfg(feature = "serde_json")]
+mod json;
 pub mod markov;
-pub(crate) mod util;
+pub mod message;
+pub mod prelude;
+pub mod range;
+pub mod util;
 
 pub use self::{
-    huffman::{Decoder, Encoder},
+    builder::Builder,
+    container::{
+        compress_bytes, compress_with, decompress_bytes, decompress_with_limit,
+        decompress_with_model, MAGIC, MAX_SUPPORTED_DEPTH, VERSION as FORMAT_VERSION,
+    },
+    depth::{suggest_depth, suggest_depth_with, DepthEstimate, DepthSuggestion},
+    error::Error,
+    huffman::{Coder, Decoder, Encoder, EscapeMode},
     markov::Markov,
 };
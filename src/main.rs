@@ -1,13 +1,53 @@
-use anyhow::Result;
-use clap::Parser;
-use huffman_markov::Markov;
+use anyhow::{anyhow, Result};
+use clap::{CommandFactory, Parser};
+use clap_complete::Shell;
+#[cfg(feature = "compare")]
+use cli::compare::Reference;
+use cli::{
+    output::{temporary_path, OutputTarget},
+    render::hexdump,
+    report::{Outcome, Report},
+    watch,
+};
+use huffman_markov::{
+    archive::{ArchiveSummary, ArchiveWriter, EntryStats, ARCHIVE_OVERHEAD},
+    container::{
+        decompress_prefix_with_model, decompress_recover_with_model, decompress_stream_with_model,
+        BlockStats, Codec, DecodeLimits, DecodeStats, Header, Progress, DEFAULT_BLOCK_SIZE,
+        DEFAULT_MAX_LENGTH, MAGIC, MAX_SUPPORTED_DEPTH,
+    },
+    depth::DepthSuggestion,
+    error::UnsupportedFeature,
+    filter::BuiltinFilter,
+    huffman::{Coverage, EncodeMiss},
+    incremental::IncrementalTrainer,
+    markov::{
+        AugmentReport, ContextStats, ExportFormat, LeadingByteStats, QuantizeReport, Sampling,
+        TrainLimits, TrainStats, TrainSummary, DEFAULT_AUGMENT_CODE_LEN, MODEL_MAGIC,
+    },
+    suggest_depth_with,
+    util::{ByteHistogram, CancellationToken, HashingReader},
+    Builder, Coder, Error, EscapeMode, Markov,
+};
+use serde_json::{json, Value};
 use std::{
     fs::File,
-    io::{copy, stdout, Seek, SeekFrom, Write},
-    path::PathBuf,
+    io::{stdout, BufReader, Read, Result as IoResult, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
+mod cli;
+
 #[derive(Parser)]
+#[clap(
+    after_help = "Exit codes: 1 for other errors, 2 for invalid usage, 3 for input that is \
+too short, 4 for invalid or corrupted data, 5 for a block checksum mismatch and 6 for a model \
+that does not match. Commands interrupted by Ctrl-C exit with 130."
+)]
 pub struct Options {
     #[clap(flatten)]
     global: GlobalOptions,
@@ -17,72 +57,2030 @@ pub struct Options {
 }
 
 #[derive(Parser)]
-pub struct GlobalOptions {}
+pub struct GlobalOptions {
+    /// How to print errors, `text` or `json`.
+    #[clap(long, global = true, default_value = "text")]
+    error_format: ErrorFormat,
+    /// Print more details of what commands do on standard error.
+    #[clap(short, long, global = true)]
+    verbose: bool,
+    /// Print the result of the command as a single JSON document, on standard error if standard
+    /// output carries binary data.
+    #[clap(long, global = true)]
+    json: bool,
+    /// Set by Ctrl-C, long-running commands stop and clean up when it is.
+    #[clap(skip)]
+    cancel: CancellationToken,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            other => Err(format!(
+                "unknown error format {other:?}, expected text or json"
+            )),
+        }
+    }
+}
 
 #[derive(Parser)]
 pub enum Command {
     Markov(MarkovOptions),
+    Train(TrainOptions),
+    Model(ModelCommandOptions),
     Compress(CompressOptions),
+    Decompress(DecompressOptions),
+    Info(InfoOptions),
+    Stats(StatsOptions),
+    #[cfg(feature = "compare")]
+    Compare(CompareOptions),
+    Completions(CompletionsOptions),
+}
+
+/// Depth of the model, or `auto` to pick the one that compresses a sample of the input best.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepthArg {
+    Auto,
+    Fixed(usize),
+}
+
+impl FromStr for DepthArg {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "auto" => Ok(DepthArg::Auto),
+            depth => match depth.parse() {
+                Ok(0) => Err("depth must be at least 1".into()),
+                Ok(depth) if depth > MAX_SUPPORTED_DEPTH => Err(format!(
+                    "depth {depth} exceeds the maximum of {MAX_SUPPORTED_DEPTH}, longer contexts \
+                     are too sparse to model bytes with, consider modeling tokens instead"
+                )),
+                Ok(depth) => Ok(DepthArg::Fixed(depth)),
+                Err(_) => Err(format!(
+                    "invalid depth {depth:?}, expected a number or auto"
+                )),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for DepthArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DepthArg::Auto => write!(f, "auto"),
+            DepthArg::Fixed(depth) => write!(f, "{depth}"),
+        }
+    }
 }
 
+/// Training input whose windows count `weight` times, as `path:weight` or just a path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WeightedInput {
+    path: PathBuf,
+    weight: usize,
+}
+
+impl FromStr for WeightedInput {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        // paths can have colons, the weight is only what follows the last one if it is a number.
+        let (path, weight) = match input.rsplit_once(':') {
+            Some((path, weight)) if weight.parse::<usize>().is_ok() => {
+                (path, weight.parse().unwrap())
+            }
+            _ => (input, 1),
+        };
+        if weight == 0 {
+            return Err(format!("weight of {path:?} must be at least 1"));
+        }
+        Ok(WeightedInput {
+            path: path.into(),
+            weight,
+        })
+    }
+}
+
+// deepest model `--depth auto` considers, deeper ones rarely pay for their tables.
+const AUTO_MAX_DEPTH: usize = 6;
+
 #[derive(Parser)]
-pub struct MarkovOptions {
+pub struct ModelOptions {
+    /// Depth of the model, `auto` is only supported when compressing.
     #[clap(short, long, default_value = "4")]
-    depth: usize,
+    depth: DepthArg,
+    #[clap(long, default_value = "0")]
+    prune_below: usize,
+    #[clap(long)]
+    top_successors: Option<usize>,
+    #[clap(long)]
+    max_sequences: Option<usize>,
+    /// Approximate memory limit for the model, in bytes.
+    #[clap(long)]
+    max_memory: Option<usize>,
+    /// Train on about this fraction of the input, in chunks picked deterministically.
+    #[clap(long, conflicts_with = "sample_bytes")]
+    sample_rate: Option<f64>,
+    /// Train on about this many bytes of the input, in chunks picked deterministically.
+    #[clap(long)]
+    sample_bytes: Option<u64>,
+    /// Weight every window adds to the model, larger ones keep resolution when it is quantized.
+    #[clap(long, default_value_t = 1)]
+    increment: usize,
+}
+
+impl ModelOptions {
+    fn depth(&self) -> Result<usize, Error> {
+        match self.depth {
+            DepthArg::Fixed(depth) => Ok(depth),
+            DepthArg::Auto => Err(Error::Config(
+                "depth auto is only supported by compress".into(),
+            )),
+        }
+    }
+
+    // builder for an input of `len` bytes, which the sample size is relative to.
+    fn builder(&self, len: u64, global: &GlobalOptions) -> Result<Builder> {
+        self.builder_with_depth(self.depth()?, len, global)
+    }
+
+    fn builder_with_depth(
+        &self,
+        depth: usize,
+        len: u64,
+        global: &GlobalOptions,
+    ) -> Result<Builder> {
+        let builder = Builder::new()
+            .cancellation(global.cancel.clone())
+            .depth(depth)
+            .increment(self.increment)
+            .prune_below(self.prune_below)
+            .limits(TrainLimits {
+                max_sequences: self.max_sequences,
+                max_memory: self.max_memory,
+            });
+        let builder = match self.top_successors {
+            Some(k) => builder.top_successors(k),
+            None => builder,
+        };
+        let rate = match (self.sample_rate, self.sample_bytes) {
+            (Some(rate), _) => rate,
+            (None, Some(bytes)) => (bytes as f64 / len.max(1) as f64).min(1.0),
+            (None, None) => return Ok(builder),
+        };
+        Ok(builder.sample(Sampling::new(rate)?))
+    }
+
+    fn sampling(&self) -> bool {
+        self.sample_rate.is_some() || self.sample_bytes.is_some()
+    }
+
+    fn train(&self, file: &Path, global: &GlobalOptions) -> Result<(Markov, TrainStats)> {
+        let file = File::open(file)?;
+        let len = file.metadata()?.len();
+        let builder = self.builder(len, global)?;
+        let (markov, stats) = self.check_limits(builder.train_reader_stats(file))?;
+        if self.sampling() {
+            eprintln!("trained on {} of {len} bytes", stats.trained());
+        }
+        Ok((markov, stats))
+    }
+
+    fn check_limits<T>(&self, result: Result<T, Error>) -> Result<T> {
+        match result {
+            Err(error @ Error::LimitExceeded { .. }) => Err(anyhow!(
+                "{error} while training, try a depth lower than {}",
+                self.depth
+            )),
+            result => Ok(result?),
+        }
+    }
+}
+
+#[derive(Parser)]
+pub struct CoderOptions {
+    #[clap(long, default_value = "0")]
+    smoothing: usize,
+    #[clap(long, default_value = "15")]
+    max_code_length: u8,
+    #[clap(long, default_value = "none")]
+    escape: EscapeMode,
+    #[clap(long, default_value = "huffman")]
+    codec: Codec,
+    /// Only give codes to the bytes seen in training, for data with a small alphabet.
+    #[clap(long)]
+    restrict_alphabet: bool,
+}
+
+impl CoderOptions {
+    fn apply(&self, builder: Builder) -> Builder {
+        builder
+            .smoothing(self.smoothing)
+            .max_code_length(self.max_code_length)
+            .escape(self.escape)
+            .codec(self.codec)
+            .restrict_alphabet(self.restrict_alphabet)
+    }
+
+    // coder of a model file for `--model`, which compressing and decompressing have to build
+    // with the same options.
+    fn load_coder(&self, path: &Path) -> Result<Coder> {
+        let markov = Markov::load_file(path)?;
+        let builder = self.apply(Builder::new().depth(markov.len()));
+        Ok(builder.build_coder(&markov)?)
+    }
+}
+
+#[derive(Parser)]
+pub struct MarkovOptions {
+    #[clap(flatten)]
+    markov: ModelOptions,
     file: PathBuf,
 }
 
 pub trait Runnable {
-    fn run(&self, global: &GlobalOptions) -> Result<()>;
+    fn run(&self, global: &GlobalOptions) -> Result<Outcome>;
+}
+
+// size and fingerprint of a model, reported by the commands that make one.
+struct ModelSummary {
+    depth: usize,
+    sequences: usize,
+    contexts: usize,
+    fingerprint: u64,
+}
+
+impl ModelSummary {
+    fn new(markov: &Markov) -> Self {
+        ModelSummary {
+            depth: markov.len(),
+            sequences: markov.iter().count(),
+            contexts: markov.iter_prefix().count(),
+            fingerprint: markov.content_hash(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "depth": self.depth,
+            "sequences": self.sequences,
+            "contexts": self.contexts,
+            "fingerprint": format!("{:016x}", self.fingerprint),
+        })
+    }
+}
+
+struct MarkovReport {
+    debug: String,
+    model: ModelSummary,
+}
+
+impl Report for MarkovReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        writeln!(output, "{}", self.debug)
+    }
+
+    fn to_json(&self) -> Value {
+        self.model.to_json()
+    }
 }
 
 impl Runnable for MarkovOptions {
-    fn run(&self, global: &GlobalOptions) -> Result<()> {
-        let mut markov = Markov::new(self.depth);
-        let mut file = File::open(&self.file)?;
-        copy(&mut file, &mut markov.writer())?;
-        println!("{markov:?}");
+    fn run(&self, global: &GlobalOptions) -> Result<Outcome> {
+        let (markov, _) = self.markov.train(&self.file, global)?;
+        Ok(Outcome::listing(MarkovReport {
+            debug: format!("{markov:?}"),
+            model: ModelSummary::new(&markov),
+        }))
+    }
+}
+
+#[derive(Parser)]
+pub struct TrainOptions {
+    #[clap(flatten)]
+    markov: ModelOptions,
+    #[clap(short, long)]
+    model: PathBuf,
+    /// Overwrite the model file if it exists.
+    #[clap(short, long)]
+    force: bool,
+    #[clap(long)]
+    compact: bool,
+    /// Weigh windows so that their weight halves every this many bytes from the end of the input.
+    #[clap(long)]
+    recency_halflife: Option<u64>,
+    /// Rescale the weights to fit in this many bits, which shrinks the saved model.
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=64))]
+    quantize: Option<u8>,
+    /// Also train on this file, with its windows counting `weight` times, as `path:weight`.
+    /// Can be repeated, windows do not span two files.
+    #[clap(
+        long = "input",
+        value_name = "PATH:WEIGHT",
+        conflicts_with = "recency_halflife"
+    )]
+    inputs: Vec<WeightedInput>,
+    /// Keep training on the files of the input directory as they are written to, saving the
+    /// model after every change until interrupted.
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "inputs",
+            "recency_halflife",
+            "sample_rate",
+            "sample_bytes",
+            "prune_below",
+            "top_successors",
+            "max_sequences",
+            "max_memory",
+        ]
+    )]
+    watch: bool,
+    /// Milliseconds to wait for more changes before the model is saved, with `--watch`.
+    #[clap(long, default_value_t = 500, requires = "watch")]
+    debounce: u64,
+    #[clap(required_unless_present = "inputs")]
+    file: Option<PathBuf>,
+}
+
+// weight of the most recent window with recency weighting, older ones decay from it.
+const RECENCY_SCALE: f64 = 1024.0;
+
+impl TrainOptions {
+    // the input file, which is only missing when training on `--input` files.
+    fn file(&self) -> &Path {
+        self.file
+            .as_deref()
+            .expect("the file is required without inputs")
+    }
+
+    // trains on the input file, with what was trained on unless weighted by recency.
+    fn train(&self, global: &GlobalOptions) -> Result<(Markov, Option<TrainStats>)> {
+        let Some(halflife) = self.recency_halflife else {
+            let (markov, stats) = self.markov.train(self.file(), global)?;
+            return Ok((markov, Some(stats)));
+        };
+        if halflife == 0 {
+            return Err(anyhow!("recency halflife must be at least 1 byte"));
+        }
+
+        let data = std::fs::read(self.file())?;
+        let last = data.len().saturating_sub(self.markov.depth()?) as u64;
+        let weight = |index: u64| {
+            let age = (last - index) as f64 / halflife as f64;
+            let weight = ((RECENCY_SCALE * 0.5f64.powf(age)).round() as usize).max(1);
+            weight.saturating_mul(self.markov.increment)
+        };
+        let markov = self.markov.check_limits(
+            self.markov
+                .builder(data.len() as u64, global)?
+                .train_weighted(&data, weight),
+        )?;
+        Ok((markov, None))
+    }
+
+    // the input file, if any, followed by the `--input` files.
+    fn weighted_inputs(&self) -> Vec<WeightedInput> {
+        let file = self.file.iter().map(|path| WeightedInput {
+            path: path.clone(),
+            weight: 1,
+        });
+        file.chain(self.inputs.iter().cloned()).collect()
+    }
+
+    // trains on the inputs, returning the bytes read from each.
+    fn train_inputs(
+        &self,
+        inputs: &[WeightedInput],
+        global: &GlobalOptions,
+    ) -> Result<(Markov, TrainSummary)> {
+        let files = inputs
+            .iter()
+            .map(|input| Ok((BufReader::new(File::open(&input.path)?), input.weight)))
+            .collect::<IoResult<Vec<_>>>()?;
+        let mut len = 0;
+        for (file, _) in &files {
+            len += file.get_ref().metadata()?.len();
+        }
+
+        let builder = self.markov.builder(len, global)?;
+        self.markov.check_limits(builder.train_sources(files))
+    }
+
+    fn save(&self, markov: &mut Markov, force: bool) -> Result<Option<(u8, QuantizeReport)>> {
+        let quantized = self.quantize.map(|bits| (bits, markov.quantize(bits)));
+        let format = if self.compact {
+            ExportFormat::Compact
+        } else {
+            ExportFormat::Plain
+        };
+        OutputTarget::new(Some(&self.model), force)
+            .write_with(true, |output| Ok(markov.save(output, format)?))?;
+        Ok(quantized)
+    }
+
+    // trains on the files of the directory and then on what is written to them, printing a
+    // report every time the model is saved.
+    fn watch(&self, global: &GlobalOptions) -> Result<Outcome> {
+        let dir = self.file().canonicalize()?;
+        if !dir.is_dir() {
+            return Err(anyhow!("{} is not a directory", self.file().display()));
+        }
+        // the model may be in the directory, its own writes are not trained on.
+        let name = self
+            .model
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a file name", self.model.display()))?;
+        let parent = match self.model.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let model = parent.canonicalize()?.join(name);
+        let temporary = temporary_path(&model);
+        let ignore = |path: &Path| path == model || path == temporary;
+
+        let markov = Markov::new(self.markov.depth()?);
+        let mut trainer = IncrementalTrainer::new(markov).with_increment(self.markov.increment);
+        let mut saves = 0;
+        let update = |paths: Vec<PathBuf>| -> Result<()> {
+            let mut report = WatchReport::default();
+            for path in paths.iter().filter(|path| !ignore(path)) {
+                match trainer.update(path) {
+                    Ok(file) => {
+                        report.files += usize::from(file.bytes > 0);
+                        report.bytes += file.bytes;
+                        report.restarted += usize::from(file.restarted);
+                    }
+                    // files can be gone or unreadable by the time they are read.
+                    Err(Error::Io(error)) => {
+                        eprintln!("warning: skipping {}: {error}", path.display())
+                    }
+                    Err(error) => return Err(error.into()),
+                }
+            }
+            if report.bytes == 0 && saves > 0 {
+                return Ok(());
+            }
+
+            let mut markov = trainer.markov().clone();
+            self.save(&mut markov, self.force || saves > 0)?;
+            saves += 1;
+            report.model = Some(ModelSummary::new(&markov));
+            Outcome::summary(report, false).print(global.json)?;
+            Ok(())
+        };
+
+        watch::watch(
+            &dir,
+            Duration::from_millis(self.debounce),
+            &global.cancel,
+            ignore,
+            update,
+        )?;
+        Ok(Outcome::none())
+    }
+}
+
+struct TrainReport {
+    model: ModelSummary,
+    quantized: Option<(u8, QuantizeReport)>,
+    // bytes read from every file, with `--input`.
+    inputs: Vec<(WeightedInput, u64)>,
+    // missing with recency weighting.
+    stats: Option<TrainStats>,
+}
+
+impl Report for TrainReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        for (input, bytes) in &self.inputs {
+            writeln!(
+                output,
+                "trained on {bytes} bytes of {} with weight {}",
+                input.path.display(),
+                input.weight
+            )?;
+        }
+        if let Some(stats) = &self.stats {
+            writeln!(
+                output,
+                "read {} bytes, {} windows: {} new sequences, {} repeated windows",
+                stats.bytes, stats.windows, stats.new_sequences, stats.repeated_windows
+            )?;
+        }
+        if let Some((bits, report)) = &self.quantized {
+            writeln!(
+                output,
+                "quantized to {bits} bits, code lengths changed in {} of {} contexts",
+                report.changed, report.contexts
+            )?;
+        }
+        Ok(())
+    }
+
+    fn to_json(&self) -> Value {
+        let mut value = self.model.to_json();
+        value["quantized"] = match &self.quantized {
+            Some((bits, report)) => json!({
+                "bits": bits,
+                "changed": report.changed,
+                "contexts": report.contexts,
+            }),
+            None => Value::Null,
+        };
+        value["inputs"] = self
+            .inputs
+            .iter()
+            .map(|(input, bytes)| {
+                json!({
+                    "path": input.path.display().to_string(),
+                    "weight": input.weight,
+                    "bytes": bytes,
+                })
+            })
+            .collect();
+        value["stats"] = match &self.stats {
+            Some(stats) => json!({
+                "bytes": stats.bytes,
+                "windows": stats.windows,
+                "new_sequences": stats.new_sequences,
+                "repeated_windows": stats.repeated_windows,
+            }),
+            None => Value::Null,
+        };
+        value
+    }
+}
+
+// what one save of `train --watch` trained on.
+#[derive(Default)]
+struct WatchReport {
+    files: usize,
+    bytes: u64,
+    restarted: usize,
+    model: Option<ModelSummary>,
+}
+
+impl Report for WatchReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        write!(
+            output,
+            "trained on {} new bytes of {} files",
+            self.bytes, self.files
+        )?;
+        if self.restarted > 0 {
+            write!(output, ", {} of them rewritten", self.restarted)?;
+        }
+        if let Some(model) = &self.model {
+            write!(output, ", saved {} sequences", model.sequences)?;
+        }
+        writeln!(output)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "files": self.files,
+            "bytes": self.bytes,
+            "restarted": self.restarted,
+            "model": self.model.as_ref().map(ModelSummary::to_json),
+        })
+    }
+}
+
+impl Runnable for TrainOptions {
+    fn run(&self, global: &GlobalOptions) -> Result<Outcome> {
+        if self.watch {
+            return self.watch(global);
+        }
+        let (mut markov, inputs, stats) = match self.inputs.is_empty() {
+            true => {
+                let (markov, stats) = self.train(global)?;
+                (markov, vec![], stats)
+            }
+            false => {
+                let inputs = self.weighted_inputs();
+                let (markov, summary) = self.train_inputs(&inputs, global)?;
+                let inputs = inputs.into_iter().zip(summary.bytes).collect();
+                (markov, inputs, Some(summary.stats))
+            }
+        };
+        let quantized = self.save(&mut markov, self.force)?;
+        let report = TrainReport {
+            model: ModelSummary::new(&markov),
+            quantized,
+            inputs,
+            stats,
+        };
+        Ok(Outcome::summary(report, false))
+    }
+}
+
+/// Transform a model file, such as blending it with another model or patching it for new data,
+/// and save the result.
+#[derive(Parser)]
+pub struct ModelCommandOptions {
+    /// Blend the model with this one, see `--lambda`.
+    #[clap(long, requires = "lambda")]
+    interpolate: Option<PathBuf>,
+    /// Share of the input model in the blend, between 0 and 1.
+    #[clap(long, requires = "interpolate")]
+    lambda: Option<f64>,
+    /// Lowest weight of the sequences of either model after blending, 0 drops those that round
+    /// to zero.
+    #[clap(long, default_value = "0")]
+    min_weight: usize,
+    /// Patch the model to code this sample of new data without escaping.
+    #[clap(long)]
+    augment: Option<PathBuf>,
+    /// Fewest occurrences in the sample of a sequence the model is patched for.
+    #[clap(long, visible_alias = "min", default_value = "1")]
+    min_occurrences: usize,
+    /// Longest code the sequences of the sample are left with, longer ones are boosted.
+    #[clap(long, default_value_t = DEFAULT_AUGMENT_CODE_LEN)]
+    max_code_len: u8,
+    #[clap(short, long)]
+    output: PathBuf,
+    /// Overwrite the output file if it exists.
+    #[clap(short, long)]
+    force: bool,
+    #[clap(long)]
+    compact: bool,
+    model: PathBuf,
+}
+
+// the model command only prints what augmenting changed, besides its JSON document.
+struct ModelCommandReport {
+    model: ModelSummary,
+    augmented: Option<AugmentReport>,
+}
+
+impl Report for ModelCommandReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        if let Some(report) = &self.augmented {
+            writeln!(
+                output,
+                "augmented with {} new and {} boosted sequences, {} too rare to add",
+                report.added, report.boosted, report.skipped
+            )?;
+        }
         Ok(())
     }
+
+    fn to_json(&self) -> Value {
+        let mut value = self.model.to_json();
+        value["augmented"] = match &self.augmented {
+            Some(report) => json!({
+                "added": report.added,
+                "boosted": report.boosted,
+                "skipped": report.skipped,
+            }),
+            None => Value::Null,
+        };
+        value
+    }
+}
+
+impl Runnable for ModelCommandOptions {
+    fn run(&self, _global: &GlobalOptions) -> Result<Outcome> {
+        let load = |path: &Path| -> Result<Markov> { Ok(Markov::load_file(path)?) };
+        let mut markov = load(&self.model)?;
+        if let (Some(other), Some(lambda)) = (&self.interpolate, self.lambda) {
+            markov = Markov::interpolate_with_min_weight(
+                &markov,
+                &load(other)?,
+                lambda,
+                self.min_weight,
+            )?;
+        }
+        let augmented = match &self.augment {
+            Some(sample) => Some(markov.augment_from_with_max_code_len(
+                &std::fs::read(sample)?,
+                self.min_occurrences,
+                self.max_code_len,
+            )?),
+            None => None,
+        };
+
+        let format = if self.compact {
+            ExportFormat::Compact
+        } else {
+            ExportFormat::Plain
+        };
+        OutputTarget::new(Some(&self.output), self.force)
+            .write_with(true, |output| Ok(markov.save(output, format)?))?;
+        let report = ModelCommandReport {
+            model: ModelSummary::new(&markov),
+            augmented,
+        };
+        Ok(Outcome::summary(report, false))
+    }
 }
 
 #[derive(Parser)]
 pub struct CompressOptions {
-    #[clap(short, long, default_value = "4")]
+    #[clap(flatten)]
+    markov: ModelOptions,
+    #[clap(flatten)]
+    coder: CoderOptions,
+    /// Only report how many bytes the model cannot encode.
+    #[clap(long)]
+    check: bool,
+    /// Number of input bytes per block, each block is coded with its own model.
+    #[clap(long, default_value_t = DEFAULT_BLOCK_SIZE)]
+    block_size: usize,
+    /// Bytes from the start of the input that `--depth auto` picks the depth with, at most a
+    /// block.
+    #[clap(long, default_value_t = DEFAULT_BLOCK_SIZE)]
+    auto_sample: usize,
+    /// Store block checksums and a sync marker after every this many output bytes, so that
+    /// damaged files can be recovered in part.
+    #[clap(long)]
+    sync_interval: Option<usize>,
+    /// Filter the input before compressing, `delta:<stride>` or `transpose:<cols>`.
+    #[clap(long)]
+    filter: Option<BuiltinFilter>,
+    /// Code every block with this model file instead of one trained on the block. Blocks carry
+    /// no tables, decompressing needs the same model and coder options.
+    #[clap(long, value_name = "FILE")]
+    model: Option<PathBuf>,
+    /// Show the progress on standard error while compressing.
+    #[clap(long)]
+    progress: bool,
+    /// Write to this file instead of standard output.
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// Overwrite the output file if it exists, or write to a terminal.
+    #[clap(short, long)]
+    force: bool,
+    /// Order of the entries in the summary of several inputs, `ratio` puts the ones that
+    /// compress worst first, `size` the largest and `name` sorts them by name.
+    #[clap(long)]
+    sort: Option<EntrySort>,
+    /// Inputs to compress. Several are written into one archive, with `--depth auto` and
+    /// `--sample-bytes` applied to the first.
+    #[clap(required = true)]
+    files: Vec<PathBuf>,
+}
+
+/// Order of the entries in the summary of `compress` with several inputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntrySort {
+    Ratio,
+    Size,
+    Name,
+}
+
+impl FromStr for EntrySort {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "ratio" => Ok(EntrySort::Ratio),
+            "size" => Ok(EntrySort::Size),
+            "name" => Ok(EntrySort::Name),
+            other => Err(format!(
+                "unknown sort order {other:?}, expected ratio, size or name"
+            )),
+        }
+    }
+}
+
+impl EntrySort {
+    fn sort(self, entries: &mut [EntryStats]) {
+        match self {
+            // empty entries have no ratio and go last.
+            EntrySort::Ratio => entries.sort_by(|a, b| {
+                let ratio = |entry: &EntryStats| entry.ratio().unwrap_or(f64::NEG_INFINITY);
+                ratio(b).total_cmp(&ratio(a))
+            }),
+            EntrySort::Size => entries.sort_by_key(|entry| std::cmp::Reverse(entry.original_bytes)),
+            EntrySort::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+    }
+}
+
+impl CompressOptions {
+    // the builder for `data` with every option but the external model, and the depth it chose.
+    fn builder(
+        &self,
+        data: &[u8],
+        model: Option<&Coder>,
+        global: &GlobalOptions,
+    ) -> Result<(Builder, usize)> {
+        // the depth is chosen last, with all other options of the builder.
+        let mut builder = self
+            .coder
+            .apply(
+                self.markov
+                    .builder_with_depth(1, data.len() as u64, global)?,
+            )
+            .block_size(self.block_size);
+        if let Some(interval) = self.sync_interval {
+            builder = builder.sync_interval(interval);
+        }
+        if let Some(filter) = self.filter {
+            builder = builder.filter(filter);
+        }
+        let depth = match model {
+            Some(coder) => coder.depth(),
+            None => self.depth(data, &builder, global)?,
+        };
+        Ok((builder.depth(depth), depth))
+    }
+
+    fn show_progress(&self, progress: Progress) {
+        if self.progress {
+            let percent = 100.0 * progress.bytes_done as f64
+                / progress.bytes_total.unwrap_or(1).max(1) as f64;
+            eprint!("\r{:<14} {percent:5.1}%", progress.phase);
+        }
+    }
+
+    fn load_model(&self) -> Result<Option<Coder>> {
+        match &self.model {
+            Some(path) => Ok(Some(self.coder.load_coder(path)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn run_archive(&self, global: &GlobalOptions) -> Result<Outcome> {
+        if self.check {
+            return Err(Error::Config("--check takes a single input".into()).into());
+        }
+        let start = Instant::now();
+        let inputs = self
+            .files
+            .iter()
+            .map(|path| Ok((path.display().to_string(), std::fs::read(path)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let model = self.load_model()?;
+        let (mut builder, _) = self.builder(&inputs[0].1, model.as_ref(), global)?;
+        if let Some(coder) = model {
+            builder = builder.external_model(coder);
+        }
+
+        let mut archive = vec![];
+        let mut writer = ArchiveWriter::new(&mut archive, builder)?;
+        for (name, data) in &inputs {
+            writer.add_with_progress(name, data, |progress| self.show_progress(progress))?;
+        }
+        let mut summary = writer.finish()?;
+        if self.progress {
+            eprintln!();
+        }
+        OutputTarget::new(self.output.as_deref(), self.force)
+            .write_with(true, |output| Ok(output.write_all(&archive)?))?;
+        if let Some(sort) = self.sort {
+            sort.sort(&mut summary.per_entry);
+        }
+        let report = ArchiveReport {
+            summary,
+            elapsed_ms: start.elapsed().as_millis(),
+        };
+        Ok(Outcome::summary(report, self.output.is_none()))
+    }
+
+    fn depth(&self, data: &[u8], builder: &Builder, global: &GlobalOptions) -> Result<usize> {
+        if self.markov.depth != DepthArg::Auto {
+            return Ok(self.markov.depth()?);
+        }
+
+        // blocks are coded with models of their own, so a sample of a block is representative.
+        let sample = &data[..data.len().min(self.auto_sample.min(self.block_size))];
+        let suggestion = suggest_depth_with(sample, AUTO_MAX_DEPTH, builder)?;
+        if global.verbose {
+            print_depth_suggestion(&suggestion, sample.len());
+        }
+        Ok(suggestion.depth)
+    }
+
+    fn check(&self, coder: &Coder, data: &[u8]) -> Result<CheckReport> {
+        // the model is checked as the decompressor sees it, from the tables it would be written
+        // with rather than the one that was built.
+        let mut tables = vec![];
+        coder.write_tables(&mut tables)?;
+        let coder = Coder::read_tables(&mut &tables[..])?;
+        let mut misses = coder.validate(data).err().unwrap_or_default();
+        misses.truncate(CHECK_MISSES);
+        Ok(CheckReport {
+            input_bytes: data.len(),
+            missed_bytes: coder.miss_count(data),
+            coverage: coder.coverage(data),
+            contexts: coder.context_count(),
+            misses,
+        })
+    }
+}
+
+// bytes `compress --check` lists of the ones the model cannot encode.
+const CHECK_MISSES: usize = 10;
+
+struct CheckReport {
+    input_bytes: usize,
+    missed_bytes: usize,
+    coverage: Coverage,
+    contexts: usize,
+    // the first few of the bytes that cannot be encoded.
+    misses: Vec<EncodeMiss>,
+}
+
+impl Report for CheckReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        writeln!(
+            output,
+            "{} of {} bytes ({:.2}%) cannot be encoded by the model",
+            self.missed_bytes,
+            self.input_bytes,
+            100.0 * self.missed_bytes as f64 / self.input_bytes.max(1) as f64
+        )?;
+        let coverage = &self.coverage;
+        let percent = |count: u64| 100.0 * count as f64 / coverage.windows.max(1) as f64;
+        writeln!(
+            output,
+            "coverage of {} windows: {:.2}% coded, {:.2}% missing the context, \
+            {:.2}% missing the byte after it, {} contexts in the model",
+            coverage.windows,
+            100.0 * coverage.covered_fraction(),
+            percent(coverage.missing_context),
+            percent(coverage.missing_symbol),
+            self.contexts
+        )?;
+        for miss in &self.misses {
+            writeln!(
+                output,
+                "  offset {}: byte {:#04x} after context {:02x?}",
+                miss.offset, miss.byte, miss.context
+            )?;
+        }
+        Ok(())
+    }
+
+    fn to_json(&self) -> Value {
+        let misses: Vec<Value> = self
+            .misses
+            .iter()
+            .map(|miss| {
+                json!({
+                    "offset": miss.offset,
+                    "byte": miss.byte,
+                    "context": hex(&miss.context),
+                })
+            })
+            .collect();
+        json!({
+            "input_bytes": self.input_bytes,
+            "missed_bytes": self.missed_bytes,
+            "windows": self.coverage.windows,
+            "missing_context": self.coverage.missing_context,
+            "missing_symbol": self.coverage.missing_symbol,
+            "contexts": self.contexts,
+            "misses": misses,
+        })
+    }
+}
+
+struct CompressReport {
+    input_bytes: usize,
+    output_bytes: usize,
     depth: usize,
-    file: PathBuf,
+    elapsed_ms: u128,
+    input_xxh3: u64,
+    stats: BlockStats,
+}
+
+impl Report for CompressReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        let (stats, writer) = (&self.stats, &self.stats.writer);
+        writeln!(
+            output,
+            "{} to {} bytes, {} blocks coded, {} stored uncompressed, {} bits for {} coded bytes, \
+            {} escapes, longest code {} bits, trained on {} bytes",
+            self.input_bytes,
+            self.output_bytes,
+            stats.coded,
+            stats.stored,
+            writer.output_bits,
+            writer.input_bytes,
+            writer.escapes,
+            writer.max_code_len_seen,
+            stats.trained_bytes
+        )
+    }
+
+    fn to_json(&self) -> Value {
+        let (stats, writer) = (&self.stats, &self.stats.writer);
+        // compressed size as a fraction of the input, none for an empty one.
+        let ratio =
+            (self.input_bytes > 0).then(|| self.output_bytes as f64 / self.input_bytes as f64);
+        json!({
+            "input_bytes": self.input_bytes,
+            "output_bytes": self.output_bytes,
+            "ratio": ratio,
+            "depth": self.depth,
+            "elapsed_ms": self.elapsed_ms,
+            "model_fingerprint": stats.model_fingerprint.map(|hash| format!("{hash:016x}")),
+            "blocks_coded": stats.coded,
+            "blocks_stored": stats.stored,
+            "coded_input_bytes": writer.input_bytes,
+            "output_bits": writer.output_bits,
+            "escapes": writer.escapes,
+            "max_code_len_seen": writer.max_code_len_seen,
+            "input_xxh3": format!("{:016x}", self.input_xxh3),
+            "trained_bytes": stats.trained_bytes,
+        })
+    }
 }
 
 impl Runnable for CompressOptions {
-    fn run(&self, global: &GlobalOptions) -> Result<()> {
-        let mut markov = Markov::new(self.depth);
-        let mut file = File::open(&self.file)?;
-        copy(&mut file, &mut markov.writer())?;
+    fn run(&self, global: &GlobalOptions) -> Result<Outcome> {
+        let [file] = &self.files[..] else {
+            return self.run_archive(global);
+        };
+        if self.sort.is_some() {
+            return Err(Error::Config("--sort orders the entries of several inputs".into()).into());
+        }
+        let start = Instant::now();
+        // the input is hashed as it is read, for the summary.
+        let mut input = HashingReader::new(File::open(file)?);
+        let mut data = vec![];
+        input.read_to_end(&mut data)?;
+        let model = self.load_model()?;
+        let (mut builder, depth) = self.builder(&data, model.as_ref(), global)?;
+        if self.check {
+            let coder = match model {
+                Some(coder) => coder,
+                None => builder.build_coder(&builder.train(&data)?)?,
+            };
+            return Ok(Outcome::summary(self.check(&coder, &data)?, false));
+        }
+        if let Some(coder) = model {
+            builder = builder.external_model(coder);
+        }
+
+        if data.len() < depth {
+            eprintln!(
+                "note: input is {} bytes, shorter than depth {depth}, storing it uncompressed",
+                data.len(),
+            );
+        }
+
+        let (compressed, stats) =
+            builder.compress_with_progress(&data, |progress| self.show_progress(progress))?;
+        if self.progress {
+            eprintln!();
+        }
+        OutputTarget::new(self.output.as_deref(), self.force)
+            .write_with(true, |output| Ok(output.write_all(&compressed)?))?;
+        let report = CompressReport {
+            input_bytes: data.len(),
+            output_bytes: compressed.len(),
+            depth,
+            elapsed_ms: start.elapsed().as_millis(),
+            input_xxh3: input.digest(),
+            stats,
+        };
+        Ok(Outcome::summary(report, self.output.is_none()))
+    }
+}
+
+struct ArchiveReport {
+    summary: ArchiveSummary,
+    elapsed_ms: u128,
+}
+
+impl Report for ArchiveReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        let summary = &self.summary;
+        let width = summary
+            .per_entry
+            .iter()
+            .map(|entry| entry.name.len())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or_default();
+        let ratio = |ratio: Option<f64>| match ratio {
+            Some(ratio) => format!("{ratio:.3}"),
+            None => "-".into(),
+        };
+        writeln!(
+            output,
+            "{:<width$}  {:>10}  {:>10}  {:>6}  {:>8}  stored",
+            "name", "original", "compressed", "ratio", "escapes"
+        )?;
+        for entry in &summary.per_entry {
+            writeln!(
+                output,
+                "{:<width$}  {:>10}  {:>10}  {:>6}  {:>8}  {}",
+                entry.name,
+                entry.original_bytes,
+                entry.compressed_bytes,
+                ratio(entry.ratio()),
+                entry.escapes,
+                if entry.stored { "yes" } else { "no" }
+            )?;
+        }
+        let total = (summary.original_bytes > 0)
+            .then(|| summary.compressed_bytes as f64 / summary.original_bytes as f64);
+        writeln!(
+            output,
+            "{:<width$}  {:>10}  {:>10}  {:>6}  {:>8}",
+            "total",
+            summary.original_bytes,
+            summary.compressed_bytes,
+            ratio(total),
+            summary.escapes
+        )
+    }
+
+    fn to_json(&self) -> Value {
+        let summary = &self.summary;
+        let entries: Vec<Value> = summary
+            .per_entry
+            .iter()
+            .map(|entry| {
+                json!({
+                    "name": entry.name,
+                    "original_bytes": entry.original_bytes,
+                    "compressed_bytes": entry.compressed_bytes,
+                    "ratio": entry.ratio(),
+                    "escapes": entry.escapes,
+                    "stored": entry.stored,
+                })
+            })
+            .collect();
+        json!({
+            "original_bytes": summary.original_bytes,
+            "compressed_bytes": summary.compressed_bytes,
+            "output_bytes": summary.compressed_bytes + ARCHIVE_OVERHEAD,
+            "escapes": summary.escapes,
+            "elapsed_ms": self.elapsed_ms,
+            "entries": entries,
+        })
+    }
+}
+
+#[derive(Parser)]
+pub struct DecompressOptions {
+    /// Skip damaged blocks instead of failing, writing zeros in their place.
+    #[clap(long)]
+    recover: bool,
+    /// Write to this file instead of standard output.
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// Overwrite the output file if it exists, or write to a terminal.
+    #[clap(short, long)]
+    force: bool,
+    /// Fail before writing anything if the data is longer than this many bytes.
+    #[clap(long, value_name = "BYTES")]
+    max_output: Option<u64>,
+    /// Print the header and a hexdump of the first bytes instead of writing the output,
+    /// without decoding any further.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "256",
+        conflicts_with_all = ["recover", "output"]
+    )]
+    inspect: Option<usize>,
+    /// Model file the data was compressed with by `compress --model`.
+    #[clap(long, value_name = "FILE")]
+    model: Option<PathBuf>,
+    /// Options the model was compressed with, its coder has to be built the same way.
+    #[clap(flatten)]
+    coder: CoderOptions,
+    file: PathBuf,
+}
+
+struct InspectReport {
+    header: Header,
+    preview: Vec<u8>,
+}
+
+impl Report for InspectReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        write_header(output, &self.header)?;
+        writeln!(
+            output,
+            "first {} of {} bytes:",
+            self.preview.len(),
+            self.header.length
+        )?;
+        write!(output, "{}", hexdump(&self.preview, 0))
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "header": header_json(&self.header),
+            "preview": hex(&self.preview),
+        })
+    }
+}
+
+// decompressing prints nothing but its JSON document.
+struct DecompressReport {
+    checksum: Option<&'static str>,
+    stats: DecodeStats,
+    elapsed_ms: u128,
+}
+
+impl Report for DecompressReport {
+    fn write_text(&self, _output: &mut dyn Write) -> IoResult<()> {
+        Ok(())
+    }
+
+    fn to_json(&self) -> Value {
+        // every block was checked against its checksum, if there are any.
+        let verified_blocks = match self.checksum {
+            Some(_) => self.stats.blocks,
+            None => 0,
+        };
+        json!({
+            "input_bytes": self.stats.input_bytes,
+            "output_bytes": self.stats.output_bytes,
+            "blocks": self.stats.blocks,
+            "checksum": self.checksum,
+            "verified_blocks": verified_blocks,
+            "elapsed_ms": self.elapsed_ms,
+        })
+    }
+}
 
-        let encoder = markov.encoder();
-        file.seek(SeekFrom::Start(0))?;
-        copy(&mut file, &mut encoder.writer(stdout()))?;
+struct RecoverReport {
+    output_bytes: usize,
+    lost: Vec<Range<u64>>,
+}
 
+impl Report for RecoverReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        for range in &self.lost {
+            writeln!(
+                output,
+                "lost bytes {}..{} ({} bytes)",
+                range.start,
+                range.end,
+                range.end - range.start
+            )?;
+        }
         Ok(())
     }
+
+    fn to_json(&self) -> Value {
+        let lost: Vec<Value> = self
+            .lost
+            .iter()
+            .map(|range| json!({"start": range.start, "end": range.end}))
+            .collect();
+        json!({
+            "output_bytes": self.output_bytes,
+            "lost_bytes": self.lost.iter().map(|range| range.end - range.start).sum::<u64>(),
+            "lost": lost,
+        })
+    }
+}
+
+impl Runnable for DecompressOptions {
+    fn run(&self, global: &GlobalOptions) -> Result<Outcome> {
+        let model = match &self.model {
+            Some(path) => Some(self.coder.load_coder(path)?),
+            None => None,
+        };
+        let model = model.as_ref();
+        if let Some(len) = self.inspect {
+            let reader = BufReader::new(File::open(&self.file)?);
+            let (header, preview) = decompress_prefix_with_model(reader, len, model)?;
+            return Ok(Outcome::listing(InspectReport { header, preview }));
+        }
+
+        let start = Instant::now();
+        let target = OutputTarget::new(self.output.as_deref(), self.force);
+        let binary_stdout = self.output.is_none();
+        if !self.recover {
+            // blocks are written as they are decoded, the input is never held whole.
+            let reader = BufReader::new(File::open(&self.file)?);
+            let limits = DecodeLimits {
+                max_output: self.max_output,
+                ..Default::default()
+            };
+            let (mut checksum, mut stats) = (None, DecodeStats::default());
+            target.write_with(true, |output| {
+                // the header is read again for the report, the stream does not return it.
+                checksum = Header::read(File::open(&self.file)?)?.checksum();
+                stats =
+                    decompress_stream_with_model(reader, output, &limits, &global.cancel, model)?;
+                Ok(())
+            })?;
+            let report = DecompressReport {
+                checksum,
+                stats,
+                elapsed_ms: start.elapsed().as_millis(),
+            };
+            return Ok(Outcome::summary(report, binary_stdout));
+        }
+
+        let data = std::fs::read(&self.file)?;
+        let max_length = self.max_output.unwrap_or(DEFAULT_MAX_LENGTH);
+        let recovered = decompress_recover_with_model(&data, max_length, model)?;
+        target.write_with(true, |output| Ok(output.write_all(&recovered.data)?))?;
+        let report = RecoverReport {
+            output_bytes: recovered.data.len(),
+            lost: recovered.lost,
+        };
+        Ok(Outcome::summary(report, binary_stdout))
+    }
+}
+
+/// Print the header of a compressed file or a summary of a model file.
+#[derive(Parser)]
+pub struct InfoOptions {
+    file: PathBuf,
+}
+
+enum InfoReport {
+    Compressed(Box<Header>),
+    Model {
+        model: ModelSummary,
+        stats: ContextStats,
+        fanout: Box<[usize; 257]>,
+    },
+}
+
+impl Report for InfoReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        let (model, stats, fanout) = match self {
+            InfoReport::Compressed(header) => return write_header(output, header),
+            InfoReport::Model {
+                model,
+                stats,
+                fanout,
+            } => (model, stats, fanout),
+        };
+        writeln!(output, "model file")?;
+        writeln!(output, "depth: {}", model.depth)?;
+        writeln!(output, "sequences: {}", model.sequences)?;
+        writeln!(output, "contexts: {}", stats.contexts)?;
+        writeln!(
+            output,
+            "deterministic contexts: {} ({:.1}% of weight)",
+            stats.deterministic,
+            100.0 * stats.deterministic_fraction()
+        )?;
+        write_fanout(output, fanout)?;
+        writeln!(output, "fingerprint: {:016x}", model.fingerprint)
+    }
+
+    fn to_json(&self) -> Value {
+        let (model, stats, fanout) = match self {
+            InfoReport::Compressed(header) => {
+                let mut value = header_json(header);
+                value["kind"] = "compressed".into();
+                return value;
+            }
+            InfoReport::Model {
+                model,
+                stats,
+                fanout,
+            } => (model, stats, fanout),
+        };
+        let mut value = model.to_json();
+        value["kind"] = "model".into();
+        value["deterministic_contexts"] = stats.deterministic.into();
+        value["deterministic_fraction"] = stats.deterministic_fraction().into();
+        value["fanout"] = fanout_json(fanout);
+        value
+    }
+}
+
+impl Runnable for InfoOptions {
+    fn run(&self, _global: &GlobalOptions) -> Result<Outcome> {
+        let mut reader = BufReader::new(File::open(&self.file)?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).map_err(Error::from)?;
+        let reader = (&magic[..]).chain(reader);
+        let report = if magic == MAGIC {
+            InfoReport::Compressed(Box::new(Header::read(reader)?))
+        } else if magic == MODEL_MAGIC {
+            let markov = Markov::load(reader)?;
+            InfoReport::Model {
+                model: ModelSummary::new(&markov),
+                stats: markov.context_stats(),
+                fanout: Box::new(markov.fanout_histogram()),
+            }
+        } else {
+            return Err(anyhow!(
+                "{} is not a compressed or model file",
+                self.file.display()
+            ));
+        };
+        Ok(Outcome::listing(report))
+    }
+}
+
+/// Print how much the contexts of a model of a file help predicting it.
+#[derive(Parser)]
+pub struct StatsOptions {
+    #[clap(flatten)]
+    markov: ModelOptions,
+    /// Also list this many of the contexts with the most training weight.
+    #[clap(long, default_value_t = 0)]
+    top_contexts: usize,
+    file: PathBuf,
+}
+
+struct StatsReport {
+    order: usize,
+    order0_entropy: f64,
+    conditional_entropy: f64,
+    gain: f64,
+    leading: Box<[LeadingByteStats; 256]>,
+    top: Vec<(Box<[u8]>, u64)>,
+    stats: ContextStats,
+    fanout: Box<[usize; 257]>,
+}
+
+impl Report for StatsReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        writeln!(
+            output,
+            "order-0 entropy: {:.3} bpb, order-{} conditional entropy: {:.3} bpb, gain: {:.1}%",
+            self.order0_entropy, self.order, self.conditional_entropy, self.gain
+        )?;
+        writeln!(
+            output,
+            "deterministic contexts: {} ({:.1}% of weight)",
+            self.stats.deterministic,
+            100.0 * self.stats.deterministic_fraction()
+        )?;
+        write_fanout(output, &self.fanout)?;
+        if self.order > 0 {
+            write_heatmap(output, &self.leading)?;
+        }
+        if !self.top.is_empty() {
+            writeln!(output, "heaviest contexts:")?;
+            for (context, weight) in &self.top {
+                writeln!(
+                    output,
+                    "  {:<w$} {weight}",
+                    hex(context),
+                    w = 2 * self.order
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn to_json(&self) -> Value {
+        let bytes: Vec<Value> = self
+            .leading
+            .iter()
+            .enumerate()
+            .filter(|(_, stats)| stats.weight > 0)
+            .map(|(byte, stats)| {
+                json!({
+                    "byte": byte,
+                    "weight": stats.weight,
+                    "entropy": rounded(stats.entropy, 6),
+                })
+            })
+            .collect();
+        let top: Vec<Value> = self
+            .top
+            .iter()
+            .map(|(context, weight)| json!({"context": hex(context), "weight": weight}))
+            .collect();
+        json!({
+            "order": self.order,
+            "order0_entropy": rounded(self.order0_entropy, 6),
+            "conditional_entropy": rounded(self.conditional_entropy, 6),
+            "gain": rounded(self.gain, 3),
+            "leading_bytes": bytes,
+            "top_contexts": top,
+            "deterministic_contexts": self.stats.deterministic,
+            "deterministic_fraction": self.stats.deterministic_fraction(),
+            "fanout": fanout_json(&self.fanout),
+        })
+    }
+}
+
+// `value` rounded to `digits` decimals, so that JSON documents don't carry float noise.
+fn rounded(value: f64, digits: i32) -> f64 {
+    let scale = 10f64.powi(digits);
+    (value * scale).round() / scale
+}
+
+impl Runnable for StatsOptions {
+    fn run(&self, global: &GlobalOptions) -> Result<Outcome> {
+        let file = File::open(&self.file)?;
+        let builder = self.markov.builder(file.metadata()?.len(), global)?;
+        // the histogram sees the input as it is read for training.
+        let mut histogram = ByteHistogram::new();
+        let reader = Tee {
+            reader: BufReader::new(file),
+            copy: &mut histogram,
+        };
+        let (markov, _) = self
+            .markov
+            .check_limits(builder.train_reader_counted(reader))?;
+
+        let order0 = histogram.entropy();
+        let conditional = markov.conditional_entropy();
+        let gain = if order0 > 0.0 {
+            100.0 * (order0 - conditional) / order0
+        } else {
+            0.0
+        };
+        Ok(Outcome::listing(StatsReport {
+            order: markov.len() - 1,
+            order0_entropy: order0,
+            conditional_entropy: conditional,
+            gain,
+            leading: Box::new(markov.entropy_by_leading_byte()),
+            top: markov.top_contexts(self.top_contexts),
+            stats: markov.context_stats(),
+            fanout: Box::new(markov.fanout_histogram()),
+        }))
+    }
+}
+
+fn write_header(output: &mut dyn Write, header: &Header) -> IoResult<()> {
+    writeln!(output, "compressed file, version {}", header.version)?;
+    writeln!(output, "depth: {}", header.depth)?;
+    writeln!(output, "codec: {}", header.codec)?;
+    writeln!(output, "length: {} bytes", header.length)?;
+    writeln!(output, "block size: {} bytes", header.block_size)?;
+    writeln!(output, "checksum: {}", header.checksum().unwrap_or("none"))?;
+    if let Some(filter) = header.filter {
+        writeln!(output, "filter: {filter}")?;
+    }
+    if header.byte_map.is_some() {
+        writeln!(output, "byte map: stored, undone when decompressing")?;
+    }
+    if let Some(fingerprint) = header.model_fingerprint {
+        writeln!(output, "model: external, fingerprint {fingerprint:016x}")?;
+    }
+    if header.literal() {
+        writeln!(output, "stored uncompressed, shorter than the depth")?;
+    }
+    Ok(())
+}
+
+fn header_json(header: &Header) -> Value {
+    json!({
+        "version": header.version,
+        "depth": header.depth,
+        "codec": header.codec.to_string(),
+        "length": header.length,
+        "block_size": header.block_size,
+        "checksum": header.checksum(),
+        "filter": header.filter.map(|filter| filter.to_string()),
+        "byte_map": header.byte_map.is_some(),
+        "literal": header.literal(),
+        "model_fingerprint": header.model_fingerprint.map(|fingerprint| format!("{fingerprint:016x}")),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// shades of the heatmap from no uncertainty to eight or more bits per byte.
+const HEATMAP_SHADES: &[u8] = b".:-=+*#%@";
+
+// writes the conditional entropy of the contexts by their first byte, in rows of the high nibble
+// and columns of the low one. bytes that start no context are blank.
+fn write_heatmap(output: &mut dyn Write, leading: &[LeadingByteStats; 256]) -> IoResult<()> {
+    writeln!(output, "conditional entropy by leading byte:")?;
+    writeln!(output, "   0123456789abcdef")?;
+    for (high, row) in leading.chunks(16).enumerate() {
+        let cells: String = row
+            .iter()
+            .map(|stats| {
+                if stats.weight == 0 {
+                    return ' ';
+                }
+                let last = HEATMAP_SHADES.len() - 1;
+                let level = (stats.entropy / 8.0 * last as f64).round() as usize;
+                char::from(HEATMAP_SHADES[level.min(last)])
+            })
+            .collect();
+        writeln!(output, "{high:x}_ {cells}")?;
+    }
+    writeln!(
+        output,
+        "   {} = 0 to 8 bits per byte",
+        std::str::from_utf8(HEATMAP_SHADES).unwrap()
+    )
+}
+
+// prints the estimated size of a sample compressed at every depth `--depth auto` considered.
+fn print_depth_suggestion(suggestion: &DepthSuggestion, sample: usize) {
+    eprintln!("depth estimates for a sample of {sample} bytes:");
+    eprintln!("depth  contexts  tables bytes  code bytes  total bytes");
+    for estimate in &suggestion.estimates {
+        eprintln!(
+            "{:>5}  {:>8}  {:>12}  {:>10}  {:>11}",
+            estimate.depth,
+            estimate.contexts,
+            estimate.table_bytes,
+            estimate.code_bytes,
+            estimate.total_bytes
+        );
+    }
+    eprintln!("chose depth {}", suggestion.depth);
+}
+
+// reader that copies everything read from it into another writer.
+struct Tee<R, W> {
+    reader: R,
+    copy: W,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let count = self.reader.read(buf)?;
+        self.copy.write_all(&buf[..count])?;
+        Ok(count)
+    }
+}
+
+// number of contexts of every fan-out that occurs.
+fn fanout_json(histogram: &[usize; 257]) -> Value {
+    histogram
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(fanout, count)| json!({"fanout": fanout, "contexts": count}))
+        .collect()
+}
+
+// writes the range and mean of the fan-out, and the number of contexts in buckets of powers of
+// two, which is enough to tell predictive contexts from ones that are followed by anything.
+fn write_fanout(output: &mut dyn Write, histogram: &[usize; 257]) -> IoResult<()> {
+    let contexts: usize = histogram.iter().sum();
+    let used = || {
+        histogram
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+    };
+    let (Some((min, _)), Some((max, _))) = (used().next(), used().next_back()) else {
+        return Ok(());
+    };
+    let successors: usize = used().map(|(fanout, count)| fanout * count).sum();
+    writeln!(
+        output,
+        "fan-out: min {min}, max {max}, mean {:.2}",
+        successors as f64 / contexts as f64
+    )?;
+    let mut buckets = vec![];
+    let mut low = 1;
+    while low <= 256 {
+        let high = (2 * low - 1).min(256);
+        let count: usize = histogram[low..=high].iter().sum();
+        if count > 0 {
+            if low == high {
+                buckets.push(format!("{low}:{count}"));
+            } else {
+                buckets.push(format!("{low}-{high}:{count}"));
+            }
+        }
+        low *= 2;
+    }
+    writeln!(output, "fan-out histogram: {}", buckets.join(" "))
+}
+
+/// Compare the compressed size and speed of models of several depths with general purpose
+/// codecs.
+#[cfg(feature = "compare")]
+#[derive(Parser)]
+pub struct CompareOptions {
+    #[clap(flatten)]
+    coder: CoderOptions,
+    /// Depths of the models to compress with.
+    #[clap(long, value_delimiter = ',', default_value = "2,3,4")]
+    depths: Vec<usize>,
+    /// Codecs to compare with, `gzip` or `zstd`, at their default levels.
+    #[clap(long, value_delimiter = ',', default_value = "gzip,zstd")]
+    against: Vec<Reference>,
+    /// Number of input bytes per block, each block is coded with its own model.
+    #[clap(long, default_value_t = DEFAULT_BLOCK_SIZE)]
+    block_size: usize,
+    file: PathBuf,
+}
+
+// outcome of compressing the input one way, for `compare`.
+#[cfg(feature = "compare")]
+struct Comparison {
+    codec: String,
+    // none for the reference codecs.
+    depth: Option<usize>,
+    output_bytes: usize,
+    elapsed: Duration,
+}
+
+#[cfg(feature = "compare")]
+struct CompareReport {
+    input_bytes: usize,
+    comparisons: Vec<Comparison>,
+}
+
+#[cfg(feature = "compare")]
+impl CompareReport {
+    // compressed size as a fraction of the input, none for an empty one.
+    fn ratio(&self, comparison: &Comparison) -> Option<f64> {
+        (self.input_bytes > 0).then(|| comparison.output_bytes as f64 / self.input_bytes as f64)
+    }
+}
+
+#[cfg(feature = "compare")]
+impl Report for CompareReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        writeln!(
+            output,
+            "{:<8} {:>5} {:>12} {:>7} {:>10}",
+            "codec", "depth", "bytes", "ratio", "time"
+        )?;
+        for comparison in &self.comparisons {
+            let depth = comparison.depth.map(|depth| depth.to_string());
+            let ratio = self.ratio(comparison).map(|ratio| format!("{ratio:.3}"));
+            writeln!(
+                output,
+                "{:<8} {:>5} {:>12} {:>7} {:>7.1} ms",
+                comparison.codec,
+                depth.as_deref().unwrap_or("-"),
+                comparison.output_bytes,
+                ratio.as_deref().unwrap_or("-"),
+                comparison.elapsed.as_secs_f64() * 1000.0
+            )?;
+        }
+        Ok(())
+    }
+
+    fn to_json(&self) -> Value {
+        let comparisons: Vec<Value> = self
+            .comparisons
+            .iter()
+            .map(|comparison| {
+                json!({
+                    "codec": comparison.codec,
+                    "depth": comparison.depth,
+                    "output_bytes": comparison.output_bytes,
+                    "ratio": self.ratio(comparison),
+                    "elapsed_ms": rounded(comparison.elapsed.as_secs_f64() * 1000.0, 3),
+                })
+            })
+            .collect();
+        json!({
+            "input_bytes": self.input_bytes,
+            "comparisons": comparisons,
+        })
+    }
+}
+
+#[cfg(feature = "compare")]
+impl Runnable for CompareOptions {
+    fn run(&self, global: &GlobalOptions) -> Result<Outcome> {
+        let data = std::fs::read(&self.file)?;
+        let mut comparisons = vec![];
+        // the models go through the whole pipeline, their tables are in the output.
+        for &depth in &self.depths {
+            let builder = self
+                .coder
+                .apply(Builder::new().cancellation(global.cancel.clone()))
+                .depth(depth)
+                .block_size(self.block_size);
+            let start = Instant::now();
+            let compressed = builder.compress(&data)?;
+            comparisons.push(Comparison {
+                codec: self.coder.codec.to_string(),
+                depth: Some(depth),
+                output_bytes: compressed.len(),
+                elapsed: start.elapsed(),
+            });
+        }
+        for reference in &self.against {
+            global.cancel.check()?;
+            let start = Instant::now();
+            let compressed = reference.compress(&data)?;
+            comparisons.push(Comparison {
+                codec: reference.to_string(),
+                depth: None,
+                output_bytes: compressed.len(),
+                elapsed: start.elapsed(),
+            });
+        }
+        Ok(Outcome::listing(CompareReport {
+            input_bytes: data.len(),
+            comparisons,
+        }))
+    }
+}
+
+/// Print a shell completion script or a man page, generated from the command line options.
+#[derive(Parser)]
+pub struct CompletionsOptions {
+    #[clap(required_unless_present = "man")]
+    shell: Option<Shell>,
+    /// Print a roff man page instead.
+    #[clap(long, conflicts_with = "shell")]
+    man: bool,
+}
+
+impl Runnable for CompletionsOptions {
+    fn run(&self, _global: &GlobalOptions) -> Result<Outcome> {
+        let name = env!("CARGO_BIN_NAME");
+        let mut command = Options::command().name(name);
+        match self.shell {
+            Some(shell) => clap_complete::generate(shell, &mut command, name, &mut stdout()),
+            None => clap_mangen::Man::new(command).render(&mut stdout())?,
+        }
+        Ok(Outcome::none())
+    }
 }
 
 impl Runnable for Command {
-    fn run(&self, global: &GlobalOptions) -> Result<()> {
+    fn run(&self, global: &GlobalOptions) -> Result<Outcome> {
         match self {
             Command::Markov(command) => command.run(global),
+            Command::Train(command) => command.run(global),
+            Command::Model(command) => command.run(global),
             Command::Compress(command) => command.run(global),
+            Command::Decompress(command) => command.run(global),
+            Command::Info(command) => command.run(global),
+            Command::Stats(command) => command.run(global),
+            #[cfg(feature = "compare")]
+            Command::Compare(command) => command.run(global),
+            Command::Completions(command) => command.run(global),
         }
     }
 }
 
 impl Options {
+    // runs the command and prints its report, the only place that does.
     fn run(&self) -> Result<()> {
-        self.command.run(&self.global)
+        let outcome = self.command.run(&self.global)?;
+        outcome.print(self.global.json)?;
+        Ok(())
+    }
+}
+
+// exit codes, clap exits with the usage code by itself when the arguments do not parse.
+const EXIT_FAILURE: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_INPUT: u8 = 3;
+const EXIT_FORMAT: u8 = 4;
+const EXIT_CHECKSUM: u8 = 5;
+const EXIT_MODEL: u8 = 6;
+// what shells report for processes ended by SIGINT.
+const EXIT_CANCELLED: u8 = 130;
+
+/// Error a command failed with, printed on standard error as text or with
+/// `--error-format json` as a single JSON document.
+struct ErrorReport {
+    code: u8,
+    kind: &'static str,
+    detail: Value,
+    error: anyhow::Error,
+}
+
+impl ErrorReport {
+    // exit code, kind and details of an error, from the library error if there is one.
+    fn new(error: anyhow::Error) -> Self {
+        let (code, kind, detail) = match error.downcast_ref::<Error>() {
+            Some(error) => describe_error(error),
+            None if error.is::<std::io::Error>() => (EXIT_FAILURE, "io", json!({})),
+            None => (EXIT_FAILURE, "other", json!({})),
+        };
+        ErrorReport {
+            code,
+            kind,
+            detail,
+            error,
+        }
+    }
+}
+
+impl Report for ErrorReport {
+    fn write_text(&self, output: &mut dyn Write) -> IoResult<()> {
+        writeln!(output, "Error: {:?}", self.error)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "error": format!("{:#}", self.error),
+            "kind": self.kind,
+            "detail": self.detail,
+        })
     }
 }
 
-fn main() -> Result<()> {
+fn describe_error(error: &Error) -> (u8, &'static str, Value) {
+    match error {
+        Error::Config(_) => (EXIT_USAGE, "config", json!({})),
+        Error::InputTooShort { len, depth } => (
+            EXIT_INPUT,
+            "input_too_short",
+            json!({"len": len, "depth": depth}),
+        ),
+        Error::Format(reason) => (EXIT_FORMAT, "format", json!({"reason": reason})),
+        Error::Truncated => (EXIT_FORMAT, "truncated", json!({})),
+        // the kind of what failed, with the position added to its details.
+        Error::DecodeFailed {
+            bit_offset,
+            bytes_produced,
+            source,
+            ..
+        } => {
+            let (code, kind, mut detail) = describe_error(source);
+            detail["bit_offset"] = (*bit_offset).into();
+            detail["bytes_produced"] = (*bytes_produced).into();
+            (code, kind, detail)
+        }
+        Error::SequenceLength(error) => (
+            EXIT_FORMAT,
+            "sequence_length",
+            json!({"expected": error.expected, "actual": error.actual}),
+        ),
+        Error::CodeTable(error) => (
+            EXIT_FORMAT,
+            "code_table",
+            json!({"reason": error.to_string()}),
+        ),
+        Error::ModelShape(error) => (
+            EXIT_MODEL,
+            "model_shape_changed",
+            json!({"reason": error.to_string()}),
+        ),
+        Error::Unsupported(UnsupportedFeature::Version { found, supported }) => (
+            EXIT_FORMAT,
+            "unsupported_version",
+            json!({"found": found, "supported": supported}),
+        ),
+        Error::Unsupported(feature) => (
+            EXIT_FORMAT,
+            "unsupported",
+            json!({"feature": feature.to_string()}),
+        ),
+        Error::ChecksumMismatch { block } => {
+            (EXIT_CHECKSUM, "checksum_mismatch", json!({"block": block}))
+        }
+        Error::ModelMismatch { expected, found } => (
+            EXIT_MODEL,
+            "model_mismatch",
+            json!({"expected": expected, "found": found}),
+        ),
+        Error::ModelFingerprintMismatch { expected, found } => (
+            EXIT_MODEL,
+            "model_fingerprint_mismatch",
+            json!({"expected": format!("{expected:016x}"), "found": format!("{found:016x}")}),
+        ),
+        Error::LimitExceeded { kind, limit } => (
+            EXIT_FAILURE,
+            "limit_exceeded",
+            json!({"kind": kind, "limit": limit}),
+        ),
+        #[cfg(feature = "serde_json")]
+        Error::Json(_) => (EXIT_FORMAT, "json", json!({})),
+        Error::Cancelled => (EXIT_CANCELLED, "cancelled", json!({})),
+        Error::Poisoned => (EXIT_FAILURE, "poisoned", json!({})),
+        Error::WeightOverflow => (EXIT_FAILURE, "weight_overflow", json!({})),
+        Error::Io(_) => (EXIT_FAILURE, "io", json!({})),
+    }
+}
+
+fn main() -> ExitCode {
     let options = Options::parse();
-    options.run()
+    // the first Ctrl-C lets the command stop cleanly, another one exits right away.
+    let cancel = options.global.cancel.clone();
+    let handler = ctrlc::set_handler(move || {
+        if cancel.is_cancelled() {
+            std::process::exit(EXIT_CANCELLED.into());
+        }
+        cancel.cancel();
+    });
+    if let Err(error) = handler {
+        eprintln!("warning: cannot handle Ctrl-C: {error}");
+    }
+
+    let Err(error) = options.run() else {
+        return ExitCode::SUCCESS;
+    };
+
+    let report = ErrorReport::new(error);
+    let code = report.code;
+    let json = options.global.error_format == ErrorFormat::Json;
+    // there is nowhere left to report it if standard error fails.
+    let _ = Outcome::error(report).print(json);
+    ExitCode::from(code)
 }
@@ -1,274 +1,3643 @@
 use crate::{
-    huffman::{Decoder, Encoder, WeightedItem},
-    util::buffered_windows,
+    container::MAX_SUPPORTED_DEPTH,
+    error::{Error, UnsupportedFeature},
+    huffman::{Decoder, Encoder, WeightedItem, MAX_CODE_LENGTH},
+    util::{
+        buffered_windows, entropy, read_byte_or_end, read_varint, write_varint, ByteMapper,
+        CancellationToken,
+    },
 };
 use std::{
     borrow::BorrowMut,
-    collections::BTreeMap,
-    io::{Result as IoResult, Write},
+    collections::{btree_map, BTreeMap},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Result as IoResult, Write},
+    iter::FusedIterator,
+    ops::{AddAssign, Deref},
+    path::Path,
+    sync::{Arc, Mutex},
 };
+use xxhash_rust::xxh3::{xxh3_64_with_seed, Xxh3};
 
 pub type Map<K, V> = BTreeMap<K, V>;
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum Node {
+pub const MODEL_MAGIC: [u8; 4] = *b"HMMD";
+pub const MODEL_VERSION: u16 = 1;
+
+/// Longest code [`Markov::augment_from`] leaves a sequence of the sample with, one less than
+/// the longest code a tree may have.
+pub const DEFAULT_AUGMENT_CODE_LEN: u8 = MAX_CODE_LENGTH - 1;
+
+// set when the records in a model file are front-coded.
+const MODEL_FLAG_COMPACT: u16 = 1 << 0;
+
+// set when the records are preceded by the escape weights of capped contexts.
+const MODEL_FLAG_ESCAPES: u16 = 1 << 1;
+
+// rough cost of a trie node, including its share of the parent's map.
+const NODE_MEMORY: usize = std::mem::size_of::<(u8, MarkovNode)>() + 16;
+
+/// Bounds on the size of a model while it is trained.
+///
+/// Memory is an estimate of the space taken by the trie nodes in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrainLimits {
+    pub max_sequences: Option<usize>,
+    pub max_memory: Option<usize>,
+}
+
+/// Bytes read from each source by [`Markov::train_sources`], in the order of the sources.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TrainSummary {
+    pub bytes: Vec<u64>,
+    /// Windows of all sources.
+    pub stats: TrainStats,
+}
+
+impl TrainSummary {
+    /// Bytes read from all sources.
+    pub fn total(&self) -> u64 {
+        self.bytes.iter().sum()
+    }
+}
+
+/// What a [`MarkovWriter`] trained on so far, see [`MarkovWriter::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TrainStats {
+    /// Bytes written.
+    pub bytes: u64,
+    /// Windows of the bytes, including the ones left out such as by sampling.
+    pub windows: u64,
+    /// Windows whose sequence was not in the model before.
+    pub new_sequences: u64,
+    /// Windows whose sequence was already in the model, from earlier windows or training.
+    pub repeated_windows: u64,
+}
+
+impl TrainStats {
+    /// Windows the model was trained on.
+    pub fn trained(&self) -> u64 {
+        self.new_sequences + self.repeated_windows
+    }
+
+    fn record(&mut self, insertion: Insertion) {
+        self.windows += 1;
+        match insertion {
+            Insertion::New => self.new_sequences += 1,
+            Insertion::Repeated => self.repeated_windows += 1,
+            Insertion::Skipped => {}
+        }
+    }
+}
+
+impl AddAssign for TrainStats {
+    fn add_assign(&mut self, other: Self) {
+        self.bytes += other.bytes;
+        self.windows += other.windows;
+        self.new_sequences += other.new_sequences;
+        self.repeated_windows += other.repeated_windows;
+    }
+}
+
+/// Outcome of [`Markov::insert`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InsertOutcome {
+    /// Weight of the sequence after the insert.
+    pub count: usize,
+    /// The sequence was not in the model before.
+    pub newly_created: bool,
+}
+
+/// What writing a window did to the model, see [`SequenceWriter::write_weighted`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Insertion {
+    /// The sequence was not in the model before.
+    New,
+    /// The sequence was already in the model, its weight grew.
+    Repeated,
+    /// The window was left out, such as by a [`SamplingWriter`].
+    Skipped,
+}
+
+impl Insertion {
+    // outcome of an insert that created `created` nodes, the leaf is among them for new ones.
+    fn from_created(created: usize) -> Self {
+        match created {
+            0 => Insertion::Repeated,
+            _ => Insertion::New,
+        }
+    }
+}
+
+impl From<InsertOutcome> for Insertion {
+    fn from(outcome: InsertOutcome) -> Self {
+        match outcome.newly_created {
+            true => Insertion::New,
+            false => Insertion::Repeated,
+        }
+    }
+}
+
+/// Deterministic selection of the input to train on, see [`SamplingWriter`].
+///
+/// The input is split into chunks, each of which is kept with probability `rate` based on a
+/// hash of its index and the seed, so the same input and seed always yield the same sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sampling {
+    // kept chunks hash below this many 2^-32ths, one above the maximum keeps every chunk.
+    keep: u64,
+    chunk_size: u64,
+    seed: u64,
+}
+
+impl Sampling {
+    pub fn new(rate: f64) -> Result<Self, Error> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(Error::Config(format!(
+                "sample rate {rate} must be between 0 and 1"
+            )));
+        }
+        Ok(Sampling {
+            keep: (rate * (1u64 << 32) as f64).round() as u64,
+            ..Default::default()
+        })
+    }
+
+    /// Windows per chunk, at least one.
+    pub fn chunk_size(mut self, size: u64) -> Self {
+        self.chunk_size = size.max(1);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.keep as f64 / (1u64 << 32) as f64
+    }
+
+    fn keeps(&self, chunk: u64) -> bool {
+        xxh3_64_with_seed(&chunk.to_le_bytes(), self.seed) >> 32 < self.keep
+    }
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling {
+            keep: 1 << 32,
+            chunk_size: DEFAULT_SAMPLE_CHUNK,
+            seed: 0,
+        }
+    }
+}
+
+/// What adding up weights does once the sum no longer fits in a `usize`, in
+/// [`Markov::insert`], [`Markov::merge`] and the Huffman trees built from a model.
+///
+/// Bulk insertion, such as [`Markov::insert_run`], adds one per window and always saturates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum WeightPolicy {
+    /// The sum stops at `usize::MAX`, so the largest weights lose their ratios.
+    #[default]
+    Saturate,
+    /// Fails with [`Error::WeightOverflow`].
+    Error,
+    /// Halves the weights of the context the sum is in, until it fits.
+    Scale,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    #[default]
+    Plain,
+    Compact,
+}
+
+/// Node of the trie of a [`Markov`] model, with a map of children per byte of the sequences
+/// and the weight of a whole sequence at its leaf.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MarkovNode {
     Leaf(usize),
-    Node(Map<u8, Self>),
+    Node(Arc<Map<u8, Self>>),
 }
 
-impl Node {
+#[deprecated(note = "renamed to `MarkovNode`")]
+pub type Node = MarkovNode;
+
+impl MarkovNode {
+    // node at the end of `path` below this one.
+    fn descend_mut(&mut self, path: &[u8]) -> Option<&mut Self> {
+        path.iter()
+            .try_fold(self, |node, key| node.node_mut()?.get_mut(key))
+    }
+
+    // nodes that inserting `path` below this one would create.
+    fn missing(&self, path: &[u8]) -> usize {
+        let mut node = self;
+        for (index, key) in path.iter().enumerate() {
+            match node.node().and_then(|children| children.get(key)) {
+                Some(child) => node = child,
+                None => return path.len() - index,
+            }
+        }
+        0
+    }
+
     fn node_mut(&mut self) -> Option<&mut Map<u8, Self>> {
         match self {
-            Node::Node(node) => Some(node),
-            Node::Leaf(_) => None,
+            MarkovNode::Node(node) => Some(Arc::make_mut(node)),
+            MarkovNode::Leaf(_) => None,
+        }
+    }
+
+    fn leaf(&self) -> Option<usize> {
+        match self {
+            MarkovNode::Node(_) => None,
+            MarkovNode::Leaf(weight) => Some(*weight),
+        }
+    }
+
+    fn node(&self) -> Option<&Map<u8, Self>> {
+        match self {
+            MarkovNode::Node(node) => Some(node),
+            MarkovNode::Leaf(_) => None,
+        }
+    }
+
+    fn mark_bytes(&self, seen: &mut [bool; 256]) {
+        for (byte, node) in self.node().into_iter().flatten() {
+            seen[usize::from(*byte)] = true;
+            node.mark_bytes(seen);
+        }
+    }
+
+    fn count(&self) -> (usize, usize) {
+        match self {
+            MarkovNode::Leaf(_) => (1, 1),
+            MarkovNode::Node(nodes) => nodes.values().fold((0, 1), |(leaves, nodes), node| {
+                let (child_leaves, child_nodes) = node.count();
+                (leaves + child_leaves, nodes + child_nodes)
+            }),
+        }
+    }
+
+    fn cap_successors(
+        &mut self,
+        prefix: &mut Vec<u8>,
+        length: usize,
+        k: usize,
+        escapes: &mut Map<Box<[u8]>, usize>,
+    ) {
+        let Some(nodes) = self.node_mut() else {
+            return;
+        };
+
+        if length > 0 {
+            for (byte, node) in nodes.iter_mut() {
+                prefix.push(*byte);
+                node.cap_successors(prefix, length - 1, k, escapes);
+                prefix.pop();
+            }
+            return;
+        }
+
+        if nodes.len() <= k {
+            return;
+        }
+
+        // heaviest successors first, ties broken by the byte value.
+        let mut weights: Vec<(usize, u8)> = nodes
+            .iter()
+            .map(|(byte, node)| (node.leaf().unwrap_or(0), *byte))
+            .collect();
+        weights.sort_unstable_by_key(|(weight, byte)| (std::cmp::Reverse(*weight), *byte));
+        let mut escape = 0usize;
+        for (weight, byte) in &weights[k..] {
+            nodes.remove(byte);
+            escape = escape.saturating_add(*weight);
+        }
+        let entry = escapes.entry(prefix.as_slice().into()).or_default();
+        *entry = entry.saturating_add(escape);
+    }
+
+    // appends the contexts `length` bytes below this node that have successors, with the sum of
+    // their successor and escape weights.
+    fn context_weights(
+        &self,
+        prefix: &mut Vec<u8>,
+        length: usize,
+        escapes: &Map<Box<[u8]>, usize>,
+        weights: &mut Vec<(Box<[u8]>, u64)>,
+    ) {
+        let Some(nodes) = self.node() else {
+            return;
+        };
+
+        if length > 0 {
+            for (byte, node) in nodes {
+                prefix.push(*byte);
+                node.context_weights(prefix, length - 1, escapes, weights);
+                prefix.pop();
+            }
+            return;
+        }
+
+        if nodes.is_empty() {
+            return;
+        }
+        let escape = escapes.get(prefix.as_slice()).copied().unwrap_or(0);
+        let weight = nodes.values().fold(escape as u64, |sum, node| {
+            sum.saturating_add(node.leaf().unwrap_or(0) as u64)
+        });
+        weights.push((prefix.as_slice().into(), weight));
+    }
+
+    // inserts sequences that share their first `level` bytes, grouping them by the next byte
+    // so that every node on their paths is visited once.
+    fn insert_grouped(&mut self, sequences: &mut [&[u8]], level: usize, depth: usize) {
+        let nodes = match self {
+            MarkovNode::Leaf(count) => {
+                let weight = DEFAULT_WEIGHT.saturating_mul(sequences.len());
+                *count = count.saturating_add(weight);
+                return;
+            }
+            MarkovNode::Node(nodes) => Arc::make_mut(nodes),
+        };
+
+        sequences.sort_unstable_by_key(|sequence| sequence[level]);
+        for group in sequences.chunk_by_mut(|a, b| a[level] == b[level]) {
+            let child = nodes.entry(group[0][level]).or_insert_with(|| {
+                if level + 1 < depth {
+                    MarkovNode::Node(Default::default())
+                } else {
+                    MarkovNode::Leaf(Default::default())
+                }
+            });
+            child.insert_grouped(group, level + 1, depth);
+        }
+    }
+
+    fn merge(&mut self, other: MarkovNode) {
+        match (self, other) {
+            (MarkovNode::Leaf(weight), MarkovNode::Leaf(other)) => {
+                *weight = weight.saturating_add(other)
+            }
+            (MarkovNode::Node(nodes), MarkovNode::Node(others)) => {
+                let nodes = Arc::make_mut(nodes);
+                for (byte, other) in Arc::unwrap_or_clone(others) {
+                    match nodes.entry(byte) {
+                        btree_map::Entry::Vacant(entry) => {
+                            entry.insert(other);
+                        }
+                        btree_map::Entry::Occupied(mut entry) => entry.get_mut().merge(other),
+                    }
+                }
+            }
+            _ => unreachable!("merged nodes are at the same level"),
+        }
+    }
+
+    fn weights(&self) -> Box<dyn Iterator<Item = usize> + '_> {
+        match self {
+            MarkovNode::Leaf(weight) => Box::new(std::iter::once(*weight)),
+            MarkovNode::Node(nodes) => Box::new(nodes.values().flat_map(MarkovNode::weights)),
+        }
+    }
+
+    fn scale_weights(&mut self, scale: &impl Fn(usize) -> usize) {
+        match self {
+            MarkovNode::Leaf(weight) => *weight = scale(*weight),
+            MarkovNode::Node(nodes) => Arc::make_mut(nodes)
+                .values_mut()
+                .for_each(|node| node.scale_weights(scale)),
+        }
+    }
+
+    // keeps the sequences below this node for which `pred` holds, returns whether any are left.
+    fn retain(
+        &mut self,
+        prefix: &mut Vec<u8>,
+        pred: &mut impl FnMut(&[u8], usize) -> bool,
+    ) -> bool {
+        match self {
+            MarkovNode::Leaf(weight) => pred(prefix, *weight),
+            MarkovNode::Node(nodes) => {
+                let nodes = Arc::make_mut(nodes);
+                nodes.retain(|byte, node| {
+                    prefix.push(*byte);
+                    let keep = node.retain(prefix, pred);
+                    prefix.pop();
+                    keep
+                });
+                !nodes.is_empty()
+            }
+        }
+    }
+
+    fn prune(&mut self, threshold: usize) -> bool {
+        match self {
+            MarkovNode::Leaf(weight) => *weight >= threshold,
+            MarkovNode::Node(nodes) => {
+                let nodes = Arc::make_mut(nodes);
+                nodes.retain(|_, node| node.prune(threshold));
+                !nodes.is_empty()
+            }
+        }
+    }
+
+    // weighted leaves directly below this node.
+    fn items(&self) -> Vec<WeightedItem> {
+        self.node()
+            .into_iter()
+            .flatten()
+            .filter_map(|(byte, node)| Some(WeightedItem::new(*byte, node.leaf()?)))
+            .collect()
+    }
+}
+
+/// Iterator over the sequences of a [`Markov`] model and their weights, in ascending order.
+#[derive(Clone, Debug)]
+pub struct Iter<'a> {
+    // one iterator per level of the current path, the prefix holds the bytes leading to the
+    // last one.
+    stack: Vec<btree_map::Iter<'a, u8, MarkovNode>>,
+    prefix: Vec<u8>,
+    leaf: Option<usize>,
+}
+
+impl<'a> Iter<'a> {
+    fn new(root: &'a MarkovNode) -> Self {
+        match root {
+            MarkovNode::Leaf(weight) => Iter {
+                stack: vec![],
+                prefix: vec![],
+                leaf: Some(*weight),
+            },
+            MarkovNode::Node(nodes) => Iter {
+                stack: vec![nodes.iter()],
+                prefix: vec![],
+                leaf: None,
+            },
+        }
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = (Vec<u8>, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(weight) = self.leaf.take() {
+            return Some((vec![], weight));
+        }
+
+        loop {
+            match self.stack.last_mut()?.next() {
+                None => {
+                    self.stack.pop();
+                    self.prefix.pop();
+                }
+                Some((byte, MarkovNode::Leaf(weight))) => {
+                    let mut sequence = self.prefix.clone();
+                    sequence.push(*byte);
+                    return Some((sequence, *weight));
+                }
+                Some((byte, MarkovNode::Node(nodes))) => {
+                    self.prefix.push(*byte);
+                    self.stack.push(nodes.iter());
+                }
+            }
+        }
+    }
+}
+
+impl FusedIterator for Iter<'_> {}
+
+/// Iterator over the contexts of a [`Markov`] model and their successors, in ascending order.
+#[derive(Clone, Debug)]
+pub struct PrefixIter<'a> {
+    stack: Vec<btree_map::Iter<'a, u8, MarkovNode>>,
+    prefix: Vec<u8>,
+    length: usize,
+    root: Option<&'a MarkovNode>,
+}
+
+impl<'a> PrefixIter<'a> {
+    fn new(root: &'a MarkovNode, length: usize) -> Self {
+        let mut iter = PrefixIter {
+            stack: vec![],
+            prefix: vec![],
+            length,
+            root: None,
+        };
+        match root.node() {
+            _ if length == 0 => iter.root = Some(root),
+            Some(nodes) => iter.stack.push(nodes.iter()),
+            None => {}
+        }
+        iter
+    }
+}
+
+impl Iterator for PrefixIter<'_> {
+    type Item = (Vec<u8>, Vec<WeightedItem>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // contexts without successors only exist in an empty model.
+        if let Some(root) = self.root.take() {
+            let items = root.items();
+            return (!items.is_empty()).then(|| (vec![], items));
+        }
+
+        loop {
+            let Some((byte, node)) = self.stack.last_mut()?.next() else {
+                self.stack.pop();
+                self.prefix.pop();
+                continue;
+            };
+
+            self.prefix.push(*byte);
+            if self.prefix.len() == self.length {
+                let items = node.items();
+                let prefix = self.prefix.clone();
+                self.prefix.pop();
+                if !items.is_empty() {
+                    return Some((prefix, items));
+                }
+            } else if let Some(nodes) = node.node() {
+                self.stack.push(nodes.iter());
+            } else {
+                self.prefix.pop();
+            }
+        }
+    }
+}
+
+impl FusedIterator for PrefixIter<'_> {}
+
+/// Snapshot of the successor weights of every context of a [`Markov`] model, for repeated
+/// lookups of context totals and probabilities without summing the successors every time.
+///
+/// The index does not follow changes to the model, it has to be rebuilt after mutating it.
+/// Weights removed by [`Markov::cap_successors`] are not part of the totals. Successors with a
+/// weight of zero are left out, they can never be sampled, and so are contexts without any
+/// others.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContextIndex {
+    contexts: Map<Box<[u8]>, CumulativeWeights>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct CumulativeWeights {
+    symbols: Vec<u8>,
+    // starts at zero, one entry longer than the symbols.
+    cumulative: Vec<usize>,
+}
+
+impl CumulativeWeights {
+    // none if no successor has any weight, zero weights would be symbols without a range.
+    fn new(items: &[WeightedItem]) -> Option<Self> {
+        let items: Vec<_> = items.iter().filter(|item| item.weight > 0).collect();
+        if items.is_empty() {
+            return None;
+        }
+        let mut cumulative = Vec::with_capacity(items.len() + 1);
+        cumulative.push(0usize);
+        for item in &items {
+            cumulative.push(cumulative[cumulative.len() - 1].saturating_add(item.weight));
+        }
+        Some(CumulativeWeights {
+            symbols: items.iter().map(|item| item.item).collect(),
+            cumulative,
+        })
+    }
+
+    fn total(&self) -> usize {
+        self.cumulative[self.cumulative.len() - 1]
+    }
+}
+
+impl ContextIndex {
+    /// Number of contexts in the index.
+    pub fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contexts.is_empty()
+    }
+
+    /// Combined weight of the successors of `context`, if the model has seen it.
+    pub fn total(&self, context: &[u8]) -> Option<usize> {
+        Some(self.contexts.get(context)?.total())
+    }
+
+    /// Weight of `byte` following `context`, zero for bytes the context has not seen.
+    pub fn weight(&self, context: &[u8], byte: u8) -> Option<usize> {
+        let weights = self.contexts.get(context)?;
+        Some(match weights.symbols.binary_search(&byte) {
+            Ok(index) => weights.cumulative[index + 1] - weights.cumulative[index],
+            Err(_) => 0,
+        })
+    }
+
+    /// Probability of `byte` following `context`.
+    pub fn probability(&self, context: &[u8], byte: u8) -> Option<f64> {
+        let total = self.total(context)?;
+        Some(self.weight(context, byte)? as f64 / total as f64)
+    }
+
+    /// Successor of `context` selected by `value`, which is taken modulo the total weight.
+    /// Uniformly distributed values select the successors by their probability.
+    pub fn sample(&self, context: &[u8], value: usize) -> Option<u8> {
+        let weights = self.contexts.get(context)?;
+        let value = value.checked_rem(weights.total())?;
+        let index = weights
+            .cumulative
+            .partition_point(|weight| *weight <= value);
+        Some(weights.symbols[index - 1])
+    }
+}
+
+/// Shape of the contexts of a [`Markov`] model, from [`Markov::context_stats`].
+///
+/// Deterministic contexts have a single successor and no escapes, they code in close to zero
+/// bits, so a large share of weight in them suggests that a deeper model pays off.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContextStats {
+    /// Number of contexts with successors.
+    pub contexts: usize,
+    /// Contexts with exactly one successor.
+    pub deterministic: usize,
+    /// Training weight of all contexts, including escape weight.
+    pub weight: u64,
+    /// Training weight of the deterministic contexts.
+    pub deterministic_weight: u64,
+}
+
+impl ContextStats {
+    /// Fraction of the training weight in deterministic contexts, zero for an empty model.
+    pub fn deterministic_fraction(&self) -> f64 {
+        if self.weight == 0 {
+            return 0.0;
+        }
+        self.deterministic_weight as f64 / self.weight as f64
+    }
+}
+
+/// Weight and conditional entropy of the contexts starting with one byte, from
+/// [`Markov::entropy_by_leading_byte`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LeadingByteStats {
+    /// Training weight of the contexts, including escape weight.
+    pub weight: u64,
+    /// Entropy of the byte following the contexts, averaged by their weight, in bits per byte.
+    pub entropy: f64,
+}
+
+/// Outcome of [`Markov::quantize`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QuantizeReport {
+    /// Number of contexts in the model.
+    pub contexts: usize,
+    /// Contexts in which any code length differs after quantizing.
+    pub changed: usize,
+}
+
+/// Outcome of [`Markov::augment_from`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AugmentReport {
+    /// Sequences of the sample the model did not have, now inserted.
+    pub added: usize,
+    /// Sequences of the sample whose codes were too long, now with a larger weight.
+    pub boosted: usize,
+    /// Sequences of the sample occurring too rarely to be considered.
+    pub skipped: usize,
+}
+
+/// Model of the sequences of `depth` bytes in its training data and their weights.
+///
+/// The maps of successors are shared between clones and only copied when one of them changes,
+/// see [`Markov::snapshot`].
+#[derive(Clone, Debug)]
+pub struct Markov {
+    depth: usize,
+    root: MarkovNode,
+    escapes: Arc<Map<Box<[u8]>, usize>>,
+    policy: WeightPolicy,
+    pending: Pending,
+}
+
+// last bytes passed to `Markov::write_bytes`, too few to complete a window.
+#[derive(Clone, Debug, Default)]
+struct Pending(Vec<u8>);
+
+// pending bytes are not part of the model, so models compare and hash the same whatever is
+// pending.
+impl PartialEq for Markov {
+    fn eq(&self, other: &Self) -> bool {
+        self.depth == other.depth
+            && self.root == other.root
+            && self.escapes == other.escapes
+            && self.policy == other.policy
+    }
+}
+
+impl Eq for Markov {}
+
+impl std::hash::Hash for Markov {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.depth.hash(state);
+        self.root.hash(state);
+        self.escapes.hash(state);
+        self.policy.hash(state);
+    }
+}
+
+/// Point-in-time view of a [`Markov`] model, taken with [`Markov::snapshot`].
+///
+/// Dereferences to the model, so it can be read or coded from like one, as in
+/// `Decoder::new(&snapshot)`, while the model it was taken from keeps training.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MarkovSnapshot(Markov);
+
+impl MarkovSnapshot {
+    /// The model as of the snapshot, to be trained on separately.
+    pub fn into_markov(self) -> Markov {
+        self.0
+    }
+}
+
+impl Deref for MarkovSnapshot {
+    type Target = Markov;
+
+    fn deref(&self) -> &Markov {
+        &self.0
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("sequence of length {actual} does not match the expected length {expected}")]
+#[non_exhaustive]
+pub struct SequenceLengthError {
+    pub expected: usize,
+    pub actual: usize,
+}
+
+// half of a weight, rounded up so that weights never drop to zero.
+fn halve(weight: usize) -> usize {
+    weight.div_ceil(2)
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// heaviest contexts first, ties in ascending order of the contexts.
+fn by_weight(a: &(Box<[u8]>, u64), b: &(Box<[u8]>, u64)) -> std::cmp::Ordering {
+    b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))
+}
+
+fn check_length(sequence: &[u8], expected: usize) -> Result<(), SequenceLengthError> {
+    if sequence.len() != expected {
+        return Err(SequenceLengthError {
+            expected,
+            actual: sequence.len(),
+        });
+    }
+    Ok(())
+}
+
+// fails unless models of `depth` are supported.
+pub(crate) fn check_depth(depth: usize) -> Result<(), Error> {
+    if depth == 0 {
+        return Err(Error::Config("depth must be at least 1".into()));
+    }
+    if depth > MAX_SUPPORTED_DEPTH {
+        return Err(Error::Config(format!(
+            "depth {depth} exceeds the maximum of {MAX_SUPPORTED_DEPTH}"
+        )));
+    }
+    Ok(())
+}
+
+impl Markov {
+    pub fn new(depth: usize) -> Self {
+        Markov {
+            depth,
+            root: MarkovNode::Node(Default::default()),
+            escapes: Default::default(),
+            policy: WeightPolicy::default(),
+            pending: Pending::default(),
+        }
+    }
+
+    /// Model like [`Markov::new`], failing with [`Error::Config`] unless the depth is between 1
+    /// and [`MAX_SUPPORTED_DEPTH`].
+    pub fn try_new(depth: usize) -> Result<Self, Error> {
+        check_depth(depth)?;
+        Ok(Self::new(depth))
+    }
+
+    /// Sets what adding weights does when they overflow, [`WeightPolicy::Saturate`] by
+    /// default. Models read from files have the default policy.
+    pub fn with_weight_policy(mut self, policy: WeightPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn weight_policy(&self) -> WeightPolicy {
+        self.policy
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(&self.root)
+    }
+
+    pub fn iter_prefix(&self) -> PrefixIter<'_> {
+        PrefixIter::new(&self.root, self.depth - 1)
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.depth
+    }
+
+    /// Stable hash of the model contents.
+    ///
+    /// This is XXH3-64 (seed 0) over the depth as a little-endian `u64`, followed by every
+    /// sequence in ascending order, each as its raw bytes and its weight as a little-endian `u64`.
+    ///
+    /// If the model has escape weights, these follow in ascending order of their context, each
+    /// as its raw bytes and its weight as a little-endian `u64`.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = Xxh3::new();
+        hasher.update(&(self.depth as u64).to_le_bytes());
+        for (sequence, weight) in self.iter() {
+            hasher.update(&sequence);
+            hasher.update(&(weight as u64).to_le_bytes());
+        }
+        for (context, weight) in self.escapes.iter() {
+            hasher.update(context);
+            hasher.update(&(*weight as u64).to_le_bytes());
+        }
+        hasher.digest()
+    }
+
+    /// Adds `weight` to the weight of `sequence`, returning its new weight and whether it is new
+    /// to the model. Weights that overflow are handled according to the [`WeightPolicy`] of the
+    /// model.
+    ///
+    /// This used to fail with a [`SequenceLengthError`] only, now wrapped in
+    /// [`Error::SequenceLength`] next to [`Error::WeightOverflow`]. [`Markov::insert_saturating`]
+    /// keeps the old signature.
+    pub fn insert(&mut self, sequence: &[u8], weight: usize) -> Result<InsertOutcome, Error> {
+        let (count, created) = self.insert_counted(sequence, weight)?;
+        Ok(InsertOutcome {
+            count,
+            newly_created: created > 0,
+        })
+    }
+
+    /// Adds `weight` to the weight of `sequence` like [`Markov::insert`] did before weight
+    /// policies, saturating whatever the policy of the model.
+    #[deprecated(note = "use `insert`, which fails with `Error` and follows the weight policy")]
+    pub fn insert_saturating(
+        &mut self,
+        sequence: &[u8],
+        weight: usize,
+    ) -> Result<usize, SequenceLengthError> {
+        check_length(sequence, self.depth)?;
+        let policy = std::mem::replace(&mut self.policy, WeightPolicy::Saturate);
+        let inserted = self.insert_counted(sequence, weight);
+        self.policy = policy;
+        Ok(inserted
+            .expect("saturating sequences of the depth does not fail")
+            .0)
+    }
+
+    #[deprecated(note = "use `insert`, whose outcome has the new weight as `count`")]
+    pub fn insert_weight(&mut self, sequence: &[u8], weight: usize) -> Result<usize, Error> {
+        Ok(self.insert(sequence, weight)?.count)
+    }
+
+    // inserts the sequence, also returning how many nodes had to be created for it.
+    fn insert_counted(&mut self, sequence: &[u8], weight: usize) -> Result<(usize, usize), Error> {
+        check_length(sequence, self.depth)?;
+
+        let mut created = 0;
+        let leaf = sequence[..]
+            .iter()
+            .enumerate()
+            .fold(&mut self.root, |node, (index, key)| {
+                node.node_mut().unwrap().entry(*key).or_insert_with(|| {
+                    created += 1;
+                    if index < (self.depth - 1) {
+                        MarkovNode::Node(Default::default())
+                    } else {
+                        MarkovNode::Leaf(Default::default())
+                    }
+                })
+            });
+
+        let count = match leaf {
+            MarkovNode::Leaf(count) => count,
+            MarkovNode::Node(_) => unreachable!(),
+        };
+        if let Some(sum) = count.checked_add(weight) {
+            *count = sum;
+            return Ok((sum, created));
+        }
+        Ok((self.insert_overflowing(sequence, weight)?, created))
+    }
+
+    // adds to the weight of an existing sequence that overflows, according to the policy.
+    fn insert_overflowing(&mut self, sequence: &[u8], mut weight: usize) -> Result<usize, Error> {
+        let context = &sequence[..sequence.len() - 1];
+        loop {
+            let Some(MarkovNode::Leaf(count)) = self.root.descend_mut(sequence) else {
+                unreachable!("the sequence was inserted");
+            };
+            match (count.checked_add(weight), self.policy) {
+                (Some(sum), _) => {
+                    *count = sum;
+                    return Ok(sum);
+                }
+                (None, WeightPolicy::Saturate) => {
+                    *count = usize::MAX;
+                    return Ok(usize::MAX);
+                }
+                (None, WeightPolicy::Error) => return Err(Error::WeightOverflow),
+                (None, WeightPolicy::Scale) => {
+                    self.halve_context(context);
+                    weight = halve(weight);
+                }
+            }
+        }
+    }
+
+    // halves the weights of the successors of `context` and its escape weight.
+    fn halve_context(&mut self, context: &[u8]) {
+        if let Some(nodes) = self
+            .root
+            .descend_mut(context)
+            .and_then(MarkovNode::node_mut)
+        {
+            for node in nodes.values_mut() {
+                if let MarkovNode::Leaf(weight) = node {
+                    *weight = halve(*weight);
+                }
+            }
+        }
+        if self.escapes.contains_key(context) {
+            let escapes = Arc::make_mut(&mut self.escapes);
+            escapes
+                .entry(context.into())
+                .and_modify(|weight| *weight = halve(*weight));
+        }
+    }
+
+    /// Inserts every sequence with the default weight.
+    ///
+    /// The sequences are grouped by their prefix, so that every node of the trie is only
+    /// descended into once rather than once per sequence.
+    pub fn insert_all<'a>(
+        &mut self,
+        sequences: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<(), SequenceLengthError> {
+        let mut sequences: Vec<&[u8]> = sequences.into_iter().collect();
+        for sequence in &sequences {
+            check_length(sequence, self.depth)?;
+        }
+        self.root.insert_grouped(&mut sequences, 0, self.depth);
+        Ok(())
+    }
+
+    /// Inserts every window of `data`, like writing it into a fresh [`MarkovWriter`].
+    ///
+    /// Consecutive windows overlap shifted by one byte rather than sharing a prefix in the
+    /// trie, so they are inserted through [`Markov::insert_all`] in chunks instead.
+    pub fn insert_run(&mut self, data: &[u8]) {
+        self.insert_run_cancellable(data, &CancellationToken::new())
+            .expect("fresh tokens are not cancelled");
+    }
+
+    /// Trains on the bytes of `text`, see [`Markov::insert_run`].
+    pub fn train_str(&mut self, text: &str) {
+        self.insert_run(text.as_bytes());
+    }
+
+    /// Inserts like [`Markov::insert_run`], checking `token` between chunks of windows.
+    pub fn insert_run_cancellable(
+        &mut self,
+        data: &[u8],
+        token: &CancellationToken,
+    ) -> Result<(), Error> {
+        let mut windows = Vec::with_capacity(RUN_CHUNK.min(data.len()));
+        for window in data.windows(self.depth) {
+            windows.push(window);
+            if windows.len() == RUN_CHUNK {
+                token.check()?;
+                self.insert_all(windows.drain(..)).unwrap();
+            }
+        }
+        token.check()?;
+        self.insert_all(windows).unwrap();
+        Ok(())
+    }
+
+    pub fn prune(&mut self, threshold: usize) {
+        self.root.prune(threshold);
+    }
+
+    // multiplies all weights by `factor`, products that do not fit follow the weight policy like
+    // repeated insertion does.
+    pub(crate) fn scale_weights(&mut self, factor: usize) -> Result<(), Error> {
+        let overflows = |weight: usize| weight.checked_mul(factor).is_none();
+        if self.policy != WeightPolicy::Saturate {
+            // largest weight of every context with a product that does not fit.
+            let mut contexts: BTreeMap<Box<[u8]>, usize> = BTreeMap::new();
+            let successors = self.iter_prefix().filter_map(|(context, items)| {
+                let max = items.iter().map(|item| item.weight).max()?;
+                Some((context.into_boxed_slice(), max))
+            });
+            let escapes = self
+                .escapes
+                .iter()
+                .map(|(context, weight)| (context.clone(), *weight));
+            for (context, max) in successors.chain(escapes).filter(|(_, max)| overflows(*max)) {
+                let entry = contexts.entry(context).or_default();
+                *entry = (*entry).max(max);
+            }
+            if !contexts.is_empty() && self.policy == WeightPolicy::Error {
+                return Err(Error::WeightOverflow);
+            }
+            for (context, mut max) in contexts {
+                while overflows(max) {
+                    self.halve_context(&context);
+                    max = halve(max);
+                }
+            }
+        }
+
+        let scale = |weight: usize| weight.saturating_mul(factor);
+        self.root.scale_weights(&scale);
+        for weight in Arc::make_mut(&mut self.escapes).values_mut() {
+            *weight = scale(*weight);
+        }
+        Ok(())
+    }
+
+    /// Rescales all weights, escape weights included, so that the largest one fits in `bits`
+    /// bits (at least one). Weights are first divided by their greatest common divisor, which
+    /// keeps their ratios exact. Only if that is not enough are they rounded to the nearest
+    /// integer, never dropping below 1 so no sequence is lost.
+    ///
+    /// Code lengths only change where rounding breaks ties or moves weights across the
+    /// boundaries the Huffman trees are sensitive to. The report compares the trees of the
+    /// default coder before and after, models that already fit are left alone.
+    pub fn quantize(&mut self, bits: u8) -> QuantizeReport {
+        let weights = || self.root.weights().chain(self.escapes.values().copied());
+        let max = weights().max().unwrap_or(0);
+        let divisor = weights().fold(0, gcd).max(1);
+        let target = u128::MAX >> (128 - u32::from(bits.clamp(1, 64)));
+        let target = usize::try_from(target).unwrap_or(usize::MAX);
+        if max <= target {
+            let contexts = self.iter_prefix().count();
+            return QuantizeReport {
+                contexts,
+                changed: 0,
+            };
+        }
+
+        let before = Decoder::new(self);
+        let max = max / divisor;
+        let scale = |weight: usize| {
+            let weight = weight / divisor;
+            if max <= target {
+                return weight;
+            }
+            let scaled = (weight as u128 * target as u128 + max as u128 / 2) / max as u128;
+            (scaled as usize).max(1)
+        };
+        self.root.scale_weights(&scale);
+        for weight in Arc::make_mut(&mut self.escapes).values_mut() {
+            *weight = scale(*weight);
+        }
+        let after = Decoder::new(self);
+
+        let lengths = |node: &crate::huffman::HuffmanNode| -> Vec<_> {
+            node.iter()
+                .map(|(code, symbol)| (symbol, code.len()))
+                .collect()
+        };
+        let changed = before
+            .trees
+            .iter()
+            .filter(|(context, node)| {
+                after
+                    .trees
+                    .get(*context)
+                    .is_none_or(|other| lengths(node) != lengths(other))
+            })
+            .count();
+        QuantizeReport {
+            contexts: before.trees.len(),
+            changed,
+        }
+    }
+
+    /// View of the model as it is now, which later changes to the model do not affect. Takes
+    /// constant time, the snapshot shares all nodes with the model.
+    ///
+    /// The maps of successors stay shared until the model changes them. The first insert
+    /// after a snapshot copies the maps on its path from the root, each in time proportional to
+    /// its number of successors, and leaves the rest shared. Inserts into paths that were
+    /// already copied, or once the snapshot is dropped, cost what they did before. Operations
+    /// that visit every node, such as pruning or quantizing, copy the whole tree.
+    pub fn snapshot(&self) -> MarkovSnapshot {
+        MarkovSnapshot(self.clone())
+    }
+
+    /// Adds the weights and escape weights of `other` to this model, as if it had been trained
+    /// on the inputs of both. With [`WeightPolicy::Error`], the contexts merged before the one
+    /// that overflows keep their new weights.
+    pub fn merge(&mut self, other: Markov) -> Result<(), Error> {
+        if other.depth != self.depth {
+            return Err(Error::ModelMismatch {
+                expected: self.depth,
+                found: other.depth,
+            });
+        }
+        if self.policy != WeightPolicy::Saturate {
+            for (context, items) in other.iter_prefix() {
+                let escape = other.escape_weight(&context).unwrap_or(0);
+                self.merge_context(&context, items, escape)?;
+            }
+            return Ok(());
+        }
+
+        self.root.merge(other.root);
+        let escapes = Arc::make_mut(&mut self.escapes);
+        for (context, weight) in Arc::unwrap_or_clone(other.escapes) {
+            let entry = escapes.entry(context).or_default();
+            *entry = entry.saturating_add(weight);
+        }
+        Ok(())
+    }
+
+    // adds the successor and escape weights of a context of another model, checking every sum
+    // before changing any so that the context is scaled as a whole.
+    fn merge_context(
+        &mut self,
+        context: &[u8],
+        mut items: Vec<WeightedItem>,
+        mut escape: usize,
+    ) -> Result<(), Error> {
+        let mut sequence = context.to_vec();
+        sequence.push(0);
+        loop {
+            let fits = items.iter().all(|item| {
+                sequence[context.len()] = item.item;
+                let weight = self
+                    .get(&sequence)
+                    .ok()
+                    .flatten()
+                    .and_then(MarkovNode::leaf);
+                weight.unwrap_or(0).checked_add(item.weight).is_some()
+            });
+            let escape_fits = self
+                .escape_weight(context)
+                .unwrap_or(0)
+                .checked_add(escape)
+                .is_some();
+            if fits && escape_fits {
+                break;
+            }
+            match self.policy {
+                WeightPolicy::Saturate => break,
+                WeightPolicy::Error => return Err(Error::WeightOverflow),
+                WeightPolicy::Scale => {
+                    self.halve_context(context);
+                    for item in &mut items {
+                        item.weight = halve(item.weight);
+                    }
+                    escape = halve(escape);
+                }
+            }
+        }
+
+        for item in items {
+            sequence[context.len()] = item.item;
+            self.insert(&sequence, item.weight)?;
+        }
+        if escape > 0 {
+            let escapes = Arc::make_mut(&mut self.escapes);
+            let entry = escapes.entry(context.into()).or_default();
+            *entry = entry.saturating_add(escape);
+        }
+        Ok(())
+    }
+
+    /// Blends two models of the same depth, giving every sequence the weight
+    /// `round(lambda * wa + (1 - lambda) * wb)` of its weights in `a` and `b`, zero in a model
+    /// that does not have it. Escape weights are blended the same way. Unlike
+    /// [`Markov::merge`], which sums the weights, this rescales them, so `lambda` between 0 and
+    /// 1 sets how much of `a` the result has.
+    ///
+    /// Sequences whose weight rounds to zero are left out, see
+    /// [`Markov::interpolate_with_min_weight`].
+    pub fn interpolate(a: &Markov, b: &Markov, lambda: f64) -> Result<Markov, Error> {
+        Self::interpolate_with_min_weight(a, b, lambda, 0)
+    }
+
+    /// Blends two models like [`Markov::interpolate`], raising the weight of every sequence of
+    /// either model to at least `min_weight`, so that none are lost to rounding.
+    pub fn interpolate_with_min_weight(
+        a: &Markov,
+        b: &Markov,
+        lambda: f64,
+        min_weight: usize,
+    ) -> Result<Markov, Error> {
+        if b.depth != a.depth {
+            return Err(Error::ModelMismatch {
+                expected: a.depth,
+                found: b.depth,
+            });
+        }
+        if !(0.0..=1.0).contains(&lambda) {
+            return Err(Error::Config(format!(
+                "interpolation weight {lambda} must be between 0 and 1"
+            )));
+        }
+        let blend = |(wa, wb): (usize, usize)| {
+            let weight = lambda * wa as f64 + (1.0 - lambda) * wb as f64;
+            (weight.round() as usize).max(min_weight)
+        };
+
+        let mut weights: BTreeMap<Vec<u8>, (usize, usize)> = a
+            .iter()
+            .map(|(sequence, weight)| (sequence, (weight, 0)))
+            .collect();
+        for (sequence, weight) in b.iter() {
+            weights.entry(sequence).or_default().1 = weight;
+        }
+        let mut markov = Markov::new(a.depth);
+        for (sequence, weights) in weights {
+            let weight = blend(weights);
+            if weight > 0 {
+                markov.insert(&sequence, weight)?;
+            }
+        }
+
+        let mut escapes: BTreeMap<&[u8], (usize, usize)> = a
+            .escapes
+            .iter()
+            .map(|(context, weight)| (&context[..], (*weight, 0)))
+            .collect();
+        for (context, weight) in b.escapes.iter() {
+            escapes.entry(context).or_default().1 = *weight;
+        }
+        // escapes are only kept for contexts that still have successors, like in `retain`.
+        let mut blended = Map::new();
+        for (context, weights) in escapes {
+            let weight = blend(weights);
+            if weight > 0 && !markov.successors(context)?.is_empty() {
+                blended.insert(context.into(), weight);
+            }
+        }
+        markov.escapes = Arc::new(blended);
+        Ok(markov)
+    }
+
+    /// Patches the model for data like `sample`, so that it can code the sample without
+    /// escaping, while staying close to its size.
+    ///
+    /// Only sequences occurring in the sample at least `min_occurrences` times are considered.
+    /// Those the model does not have are inserted with their number of occurrences, those
+    /// whose codes are longer than [`DEFAULT_AUGMENT_CODE_LEN`] bits get a larger weight, see
+    /// [`Markov::augment_from_with_max_code_len`]. Errors of [`Markov::insert`] are passed on.
+    pub fn augment_from(
+        &mut self,
+        sample: &[u8],
+        min_occurrences: usize,
+    ) -> Result<AugmentReport, Error> {
+        self.augment_from_with_max_code_len(sample, min_occurrences, DEFAULT_AUGMENT_CODE_LEN)
+    }
+
+    /// Patches the model like [`Markov::augment_from`], boosting the sequences whose codes are
+    /// longer than `max_code_len` bits.
+    ///
+    /// A boosted sequence is raised to a weight of at least `2^(1 - max_code_len)` of its
+    /// context, which gives it a code of about that length. Huffman codes are not bound by the
+    /// weight alone, so in contexts of very uneven weights some may remain longer.
+    pub fn augment_from_with_max_code_len(
+        &mut self,
+        sample: &[u8],
+        min_occurrences: usize,
+        max_code_len: u8,
+    ) -> Result<AugmentReport, Error> {
+        let mut counts = Markov::new(self.depth);
+        counts.insert_run(sample);
+        let encoder = self.encoder();
+        let index = self.context_index();
+        let shift = u32::from(max_code_len.clamp(1, 64)) - 1;
+
+        let mut report = AugmentReport::default();
+        let mut updates = vec![];
+        for (sequence, count) in counts.iter() {
+            if count < min_occurrences {
+                report.skipped += 1;
+                continue;
+            }
+            let (byte, context) = sequence.split_last().expect("sequences are not empty");
+            match encoder.code_len(&sequence) {
+                None => {
+                    report.added += 1;
+                    updates.push((sequence, count));
+                }
+                Some(len) if len > max_code_len => {
+                    let total = index.total(context).unwrap_or(0);
+                    let weight = index.weight(context, *byte).unwrap_or(0);
+                    let wanted = total.checked_shr(shift).unwrap_or(0).max(1);
+                    if wanted > weight {
+                        report.boosted += 1;
+                        updates.push((sequence, wanted - weight));
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        for (sequence, weight) in updates {
+            self.insert(&sequence, weight)?;
+        }
+        Ok(report)
+    }
+
+    /// Keeps only the sequences for which `pred`, given the sequence and its weight, returns
+    /// true. Escape weights are kept for the contexts that still have successors.
+    pub fn retain(&mut self, mut pred: impl FnMut(&[u8], usize) -> bool) {
+        let mut prefix = Vec::with_capacity(self.depth);
+        self.root.retain(&mut prefix, &mut pred);
+        let root = &self.root;
+        Arc::make_mut(&mut self.escapes).retain(|context, _| {
+            context
+                .iter()
+                .try_fold(root, |node, byte| node.node()?.get(byte))
+                .is_some()
+        });
+    }
+
+    /// Copy of this model with only the sequences for which `pred` returns true, see
+    /// [`Markov::retain`].
+    pub fn filtered(&self, pred: impl FnMut(&[u8], usize) -> bool) -> Markov {
+        let mut markov = self.clone();
+        markov.retain(pred);
+        markov
+    }
+
+    /// Keeps only the `k` heaviest successors of every context, adding the weight of the
+    /// removed ones to the escape weight of the context.
+    pub fn cap_successors(&mut self, k: usize) {
+        let mut prefix = Vec::with_capacity(self.depth);
+        self.root.cap_successors(
+            &mut prefix,
+            self.depth - 1,
+            k,
+            Arc::make_mut(&mut self.escapes),
+        );
+    }
+
+    /// Successors of `context` along with their weights, the context is one byte shorter than
+    /// the depth.
+    pub fn successors(&self, context: &[u8]) -> Result<Vec<WeightedItem>, SequenceLengthError> {
+        check_length(context, self.depth - 1)?;
+        Ok(context
+            .iter()
+            .try_fold(&self.root, |node, key| node.node()?.get(key))
+            .map(MarkovNode::items)
+            .unwrap_or_default())
+    }
+
+    /// Builds a [`ContextIndex`] of the current weights in a single traversal.
+    pub fn context_index(&self) -> ContextIndex {
+        ContextIndex {
+            contexts: self
+                .iter_prefix()
+                .filter_map(|(context, items)| {
+                    Some((context.into(), CumulativeWeights::new(&items)?))
+                })
+                .collect(),
+        }
+    }
+
+    /// Counts the contexts and deterministic contexts with their weights in a single traversal.
+    pub fn context_stats(&self) -> ContextStats {
+        let mut stats = ContextStats::default();
+        for (context, items) in self.iter_prefix() {
+            let escape = self.escapes.get(&context[..]).copied().unwrap_or(0);
+            let weight = items.iter().fold(escape as u64, |sum, item| {
+                sum.saturating_add(item.weight as u64)
+            });
+            stats.contexts += 1;
+            stats.weight = stats.weight.saturating_add(weight);
+            if items.len() == 1 && escape == 0 {
+                stats.deterministic += 1;
+                stats.deterministic_weight = stats.deterministic_weight.saturating_add(weight);
+            }
+        }
+        stats
+    }
+
+    /// Contexts with the sum of their successor and escape weights, heaviest first and ties in
+    /// ascending order of the contexts, summed in a single traversal.
+    pub fn contexts_by_weight(&self) -> Vec<(Box<[u8]>, u64)> {
+        let mut weights = self.context_weights();
+        weights.sort_unstable_by(by_weight);
+        weights
+    }
+
+    /// The `n` heaviest contexts, like the start of [`Markov::contexts_by_weight`] without
+    /// sorting the others.
+    pub fn top_contexts(&self, n: usize) -> Vec<(Box<[u8]>, u64)> {
+        let mut weights = self.context_weights();
+        if n < weights.len() {
+            weights.select_nth_unstable_by(n, by_weight);
+            weights.truncate(n);
+        }
+        weights.sort_unstable_by(by_weight);
+        weights
+    }
+
+    fn context_weights(&self) -> Vec<(Box<[u8]>, u64)> {
+        let mut weights = vec![];
+        let mut prefix = Vec::with_capacity(self.depth);
+        self.root
+            .context_weights(&mut prefix, self.depth - 1, &self.escapes, &mut weights);
+        weights
+    }
+
+    /// Number of contexts by their number of distinct successors, counted in a single traversal.
+    /// Contexts that are followed by every byte are in the last bucket.
+    pub fn fanout_histogram(&self) -> [usize; 257] {
+        let mut histogram = [0; 257];
+        for (_, items) in self.iter_prefix() {
+            histogram[items.len()] += 1;
+        }
+        histogram
+    }
+
+    /// Entropy of the byte following a context, averaged over the contexts by their weight, in
+    /// bits per byte. Escape weights count as one more successor.
+    pub fn conditional_entropy(&self) -> f64 {
+        let (mut total, mut sum) = (0u64, 0.0);
+        for (_, weight, entropy) in self.context_entropies() {
+            total += weight;
+            sum += weight as f64 * entropy;
+        }
+        if total == 0 {
+            return 0.0;
+        }
+        sum / total as f64
+    }
+
+    /// [`Markov::conditional_entropy`] of the contexts grouped by their first byte, in a single
+    /// traversal. All entries are empty for a model of depth 1, whose only context is empty.
+    pub fn entropy_by_leading_byte(&self) -> [LeadingByteStats; 256] {
+        let mut stats = [LeadingByteStats::default(); 256];
+        for (context, weight, entropy) in self.context_entropies() {
+            let Some(first) = context.first() else {
+                continue;
+            };
+            let entry = &mut stats[usize::from(*first)];
+            entry.weight += weight;
+            // the sum of weighted entropies until it is divided below.
+            entry.entropy += weight as f64 * entropy;
+        }
+        for entry in stats.iter_mut().filter(|entry| entry.weight > 0) {
+            entry.entropy /= entry.weight as f64;
+        }
+        stats
+    }
+
+    // contexts with their weight and the entropy of their successors, escapes counting as one
+    // more successor.
+    fn context_entropies(&self) -> impl Iterator<Item = (Vec<u8>, u64, f64)> + '_ {
+        self.iter_prefix().map(|(context, items)| {
+            let escape = self.escapes.get(&context[..]).copied().unwrap_or(0);
+            let weights: Vec<u64> = items
+                .iter()
+                .map(|item| item.weight as u64)
+                .chain([escape as u64])
+                .collect();
+            let weight = weights.iter().sum();
+            (context, weight, entropy(weights))
+        })
+    }
+
+    /// Number of contexts with a single successor, see [`Markov::context_stats`].
+    pub fn deterministic_contexts(&self) -> usize {
+        self.context_stats().deterministic
+    }
+
+    /// Combined weight of the successors removed from `context` by capping.
+    pub fn escape_weight(&self, context: &[u8]) -> Option<usize> {
+        self.escapes.get(context).copied()
+    }
+
+    pub fn has_escapes(&self) -> bool {
+        !self.escapes.is_empty()
+    }
+
+    /// Bytes occurring anywhere in the sequences of the model, in ascending order.
+    pub fn alphabet(&self) -> Vec<u8> {
+        let mut seen = [false; 256];
+        self.root.mark_bytes(&mut seen);
+        (0..=u8::MAX)
+            .filter(|byte| seen[usize::from(*byte)])
+            .collect()
+    }
+
+    pub fn get(&self, sequence: &[u8]) -> Result<Option<&MarkovNode>, SequenceLengthError> {
+        check_length(sequence, self.depth)?;
+
+        let result = sequence
+            .iter()
+            .try_fold(&self.root, |node, key| match node {
+                MarkovNode::Node(node) => node.get(key),
+                MarkovNode::Leaf(_) => None,
+            });
+
+        Ok(result)
+    }
+
+    pub fn export<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        let mut record = Vec::with_capacity(self.depth + 10);
+        for (sequence, weight) in self.iter() {
+            record.clear();
+            record.extend_from_slice(&sequence);
+            write_varint(&mut record, weight as u64)?;
+            write_varint(&mut writer, record.len() as u64)?;
+            writer.write_all(&record)?;
+        }
+        Ok(())
+    }
+
+    pub fn import<R: Read>(depth: usize, mut reader: R) -> Result<Self, Error> {
+        let mut builder = SpineBuilder::new(depth);
+        let mut record = Vec::with_capacity(depth + 10);
+        // records end cleanly only at a record boundary.
+        while let Some(first) = read_byte_or_end(&mut reader)? {
+            let length = read_varint(&mut [first].chain(&mut reader))?;
+            if length <= depth as u64 || length > depth as u64 + 10 {
+                return Err(Error::Format("invalid record length"));
+            }
+            record.resize(length as usize, 0);
+            reader.read_exact(&mut record)?;
+
+            let (sequence, mut weight) = record.split_at(depth);
+            let weight = read_varint(&mut weight)
+                .ok()
+                .filter(|_| weight.is_empty())
+                .ok_or(Error::Format("invalid record weight"))?;
+            let weight = weight
+                .try_into()
+                .map_err(|_| Error::Format("weight too large"))?;
+            builder.push(sequence, weight)?;
+        }
+        Ok(builder.finish())
+    }
+
+    pub fn export_compact<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        let mut previous: Vec<u8> = vec![];
+        for (sequence, weight) in self.iter() {
+            let shared = sequence
+                .iter()
+                .zip(&previous)
+                .take_while(|(a, b)| a == b)
+                .count();
+            writer.write_all(&[shared as u8])?;
+            writer.write_all(&sequence[shared..])?;
+            write_varint(&mut writer, weight as u64)?;
+            previous = sequence;
+        }
+        Ok(())
+    }
+
+    pub fn import_compact<R: Read>(depth: usize, mut reader: R) -> Result<Self, Error> {
+        let mut builder = SpineBuilder::new(depth);
+        let mut sequence = vec![0; depth];
+        let mut previous = 0;
+        while let Some(shared) = read_byte_or_end(&mut reader)? {
+            let shared = usize::from(shared);
+            if shared > previous || shared >= depth {
+                return Err(Error::Format("invalid shared prefix length"));
+            }
+            reader.read_exact(&mut sequence[shared..])?;
+            let weight = read_varint(&mut reader)?
+                .try_into()
+                .map_err(|_| Error::Format("weight too large"))?;
+            builder.push(&sequence, weight)?;
+            previous = depth;
+        }
+        Ok(builder.finish())
+    }
+
+    /// Writes the model in `format`, with a header that [`Markov::load`] reads it back with.
+    ///
+    /// Models are written and read front to back without seeking, so that they can go through
+    /// pipes or layers such as encryption. Readers may return as few bytes at once as they like.
+    pub fn save<W: Write>(&self, mut writer: W, format: ExportFormat) -> Result<(), Error> {
+        let mut flags = match format {
+            ExportFormat::Plain => 0,
+            ExportFormat::Compact => MODEL_FLAG_COMPACT,
+        };
+        if self.has_escapes() {
+            flags |= MODEL_FLAG_ESCAPES;
+        }
+        let depth: u8 = self
+            .depth
+            .try_into()
+            .map_err(|_| Error::Format("depth too large"))?;
+        writer.write_all(&MODEL_MAGIC)?;
+        writer.write_all(&MODEL_VERSION.to_le_bytes())?;
+        writer.write_all(&flags.to_le_bytes())?;
+        writer.write_all(&[depth])?;
+        if self.has_escapes() {
+            write_varint(&mut writer, self.escapes.len() as u64)?;
+            for (context, weight) in self.escapes.iter() {
+                writer.write_all(context)?;
+                write_varint(&mut writer, *weight as u64)?;
+            }
+        }
+        match format {
+            ExportFormat::Plain => self.export(writer),
+            ExportFormat::Compact => self.export_compact(writer),
+        }
+    }
+
+    /// Writes the model in the default format, see [`Markov::save`].
+    pub fn save_to(&self, writer: impl Write) -> Result<(), Error> {
+        self.save(writer, ExportFormat::default())
+    }
+
+    /// Saves the model to the file at `path`, replacing it if it exists.
+    pub fn save_file(&self, path: impl AsRef<Path>, format: ExportFormat) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.save(&mut writer, format)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads a model from the file at `path`, see [`Markov::load`].
+    pub fn load_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::load(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads a model like [`Markov::load`], which is the same.
+    pub fn load_from(reader: impl Read) -> Result<Self, Error> {
+        Self::load(reader)
+    }
+
+    /// Reads a model written by [`Markov::save`], up to the end of `reader`.
+    pub fn load<R: Read>(mut reader: R) -> Result<Self, Error> {
+        let mut header = [0; 9];
+        reader.read_exact(&mut header)?;
+        if header[..4] != MODEL_MAGIC {
+            return Err(Error::Format("bad magic"));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version == 0 {
+            return Err(Error::Format("zero version"));
+        }
+        if version > MODEL_VERSION {
+            return Err(UnsupportedFeature::Version {
+                found: version,
+                supported: MODEL_VERSION,
+            }
+            .into());
+        }
+        let flags = u16::from_le_bytes([header[6], header[7]]);
+        let depth = usize::from(header[8]);
+        if depth == 0 {
+            return Err(Error::Format("zero depth"));
+        }
+        if depth > MAX_SUPPORTED_DEPTH {
+            return Err(Error::Format("depth too large"));
+        }
+
+        if flags & !(MODEL_FLAG_COMPACT | MODEL_FLAG_ESCAPES) != 0 {
+            return Err(Error::Format("unknown model flags"));
+        }
+
+        let mut escapes = Map::new();
+        if flags & MODEL_FLAG_ESCAPES != 0 {
+            let mut previous: Option<Box<[u8]>> = None;
+            for _ in 0..read_varint(&mut reader)? {
+                let mut context = vec![0; depth - 1];
+                reader.read_exact(&mut context)?;
+                let context: Box<[u8]> = context.into();
+                if previous.is_some_and(|previous| previous >= context) {
+                    return Err(Error::Format("escapes out of order"));
+                }
+                let weight = read_varint(&mut reader)?
+                    .try_into()
+                    .map_err(|_| Error::Format("weight too large"))?;
+                escapes.insert(context.clone(), weight);
+                previous = Some(context);
+            }
+        }
+
+        let mut markov = if flags & MODEL_FLAG_COMPACT != 0 {
+            Self::import_compact(depth, reader)?
+        } else {
+            Self::import(depth, reader)?
+        };
+        markov.escapes = Arc::new(escapes);
+        Ok(markov)
+    }
+
+    pub fn writer(&mut self) -> MarkovWriter<&mut Self> {
+        MarkovWriter::new(self)
+    }
+
+    pub fn into_writer(self) -> MarkovWriter<Self> {
+        MarkovWriter::new(self)
+    }
+
+    /// Trains on `data` as the continuation of the bytes of earlier calls, like writing it to
+    /// a [`MarkovWriter`] kept for the model. Windows across the calls are inserted once they are
+    /// complete, call [`Markov::end_bytes`] to start an unrelated input.
+    ///
+    /// Panics if weights overflow with [`WeightPolicy::Error`], see
+    /// [`Markov::try_write_bytes`].
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.try_write_bytes(data).unwrap();
+    }
+
+    /// Trains on `data` like [`Markov::write_bytes`], failing if weights overflow.
+    pub fn try_write_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut pending = std::mem::take(&mut self.pending.0);
+        let result = buffered_windows(self.depth, &mut pending, data, |window| {
+            self.insert(window, DEFAULT_WEIGHT).map(drop)
+        });
+        self.pending.0 = pending;
+        result
+    }
+
+    /// Drops the bytes of [`Markov::write_bytes`] that do not complete a window yet, so that
+    /// the next call does not continue them.
+    pub fn end_bytes(&mut self) {
+        self.pending.0.clear();
+    }
+
+    /// Trains on every source in turn, inserting its windows with the weight it comes with, such
+    /// as to count curated data more than scraped data. Windows do not span two sources.
+    pub fn train_sources<R: Read>(
+        &mut self,
+        sources: impl IntoIterator<Item = (R, usize)>,
+    ) -> IoResult<TrainSummary> {
+        let mut summary = TrainSummary::default();
+        for (mut reader, weight) in sources {
+            let mut writer = MarkovWriter::with_weight(&mut *self, weight);
+            summary.bytes.push(std::io::copy(&mut reader, &mut writer)?);
+            summary.stats += writer.stats();
+        }
+        Ok(summary)
+    }
+
+    /// Sink training the model with [`Markov::write_bytes`], such as to `io::copy` a file into.
+    pub fn train_sink(&mut self) -> TrainSink<'_> {
+        TrainSink(self)
+    }
+
+    /// Writer training on the input with every byte replaced by `mapper`, such as
+    /// [`ByteMapper::ascii_lowercase`] to fold case.
+    pub fn writer_mapped(&mut self, mapper: ByteMapper) -> MarkovWriter<Mapped<&mut Self>> {
+        MarkovWriter::new(Mapped::new(self, mapper))
+    }
+
+    pub fn writer_with_limits(&mut self, limits: TrainLimits) -> MarkovWriter<Limited<&mut Self>> {
+        MarkovWriter::new(Limited::new(self, limits))
+    }
+
+    /// Writer that inserts every window with the weight `weight_fn` returns for its index.
+    pub fn weighted_writer<F: FnMut(u64) -> usize>(
+        &mut self,
+        weight_fn: F,
+    ) -> MarkovWriter<Weighted<&mut Self, F>> {
+        MarkovWriter::new(Weighted::new(self, weight_fn))
+    }
+
+    pub fn encoder(&self) -> Encoder {
+        self.decoder().encoder()
+    }
+
+    pub fn decoder(&self) -> Decoder {
+        Decoder::new(self)
+    }
+}
+
+// builds a model from sequences in ascending order. only the rightmost path of the trie is
+// still open for insertion, so every node's children can be collected in bulk once it is closed.
+struct SpineBuilder {
+    depth: usize,
+    previous: Vec<u8>,
+    levels: Vec<Vec<(u8, MarkovNode)>>,
+}
+
+impl SpineBuilder {
+    fn new(depth: usize) -> Self {
+        SpineBuilder {
+            depth,
+            previous: vec![],
+            levels: vec![vec![]; depth],
+        }
+    }
+
+    fn close(&mut self, level: usize) {
+        for level in (level + 1..self.depth).rev() {
+            let children = std::mem::take(&mut self.levels[level]);
+            let node = MarkovNode::Node(Arc::new(children.into_iter().collect()));
+            self.levels[level - 1].push((self.previous[level - 1], node));
+        }
+    }
+
+    fn push(&mut self, sequence: &[u8], weight: usize) -> Result<(), Error> {
+        if !self.previous.is_empty() {
+            if sequence <= &self.previous[..] {
+                return Err(Error::Format("sequences out of order"));
+            }
+            let shared = sequence
+                .iter()
+                .zip(&self.previous)
+                .take_while(|(a, b)| a == b)
+                .count();
+            self.close(shared);
+        }
+
+        self.levels[self.depth - 1].push((sequence[self.depth - 1], MarkovNode::Leaf(weight)));
+        self.previous.clear();
+        self.previous.extend_from_slice(sequence);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Markov {
+        if !self.previous.is_empty() {
+            self.close(0);
+        }
+        Markov {
+            depth: self.depth,
+            root: MarkovNode::Node(Arc::new(self.levels.swap_remove(0).into_iter().collect())),
+            escapes: Default::default(),
+            policy: WeightPolicy::default(),
+            pending: Pending::default(),
+        }
+    }
+}
+
+pub(crate) const DEFAULT_WEIGHT: usize = 1;
+
+// number of windows sorted at a time by insert_run.
+const RUN_CHUNK: usize = 1 << 16;
+
+// windows per chunk when sampling, small enough for an even sample of modest inputs.
+const DEFAULT_SAMPLE_CHUNK: u64 = 1 << 12;
+
+#[allow(clippy::len_without_is_empty)]
+pub trait SequenceWriter {
+    fn len(&self) -> usize;
+    fn write_weighted(&mut self, sequence: &[u8], weight: usize) -> Result<Insertion, Error>;
+
+    fn write(&mut self, sequence: &[u8]) -> Result<Insertion, Error> {
+        self.write_weighted(sequence, DEFAULT_WEIGHT)
+    }
+}
+
+impl<T: BorrowMut<Markov>> SequenceWriter for T {
+    fn len(&self) -> usize {
+        Markov::len(self.borrow())
+    }
+
+    fn write_weighted(&mut self, sequence: &[u8], weight: usize) -> Result<Insertion, Error> {
+        Markov::insert(self.borrow_mut(), sequence, weight).map(Insertion::from)
+    }
+}
+
+/// Writes into a model shared between threads, locking it for every window.
+///
+/// Locking per window costs far more than inserting the window, and writers on other threads
+/// wait for each other, so this suits low rates of writes. For fast training from several
+/// threads, use a [`ShardedTrainer`]. Writing into a poisoned model fails with
+/// [`Error::Poisoned`].
+impl SequenceWriter for &Mutex<Markov> {
+    fn len(&self) -> usize {
+        // reading the depth is fine even after a panic.
+        self.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    fn write_weighted(&mut self, sequence: &[u8], weight: usize) -> Result<Insertion, Error> {
+        let mut markov = self.lock().map_err(|_| Error::Poisoned)?;
+        markov.insert(sequence, weight).map(Insertion::from)
+    }
+}
+
+/// Like the writer for `&Mutex<Markov>`, owning a reference to the model.
+impl SequenceWriter for Arc<Mutex<Markov>> {
+    fn len(&self) -> usize {
+        (&**self).len()
+    }
+
+    fn write_weighted(&mut self, sequence: &[u8], weight: usize) -> Result<Insertion, Error> {
+        (&**self).write_weighted(sequence, weight)
+    }
+}
+
+/// Sequence writer that weighs every window by a function of its index, such as to let recent
+/// data count more than old data.
+#[derive(Debug, Clone)]
+pub struct Weighted<S: SequenceWriter, F: FnMut(u64) -> usize> {
+    writer: S,
+    weight_fn: F,
+    index: u64,
+}
+
+impl<S: SequenceWriter, F: FnMut(u64) -> usize> Weighted<S, F> {
+    pub fn new(writer: S, weight_fn: F) -> Self {
+        Weighted {
+            writer,
+            weight_fn,
+            index: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.writer
+    }
+}
+
+impl<S: SequenceWriter, F: FnMut(u64) -> usize> SequenceWriter for Weighted<S, F> {
+    fn len(&self) -> usize {
+        self.writer.len()
+    }
+
+    fn write_weighted(&mut self, sequence: &[u8], _weight: usize) -> Result<Insertion, Error> {
+        let weight = (self.weight_fn)(self.index);
+        self.index += 1;
+        self.writer.write_weighted(sequence, weight)
+    }
+}
+
+/// Sequence writer that passes on every window with its bytes replaced by a [`ByteMapper`].
+#[derive(Debug, Clone)]
+pub struct Mapped<S: SequenceWriter> {
+    writer: S,
+    mapper: ByteMapper,
+    window: Vec<u8>,
+}
+
+impl<S: SequenceWriter> Mapped<S> {
+    pub fn new(writer: S, mapper: ByteMapper) -> Self {
+        Mapped {
+            writer,
+            mapper,
+            window: vec![],
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.writer
+    }
+}
+
+impl<S: SequenceWriter> SequenceWriter for Mapped<S> {
+    fn len(&self) -> usize {
+        self.writer.len()
+    }
+
+    fn write_weighted(&mut self, sequence: &[u8], weight: usize) -> Result<Insertion, Error> {
+        self.window.clear();
+        self.window.extend_from_slice(sequence);
+        self.mapper.map_slice(&mut self.window);
+        self.writer.write_weighted(&self.window, weight)
+    }
+}
+
+/// Sequence writer that only passes on the windows in the chunks selected by a [`Sampling`].
+///
+/// Chunks are runs of consecutive windows of the input, so every window passed on is one of
+/// the input. None span skipped data, unlike windows of the kept chunks joined together.
+#[derive(Debug, Clone)]
+pub struct SamplingWriter<S: SequenceWriter> {
+    writer: S,
+    sampling: Sampling,
+    index: u64,
+    keep: bool,
+    sampled: u64,
+}
+
+impl<S: SequenceWriter> SamplingWriter<S> {
+    pub fn new(writer: S, sampling: Sampling) -> Self {
+        SamplingWriter {
+            writer,
+            sampling,
+            index: 0,
+            keep: false,
+            sampled: 0,
+        }
+    }
+
+    /// Number of windows passed on, each of which trains the model on one byte.
+    pub fn sampled(&self) -> u64 {
+        self.sampled
+    }
+
+    pub fn into_inner(self) -> S {
+        self.writer
+    }
+}
+
+impl<S: SequenceWriter> SequenceWriter for SamplingWriter<S> {
+    fn len(&self) -> usize {
+        self.writer.len()
+    }
+
+    fn write_weighted(&mut self, sequence: &[u8], weight: usize) -> Result<Insertion, Error> {
+        let chunk_size = self.sampling.chunk_size;
+        if self.index.is_multiple_of(chunk_size) {
+            self.keep = self.sampling.keeps(self.index / chunk_size);
+        }
+        self.index += 1;
+        if !self.keep {
+            return Ok(Insertion::Skipped);
+        }
+        self.sampled += 1;
+        self.writer.write_weighted(sequence, weight)
+    }
+}
+
+/// Model that refuses to grow past its [`TrainLimits`].
+#[derive(Debug, Clone)]
+pub struct Limited<M: BorrowMut<Markov>> {
+    markov: M,
+    limits: TrainLimits,
+    sequences: usize,
+    nodes: usize,
+}
+
+impl<M: BorrowMut<Markov>> Limited<M> {
+    pub fn new(markov: M, limits: TrainLimits) -> Self {
+        let (sequences, nodes) = markov.borrow().root.count();
+        Limited {
+            markov,
+            limits,
+            sequences,
+            nodes,
+        }
+    }
+
+    pub fn into_inner(self) -> M {
+        self.markov
+    }
+}
+
+impl<M: BorrowMut<Markov>> SequenceWriter for Limited<M> {
+    fn len(&self) -> usize {
+        self.markov.borrow().len()
+    }
+
+    fn write_weighted(&mut self, sequence: &[u8], weight: usize) -> Result<Insertion, Error> {
+        // the limits are checked before inserting, so that the model never exceeds them.
+        let missing = self.markov.borrow().root.missing(sequence);
+        if missing > 0 {
+            if let Some(limit) = self.limits.max_sequences {
+                if self.sequences >= limit {
+                    return Err(Error::LimitExceeded {
+                        kind: "sequence",
+                        limit,
+                    });
+                }
+            }
+            if let Some(limit) = self.limits.max_memory {
+                if (self.nodes + missing).saturating_mul(NODE_MEMORY) > limit {
+                    return Err(Error::LimitExceeded {
+                        kind: "memory",
+                        limit,
+                    });
+                }
+            }
+        }
+
+        let (_, created) = self.markov.borrow_mut().insert_counted(sequence, weight)?;
+        if created > 0 {
+            self.sequences += 1;
+            self.nodes += created;
+        }
+        Ok(Insertion::from_created(created))
+    }
+}
+
+/// Trains a model from several streams at once, each through its own [`ShardHandle`].
+///
+/// Every handle trains a separate shard with its own window buffer, so handles on different
+/// threads never wait on each other. Dropped handles hand their shard back, and
+/// [`ShardedTrainer::finish`] merges them into one model. Windows never span two streams.
+#[derive(Debug, Clone)]
+pub struct ShardedTrainer {
+    depth: usize,
+    shards: Arc<Mutex<Vec<Markov>>>,
+}
+
+impl ShardedTrainer {
+    pub fn new(depth: usize) -> Self {
+        ShardedTrainer {
+            depth,
+            shards: Default::default(),
+        }
+    }
+
+    /// Handle training a new shard.
+    pub fn handle(&self) -> ShardHandle {
+        ShardHandle {
+            writer: MarkovWriter::new(Markov::new(self.depth)),
+            shards: self.shards.clone(),
+        }
+    }
+
+    /// Merges the shards of all dropped handles, handles still alive are left out.
+    pub fn finish(self) -> Markov {
+        let shards = std::mem::take(&mut *self.shards.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut markov = Markov::new(self.depth);
+        for shard in shards {
+            markov.merge(shard).unwrap();
+        }
+        markov
+    }
+}
+
+/// Writer into one shard of a [`ShardedTrainer`], handing it back when dropped.
+#[derive(Debug)]
+pub struct ShardHandle {
+    writer: MarkovWriter<Markov>,
+    shards: Arc<Mutex<Vec<Markov>>>,
+}
+
+impl SequenceWriter for ShardHandle {
+    fn len(&self) -> usize {
+        self.writer.writer.len()
+    }
+
+    fn write_weighted(&mut self, sequence: &[u8], weight: usize) -> Result<Insertion, Error> {
+        self.writer.writer.write_weighted(sequence, weight)
+    }
+}
+
+impl Write for ShardHandle {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.writer.try_write(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ShardHandle {
+    fn drop(&mut self) {
+        let depth = self.writer.writer.len();
+        let writer = std::mem::replace(&mut self.writer, MarkovWriter::new(Markov::new(depth)));
+        let mut shards = self.shards.lock().unwrap_or_else(|e| e.into_inner());
+        shards.push(writer.finish());
+    }
+}
+
+/// Writes into a model with [`Markov::write_bytes`], see [`Markov::train_sink`].
+#[derive(Debug)]
+pub struct TrainSink<'a>(&'a mut Markov);
+
+impl Write for TrainSink<'_> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.try_write_bytes(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// Passes every window of the bytes written to it on to a [`SequenceWriter`], such as a model
+/// it trains.
+#[derive(Debug, Clone)]
+pub struct MarkovWriter<W: SequenceWriter> {
+    writer: W,
+    buffer: Vec<u8>,
+    cancel: Option<CancellationToken>,
+    weight: usize,
+    stats: TrainStats,
+}
+
+#[deprecated(note = "renamed to `MarkovWriter`")]
+pub type Writer<W> = MarkovWriter<W>;
+
+impl<W: SequenceWriter> MarkovWriter<W> {
+    pub fn new(sequence_writer: W) -> Self {
+        Self::with_weight(sequence_writer, DEFAULT_WEIGHT)
+    }
+
+    /// Writer passing every window on with `weight` instead of 1. Larger weights keep more
+    /// resolution when the model is later decayed or quantized. Writers that pick their own
+    /// weights, such as [`Weighted`], ignore it.
+    pub fn with_weight(sequence_writer: W, weight: usize) -> Self {
+        MarkovWriter {
+            writer: sequence_writer,
+            buffer: vec![],
+            cancel: None,
+            weight,
+            stats: TrainStats::default(),
+        }
+    }
+
+    /// Checks `token` before every window, failing with [`Error::Cancelled`] once it is set.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    pub fn write(&mut self, input: &[u8]) {
+        self.try_write(input).unwrap();
+    }
+
+    pub fn try_write(&mut self, input: &[u8]) -> Result<(), Error> {
+        buffered_windows(self.writer.len(), &mut self.buffer, input, |window| {
+            if let Some(token) = &self.cancel {
+                token.check()?;
+            }
+            let stats = &mut self.stats;
+            self.writer
+                .write_weighted(window, self.weight)
+                .map(|insertion| stats.record(insertion))
+        })?;
+        self.stats.bytes += input.len() as u64;
+        Ok(())
+    }
+
+    /// What the writer trained on so far.
+    pub fn stats(&self) -> TrainStats {
+        self.stats
+    }
+
+    pub fn finish(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: SequenceWriter> Write for MarkovWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.try_write(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{huffman::Coder, util::ByteHistogram};
+    use proptest::prelude::*;
+    use std::io::ErrorKind;
+    use test_strategy::{proptest, Arbitrary};
+
+    macro_rules! test_markov_insert {
+        ($name:ident, $len:expr) => {
+            #[proptest]
+            fn $name(sequences: Vec<([u8; $len], usize)>) {
+                let mut markov = Markov::new($len);
+
+                // sequences are only new the first time they are inserted.
+                let mut seen = std::collections::BTreeSet::new();
+                for (sequence, weight) in &sequences {
+                    let outcome = markov.insert(&sequence[..], *weight).unwrap();
+                    assert_eq!(outcome.newly_created, seen.insert(*sequence));
+                }
+
+                for (sequence, weight) in &sequences {
+                    let node = markov.get(&sequence[..]).unwrap().unwrap();
+                    let count = match node {
+                        MarkovNode::Leaf(count) => *count,
+                        _ => unreachable!(),
+                    };
+                    assert!(count >= *weight);
+                }
+            }
+        };
+    }
+
+    #[derive(Arbitrary, Debug, Clone, Copy, PartialEq)]
+    pub struct Length(#[strategy(1usize..5)] usize);
+
+    impl std::ops::Deref for Length {
+        type Target = usize;
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    test_markov_insert!(test_markov1_insert, 1);
+    test_markov_insert!(test_markov2_insert, 2);
+    test_markov_insert!(test_markov3_insert, 3);
+    test_markov_insert!(test_markov4_insert, 4);
+    test_markov_insert!(test_markov5_insert, 5);
+
+    #[test]
+    fn test_content_hash_vectors() {
+        let mut markov = Markov::new(3);
+        markov.writer().write(b"abracadabra");
+        assert_eq!(markov.content_hash(), 0x4473836e82855840);
+        assert_eq!(Markov::new(3).content_hash(), 0x4d922029c1f42e7d);
+        markov.insert(b"abr", 1).unwrap();
+        assert_eq!(markov.content_hash(), 0x32c2b1411f7806da);
+    }
+
+    #[proptest]
+    fn test_export_import(inputs: Vec<Vec<u8>>, length: Length) {
+        let mut markov = Markov::new(*length);
+        for (index, input) in inputs.iter().enumerate() {
+            for window in input.windows(*length) {
+                markov.insert(window, index + 1).unwrap();
+            }
+        }
+
+        let mut exported = vec![];
+        markov.export(&mut exported).unwrap();
+        prop_assert_eq!(Markov::import(*length, &exported[..]).unwrap(), markov);
+    }
+
+    #[test]
+    fn test_import_invalid() {
+        let mut markov = Markov::new(2);
+        markov.insert(b"ab", 1).unwrap();
+        markov.insert(b"ac", 300).unwrap();
+        let mut exported = vec![];
+        markov.export(&mut exported).unwrap();
+
+        assert!(matches!(
+            Markov::import(2, &exported[..exported.len() - 1]),
+            Err(Error::Truncated)
+        ));
+
+        // swap the two records around.
+        let mut swapped = exported[4..].to_vec();
+        swapped.extend_from_slice(&exported[..4]);
+        assert!(matches!(
+            Markov::import(2, &swapped[..]),
+            Err(Error::Format(_))
+        ));
+
+        let mut duplicated = exported[..4].to_vec();
+        duplicated.extend_from_slice(&exported[..4]);
+        assert!(matches!(
+            Markov::import(2, &duplicated[..]),
+            Err(Error::Format(_))
+        ));
+
+        assert!(matches!(
+            Markov::import(2, &[1, b'a'][..]),
+            Err(Error::Format(_))
+        ));
+    }
+
+    #[proptest]
+    fn test_save_load(inputs: Vec<Vec<u8>>, length: Length) {
+        let mut markov = Markov::new(*length);
+        for input in &inputs {
+            markov.writer().write(input);
+        }
+
+        for format in [ExportFormat::Plain, ExportFormat::Compact] {
+            let mut saved = vec![];
+            markov.save(&mut saved, format).unwrap();
+            prop_assert_eq!(&Markov::load(&saved[..]).unwrap(), &markov);
+        }
+    }
+
+    // reader returning a byte at a time, interrupted before every other one.
+    struct Dribble<R> {
+        reader: R,
+        interrupt: bool,
+    }
+
+    impl<R: Read> Read for Dribble<R> {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            let len = buf.len().min(1);
+            self.reader.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_load_sequential() {
+        let mut markov = Markov::new(3);
+        markov.insert_run(include_bytes!("util.rs"));
+        // capping adds escapes, which are saved before the records.
+        markov.cap_successors(2);
+        assert!(markov.has_escapes());
+
+        let dir = tempfile::tempdir().unwrap();
+        for format in [ExportFormat::Plain, ExportFormat::Compact] {
+            let path = dir.path().join("model");
+            markov.save_file(&path, format).unwrap();
+            let loaded = Markov::load_file(&path).unwrap();
+            assert_eq!(loaded, markov);
+
+            let saved = std::fs::read(&path).unwrap();
+            let dribble = Dribble {
+                reader: &saved[..],
+                interrupt: false,
+            };
+            assert_eq!(Markov::load_from(dribble).unwrap(), loaded);
+
+            // the reader only ends once the writer is dropped with the saving thread.
+            let (reader, writer) = std::io::pipe().unwrap();
+            let markov = &markov;
+            let saving = std::thread::scope(|scope| {
+                let saving = scope.spawn(move || markov.save(writer, format));
+                let loaded = Markov::load_from(reader);
+                (saving.join().unwrap(), loaded)
+            });
+            saving.0.unwrap();
+            assert_eq!(saving.1.unwrap(), loaded);
+        }
+
+        let mut saved = vec![];
+        markov.save_to(&mut saved).unwrap();
+        assert_eq!(Markov::load(&saved[..]).unwrap(), markov);
+        assert!(matches!(
+            Markov::load_file(dir.path().join("missing")),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_unsupported_version() {
+        let mut saved = vec![];
+        Markov::new(2)
+            .save(&mut saved, ExportFormat::Plain)
+            .unwrap();
+        saved[4..6].copy_from_slice(&(MODEL_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Markov::load(&saved[..]),
+            Err(Error::Unsupported(UnsupportedFeature::Version { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        assert_eq!(Markov::try_new(MAX_SUPPORTED_DEPTH).unwrap().len(), 16);
+        for depth in [0, MAX_SUPPORTED_DEPTH + 1] {
+            assert!(matches!(Markov::try_new(depth), Err(Error::Config(_))));
+        }
+
+        let mut saved = vec![];
+        let mut markov = Markov::new(MAX_SUPPORTED_DEPTH);
+        markov.writer().write(b"abcdefghijklmnopq");
+        markov.save(&mut saved, ExportFormat::Plain).unwrap();
+        assert_eq!(Markov::load(&saved[..]).unwrap(), markov);
+        // the format has room for deeper models, they are rejected on load.
+        saved[8] = 17;
+        assert!(matches!(
+            Markov::load(&saved[..]),
+            Err(Error::Format("depth too large"))
+        ));
+
+        // so are code tables of deeper models.
+        let mut tables = vec![];
+        markov.decoder().write_tables(&mut tables).unwrap();
+        assert_eq!(
+            Decoder::read_tables(&mut &tables[..]).unwrap(),
+            markov.decoder()
+        );
+        assert_eq!(tables[0], 16);
+        tables[0] = 17;
+        assert!(matches!(
+            Decoder::read_tables(&mut &tables[..]),
+            Err(Error::Format("depth too large"))
+        ));
+        assert!(matches!(
+            Coder::read_tables(&mut &tables[..]),
+            Err(Error::Format("depth too large"))
+        ));
+    }
+
+    #[test]
+    fn test_compact_smaller() {
+        let mut markov = Markov::new(4);
+        markov
+            .writer()
+            .write(include_bytes!("markov.rs").as_slice());
+
+        let mut plain = vec![];
+        markov.export(&mut plain).unwrap();
+        let mut compact = vec![];
+        markov.export_compact(&mut compact).unwrap();
+        assert!(compact.len() * 3 < plain.len() * 2);
+    }
+
+    #[test]
+    fn test_import_compact_invalid() {
+        // first record cannot share a prefix.
+        assert!(matches!(
+            Markov::import_compact(3, &[1, b'a', b'b', 1][..]),
+            Err(Error::Format(_))
+        ));
+
+        // shared length covering the whole sequence.
+        assert!(matches!(
+            Markov::import_compact(2, &[0, b'a', b'b', 1, 2, 1][..]),
+            Err(Error::Format(_))
+        ));
+
+        // huge shared length.
+        assert!(matches!(
+            Markov::import_compact(2, &[0, b'a', b'b', 1, 255, 1][..]),
+            Err(Error::Format(_))
+        ));
+
+        assert!(matches!(
+            Markov::import_compact(2, &[0, b'a', b'b', 1, 1][..]),
+            Err(Error::Truncated)
+        ));
+        assert_eq!(
+            Markov::import_compact(2, &[0, b'a', b'b', 1, 1, b'c', 2][..]).unwrap(),
+            {
+                let mut markov = Markov::new(2);
+                markov.insert(b"ab", 1).unwrap();
+                markov.insert(b"ac", 2).unwrap();
+                markov
+            }
+        );
+    }
+
+    #[test]
+    fn test_writer_limits() {
+        let mut markov = Markov::new(2);
+        markov.writer().write(b"abab");
+        let limits = TrainLimits {
+            max_sequences: Some(3),
+            max_memory: None,
+        };
+
+        // existing sequences count against the limit.
+        let mut writer = markov.writer_with_limits(limits);
+        writer.write_all(b"bac").unwrap();
+        let error = writer.write_all(b"d").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Other);
+        assert!(matches!(
+            Error::from(error),
+            Error::LimitExceeded {
+                kind: "sequence",
+                limit: 3
+            }
+        ));
+        // the sequence over the limit was not inserted.
+        drop(writer);
+        assert_eq!(markov.root.count().0, 3);
+        assert_eq!(markov.get(b"cd").unwrap(), None);
+
+        // neither is one that needs more nodes than the memory limit allows.
+        let (_, nodes) = markov.root.count();
+        let limits = TrainLimits {
+            max_sequences: None,
+            max_memory: Some(nodes * NODE_MEMORY),
+        };
+        let mut limited = Limited::new(&mut markov, limits);
+        limited.write_weighted(b"ab", 1).unwrap();
+        assert!(matches!(
+            limited.write_weighted(b"xy", 1),
+            Err(Error::LimitExceeded { kind: "memory", .. })
+        ));
+        assert_eq!(markov.root.count().1, nodes);
+    }
+
+    #[test]
+    fn test_sampling_writer() {
+        let data: Vec<u8> = (0..100_000u32).map(|index| (index % 251) as u8).collect();
+        let sample = |sampling: Sampling| {
+            let mut writer = MarkovWriter::new(SamplingWriter::new(Markov::new(3), sampling));
+            writer.write(&data);
+            let writer = writer.finish();
+            (writer.sampled(), writer.into_inner())
+        };
+
+        let (all, markov) = sample(Sampling::default());
+        assert_eq!(all, data.len() as u64 - 2);
+        let mut full = Markov::new(3);
+        full.insert_run(&data);
+        assert_eq!(markov, full);
+
+        let sampling = Sampling::new(0.25).unwrap().chunk_size(1000).seed(7);
+        let (sampled, markov) = sample(sampling);
+        assert!((10_000..40_000).contains(&sampled), "{sampled}");
+        assert_eq!(sample(sampling), (sampled, markov.clone()));
+        assert_ne!(sample(sampling.seed(8)).0, sampled);
+        // every sampled window is one of the input.
+        assert!(markov.iter().all(|(sequence, _)| {
+            sequence[1] == ((sequence[0] as u32 + 1) % 251) as u8
+                && sequence[2] == ((sequence[0] as u32 + 2) % 251) as u8
+        }));
+        assert_eq!(
+            markov.iter().map(|(_, weight)| weight as u64).sum::<u64>(),
+            sampled
+        );
+
+        assert_eq!(sample(Sampling::new(0.0).unwrap()).0, 0);
+        assert!(Sampling::new(1.5).is_err());
+    }
+
+    #[proptest]
+    fn test_merge(a: Vec<u8>, b: Vec<u8>, length: Length) {
+        let mut merged = Markov::new(*length);
+        merged.writer().write(&a);
+        let mut other = Markov::new(*length);
+        other.writer().write(&b);
+        merged.merge(other).unwrap();
+
+        let mut expected = Markov::new(*length);
+        expected.writer().write(&a);
+        expected.writer().write(&b);
+        prop_assert_eq!(merged, expected);
+    }
+
+    #[proptest]
+    fn test_merge_policy(
+        a: Vec<u8>,
+        b: Vec<u8>,
+        length: Length,
+        #[strategy(1usize..4)] k: usize,
+        scale: bool,
+    ) {
+        // without overflows, merging context by context sums like merging the trees.
+        let policy = if scale {
+            WeightPolicy::Scale
+        } else {
+            WeightPolicy::Error
+        };
+        let train = |data: &[u8]| {
+            let mut markov = Markov::new(*length);
+            markov.writer().write(data);
+            markov.cap_successors(k);
+            markov
+        };
+        let mut expected = train(&a);
+        expected.merge(train(&b)).unwrap();
+        let mut merged = train(&a).with_weight_policy(policy);
+        merged.merge(train(&b)).unwrap();
+        prop_assert_eq!(merged.with_weight_policy(WeightPolicy::Saturate), expected);
+    }
+
+    #[proptest]
+    fn test_insert_policy(
+        #[strategy(proptest::collection::vec((0u8..3, usize::MAX / 4..=usize::MAX), 1..16))]
+        inserts: Vec<(u8, usize)>,
+    ) {
+        let mut saturate = Markov::new(2);
+        let mut error = Markov::new(2).with_weight_policy(WeightPolicy::Error);
+        let mut scale = Markov::new(2).with_weight_policy(WeightPolicy::Scale);
+        let mut sums = [0u128; 3];
+        let mut saturated = [0usize; 3];
+        for (byte, weight) in inserts {
+            let sequence = [b'a', byte];
+            let sum = sums[usize::from(byte)] + weight as u128;
+            let fits = usize::try_from(sum).ok();
+            let index = usize::from(byte);
+            saturated[index] = saturated[index].saturating_add(weight);
+            prop_assert_eq!(
+                saturate.insert(&sequence, weight).unwrap().count,
+                saturated[index]
+            );
+            match error.insert(&sequence, weight) {
+                Ok(outcome) => prop_assert_eq!(Some(outcome.count), fits),
+                Err(error) => {
+                    prop_assert!(fits.is_none() && matches!(error, Error::WeightOverflow))
+                }
+            }
+            if fits.is_some() {
+                sums[usize::from(byte)] = sum;
+            }
+            // scaling never fails, and keeps every weight of the context above zero.
+            scale.insert(&sequence, weight).unwrap();
+            prop_assert!(scale.iter().all(|(_, weight)| weight > 0));
+        }
+        // the sequences that fit are exactly where they would be without the failures.
+        for (byte, sum) in sums.iter().enumerate() {
+            let weight = error
+                .get(&[b'a', byte as u8])
+                .unwrap()
+                .and_then(MarkovNode::leaf);
+            prop_assert_eq!(weight.unwrap_or(0) as u128, *sum);
         }
     }
 
-    fn leaf(&self) -> Option<usize> {
-        match self {
-            Node::Node(_) => None,
-            Node::Leaf(weight) => Some(*weight),
+    #[test]
+    fn test_weight_policy() {
+        let train = |policy| {
+            let mut markov = Markov::new(2).with_weight_policy(policy);
+            markov.insert(b"ab", usize::MAX - 1).unwrap();
+            markov.insert(b"ac", 4).unwrap();
+            markov.cap_successors(1);
+            markov.insert(b"ad", 6).unwrap();
+            markov
+        };
+        let weights =
+            |markov: &Markov| -> Vec<usize> { markov.iter().map(|(_, weight)| weight).collect() };
+
+        let mut markov = train(WeightPolicy::Saturate);
+        assert_eq!(markov.insert(b"ab", 10).unwrap().count, usize::MAX);
+        assert_eq!(weights(&markov), [usize::MAX, 6]);
+
+        let mut markov = train(WeightPolicy::Error);
+        assert!(matches!(
+            markov.insert(b"ab", 10),
+            Err(Error::WeightOverflow)
+        ));
+        assert_eq!(weights(&markov), [usize::MAX - 1, 6]);
+
+        // the whole context is halved with the weight, escape weight included.
+        let mut markov = train(WeightPolicy::Scale);
+        assert_eq!(markov.insert(b"ab", 10).unwrap().count, (1 << 63) - 1 + 5);
+        assert_eq!(weights(&markov), [(1 << 63) + 4, 3]);
+        assert_eq!(markov.escape_weight(b"a"), Some(2));
+
+        // merging checks the whole context before adding to any of its weights.
+        let mut other = Markov::new(2);
+        other.insert(b"ab", 2).unwrap();
+        other.insert(b"ad", 1).unwrap();
+        let mut markov = train(WeightPolicy::Error);
+        assert!(matches!(
+            markov.merge(other.clone()),
+            Err(Error::WeightOverflow)
+        ));
+        assert_eq!(weights(&markov), [usize::MAX - 1, 6]);
+        let mut markov = train(WeightPolicy::Scale);
+        markov.merge(other).unwrap();
+        assert_eq!(weights(&markov), [(1 << 63) - 1 + 1, 3 + 1]);
+    }
+
+    #[proptest]
+    fn test_retain(data: Vec<u8>, length: Length) {
+        let mut markov = Markov::new(*length);
+        markov.writer().write(&data);
+        let mut retained = markov.clone();
+        retained.retain(|_, _| true);
+        prop_assert_eq!(&retained, &markov);
+    }
+
+    #[proptest]
+    fn test_filtered(data: Vec<u8>, length: Length, byte: u8) {
+        let mut markov = Markov::new(*length);
+        markov.writer().write(&data);
+        let printable = |sequence: &[u8], _: usize| sequence[0].is_ascii_graphic();
+        let mut filtered = markov.filtered(printable);
+        prop_assert!(filtered
+            .iter()
+            .all(|(sequence, _)| sequence[0].is_ascii_graphic()));
+        filtered
+            .merge(markov.filtered(|sequence, weight| !printable(sequence, weight)))
+            .unwrap();
+        prop_assert_eq!(&filtered, &markov);
+
+        // an emptied context takes its escape weight with it.
+        let mut capped = markov.clone();
+        capped.cap_successors(1);
+        capped.retain(|sequence, _| sequence[0] != byte);
+        prop_assert!(capped
+            .escapes
+            .keys()
+            .all(|context| context.first() != Some(&byte)));
+    }
+
+    #[proptest]
+    fn test_snapshot(before: Vec<u8>, after: Vec<u8>, length: Length) {
+        let mut markov = Markov::new(*length);
+        markov.writer().write(&before);
+        let sequences: Vec<_> = markov.iter().collect();
+        let decoder = markov.decoder();
+
+        let snapshot = markov.snapshot();
+        markov.writer().write(&after);
+        markov.cap_successors(2);
+        markov.prune(2);
+        prop_assert_eq!(&snapshot.iter().collect::<Vec<_>>(), &sequences);
+        prop_assert_eq!(&Decoder::new(&snapshot), &decoder);
+
+        // the model as of the snapshot trains on its own.
+        let mut expected = Markov::new(*length);
+        expected.writer().write(&before);
+        expected.writer().write(&after);
+        let mut restored = snapshot.into_markov();
+        restored.writer().write(&after);
+        prop_assert_eq!(restored, expected);
+    }
+
+    #[test]
+    fn test_snapshot_concurrent() {
+        let data: &[u8] = include_bytes!("markov.rs");
+        let mut markov = Markov::new(3);
+        markov.writer().write(&data[..data.len() / 2]);
+        let reference = markov.decoder();
+        let snapshot = markov.snapshot();
+        let expected = snapshot.iter().collect::<Vec<_>>();
+        std::thread::scope(|scope| {
+            let decoder = scope.spawn(|| Decoder::new(&snapshot));
+            for chunk in data[data.len() / 2..].chunks(1000) {
+                markov.writer().write(chunk);
+            }
+            assert_eq!(decoder.join().unwrap(), reference);
+        });
+        assert_eq!(snapshot.iter().collect::<Vec<_>>(), expected);
+        assert!(markov.iter().count() > expected.len());
+    }
+
+    #[proptest]
+    fn test_interpolate(a: Vec<u8>, b: Vec<u8>, length: Length, #[strategy(0usize..3)] k: usize) {
+        let train = |data: &[u8]| {
+            let mut markov = Markov::new(*length);
+            markov.writer().write(data);
+            // capping adds escape weights.
+            markov.cap_successors(k + 1);
+            markov
+        };
+        let (a, b) = (train(&a), train(&b));
+        prop_assert_eq!(&Markov::interpolate(&a, &b, 1.0)?, &a);
+        prop_assert_eq!(&Markov::interpolate(&a, &b, 0.0)?, &b);
+        prop_assert_eq!(&Markov::interpolate(&a, &a, 0.5)?, &a);
+
+        // with a minimum weight, every sequence of either model is kept.
+        let blended = Markov::interpolate_with_min_weight(&a, &b, 0.3, 1)?;
+        let sequences: std::collections::BTreeSet<Vec<u8>> =
+            a.iter().chain(b.iter()).map(|(s, _)| s).collect();
+        prop_assert!(blended.iter().map(|(s, _)| s).eq(sequences));
+    }
+
+    #[proptest]
+    fn test_augment_from(model: Vec<u8>, sample: Vec<u8>, length: Length) {
+        let mut markov = Markov::new(*length);
+        markov.insert_run(&model);
+        let before = markov.clone();
+        let report = markov.augment_from(&sample, 1).unwrap();
+
+        // the model now codes every window of the sample, and only gained its sequences.
+        let coverage = markov.encoder().coverage(&sample);
+        prop_assert_eq!(coverage.covered(), coverage.windows);
+        let mut counts = Markov::new(*length);
+        counts.insert_run(&sample);
+        let missing = counts
+            .iter()
+            .filter(|(sequence, _)| before.get(sequence).unwrap().is_none())
+            .count();
+        prop_assert_eq!(report.added, missing);
+        prop_assert_eq!(report.skipped, 0);
+        prop_assert_eq!(markov.iter().count(), before.iter().count() + missing);
+    }
+
+    #[test]
+    fn test_augment_min_occurrences() {
+        let mut markov = Markov::new(2);
+        markov.insert_run(b"abababab");
+        let report = markov.augment_from(b"acacacad", 3).unwrap();
+        // "ac" and "ca" occur three times, "ad" once.
+        assert_eq!(
+            report,
+            AugmentReport {
+                added: 2,
+                boosted: 0,
+                skipped: 1,
+            }
+        );
+        assert_eq!(markov.get(b"ac").unwrap(), Some(&MarkovNode::Leaf(3)));
+        assert_eq!(markov.get(b"ca").unwrap(), Some(&MarkovNode::Leaf(3)));
+        assert_eq!(markov.get(b"ad").unwrap(), None);
+        let coverage = markov.encoder().coverage(b"acacacad");
+        assert_eq!(coverage.missing_symbol, 1);
+    }
+
+    #[test]
+    fn test_augment_boost() {
+        // weights doubling from one byte to the next give the rarest ones 14 bit codes.
+        let mut markov = Markov::new(2);
+        for byte in 0..15u8 {
+            markov.insert(&[b'a', byte], 1 << byte).unwrap();
+        }
+        let sample: Vec<u8> = (0..15u8).flat_map(|byte| [b'a', byte]).collect();
+        let longest = |markov: &Markov| {
+            let encoder = markov.encoder();
+            (0..15u8)
+                .map(|byte| encoder.code_len(&[b'a', byte]).unwrap())
+                .max()
+                .unwrap()
+        };
+        assert_eq!(longest(&markov), 14);
+
+        // only the pairs after "a" were there before, the others are added.
+        let report = markov
+            .augment_from_with_max_code_len(&sample, 1, 8)
+            .unwrap();
+        assert_eq!(report.added, 14);
+        assert!(report.boosted > 0);
+        assert!(longest(&markov) <= 8);
+        // pairs that were short enough are left alone.
+        assert_eq!(
+            markov.get(&[b'a', 14]).unwrap(),
+            Some(&MarkovNode::Leaf(1 << 14))
+        );
+
+        // by default, codes as long as trees allow are boosted.
+        let mut markov = Markov::new(1);
+        for byte in 0..16u8 {
+            markov.insert(&[byte], 1 << byte).unwrap();
         }
+        let longest = |markov: &Markov| {
+            let encoder = markov.encoder();
+            (0..16u8)
+                .map(|byte| encoder.code_len(&[byte]).unwrap())
+                .max()
+        };
+        assert_eq!(longest(&markov), Some(MAX_CODE_LENGTH));
+        let report = markov.augment_from(&[0, 1, 2], 1).unwrap();
+        assert!(report.boosted > 0);
+        assert!(longest(&markov) <= Some(DEFAULT_AUGMENT_CODE_LEN));
     }
 
-    fn node(&self) -> Option<&Map<u8, Self>> {
-        match self {
-            Node::Node(node) => Some(node),
-            Node::Leaf(_) => None,
+    #[test]
+    fn test_interpolate_weights() {
+        let mut a = Markov::new(2);
+        a.insert(b"ab", 10).unwrap();
+        a.insert(b"ac", 1).unwrap();
+        let mut b = Markov::new(2);
+        b.insert(b"ab", 2).unwrap();
+        b.insert(b"bc", 4).unwrap();
+
+        let blended = Markov::interpolate(&a, &b, 0.25).unwrap();
+        let weights: Vec<(Vec<u8>, usize)> = blended.iter().collect();
+        // 0.25 * 10 + 0.75 * 2, and 0.25 * 1 rounds to zero.
+        assert_eq!(weights, [(b"ab".to_vec(), 4), (b"bc".to_vec(), 3)]);
+
+        assert!(matches!(
+            Markov::interpolate(&a, &Markov::new(3), 0.5),
+            Err(Error::ModelMismatch {
+                expected: 2,
+                found: 3
+            })
+        ));
+        for lambda in [-0.1, 1.5, f64::NAN] {
+            assert!(matches!(
+                Markov::interpolate(&a, &b, lambda),
+                Err(Error::Config(_))
+            ));
         }
     }
 
-    fn iter(&self, prefix: Vec<u8>) -> Box<dyn Iterator<Item = (Vec<u8>, usize)> + '_> {
-        match self {
-            Self::Leaf(weight) => Box::new(std::iter::once((prefix, *weight))),
-            Self::Node(nodes) => Box::new(nodes.iter().flat_map(move |(byte, node)| {
-                let mut prefix = prefix.clone();
-                prefix.push(*byte);
-                node.iter(prefix)
-            })),
+    #[test]
+    fn test_merge_mismatch() {
+        assert!(matches!(
+            Markov::new(2).merge(Markov::new(3)),
+            Err(Error::ModelMismatch {
+                expected: 2,
+                found: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_sharded_trainer() {
+        let streams: Vec<&[u8]> = vec![
+            include_bytes!("markov.rs"),
+            include_bytes!("huffman.rs"),
+            include_bytes!("builder.rs"),
+            b"ab",
+        ];
+        let trainer = ShardedTrainer::new(3);
+        std::thread::scope(|scope| {
+            for stream in &streams {
+                let mut handle = trainer.handle();
+                scope.spawn(move || {
+                    for chunk in stream.chunks(1000) {
+                        handle.write_all(chunk).unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut expected = Markov::new(3);
+        for stream in &streams {
+            expected.writer().write(stream);
         }
+        assert_eq!(trainer.finish(), expected);
     }
 
-    fn iter_prefix(
-        &self,
-        prefix: Vec<u8>,
-        length: usize,
-    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<WeightedItem>)> + '_> {
-        if length == 0 {
-            let items = self
-                .node()
-                .unwrap()
-                .iter()
-                .map(|(byte, node)| WeightedItem {
-                    item: *byte,
-                    weight: node.leaf().unwrap(),
+    #[proptest]
+    fn test_writer_weight(
+        #[strategy(1usize..5)] depth: usize,
+        data: Vec<u8>,
+        #[strategy(1usize..100)] weight: usize,
+    ) {
+        let mut expected = Markov::new(depth);
+        expected.writer().write(&data);
+        expected.scale_weights(weight).unwrap();
+
+        let mut writer = MarkovWriter::with_weight(Markov::new(depth), weight);
+        writer.write(&data);
+        prop_assert_eq!(writer.finish(), expected);
+    }
+
+    #[test]
+    fn test_shared_writer() {
+        let streams: Vec<&[u8]> = vec![
+            include_bytes!("markov.rs"),
+            include_bytes!("huffman.rs"),
+            b"ab",
+        ];
+        let shared = Arc::new(Mutex::new(Markov::new(3)));
+        std::thread::scope(|scope| {
+            for stream in &streams {
+                let mut writer = MarkovWriter::new(shared.clone());
+                scope.spawn(move || {
+                    for chunk in stream.chunks(1000) {
+                        writer.write_all(chunk).unwrap();
+                    }
+                });
+            }
+            // borrowing the mutex works as well.
+            let mut writer = MarkovWriter::new(&*shared);
+            scope.spawn(move || writer.write(b"abracadabra"));
+        });
+
+        let markov = Arc::into_inner(shared).unwrap().into_inner().unwrap();
+        let windows: usize = streams
+            .iter()
+            .chain([&&b"abracadabra"[..]])
+            .map(|stream| stream.len().saturating_sub(2))
+            .sum();
+        let total: usize = markov.iter().map(|(_, weight)| weight).sum();
+        assert_eq!(total, windows * DEFAULT_WEIGHT);
+    }
+
+    #[test]
+    fn test_shared_writer_poisoned() {
+        let shared = Mutex::new(Markov::new(2));
+        let _ = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _guard = shared.lock().unwrap();
+                    panic!("poisoning the lock");
                 })
-                .collect();
-            Box::new(std::iter::once((prefix, items)))
-        } else {
-            Box::new(self.node().unwrap().iter().flat_map(move |(byte, node)| {
-                let mut prefix = prefix.clone();
-                prefix.push(*byte);
-                node.iter_prefix(prefix, length - 1)
-            }))
-        }
+                .join()
+        });
+        let mut writer = MarkovWriter::new(&shared);
+        assert!(matches!(writer.try_write(b"abc"), Err(Error::Poisoned)));
+        assert_eq!(SequenceWriter::len(&&shared), 2);
     }
-}
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct Markov {
-    depth: usize,
-    root: Node,
-}
+    #[proptest]
+    fn test_writer_mapped(data: Vec<u8>, length: Length) {
+        let mut mapped = Markov::new(*length);
+        mapped
+            .writer_mapped(ByteMapper::ascii_lowercase())
+            .write(&data);
+        let mut expected = Markov::new(*length);
+        expected.writer().write(&data.to_ascii_lowercase());
+        prop_assert_eq!(mapped, expected);
+    }
 
-#[derive(thiserror::Error, Debug)]
-#[error("sequence length mismatch")]
-pub struct SequenceLengthError;
+    #[test]
+    fn test_writer_cancelled() {
+        let token = CancellationToken::new();
+        let mut markov = Markov::new(2);
+        let mut writer = markov.writer().with_cancellation(token.clone());
+        writer.try_write(b"abc").unwrap();
+        token.cancel();
+        assert!(matches!(writer.try_write(b"d"), Err(Error::Cancelled)));
+        assert_eq!(markov.iter().count(), 2);
+    }
 
-impl Markov {
-    pub fn new(depth: usize) -> Self {
-        Markov {
-            depth,
-            root: Node::Node(Default::default()),
+    #[test]
+    fn test_context_stats() {
+        let mut markov = Markov::new(2);
+        for (sequence, weight) in [(b"ab", 3), (b"ac", 1), (b"bc", 4), (b"cd", 2)] {
+            markov.insert(sequence, weight).unwrap();
         }
+        let stats = markov.context_stats();
+        assert_eq!(
+            stats,
+            ContextStats {
+                contexts: 3,
+                deterministic: 2,
+                weight: 10,
+                deterministic_weight: 6,
+            }
+        );
+        assert_eq!(stats.deterministic_fraction(), 0.6);
+        assert_eq!(markov.deterministic_contexts(), 2);
+
+        // a capped context with one successor left still needs escapes.
+        markov.cap_successors(1);
+        let stats = markov.context_stats();
+        assert_eq!((stats.deterministic, stats.weight), (2, 10));
+
+        assert_eq!(Markov::new(3).context_stats(), ContextStats::default());
+        assert_eq!(ContextStats::default().deterministic_fraction(), 0.0);
     }
 
-    pub fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, usize)> + '_> {
-        self.root.iter(vec![])
+    #[proptest]
+    fn test_fanout_histogram(data: Vec<u8>, length: Length) {
+        let mut markov = Markov::new(*length);
+        markov.writer().write(&data);
+        let histogram = markov.fanout_histogram();
+        prop_assert_eq!(histogram[0], 0);
+        prop_assert_eq!(
+            histogram.iter().sum::<usize>(),
+            markov.context_stats().contexts
+        );
+        let successors: usize = histogram
+            .iter()
+            .enumerate()
+            .map(|(fanout, count)| fanout * count)
+            .sum();
+        prop_assert_eq!(successors, markov.iter().count());
     }
 
-    pub fn iter_prefix(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<WeightedItem>)> + '_> {
-        self.root.iter_prefix(vec![], self.depth - 1)
+    #[proptest]
+    fn test_conditional_entropy(data: Vec<u8>) {
+        // without context, this is the entropy of the bytes themselves.
+        let mut markov = Markov::new(1);
+        markov.writer().write(&data);
+        let mut histogram = ByteHistogram::new();
+        histogram.write_all(&data).unwrap();
+        prop_assert!((markov.conditional_entropy() - histogram.entropy()).abs() < 1e-9);
+
+        // a context never makes the bytes that follow it less predictable than they are alone.
+        let mut deeper = Markov::new(2);
+        deeper.writer().write(&data);
+        let mut successors = ByteHistogram::new();
+        successors
+            .write_all(data.get(1..).unwrap_or_default())
+            .unwrap();
+        prop_assert!(deeper.conditional_entropy() <= successors.entropy() + 1e-9);
     }
 
-    pub fn len(&self) -> usize {
-        self.depth
+    #[test]
+    fn test_conditional_entropy_deterministic() {
+        let data = b"abc".repeat(100);
+        let mut markov = Markov::new(2);
+        markov.writer().write(&data);
+        assert_eq!(markov.conditional_entropy(), 0.0);
+        assert_eq!(Markov::new(3).conditional_entropy(), 0.0);
+
+        // two equally likely successors in every context are a bit.
+        let mut markov = Markov::new(2);
+        for sequence in [b"ab", b"ac", b"ba", b"bb"] {
+            markov.insert(sequence, 5).unwrap();
+        }
+        assert!((markov.conditional_entropy() - 1.0).abs() < 1e-9);
     }
 
-    pub fn insert(&mut self, sequence: &[u8], weight: usize) -> Result<usize, SequenceLengthError> {
-        if sequence.len() != self.depth {
-            return Err(SequenceLengthError);
+    #[proptest]
+    fn test_entropy_by_leading_byte(#[strategy(1usize..4)] depth: usize, data: Vec<u8>) {
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data);
+        let stats = markov.entropy_by_leading_byte();
+        if depth == 1 {
+            prop_assert!(stats
+                .iter()
+                .all(|entry| *entry == LeadingByteStats::default()));
+            return Ok(());
         }
 
-        let leaf =
-            sequence[..]
-                .into_iter()
-                .enumerate()
-                .fold(&mut self.root, |node, (index, key)| {
-                    let default = if index < (self.depth - 1) {
-                        Node::Node(Default::default())
-                    } else {
-                        Node::Leaf(Default::default())
-                    };
-                    node.node_mut().unwrap().entry(*key).or_insert(default)
-                });
+        // the groups add up to the whole model.
+        let total: u64 = stats.iter().map(|entry| entry.weight).sum();
+        prop_assert_eq!(total, markov.context_stats().weight);
+        let sum: f64 = stats
+            .iter()
+            .map(|entry| entry.weight as f64 * entry.entropy)
+            .sum();
+        let expected = markov.conditional_entropy() * total as f64;
+        prop_assert!((sum - expected).abs() < 1e-6 * expected.max(1.0));
+    }
 
-        let count = match leaf {
-            Node::Leaf(count) => {
-                *count = count.saturating_add(weight);
-                *count
+    #[test]
+    fn test_entropy_by_leading_byte_contexts() {
+        let mut markov = Markov::new(2);
+        for sequence in [b"ab", b"ac", b"ba"] {
+            markov.insert(sequence, 5).unwrap();
+        }
+        let stats = markov.entropy_by_leading_byte();
+        assert_eq!(stats[usize::from(b'a')].weight, 10);
+        assert!((stats[usize::from(b'a')].entropy - 1.0).abs() < 1e-9);
+        assert_eq!(
+            stats[usize::from(b'b')],
+            LeadingByteStats {
+                weight: 5,
+                entropy: 0.0
             }
-            Node::Node(_) => unreachable!(),
-        };
+        );
+        assert_eq!(stats[usize::from(b'c')], LeadingByteStats::default());
+    }
+
+    #[proptest]
+    fn test_contexts_by_weight(data: Vec<u8>, length: Length, #[strategy(0usize..8)] n: usize) {
+        let mut markov = Markov::new(*length);
+        markov.writer().write(&data);
+        let contexts = markov.contexts_by_weight();
 
-        Ok(count)
+        // the same contexts and weights as the prefix iterator, sorted.
+        let mut expected: Vec<(Box<[u8]>, u64)> = markov
+            .iter_prefix()
+            .map(|(context, items)| {
+                let weight = items.iter().map(|item| item.weight as u64).sum();
+                (context.into(), weight)
+            })
+            .collect();
+        expected.sort_by_key(|(context, weight)| (std::cmp::Reverse(*weight), context.clone()));
+        prop_assert_eq!(&contexts, &expected);
+        prop_assert_eq!(
+            &markov.top_contexts(n)[..],
+            &contexts[..n.min(contexts.len())]
+        );
     }
 
-    pub fn get(&self, sequence: &[u8]) -> Result<Option<&Node>, SequenceLengthError> {
-        if sequence.len() != self.depth {
-            return Err(SequenceLengthError);
+    #[test]
+    fn test_contexts_by_weight_order() {
+        let mut markov = Markov::new(3);
+        for (sequence, weight) in [
+            (b"abc", 2),
+            (b"abd", 2),
+            (b"bca", 4),
+            (b"aaa", 1),
+            (b"cab", 3),
+        ] {
+            markov.insert(sequence, weight).unwrap();
         }
+        let expected: Vec<(Box<[u8]>, u64)> = vec![
+            (b"ab"[..].into(), 4),
+            (b"bc"[..].into(), 4),
+            (b"ca"[..].into(), 3),
+            (b"aa"[..].into(), 1),
+        ];
+        assert_eq!(markov.contexts_by_weight(), expected);
+        assert_eq!(markov.top_contexts(2), expected[..2]);
+        assert_eq!(markov.top_contexts(10), expected);
+        assert!(markov.top_contexts(0).is_empty());
 
-        let result = sequence
-            .into_iter()
-            .fold(Some(&self.root), |node, key| match node? {
-                Node::Node(node) => node.get(key),
-                Node::Leaf(_) => None,
-            });
+        // escapes count towards the weight of their context.
+        markov.cap_successors(1);
+        assert_eq!(markov.contexts_by_weight(), expected);
+        assert!(Markov::new(2).contexts_by_weight().is_empty());
+    }
 
-        Ok(result)
+    #[test]
+    fn test_fanout_full() {
+        let mut markov = Markov::new(2);
+        let data: Vec<u8> = (0..=255).flat_map(|byte| [0, byte]).collect();
+        markov.writer().write(&data);
+        let histogram = markov.fanout_histogram();
+        assert_eq!(histogram[256], 1);
+        // every byte but the last one is followed by a zero.
+        assert_eq!(histogram[1], 254);
     }
 
-    pub fn writer(&mut self) -> Writer<&mut Self> {
-        Writer::new(self)
+    #[test]
+    fn test_cap_successors() {
+        let mut markov = Markov::new(2);
+        markov.writer().write(b"abacadaeaabb");
+        markov.cap_successors(2);
+
+        let successors = |context: u8| -> Vec<(u8, usize)> {
+            markov
+                .iter()
+                .filter(|(sequence, _)| sequence[0] == context)
+                .map(|(sequence, weight)| (sequence[1], weight))
+                .collect()
+        };
+        // a is followed by b twice, then a, c, d and e once each.
+        assert_eq!(successors(b'a'), [(b'a', 1), (b'b', 2)]);
+        assert_eq!(markov.escape_weight(b"a"), Some(3));
+        assert_eq!(successors(b'b'), [(b'a', 1), (b'b', 1)]);
+        assert_eq!(markov.escape_weight(b"b"), None);
+
+        for format in [ExportFormat::Plain, ExportFormat::Compact] {
+            let mut saved = vec![];
+            markov.save(&mut saved, format).unwrap();
+            assert_eq!(Markov::load(&saved[..]).unwrap(), markov);
+        }
     }
 
-    pub fn into_writer(self) -> Writer<Self> {
-        Writer::new(self)
+    #[test]
+    fn test_quantize() {
+        let data = include_bytes!("markov.rs");
+        let mut markov = Markov::new(3);
+        markov.weighted_writer(|_| 1000).write(data);
+        let mut saved = vec![];
+        markov.save(&mut saved, ExportFormat::Plain).unwrap();
+
+        let mut quantized = markov.clone();
+        let report = quantized.quantize(16);
+        assert_eq!(report.contexts, markov.iter_prefix().count());
+        assert_eq!(report.changed, 0);
+        assert!(quantized.iter().all(|(_, weight)| weight < 1 << 16));
+        assert_eq!(quantized.iter().count(), markov.iter().count());
+
+        let report = quantized.quantize(8);
+        assert!(quantized
+            .iter()
+            .all(|(_, weight)| (1..1 << 8).contains(&weight)));
+        assert!(report.changed <= report.contexts);
+        let mut small = vec![];
+        quantized.save(&mut small, ExportFormat::Plain).unwrap();
+        assert!(small.len() < saved.len());
+
+        // models that fit are unchanged.
+        let before = quantized.clone();
+        assert_eq!(quantized.quantize(8).changed, 0);
+        assert_eq!(quantized, before);
     }
 
-    pub fn encoder(&self) -> Encoder {
-        self.decoder().encoder()
+    #[test]
+    fn test_prune() {
+        let mut markov = Markov::new(3);
+        markov.insert(b"abc", 1).unwrap();
+        markov.insert(b"abd", 5).unwrap();
+        markov.insert(b"xyz", 2).unwrap();
+        markov.prune(3);
+
+        assert_eq!(
+            markov.iter().collect::<Vec<_>>(),
+            vec![(b"abd".to_vec(), 5)]
+        );
+        markov.prune(10);
+        assert_eq!(markov, Markov::new(3));
     }
 
-    pub fn decoder(&self) -> Decoder {
-        Decoder::new(self)
+    #[proptest]
+    fn test_insert_run(input: Vec<u8>, length: Length) {
+        let mut markov = Markov::new(*length);
+        markov.insert_run(&input);
+
+        let mut naive = Markov::new(*length);
+        for window in input.windows(*length) {
+            naive.insert(window, DEFAULT_WEIGHT).unwrap();
+        }
+        prop_assert_eq!(markov, naive);
     }
-}
 
-const DEFAULT_WEIGHT: usize = 1;
+    #[test]
+    fn test_insert_run_large() {
+        // spans several chunks, with repeating windows within and across them.
+        let input: Vec<u8> = (0..3 * RUN_CHUNK as u32 + 7)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 29) as u8 ^ b'a')
+            .collect();
+        let mut markov = Markov::new(4);
+        markov.insert_run(&input);
+        markov.insert_run(&input[..100]);
 
-pub trait SequenceWriter {
-    fn len(&self) -> usize;
-    fn write(&mut self, sequence: &[u8]) -> Result<(), SequenceLengthError>;
-}
+        let mut writer = Markov::new(4).into_writer();
+        writer.write(&input);
+        let mut expected = writer.finish();
+        for window in input[..100].windows(4) {
+            expected.insert(window, DEFAULT_WEIGHT).unwrap();
+        }
+        assert_eq!(markov, expected);
+        assert_eq!(
+            markov.iter().map(|(_, count)| count).sum::<usize>(),
+            input.len() - 3 + 97
+        );
 
-impl<T: BorrowMut<Markov>> SequenceWriter for T {
-    fn len(&self) -> usize {
-        Markov::len(self.borrow())
+        let mut short = Markov::new(3);
+        assert!(short.insert_all([&b"ab"[..]]).is_err());
+        short.insert_run(b"ab");
+        assert_eq!(short, Markov::new(3));
     }
 
-    fn write(&mut self, sequence: &[u8]) -> Result<(), SequenceLengthError> {
-        Markov::insert(self.borrow_mut(), sequence, DEFAULT_WEIGHT).map(|_| ())
+    #[test]
+    fn test_sequence_length_error() {
+        let mut markov = Markov::new(3);
+        let error = SequenceLengthError {
+            expected: 3,
+            actual: 2,
+        };
+        assert!(matches!(
+            markov.insert(b"ab", 1),
+            Err(Error::SequenceLength(inner)) if inner == error
+        ));
+        assert_eq!(markov.get(b"ab"), Err(error));
+        assert_eq!(markov.insert_all([&b"abc"[..], b"ab"]), Err(error));
+        #[allow(deprecated)]
+        {
+            assert_eq!(markov.insert_saturating(b"ab", 1), Err(error));
+            let mut markov = Markov::new(1).with_weight_policy(WeightPolicy::Error);
+            markov.insert(b"a", usize::MAX).unwrap();
+            assert_eq!(markov.insert_saturating(b"a", 1), Ok(usize::MAX));
+            assert_eq!(markov.weight_policy(), WeightPolicy::Error);
+        }
+        assert_eq!(
+            markov.successors(b"abc"),
+            Err(SequenceLengthError {
+                expected: 2,
+                actual: 3
+            })
+        );
+        assert!(matches!(
+            SequenceWriter::write(&mut markov, b"abcd"),
+            Err(Error::SequenceLength(SequenceLengthError {
+                expected: 3,
+                actual: 4
+            }))
+        ));
+        assert_eq!(
+            error.to_string(),
+            "sequence of length 2 does not match the expected length 3"
+        );
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct Writer<W: SequenceWriter> {
-    writer: W,
-    buffer: Vec<u8>,
-}
+    // the recursive traversals the iterators replaced.
+    fn recursive_iter(node: &MarkovNode, prefix: Vec<u8>, output: &mut Vec<(Vec<u8>, usize)>) {
+        match node {
+            MarkovNode::Leaf(weight) => output.push((prefix, *weight)),
+            MarkovNode::Node(nodes) => {
+                for (byte, node) in nodes.iter() {
+                    recursive_iter(node, [&prefix[..], &[*byte]].concat(), output);
+                }
+            }
+        }
+    }
 
-impl<W: SequenceWriter> Writer<W> {
-    pub fn new(sequence_writer: W) -> Self {
-        Writer {
-            writer: sequence_writer,
-            buffer: vec![],
+    fn recursive_prefixes(
+        node: &MarkovNode,
+        prefix: Vec<u8>,
+        length: usize,
+        output: &mut Vec<(Vec<u8>, Vec<WeightedItem>)>,
+    ) {
+        if length == 0 {
+            let items = node.items();
+            if !items.is_empty() {
+                output.push((prefix, items));
+            }
+            return;
+        }
+        for (byte, node) in node.node().into_iter().flatten() {
+            recursive_prefixes(node, [&prefix[..], &[*byte]].concat(), length - 1, output);
         }
     }
 
-    pub fn write(&mut self, input: &[u8]) {
-        buffered_windows(self.writer.len(), &mut self.buffer, input, |window| {
-            self.writer.write(window)
-        })
-        .unwrap();
+    #[proptest]
+    fn test_iterators(input: Vec<u8>, length: Length, #[strategy(0usize..3)] prune: usize) {
+        let mut markov = Markov::new(*length);
+        markov.insert_run(&input);
+        markov.prune(prune);
+
+        let mut sequences = vec![];
+        recursive_iter(&markov.root, vec![], &mut sequences);
+        prop_assert_eq!(markov.iter().collect::<Vec<_>>(), sequences);
+
+        let mut prefixes = vec![];
+        recursive_prefixes(&markov.root, vec![], *length - 1, &mut prefixes);
+        prop_assert_eq!(markov.iter_prefix().collect::<Vec<_>>(), prefixes);
     }
 
-    pub fn finish(self) -> W {
-        self.writer
+    #[proptest]
+    fn test_context_index(input: Vec<u8>, length: Length, value: usize) {
+        let mut markov = Markov::new(*length);
+        markov.insert_run(&input);
+        let index = markov.context_index();
+        prop_assert_eq!(index.len(), markov.iter_prefix().count());
+
+        for (context, items) in markov.iter_prefix() {
+            let total: usize = items.iter().map(|item| item.weight).sum();
+            prop_assert_eq!(index.total(&context), Some(total));
+            for byte in 0..=u8::MAX {
+                let weight = items
+                    .iter()
+                    .find(|item| item.item == byte)
+                    .map_or(0, |item| item.weight);
+                prop_assert_eq!(index.weight(&context, byte), Some(weight));
+                prop_assert_eq!(
+                    index.probability(&context, byte),
+                    Some(weight as f64 / total as f64)
+                );
+            }
+
+            for (byte, probability) in WeightedItem::normalize(&items) {
+                prop_assert_eq!(index.probability(&context, byte), Some(probability));
+            }
+
+            // the sampled successor covers the value in cumulative order.
+            let value = value % total;
+            let mut below = 0;
+            let expected = items.iter().find_map(|item| {
+                below += item.weight;
+                (value < below).then_some(item.item)
+            });
+            prop_assert_eq!(index.sample(&context, value), expected);
+        }
+
+        let unknown = vec![0xff; *length - 1];
+        if markov.successors(&unknown).unwrap().is_empty() {
+            prop_assert_eq!(index.total(&unknown), None);
+            prop_assert_eq!(index.sample(&unknown, value), None);
+        }
     }
-}
 
-impl<W: SequenceWriter> Write for Writer<W> {
-    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        Writer::write(self, buf);
-        Ok(buf.len())
+    #[test]
+    fn test_context_index_zero_weights() {
+        let mut markov = Markov::new(2);
+        markov.insert(b"ab", 2).unwrap();
+        markov.insert(b"ac", 0).unwrap();
+        markov.insert(b"xy", 0).unwrap();
+        let index = markov.context_index();
+
+        // successors without weight are never sampled, contexts without any are left out.
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.total(b"a"), Some(2));
+        assert_eq!(index.weight(b"a", b'c'), Some(0));
+        assert!((0..4).all(|value| index.sample(b"a", value) == Some(b'b')));
+        assert_eq!(index.total(b"x"), None);
+        assert_eq!(index.sample(b"x", 0), None);
     }
 
-    fn flush(&mut self) -> IoResult<()> {
-        Ok(())
+    #[test]
+    fn test_iterators_send() {
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<Iter<'_>>();
+        is_send_sync::<PrefixIter<'_>>();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use proptest::prelude::*;
-    use test_strategy::{proptest, Arbitrary};
+    #[proptest]
+    fn test_weighted_writer(inputs: Vec<Vec<u8>>, length: Length) {
+        let mut markov = Markov::new(*length);
+        let mut writer = markov.writer();
+        inputs.iter().for_each(|input| writer.write(input));
 
-    macro_rules! test_markov_insert {
-        ($name:ident, $len:expr) => {
-            #[proptest]
-            fn $name(sequences: Vec<([u8; $len], usize)>) {
-                let mut markov = Markov::new($len);
+        let mut weighted = Markov::new(*length);
+        let mut writer = weighted.weighted_writer(|_| DEFAULT_WEIGHT);
+        inputs.iter().for_each(|input| writer.write(input));
+        prop_assert_eq!(weighted, markov);
+    }
 
-                for (sequence, weight) in &sequences {
-                    markov.insert(&sequence[..], *weight).unwrap();
-                }
+    #[test]
+    fn test_weighted_steps() {
+        // the second half counts twice, across a write boundary.
+        let input = b"abababab";
+        let mut markov = Markov::new(2);
+        let mut writer = markov.weighted_writer(|index| if index < 4 { 1 } else { 2 });
+        writer.write(&input[..3]);
+        writer.write(&input[3..]);
+        assert_eq!(
+            markov.iter().collect::<Vec<_>>(),
+            vec![(b"ab".to_vec(), 1 + 1 + 2 + 2), (b"ba".to_vec(), 1 + 1 + 2)]
+        );
 
-                for (sequence, weight) in &sequences {
-                    let node = markov.get(&sequence[..]).unwrap().unwrap();
-                    let count = match node {
-                        Node::Leaf(count) => *count,
-                        _ => unreachable!(),
-                    };
-                    assert!(count >= *weight);
-                }
-            }
-        };
+        let mut markov = Markov::new(2);
+        let limited = Limited::new(
+            &mut markov,
+            TrainLimits {
+                max_sequences: Some(1),
+                max_memory: None,
+            },
+        );
+        let mut writer = MarkovWriter::new(Weighted::new(limited, |index| index as usize + 1));
+        assert!(writer.try_write(b"aaaa").is_ok());
+        assert!(writer.try_write(b"b").is_err());
+        assert_eq!(
+            markov.get(b"aa").unwrap(),
+            Some(&MarkovNode::Leaf(1 + 2 + 3))
+        );
     }
 
-    #[derive(Arbitrary, Debug, Clone, Copy, PartialEq)]
-    pub struct Length(#[strategy(1usize..5)] usize);
-
-    impl std::ops::Deref for Length {
-        type Target = usize;
-        fn deref(&self) -> &Self::Target {
-            &self.0
+    #[test]
+    fn test_empty_model() {
+        for depth in 1..5 {
+            let markov = Markov::new(depth);
+            assert_eq!(markov.iter().count(), 0);
+            assert_eq!(markov.iter_prefix().count(), 0);
         }
     }
 
-    test_markov_insert!(test_markov1_insert, 1);
-    test_markov_insert!(test_markov2_insert, 2);
-    test_markov_insert!(test_markov3_insert, 3);
-    test_markov_insert!(test_markov4_insert, 4);
-    test_markov_insert!(test_markov5_insert, 5);
-
     #[proptest]
     fn test_writer(inputs: Vec<Vec<u8>>, length: Length) {
         let markov_writer = {
             let mut writer = Markov::new(*length).into_writer();
-            inputs.iter().for_each(|input| writer.write(&input));
+            inputs.iter().for_each(|input| writer.write(input));
             writer.finish()
         };
 
@@ -286,4 +3655,168 @@ mod tests {
 
         prop_assert_eq!(markov_writer, markov_full);
     }
+
+    #[proptest]
+    fn test_write_bytes(inputs: Vec<Vec<u8>>, length: Length) {
+        let mut writer = Markov::new(*length).into_writer();
+        let mut markov = Markov::new(*length);
+        for input in &inputs {
+            writer.write(input);
+            markov.write_bytes(input);
+        }
+        prop_assert_eq!(markov, writer.finish());
+    }
+
+    #[proptest]
+    fn test_train_sink(inputs: Vec<Vec<u8>>, length: Length) {
+        let mut writer = Markov::new(*length).into_writer();
+        inputs.iter().for_each(|input| writer.write(input));
+
+        // a chain of the inputs reads them one at a time, so every boundary is kept.
+        let mut reader: Box<dyn Read> = Box::new(std::io::empty());
+        for input in &inputs {
+            reader = Box::new(reader.chain(&input[..]));
+        }
+        let mut markov = Markov::new(*length);
+        std::io::copy(&mut reader, &mut markov.train_sink()).unwrap();
+        prop_assert_eq!(markov, writer.finish());
+    }
+
+    #[proptest]
+    fn test_train_sources(
+        #[strategy(proptest::collection::vec((any::<Vec<u8>>(), 1usize..20), 0..4))] sources: Vec<
+            (Vec<u8>, usize),
+        >,
+        length: Length,
+    ) {
+        // training on the sources together is the sum of training on each of them.
+        let mut markov = Markov::new(*length);
+        let summary = markov
+            .train_sources(sources.iter().map(|(data, weight)| (&data[..], *weight)))
+            .unwrap();
+        let mut expected = Markov::new(*length);
+        for (data, weight) in &sources {
+            let mut writer = Markov::new(*length).into_writer();
+            writer.write(data);
+            let mut alone = writer.finish();
+            alone.scale_weights(*weight).unwrap();
+            expected.merge(alone).unwrap();
+        }
+        prop_assert_eq!(markov, expected);
+        let bytes: Vec<u64> = sources.iter().map(|(data, _)| data.len() as u64).collect();
+        prop_assert_eq!(summary.total(), bytes.iter().sum::<u64>());
+        prop_assert_eq!(summary.bytes, bytes);
+    }
+
+    #[test]
+    fn test_train_sources_boundary() {
+        let mut markov = Markov::new(3);
+        let sources = [(&b"ab"[..], 10), (&b"cd"[..], 1), (&b"abc"[..], 1)];
+        markov.train_sources(sources).unwrap();
+        // no window spans "ab" and "cd".
+        let sequences: Vec<_> = markov.iter().collect();
+        assert_eq!(sequences, [(b"abc".to_vec(), 1)]);
+
+        let mut markov = Markov::new(2);
+        markov
+            .train_sources([(&b"ab"[..], 10), (&b"ab"[..], 1)])
+            .unwrap();
+        assert_eq!(markov.iter().collect::<Vec<_>>(), [(b"ab".to_vec(), 11)]);
+    }
+
+    #[proptest]
+    fn test_writer_stats(data: Vec<u8>, length: Length) {
+        let mut markov = Markov::new(*length);
+        let mut writer = markov.writer();
+        writer.write(&data);
+        let stats = writer.stats();
+        prop_assert_eq!(stats.bytes, data.len() as u64);
+        prop_assert_eq!(
+            stats.windows,
+            (data.len() + 1).saturating_sub(*length) as u64
+        );
+        prop_assert_eq!(stats.trained(), stats.windows);
+        prop_assert_eq!(stats.new_sequences, markov.iter().count() as u64);
+    }
+
+    #[test]
+    fn test_writer_stats_repeated() {
+        let mut markov = Markov::new(3);
+        assert!(markov.insert(b"abr", 1).unwrap().newly_created);
+        assert_eq!(
+            markov.insert(b"abr", 1).unwrap(),
+            InsertOutcome {
+                count: 2,
+                newly_created: false
+            }
+        );
+        #[allow(deprecated)]
+        let weight = markov.insert_weight(b"abr", 1).unwrap();
+        assert_eq!(weight, 3);
+
+        // windows continue across writes, "abr" is repeated from the model and the input.
+        let mut writer = markov.writer();
+        writer.write(b"abrac");
+        writer.write(b"abra");
+        let expected = TrainStats {
+            bytes: 9,
+            windows: 7,
+            new_sequences: 4,
+            repeated_windows: 3,
+        };
+        assert_eq!(writer.stats(), expected);
+
+        // windows left out by sampling are counted but not trained on.
+        let sampled = SamplingWriter::new(&mut markov, Sampling::new(0.0).unwrap());
+        let mut writer = MarkovWriter::new(sampled);
+        writer.write(b"abcd");
+        assert_eq!(writer.stats().windows, 2);
+        assert_eq!(writer.stats().trained(), 0);
+    }
+
+    #[test]
+    fn test_end_bytes() {
+        let mut markov = Markov::new(3);
+        markov.write_bytes(b"abc");
+        markov.write_bytes(b"d");
+        markov.end_bytes();
+        markov.write_bytes(b"ef");
+        markov.write_bytes(b"g");
+        let sequences: Vec<_> = markov.iter().map(|(sequence, _)| sequence).collect();
+        assert_eq!(sequences, [&b"abc"[..], b"bcd", b"efg"]);
+
+        // windows whose weights overflow are errors, also of the sink.
+        let overflowing = || {
+            let mut markov = Markov::new(3).with_weight_policy(WeightPolicy::Error);
+            markov.insert(b"bcd", usize::MAX).unwrap();
+            markov
+        };
+        let mut markov = overflowing();
+        markov.write_bytes(b"ab");
+        assert!(matches!(
+            markov.try_write_bytes(b"cd"),
+            Err(Error::WeightOverflow)
+        ));
+        assert!(matches!(
+            overflowing().train_sink().write(b"abcd"),
+            Err(error) if error.kind() == ErrorKind::Other
+        ));
+
+        // pending bytes do not make models differ.
+        let mut markov = Markov::new(3);
+        markov.write_bytes(b"abcd");
+        let mut other = markov.clone();
+        other.end_bytes();
+        assert_eq!(markov, other);
+        let hash = |markov: &Markov| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            std::hash::Hash::hash(markov, &mut hasher);
+            std::hash::Hasher::finish(&hasher)
+        };
+        assert_eq!(hash(&markov), hash(&other));
+        // they only tell apart once the pending bytes complete a window.
+        markov.write_bytes(b"e");
+        other.write_bytes(b"e");
+        assert_ne!(markov, other);
+    }
 }
@@ -0,0 +1,264 @@
+//! Streams of delimited messages coded with one model, see [`Encoder::message_writer`].
+//!
+//! Every message starts with its length plus one as a varint, written as 8-bit groups into the
+//! bit stream, followed by the codes of its bytes. A zero length ends the stream, which is then
+//! padded to a whole byte. Bytes are coded after the `depth - 1` bytes before them, see
+//! [`MessageContext`], bytes without that many before them are written as 8-bit literals.
+use crate::{
+    error::Error,
+    huffman::{DecodeSymbol, Decoder, EncodeSymbol, Encoder},
+    util::{read_varint, write_varint},
+};
+use bitstream_io::{BigEndian, BitRead, BitReader, BitWrite, BitWriter};
+use std::io::{Read, Result as IoResult, Write};
+
+/// Where the context of the first bytes of a message comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum MessageContext {
+    /// The context runs on from the end of the previous message, short messages code best.
+    #[default]
+    Carry,
+    /// Every message starts without a context, so that its codes do not depend on the messages
+    /// before it.
+    Reset,
+}
+
+// moves the context past a byte, keeping at most `depth - 1` bytes.
+fn advance(context: &mut Vec<u8>, depth: usize, byte: u8) {
+    context.push(byte);
+    if context.len() >= depth {
+        context.remove(0);
+    }
+}
+
+pub struct MessageWriter<H: EncodeSymbol, W: Write> {
+    encoder: H,
+    writer: BitWriter<W, BigEndian>,
+    context: Vec<u8>,
+    mode: MessageContext,
+}
+
+impl<H: EncodeSymbol, W: Write> MessageWriter<H, W> {
+    pub fn new(encoder: H, writer: W) -> Self {
+        MessageWriter {
+            context: Vec::with_capacity(encoder.depth()),
+            encoder,
+            writer: BitWriter::new(writer),
+            mode: MessageContext::default(),
+        }
+    }
+
+    pub fn with_context(mut self, mode: MessageContext) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Writes the length and the codes of `message`. Nothing reaches the inner writer in full
+    /// until [`MessageWriter::finish`].
+    pub fn write_message(&mut self, message: &[u8]) -> IoResult<()> {
+        let mut length = Vec::with_capacity(10);
+        write_varint(&mut length, message.len() as u64 + 1)?;
+        for byte in length {
+            self.writer.write(8, byte)?;
+        }
+
+        if self.mode == MessageContext::Reset {
+            self.context.clear();
+        }
+        let depth = self.encoder.depth();
+        for &byte in message {
+            if self.context.len() + 1 == depth {
+                self.encoder
+                    .write_symbol(&mut self.writer, &self.context, byte)?;
+            } else {
+                self.writer.write(8, byte)?;
+            }
+            advance(&mut self.context, depth, byte);
+        }
+        Ok(())
+    }
+
+    /// Ends the stream and returns the inner writer.
+    pub fn finish(mut self) -> IoResult<W> {
+        self.writer.write(8, 0u8)?;
+        self.writer.byte_align()?;
+        self.writer.flush()?;
+        Ok(self.writer.into_writer())
+    }
+}
+
+pub struct MessageReader<H: DecodeSymbol, R: Read> {
+    decoder: H,
+    depth: usize,
+    reader: BitReader<R, BigEndian>,
+    context: Vec<u8>,
+    mode: MessageContext,
+    done: bool,
+}
+
+impl<H: DecodeSymbol, R: Read> MessageReader<H, R> {
+    pub fn new(decoder: H, depth: usize, reader: R) -> Self {
+        MessageReader {
+            decoder,
+            depth,
+            reader: BitReader::new(reader),
+            context: Vec::with_capacity(depth),
+            mode: MessageContext::default(),
+            done: false,
+        }
+    }
+
+    /// Must match the mode the messages were written with.
+    pub fn with_context(mut self, mode: MessageContext) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Reads the next message, `None` once the end of the stream is reached.
+    pub fn read_message(&mut self) -> IoResult<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let Some(length) = read_varint(&mut ByteReader(&mut self.reader))?.checked_sub(1) else {
+            self.done = true;
+            return Ok(None);
+        };
+
+        if self.mode == MessageContext::Reset {
+            self.context.clear();
+        }
+        // the length comes from the input, so the message only grows as its bytes decode.
+        let mut message = Vec::with_capacity(length.min(1 << 12) as usize);
+        for _ in 0..length {
+            let byte = if self.context.len() + 1 == self.depth {
+                self.decoder
+                    .decode_symbol(&self.context, &mut self.reader)?
+                    .ok_or(Error::Format("end symbol inside a message"))?
+            } else {
+                self.reader.read(8)?
+            };
+            advance(&mut self.context, self.depth, byte);
+            message.push(byte);
+        }
+        Ok(Some(message))
+    }
+}
+
+// whole bytes of a bit reader, for the varints between messages.
+struct ByteReader<'a, R>(&'a mut R);
+
+impl<R: BitRead> Read for ByteReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        for slot in buf.iter_mut() {
+            *slot = self.0.read(8)?;
+        }
+        Ok(buf.len())
+    }
+}
+
+impl Encoder {
+    /// Writer of delimited messages, see the [module documentation](self).
+    pub fn message_writer<W: Write>(&self, writer: W) -> MessageWriter<&Self, W> {
+        MessageWriter::new(self, writer)
+    }
+}
+
+impl Decoder {
+    /// Reader of the messages of a [`MessageWriter`].
+    pub fn message_reader<R: Read>(&self, reader: R) -> MessageReader<&Self, R> {
+        MessageReader::new(self, self.depth, reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        huffman::{CodeOptions, EscapeMode},
+        util::synthetic::{markov_corpus, Profile},
+        Markov,
+    };
+    use test_strategy::proptest;
+
+    fn decoder(depth: usize, data: &[u8]) -> Decoder {
+        let mut markov = Markov::new(depth);
+        markov.insert_run(data);
+        let options = CodeOptions {
+            escape: EscapeMode::Literal,
+            ..Default::default()
+        };
+        Decoder::with_options(&markov, &options)
+    }
+
+    fn roundtrip(decoder: &Decoder, messages: &[Vec<u8>], mode: MessageContext) -> Vec<u8> {
+        let encoder = decoder.encoder();
+        let mut writer = encoder.message_writer(vec![]).with_context(mode);
+        for message in messages {
+            writer.write_message(message).unwrap();
+        }
+        let data = writer.finish().unwrap();
+
+        let mut reader = decoder.message_reader(&data[..]).with_context(mode);
+        for message in messages {
+            assert_eq!(reader.read_message().unwrap().as_ref(), Some(message));
+        }
+        assert_eq!(reader.read_message().unwrap(), None);
+        assert_eq!(reader.read_message().unwrap(), None);
+        data
+    }
+
+    #[proptest]
+    fn test_roundtrip(
+        #[strategy(1usize..5)] depth: usize,
+        training: Vec<u8>,
+        messages: Vec<Vec<u8>>,
+        reset: bool,
+    ) {
+        let mode = if reset {
+            MessageContext::Reset
+        } else {
+            MessageContext::Carry
+        };
+        roundtrip(&decoder(depth, &training), &messages, mode);
+    }
+
+    #[test]
+    fn test_many_messages() {
+        let corpus = markov_corpus(3, 40_000, Profile::EnglishLike);
+        let decoder = decoder(3, &corpus);
+
+        // messages of up to 16 bytes, every fifth one empty.
+        let mut messages = vec![];
+        let mut rest = &corpus[..];
+        for index in 0.. {
+            let len = if index % 5 == 0 { 0 } else { index % 17 };
+            if rest.len() < len {
+                break;
+            }
+            let (message, tail) = rest.split_at(len);
+            messages.push(message.to_vec());
+            rest = tail;
+        }
+        assert!(messages.len() > 4000);
+
+        let carry = roundtrip(&decoder, &messages, MessageContext::Carry);
+        let reset = roundtrip(&decoder, &messages, MessageContext::Reset);
+        assert!(carry.len() < reset.len());
+        // every length takes a byte, the text codes in less than half of its size.
+        assert!(carry.len() - messages.len() < corpus.len() / 2);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let decoder = decoder(2, b"abracadabra");
+        let encoder = decoder.encoder();
+        let mut writer = encoder.message_writer(vec![]);
+        writer.write_message(b"abracadabra").unwrap();
+        let data = writer.finish().unwrap();
+        for len in 0..data.len() {
+            let mut reader = decoder.message_reader(&data[..len]);
+            let result = reader.read_message().and_then(|_| reader.read_message());
+            assert!(result.is_err());
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! The types most programs work with, to be imported with `use huffman_markov::prelude::*`.
+//!
+//! The trie node and the writer of the models and of the Huffman codes are named after their
+//! module, so that both can be imported together.
+//!
+//! ```
+//! use huffman_markov::prelude::*;
+//! use std::io::Write;
+//!
+//! let mut training = MarkovWriter::new(Markov::new(2));
+//! training.write(b"abracadabra");
+//! let markov = training.finish();
+//! assert_eq!(markov.get(b"ab")?, Some(&MarkovNode::Leaf(2)));
+//!
+//! let mut writer = HuffmanWriter::new(markov.encoder(), vec![]);
+//! writer.write_all(b"abracadabra")?;
+//! let (coded, _) = writer.finish()?;
+//! assert!(coded.len() < 11);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+pub use crate::{
+    builder::Builder,
+    error::{Error, UnsupportedFeature},
+    huffman::{
+        CodeTableError, Coder, Decoder, EncodeError, Encoder, EscapeMode, HuffmanNode,
+        HuffmanWriter, SymbolError, WeightedItem,
+    },
+    markov::{
+        ExportFormat, Insertion, Markov, MarkovNode, MarkovWriter, SequenceLengthError,
+        SequenceWriter, WeightPolicy,
+    },
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_names() {
+        use crate::{huffman, markov};
+
+        // the old names still work for a release, as aliases of the new ones.
+        let node: huffman::Node = HuffmanNode::Leaf(b'a');
+        assert!(matches!(node, huffman::Node::Leaf(b'a')));
+        assert!(matches!(MarkovNode::Leaf(1), markov::Node::Leaf(1)));
+
+        let mut writer: markov::Writer<Markov> = MarkovWriter::new(Markov::new(2));
+        writer.write(b"ab");
+        let markov = writer.finish();
+        let writer: huffman::Writer<_, Vec<u8>> = HuffmanWriter::new(markov.encoder(), vec![]);
+        assert!(writer.finish().unwrap().0.is_empty());
+    }
+}
@@ -0,0 +1,681 @@
+use crate::{
+    alphabet::AlphabetMap,
+    context_map::ContextMap,
+    error::Error,
+    huffman::{
+        read_context, read_symbol_set, read_table_header, write_context, write_symbol_set,
+        write_table_header, CodeOptions, EscapeMode, Symbol, TableHeader, WeightedItem,
+    },
+    markov::Markov,
+    util::{buffered_windows, read_varint, write_varint},
+};
+use std::{
+    borrow::Borrow,
+    io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write},
+};
+
+/// Largest total frequency of a context, larger weights are scaled down to fit.
+pub const MAX_TOTAL: u32 = 1 << 16;
+
+// the range is renormalized whenever it drops below this.
+const TOP: u32 = 1 << 24;
+
+// escaped bytes and bytes in unknown contexts are coded with a uniform distribution.
+const LITERAL_TOTAL: u32 = 256;
+
+/// Cumulative frequencies of the symbols of a single context.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FrequencyTable {
+    symbols: Vec<Symbol>,
+    // frequency of all symbols before each one, followed by the total.
+    cumulative: Vec<u32>,
+}
+
+impl FrequencyTable {
+    fn new(items: impl Iterator<Item = WeightedItem<Symbol>>) -> Option<Self> {
+        let items: Vec<_> = items.collect();
+        if items.is_empty() {
+            return None;
+        }
+
+        // every symbol keeps a frequency of at least one, so it stays codable.
+        let count = items.len() as u128;
+        let total = items.iter().map(|item| item.weight as u128).sum::<u128>();
+        let frequencies = items.iter().map(|item| {
+            let weight = item.weight as u128;
+            let frequency = if total + count <= u128::from(MAX_TOTAL) {
+                weight
+            } else {
+                weight * (u128::from(MAX_TOTAL) - count) / total
+            };
+            frequency.max(1) as u32
+        });
+        let frequencies: Vec<u32> = frequencies.collect();
+        Self::from_frequencies(
+            items.into_iter().map(|item| item.item).collect(),
+            &frequencies,
+        )
+    }
+
+    fn from_frequencies(symbols: Vec<Symbol>, frequencies: &[u32]) -> Option<Self> {
+        let mut cumulative = Vec::with_capacity(frequencies.len() + 1);
+        let mut total = 0u32;
+        cumulative.push(0);
+        for frequency in frequencies {
+            if *frequency == 0 {
+                return None;
+            }
+            total = total.checked_add(*frequency)?;
+            cumulative.push(total);
+        }
+        if symbols.is_empty() || symbols.len() != frequencies.len() || total > MAX_TOTAL {
+            return None;
+        }
+        Some(FrequencyTable {
+            symbols,
+            cumulative,
+        })
+    }
+
+    pub fn total(&self) -> u32 {
+        self.cumulative[self.symbols.len()]
+    }
+
+    /// Symbols of the context along with their frequencies, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = (Symbol, u32)> + '_ {
+        self.symbols
+            .iter()
+            .zip(self.cumulative.windows(2))
+            .map(|(symbol, range)| (*symbol, range[1] - range[0]))
+    }
+
+    fn find(&self, symbol: Symbol) -> Option<(u32, u32)> {
+        let index = self.symbols.binary_search(&symbol).ok()?;
+        Some((
+            self.cumulative[index],
+            self.cumulative[index + 1] - self.cumulative[index],
+        ))
+    }
+
+    fn lookup(&self, value: u32) -> (Symbol, u32, u32) {
+        let index = self.cumulative[1..].partition_point(|cumulative| *cumulative <= value);
+        (
+            self.symbols[index],
+            self.cumulative[index],
+            self.cumulative[index + 1] - self.cumulative[index],
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
+struct Tables {
+    depth: usize,
+    escape: EscapeMode,
+    alphabet: AlphabetMap,
+    contexts: ContextMap<FrequencyTable>,
+}
+
+impl Tables {
+    fn new(markov: &Markov, options: &CodeOptions) -> Self {
+        let escape = options.escape_mode(markov);
+        let mut tables = Tables {
+            depth: markov.len(),
+            escape,
+            alphabet: options.alphabet,
+            contexts: ContextMap::new(markov.len()),
+        };
+        for (prefix, items) in markov.iter_prefix() {
+            let escape = (escape == EscapeMode::Literal)
+                .then(|| markov.escape_weight(&prefix).unwrap_or(0).max(1));
+            if let Some(table) = FrequencyTable::new(options.weights(&items, escape)) {
+                tables.contexts.insert(prefix.into(), table);
+            }
+        }
+        tables
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        write_table_header(
+            writer,
+            self.depth,
+            self.escape,
+            &self.alphabet,
+            false,
+            false,
+            self.contexts.len(),
+        )?;
+        let mut previous: &[u8] = &[];
+        for (context, table) in &self.contexts {
+            write_context(writer, previous, context)?;
+            previous = context;
+
+            if (self.escape == EscapeMode::Literal) != table.find(Symbol::Escape).is_some() {
+                return Err(Error::Format("escape symbol does not match escape mode"));
+            }
+            let symbols: Vec<u8> = table
+                .symbols
+                .iter()
+                .filter_map(|symbol| match symbol {
+                    Symbol::Byte(byte) => Some(*byte),
+                    Symbol::Escape | Symbol::Eof => None,
+                })
+                .collect();
+            write_symbol_set(writer, &symbols, &self.alphabet)?;
+
+            // most frequencies are small, they are packed into nibbles like the code lengths
+            // of the huffman tables. larger ones follow as varints.
+            let frequencies: Vec<u32> = table.iter().map(|(_, frequency)| frequency).collect();
+            let nibble = |frequency: u32| if frequency < 16 { frequency as u8 } else { 0 };
+            let packed: Vec<u8> = frequencies
+                .chunks(2)
+                .map(|pair| (nibble(pair[0]) << 4) | pair.get(1).map_or(0, |f| nibble(*f)))
+                .collect();
+            writer.write_all(&packed)?;
+            for frequency in frequencies.iter().filter(|frequency| **frequency >= 16) {
+                write_varint(writer, (*frequency).into())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let TableHeader {
+            depth,
+            escape,
+            alphabet,
+            eof,
+            order0,
+            count,
+        } = read_table_header(reader)?;
+        if eof {
+            return Err(Error::Format(
+                "end symbols are not supported by the range coder",
+            ));
+        }
+        if order0 {
+            return Err(Error::Format(
+                "order-0 tables are not supported by the range coder",
+            ));
+        }
+        let mut tables = Tables {
+            depth,
+            escape,
+            alphabet,
+            contexts: ContextMap::new(depth),
+        };
+        let mut context = vec![0; depth - 1];
+        for index in 0..count {
+            read_context(reader, index == 0, &mut context)?;
+            let mut symbols: Vec<Symbol> = read_symbol_set(reader, &alphabet)?
+                .into_iter()
+                .map(Symbol::Byte)
+                .collect();
+            if escape == EscapeMode::Literal {
+                symbols.push(Symbol::Escape);
+            }
+
+            let mut packed = vec![0; symbols.len().div_ceil(2)];
+            reader.read_exact(&mut packed)?;
+            let mut frequencies = Vec::with_capacity(symbols.len());
+            for index in 0..symbols.len() {
+                let pair = packed[index / 2];
+                let nibble = if index % 2 == 0 {
+                    pair >> 4
+                } else {
+                    pair & 0xf
+                };
+                frequencies.push(u32::from(nibble));
+            }
+            for frequency in frequencies.iter_mut().filter(|frequency| **frequency == 0) {
+                *frequency = read_varint(reader)?
+                    .try_into()
+                    .ok()
+                    .filter(|frequency| *frequency >= 16)
+                    .ok_or(Error::Format("invalid frequency"))?;
+            }
+            let table = FrequencyTable::from_frequencies(symbols, &frequencies)
+                .ok_or(Error::Format("invalid frequencies"))?;
+            tables.contexts.insert(context.clone().into(), table);
+        }
+        Ok(tables)
+    }
+
+    fn encode<W: Write>(&self, coder: &mut Encoder<W>, prefix: &[u8], byte: u8) -> IoResult<()> {
+        let table = self.contexts.get(prefix);
+        if let Some((cumulative, frequency)) =
+            table.and_then(|table| table.find(Symbol::Byte(byte)))
+        {
+            return coder.encode(cumulative, frequency, table.unwrap().total());
+        }
+
+        if self.escape == EscapeMode::None {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "symbol missing from model",
+            ));
+        }
+
+        // unknown contexts have no escape symbol, the decoder knows to read a literal there.
+        if let Some(table) = table {
+            let (cumulative, frequency) = table.find(Symbol::Escape).unwrap();
+            coder.encode(cumulative, frequency, table.total())?;
+        }
+        coder.encode(byte.into(), 1, LITERAL_TOTAL)
+    }
+
+    fn decode<R: Read>(&self, coder: &mut Decoder<R>, prefix: &[u8]) -> IoResult<u8> {
+        match self.contexts.get(prefix) {
+            Some(table) => {
+                let value = coder.decode_frequency(table.total())?;
+                let (symbol, cumulative, frequency) = table.lookup(value);
+                coder.update(cumulative, frequency)?;
+                match symbol {
+                    Symbol::Byte(byte) => return Ok(byte),
+                    Symbol::Escape => {}
+                    Symbol::Eof => return Err(Error::Format("unexpected end symbol").into()),
+                }
+            }
+            None if self.escape == EscapeMode::Literal => {}
+            None => return Err(Error::Format("context missing from model").into()),
+        }
+
+        let byte = coder.decode_frequency(LITERAL_TOTAL)?;
+        coder.update(byte, 1)?;
+        Ok(byte as u8)
+    }
+}
+
+// range encoder with carry propagation, emitting the pending byte once a carry can no longer
+// reach it.
+struct Encoder<W: Write> {
+    low: u64,
+    range: u32,
+    cache: u8,
+    pending: u64,
+    writer: W,
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(writer: W) -> Self {
+        Encoder {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            pending: 1,
+            writer,
+        }
+    }
+
+    fn encode(&mut self, cumulative: u32, frequency: u32, total: u32) -> IoResult<()> {
+        let range = self.range / total;
+        self.low += u64::from(range) * u64::from(cumulative);
+        self.range = range * frequency;
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low()?;
+        }
+        Ok(())
+    }
+
+    fn shift_low(&mut self) -> IoResult<()> {
+        if self.low < 0xFF00_0000 || self.low > u64::from(u32::MAX) {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.pending > 0 {
+                self.writer.write_all(&[byte.wrapping_add(carry)])?;
+                byte = 0xFF;
+                self.pending -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.pending += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+        Ok(())
+    }
+
+    fn finish(mut self) -> IoResult<W> {
+        for _ in 0..5 {
+            self.shift_low()?;
+        }
+        Ok(self.writer)
+    }
+}
+
+struct Decoder<R: Read> {
+    range: u32,
+    code: u32,
+    step: u32,
+    started: bool,
+    reader: R,
+}
+
+impl<R: Read> Decoder<R> {
+    fn new(reader: R) -> Self {
+        Decoder {
+            range: u32::MAX,
+            code: 0,
+            step: 0,
+            started: false,
+            reader,
+        }
+    }
+
+    fn next(&mut self) -> IoResult<u32> {
+        let mut byte = [0];
+        self.reader.read_exact(&mut byte)?;
+        Ok(byte[0].into())
+    }
+
+    fn decode_frequency(&mut self, total: u32) -> IoResult<u32> {
+        if !self.started {
+            for _ in 0..5 {
+                self.code = (self.code << 8) | self.next()?;
+            }
+            self.started = true;
+        }
+        self.step = self.range / total;
+        Ok((self.code / self.step).min(total - 1))
+    }
+
+    fn update(&mut self, cumulative: u32, frequency: u32) -> IoResult<()> {
+        let low = self.step * cumulative;
+        if self.code < low {
+            return Err(Error::Format("invalid range coded data").into());
+        }
+        self.code -= low;
+        self.range = self.step * frequency;
+        while self.range < TOP {
+            self.code = (self.code << 8) | self.next()?;
+            self.range <<= 8;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes bytes with range coding over the successor distributions of a model.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub struct RangeEncoder {
+    tables: Tables,
+}
+
+impl RangeEncoder {
+    pub fn new(markov: &Markov) -> Self {
+        Self::with_options(markov, &CodeOptions::default())
+    }
+
+    pub(crate) fn with_options(markov: &Markov, options: &CodeOptions) -> Self {
+        RangeEncoder {
+            tables: Tables::new(markov, options),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.tables.depth
+    }
+
+    pub fn table(&self, prefix: &[u8]) -> Option<&FrequencyTable> {
+        self.tables.contexts.get(prefix)
+    }
+
+    pub fn decoder(&self) -> RangeDecoder {
+        RangeDecoder {
+            tables: self.tables.clone(),
+        }
+    }
+
+    pub fn write_tables<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        self.tables.write(writer)
+    }
+
+    pub fn writer<W: Write>(&self, writer: W) -> RangeWriter<&Self, W> {
+        RangeWriter::new(self, writer)
+    }
+
+    pub fn encode_all(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.is_empty() {
+            return Ok(vec![]);
+        }
+
+        if data.len() < self.depth() {
+            return Err(Error::InputTooShort {
+                len: data.len(),
+                depth: self.depth(),
+            });
+        }
+
+        let mut writer = self.writer(vec![]);
+        writer.write_all(data)?;
+        Ok(writer.finish()?)
+    }
+}
+
+/// Decodes bytes written by a [`RangeEncoder`] with the same tables.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub struct RangeDecoder {
+    tables: Tables,
+}
+
+impl RangeDecoder {
+    pub fn new(markov: &Markov) -> Self {
+        RangeEncoder::new(markov).decoder()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.tables.depth
+    }
+
+    pub fn table(&self, prefix: &[u8]) -> Option<&FrequencyTable> {
+        self.tables.contexts.get(prefix)
+    }
+
+    pub fn read_tables<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        Ok(RangeDecoder {
+            tables: Tables::read(reader)?,
+        })
+    }
+
+    pub fn reader<R: Read>(&self, reader: R, context: &[u8], len: u64) -> RangeReader<&Self, R> {
+        RangeReader::new(self, reader, context, len)
+    }
+
+    pub fn decode_all(&self, context: &[u8], data: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        if context.len() + 1 != self.depth() {
+            return Err(Error::Format("context length does not match model depth"));
+        }
+
+        // a single symbol context takes next to no space, so the length is only trusted as far
+        // as the input could plausibly back it.
+        let mut output = Vec::with_capacity(len.min(data.len().saturating_mul(8)));
+        self.reader(data, context, len as u64)
+            .read_to_end(&mut output)?;
+        Ok(output)
+    }
+}
+
+pub struct RangeWriter<H: Borrow<RangeEncoder>, W: Write> {
+    buffer: Vec<u8>,
+    encoder: H,
+    coder: Encoder<W>,
+}
+
+impl<H: Borrow<RangeEncoder>, W: Write> RangeWriter<H, W> {
+    pub fn new(encoder: H, writer: W) -> Self {
+        RangeWriter {
+            buffer: vec![],
+            encoder,
+            coder: Encoder::new(writer),
+        }
+    }
+
+    /// Flushes the state of the range coder, which the decoder needs to decode the final bytes.
+    pub fn finish(self) -> IoResult<W> {
+        self.coder.finish()
+    }
+}
+
+impl<H: Borrow<RangeEncoder>, W: Write> Write for RangeWriter<H, W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let tables = &self.encoder.borrow().tables;
+        buffered_windows(tables.depth, &mut self.buffer, buf, |window| {
+            let (byte, prefix) = window.split_last().unwrap();
+            tables.encode(&mut self.coder, prefix, *byte)
+        })?;
+        Ok(buf.len())
+    }
+
+    // the coder state can only be written out once, in finish.
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+pub struct RangeReader<H: Borrow<RangeDecoder>, R: Read> {
+    context: Vec<u8>,
+    decoder: H,
+    coder: Decoder<R>,
+    remaining: u64,
+}
+
+impl<H: Borrow<RangeDecoder>, R: Read> RangeReader<H, R> {
+    pub fn new(decoder: H, reader: R, context: &[u8], len: u64) -> Self {
+        RangeReader {
+            context: context.into(),
+            decoder,
+            coder: Decoder::new(reader),
+            remaining: len,
+        }
+    }
+}
+
+impl<H: Borrow<RangeDecoder>, R: Read> Read for RangeReader<H, R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let tables = &self.decoder.borrow().tables;
+        let count = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        for slot in &mut buf[..count] {
+            let byte = tables.decode(&mut self.coder, &self.context)?;
+            if let Some(first) = self.context.first_mut() {
+                *first = byte;
+                self.context.rotate_left(1);
+            }
+            *slot = byte;
+        }
+        self.remaining -= count as u64;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use test_strategy::proptest;
+
+    fn roundtrip(encoder: &RangeEncoder, data: &[u8]) -> Vec<u8> {
+        let depth = encoder.depth();
+        let encoded = encoder.encode_all(data).unwrap();
+
+        let mut tables = vec![];
+        encoder.write_tables(&mut tables).unwrap();
+        let decoder = RangeDecoder::read_tables(&mut &tables[..]).unwrap();
+        assert_eq!(decoder, encoder.decoder());
+
+        let decoded = decoder
+            .decode_all(&data[..depth - 1], &encoded, data.len() - (depth - 1))
+            .unwrap();
+        assert_eq!(decoded, &data[depth - 1..]);
+        encoded
+    }
+
+    #[proptest]
+    fn test_roundtrip(#[strategy(1usize..5)] depth: usize, data: Vec<u8>) {
+        prop_assume!(data.len() >= depth);
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data);
+        roundtrip(&RangeEncoder::new(&markov), &data);
+    }
+
+    #[proptest]
+    fn test_writer_chunked(
+        #[strategy(1usize..5)] depth: usize,
+        data: Vec<u8>,
+        #[strategy(proptest::collection::vec(any::<usize>(), 0..32))] cuts: Vec<usize>,
+    ) {
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&data);
+        let encoder = RangeEncoder::new(&markov);
+        let write = |chunks: &[&[u8]]| {
+            let mut writer = RangeWriter::new(&encoder, vec![]);
+            for chunk in chunks {
+                writer.write_all(chunk).unwrap();
+                writer.flush().unwrap();
+            }
+            writer.finish().unwrap()
+        };
+
+        let mut cuts: Vec<usize> = cuts.iter().map(|cut| cut % (data.len() + 1)).collect();
+        cuts.sort_unstable();
+        let chunks: Vec<&[u8]> = [0]
+            .iter()
+            .chain(&cuts)
+            .zip(cuts.iter().chain([&data.len()]))
+            .map(|(start, end)| &data[*start..*end])
+            .collect();
+        prop_assert_eq!(write(&chunks), write(&[&data]));
+    }
+
+    #[proptest]
+    fn test_escape_roundtrip(
+        #[strategy(1usize..4)] depth: usize,
+        training: Vec<u8>,
+        data: Vec<u8>,
+    ) {
+        prop_assume!(data.len() >= depth);
+        let mut markov = Markov::new(depth);
+        markov.writer().write(&training);
+        let options = CodeOptions {
+            escape: EscapeMode::Literal,
+            ..Default::default()
+        };
+        roundtrip(&RangeEncoder::with_options(&markov, &options), &data);
+    }
+
+    #[test]
+    fn test_skewed() {
+        // long runs of a very likely symbol exercise the carry propagation.
+        let mut data = vec![b'a'; 100_000];
+        for index in (0..data.len()).step_by(997) {
+            data[index] = b'b';
+        }
+        let mut markov = Markov::new(1);
+        markov.writer().write(&data);
+        let encoded = roundtrip(&RangeEncoder::new(&markov), &data);
+        assert!(encoded.len() < 200, "{}", encoded.len());
+    }
+
+    #[test]
+    fn test_scaled_frequencies() {
+        let mut markov = Markov::new(1);
+        markov.insert(b"a", usize::MAX).unwrap();
+        markov.insert(b"b", 1).unwrap();
+        markov.insert(b"c", 1 << 20).unwrap();
+        let encoder = RangeEncoder::new(&markov);
+        let table = encoder.table(b"").unwrap();
+        assert!(table.total() <= MAX_TOTAL);
+        assert!(table.iter().all(|(_, frequency)| frequency >= 1));
+        roundtrip(&encoder, b"abcabcaaaa");
+    }
+
+    #[test]
+    fn test_missing_symbol() {
+        let mut markov = Markov::new(2);
+        markov.writer().write(b"abab");
+        let encoder = RangeEncoder::new(&markov);
+        assert!(encoder.encode_all(b"abc").is_err());
+    }
+}
@@ -1,4 +1,272 @@
-pub fn buffered_windows<T: Clone, E>(
+//! I/O adapters and other helpers used around the coders.
+use crate::error::Error;
+use std::{
+    hash::Hasher,
+    io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use xxhash_rust::xxh3::Xxh3;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod synthetic;
+
+/// Writer that hashes every byte it forwards, XXH3-64 unless another [`Hasher`] is given.
+///
+/// Only the bytes the inner writer accepted are hashed, so short writes are accounted for.
+#[derive(Clone, Debug)]
+pub struct HashingWriter<W: Write, H = Xxh3> {
+    inner: W,
+    hasher: H,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_hasher(inner, Xxh3::new())
+    }
+}
+
+impl<W: Write, H: Hasher> HashingWriter<W, H> {
+    pub fn with_hasher(inner: W, hasher: H) -> Self {
+        HashingWriter { inner, hasher }
+    }
+
+    /// Digest of the bytes written so far.
+    pub fn digest(&self) -> u64 {
+        self.hasher.finish()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> (W, H) {
+        (self.inner, self.hasher)
+    }
+}
+
+impl<W: Write, H: Hasher> Write for HashingWriter<W, H> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let count = self.inner.write(buf)?;
+        self.hasher.write(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that hashes every byte it reads, the input side analog of [`HashingWriter`].
+#[derive(Clone, Debug)]
+pub struct HashingReader<R: Read, H = Xxh3> {
+    inner: R,
+    hasher: H,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_hasher(inner, Xxh3::new())
+    }
+}
+
+impl<R: Read, H: Hasher> HashingReader<R, H> {
+    pub fn with_hasher(inner: R, hasher: H) -> Self {
+        HashingReader { inner, hasher }
+    }
+
+    /// Digest of the bytes read so far.
+    pub fn digest(&self) -> u64 {
+        self.hasher.finish()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> (R, H) {
+        (self.inner, self.hasher)
+    }
+}
+
+impl<R: Read, H: Hasher> Read for HashingReader<R, H> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.write(&buf[..count]);
+        Ok(count)
+    }
+}
+
+/// Writer that counts how often every byte value is written to it, for the entropy of data
+/// without any context.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ByteHistogram {
+    counts: [u64; 256],
+}
+
+impl ByteHistogram {
+    pub fn new() -> Self {
+        ByteHistogram { counts: [0; 256] }
+    }
+
+    pub fn counts(&self) -> &[u64; 256] {
+        &self.counts
+    }
+
+    /// Number of bytes written so far.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Order-0 entropy of the bytes written so far, in bits per byte.
+    pub fn entropy(&self) -> f64 {
+        entropy(self.counts)
+    }
+}
+
+impl Default for ByteHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for ByteHistogram {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        for byte in buf {
+            self.counts[usize::from(*byte)] += 1;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+// entropy of the distribution given by `weights` in bits, zero if there is no weight at all.
+pub(crate) fn entropy(weights: impl IntoIterator<Item = u64>) -> f64 {
+    let (total, sum) = weights.into_iter().filter(|weight| *weight > 0).fold(
+        (0.0, 0.0),
+        |(total, sum), weight| {
+            let weight = weight as f64;
+            (total + weight, sum + weight * weight.log2())
+        },
+    );
+    if total == 0.0 {
+        return 0.0;
+    }
+    (total.log2() - sum / total).max(0.0)
+}
+
+/// Flag for stopping long-running operations from another thread, such as a signal handler.
+///
+/// Clones share the flag. Operations given a token check it as they go and fail with
+/// [`Error::Cancelled`] once it is set.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Fails with [`Error::Cancelled`] if the token was cancelled.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+/// Tokens are equal if they share the flag.
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+    }
+}
+
+impl Eq for CancellationToken {}
+
+/// Table replacing every byte by another one, such as to fold case before modeling.
+///
+/// Only a bijection can be undone, see [`ByteMapper::inverse`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ByteMapper {
+    table: [u8; 256],
+}
+
+impl ByteMapper {
+    pub fn new(table: [u8; 256]) -> Self {
+        ByteMapper { table }
+    }
+
+    pub fn identity() -> Self {
+        Self::new(std::array::from_fn(|byte| byte as u8))
+    }
+
+    /// Maps ASCII uppercase letters to lowercase, leaving all other bytes.
+    pub fn ascii_lowercase() -> Self {
+        Self::new(std::array::from_fn(|byte| {
+            (byte as u8).to_ascii_lowercase()
+        }))
+    }
+
+    pub fn table(&self) -> &[u8; 256] {
+        &self.table
+    }
+
+    pub fn map(&self, byte: u8) -> u8 {
+        self.table[usize::from(byte)]
+    }
+
+    /// Maps every byte of `data` in place.
+    pub fn map_slice(&self, data: &mut [u8]) {
+        for byte in data {
+            *byte = self.map(*byte);
+        }
+    }
+
+    /// Whether every byte is mapped to a different one, so the mapping can be undone.
+    pub fn is_bijection(&self) -> bool {
+        let mut seen = [false; 256];
+        self.table
+            .iter()
+            .all(|byte| !std::mem::replace(&mut seen[usize::from(*byte)], true))
+    }
+
+    /// Mapper undoing this one, if it is a bijection.
+    pub fn inverse(&self) -> Option<Self> {
+        if !self.is_bijection() {
+            return None;
+        }
+        let mut table = [0; 256];
+        for (byte, mapped) in self.table.iter().enumerate() {
+            table[usize::from(*mapped)] = byte as u8;
+        }
+        Some(Self::new(table))
+    }
+}
+
+impl Default for ByteMapper {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+pub(crate) fn buffered_windows<T: Clone, E>(
     window_size: usize,
     buffer: &mut Vec<T>,
     input: &[T],
@@ -17,7 +285,7 @@ pub fn buffered_windows<T: Clone, E>(
 
     // first, write the first n chars to fill the buffer.
     let count = input.len().min(buffer.len());
-    buffer.extend(input[0..count].into_iter().cloned());
+    buffer.extend(input[0..count].iter().cloned());
     for window in buffer.windows(window_size) {
         write(window)?;
     }
@@ -40,3 +308,188 @@ pub fn buffered_windows<T: Clone, E>(
 
     Ok(())
 }
+
+pub(crate) fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> IoResult<()> {
+    while value >= 0x80 {
+        writer.write_all(&[(value as u8) | 0x80])?;
+        value >>= 7;
+    }
+    writer.write_all(&[value as u8])
+}
+
+// next byte of `reader`, `None` at its end. reads of nothing are only taken as the end, other
+// short reads are retried like by `read_exact`.
+pub(crate) fn read_byte_or_end<R: Read>(reader: &mut R) -> IoResult<Option<u8>> {
+    let mut byte = [0];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+pub(crate) fn read_varint<R: Read>(reader: &mut R) -> IoResult<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        let bits = u64::from(byte[0] & 0x7f);
+        if shift == 63 && bits > 1 {
+            break;
+        }
+        value |= bits << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(IoError::new(ErrorKind::InvalidData, "varint overflow"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::io::copy;
+    use test_strategy::proptest;
+    use xxhash_rust::xxh3::xxh3_64;
+
+    // accepts or returns at most `limit` bytes per call.
+    struct Short<T> {
+        inner: T,
+        limit: usize,
+    }
+
+    impl<W: Write> Write for Short<W> {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            let count = buf.len().min(self.limit);
+            self.inner.write(&buf[..count])
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl<R: Read> Read for Short<R> {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            let count = buf.len().min(self.limit);
+            self.inner.read(&mut buf[..count])
+        }
+    }
+
+    #[proptest]
+    fn test_hashing_writer(data: Vec<u8>, #[strategy(1usize..16)] limit: usize) {
+        let mut writer = HashingWriter::new(Short {
+            inner: vec![],
+            limit,
+        });
+        writer.write_all(&data).unwrap();
+        prop_assert_eq!(writer.digest(), xxh3_64(&data));
+        prop_assert_eq!(&writer.into_inner().0.inner, &data);
+    }
+
+    #[proptest]
+    fn test_hashing_reader(data: Vec<u8>, #[strategy(1usize..16)] limit: usize) {
+        let mut reader = HashingReader::new(Short {
+            inner: &data[..],
+            limit,
+        });
+        let mut output = vec![];
+        copy(&mut reader, &mut output).unwrap();
+        prop_assert_eq!(reader.digest(), xxh3_64(&data));
+        prop_assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_byte_histogram() {
+        // every byte equally often is the most there is, a single byte value the least.
+        let mut uniform = ByteHistogram::new();
+        for _ in 0..16 {
+            uniform.write_all(&(0..=255).collect::<Vec<u8>>()).unwrap();
+        }
+        assert_eq!(uniform.total(), 16 * 256);
+        assert!((uniform.entropy() - 8.0).abs() < 1e-9);
+
+        let mut constant = ByteHistogram::default();
+        constant.write_all(&[b'a'; 100]).unwrap();
+        assert_eq!(constant.counts()[usize::from(b'a')], 100);
+        assert_eq!(constant.entropy(), 0.0);
+        assert_eq!(ByteHistogram::new().entropy(), 0.0);
+
+        // one byte in a hundred differs, which is about 0.08 bits per byte.
+        let mut skewed = ByteHistogram::new();
+        for _ in 0..100 {
+            skewed.write_all(&[b'a'; 99]).unwrap();
+            skewed.write_all(b"b").unwrap();
+        }
+        assert!((0.080..0.081).contains(&skewed.entropy()));
+    }
+
+    #[proptest]
+    fn test_byte_histogram_random(#[strategy(1u64..)] seed: u64) {
+        // pseudo-random bytes come close to eight bits, but never above.
+        let mut state = seed;
+        let data: Vec<u8> = (0..1 << 16)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect();
+        let mut histogram = ByteHistogram::new();
+        histogram.write_all(&data).unwrap();
+        prop_assert!((7.99..=8.0).contains(&histogram.entropy()));
+    }
+
+    #[test]
+    fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(token.check().is_ok());
+        assert_eq!(token, clone);
+        assert_ne!(token, CancellationToken::new());
+
+        clone.cancel();
+        assert!(token.is_cancelled());
+        assert!(matches!(token.check(), Err(Error::Cancelled)));
+    }
+
+    #[test]
+    fn test_byte_mapper() {
+        let lowercase = ByteMapper::ascii_lowercase();
+        let mut data = *b"Hello, World!";
+        lowercase.map_slice(&mut data);
+        assert_eq!(&data, b"hello, world!");
+        assert!(!lowercase.is_bijection());
+        assert_eq!(lowercase.inverse(), None);
+        assert!(ByteMapper::default().is_bijection());
+    }
+
+    #[proptest]
+    fn test_byte_mapper_inverse(seed: u64, data: Vec<u8>) {
+        // a rotation followed by a xor is always a bijection.
+        let mapper = ByteMapper::new(std::array::from_fn(|byte| {
+            (byte as u8).wrapping_add(seed as u8) ^ (seed >> 8) as u8
+        }));
+        let inverse = mapper.inverse().unwrap();
+        let mut mapped = data.clone();
+        mapper.map_slice(&mut mapped);
+        inverse.map_slice(&mut mapped);
+        prop_assert_eq!(mapped, data);
+    }
+
+    #[test]
+    fn test_partial_write() {
+        // the bytes the inner writer did not take are left out of the digest.
+        let mut writer = HashingWriter::new(Short {
+            inner: vec![],
+            limit: 4,
+        });
+        assert_eq!(writer.write(b"abracadabra").unwrap(), 4);
+        assert_eq!(writer.digest(), xxh3_64(b"abra"));
+    }
+}
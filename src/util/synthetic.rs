@@ -0,0 +1,228 @@
+//! Reproducible inputs for examples and tests, generated from a seed without shipping corpora.
+//!
+//! The output only depends on the seed, length and profile, on every platform, so golden tests
+//! can be built on it. Available with the `testing` feature.
+
+/// Kind of data [`markov_corpus`] generates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Profile {
+    /// Lowercase words and punctuation from a hardcoded table of English letter transitions,
+    /// text that an order-n model compresses well.
+    EnglishLike,
+    /// Records of 16 bytes with a counter, slowly changing values and random bytes, like a
+    /// binary log.
+    Binary,
+    /// A few random phrases repeated in random order, with the odd byte changed.
+    Repetitive,
+    /// Uniformly random bytes, which do not compress.
+    Random,
+}
+
+impl Profile {
+    pub const ALL: [Profile; 4] = [
+        Profile::EnglishLike,
+        Profile::Binary,
+        Profile::Repetitive,
+        Profile::Random,
+    ];
+}
+
+/// `len` bytes of data of the given profile, determined by `seed`.
+pub fn markov_corpus(seed: u64, len: usize, profile: Profile) -> Vec<u8> {
+    let mut rng = Pcg32::new(seed, 0);
+    let mut output = Vec::with_capacity(len + PHRASE_LEN);
+    match profile {
+        Profile::EnglishLike => english_like(&mut rng, &mut output, len),
+        Profile::Binary => binary(&mut rng, &mut output, len),
+        Profile::Repetitive => repetitive(&mut rng, &mut output, len),
+        Profile::Random => output.extend((0..len).map(|_| rng.next_u32() as u8)),
+    }
+    output.truncate(len);
+    output
+}
+
+// successors of every letter, space and punctuation mark, each as often as it should follow.
+// roughly the english bigram frequencies, spaces end words and the marks end clauses.
+const TRANSITIONS: &[(u8, &[u8])] = &[
+    (b' ', b"ttttttaaaaoooiiisssswwwcccbbbpppmmhhfffdrrleeggnnuy"),
+    (b',', b" "),
+    (b'.', b" "),
+    (b'a', b"nnnnnttttsssrrrlllldccmyyiippgbkvw      "),
+    (b'b', b"eeeelllouuaiyrs "),
+    (b'c', b"oooheeeaaatkiiurl "),
+    (b'd', b"         eeeeiiaooursy,."),
+    (b'e', b"        rrrrnnnnsssaaadddtttcclleemmvxiwyp,,.."),
+    (b'f', b"    ooorreeiiaatuf"),
+    (b'g', b"     eeehhrroaailu"),
+    (b'h', b"eeeeeeeaaaaiiiooot   "),
+    (b'i', b"nnnnnnsssstttcccooollleedmrvgfa"),
+    (b'j', b"uuoe"),
+    (b'k', b"eeei  ns"),
+    (b'l', b"eeeelllliiiaaaooydu   "),
+    (b'm', b"eeeaaaoopiu   "),
+    (b'n', b"       ddddgggeeeetttsssaaioccy,."),
+    (b'o', b"nnnnurrrrffff     mmttwwlloossppdvkc"),
+    (b'p', b"eeerraaoollhtuip "),
+    (b'q', b"u"),
+    (b'r', b"eeeeee     aaoooiiisstdyum,."),
+    (b's', b"       tttteeeesshhiioouacp,."),
+    (b't', b"hhhhhhhheeeeiiiooo        aarsuy,."),
+    (b'u', b"rrrrsssnnntttlllpcgmeai "),
+    (b'v', b"eeeeei"),
+    (b'w', b"aaaahhhiiieeoo "),
+    (b'x', b"tpica "),
+    (b'y', b"      ooeias,."),
+    (b'z', b"eeaio"),
+];
+
+fn english_like(rng: &mut Pcg32, output: &mut Vec<u8>, len: usize) {
+    let mut byte = b' ';
+    while output.len() < len {
+        let successors = TRANSITIONS
+            .iter()
+            .find(|(from, _)| *from == byte)
+            .map(|(_, successors)| *successors)
+            .unwrap();
+        byte = successors[rng.below(successors.len())];
+        output.push(byte);
+    }
+}
+
+fn binary(rng: &mut Pcg32, output: &mut Vec<u8>, len: usize) {
+    let mut value = rng.next_u32() as u16;
+    for counter in 0u32.. {
+        if output.len() >= len {
+            break;
+        }
+        value = value.wrapping_add(rng.below(8) as u16).wrapping_sub(3);
+        output.extend_from_slice(&counter.to_le_bytes());
+        output.extend_from_slice(&value.to_le_bytes());
+        output.extend((0..8).map(|_| rng.next_u32() as u8));
+        output.extend_from_slice(&[0, 0]);
+    }
+}
+
+const PHRASES: usize = 8;
+const PHRASE_LEN: usize = 24;
+
+fn repetitive(rng: &mut Pcg32, output: &mut Vec<u8>, len: usize) {
+    let phrases: Vec<Vec<u8>> = (0..PHRASES)
+        .map(|_| {
+            (0..PHRASE_LEN)
+                .map(|_| b'a' + rng.below(26) as u8)
+                .collect()
+        })
+        .collect();
+    while output.len() < len {
+        let start = output.len();
+        output.extend_from_slice(&phrases[rng.below(PHRASES)]);
+        if rng.below(16) == 0 {
+            output[start + rng.below(PHRASE_LEN)] = rng.next_u32() as u8;
+        }
+    }
+}
+
+// PCG-XSH-RR with 64 bits of state and 32 bits of output, see https://www.pcg-random.org.
+struct Pcg32 {
+    state: u64,
+    increment: u64,
+}
+
+impl Pcg32 {
+    const MULTIPLIER: u64 = 6364136223846793005;
+
+    // seeds like pcg32_srandom_r, the stream picks one of 2^63 sequences.
+    fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Pcg32 {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let state = self.state;
+        self.state = state
+            .wrapping_mul(Self::MULTIPLIER)
+            .wrapping_add(self.increment);
+        let xorshifted = (((state >> 18) ^ state) >> 27) as u32;
+        xorshifted.rotate_right((state >> 59) as u32)
+    }
+
+    // slightly biased towards small values for bounds that are not powers of two, which does
+    // not matter for test data.
+    fn below(&mut self, bound: usize) -> usize {
+        ((u64::from(self.next_u32()) * bound as u64) >> 32) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_bytes, decompress_bytes};
+    use proptest::prelude::*;
+    use std::hash::Hasher;
+    use test_strategy::proptest;
+    use xxhash_rust::xxh3::Xxh3;
+
+    #[proptest]
+    fn test_deterministic(seed: u64, #[strategy(0usize..4096)] len: usize, profile: usize) {
+        let profile = Profile::ALL[profile % Profile::ALL.len()];
+        let corpus = markov_corpus(seed, len, profile);
+        prop_assert_eq!(corpus.len(), len);
+        prop_assert_eq!(&markov_corpus(seed, len, profile), &corpus);
+
+        // shorter outputs are prefixes of longer ones.
+        prop_assert_eq!(
+            &markov_corpus(seed, len / 2, profile)[..],
+            &corpus[..len / 2]
+        );
+        let compressed = compress_bytes(&corpus, 3).unwrap();
+        prop_assert_eq!(decompress_bytes(&compressed).unwrap(), corpus);
+    }
+
+    #[test]
+    fn test_pcg32() {
+        // first outputs of the reference implementation seeded with 42 and stream 54.
+        let mut rng = Pcg32::new(42, 54);
+        let outputs: Vec<u32> = (0..3).map(|_| rng.next_u32()).collect();
+        assert_eq!(outputs, [0xa15c02b7, 0x7b47f409, 0xba1d3330]);
+    }
+
+    #[test]
+    fn test_golden() {
+        let hash = |profile| {
+            let mut hasher = Xxh3::new();
+            hasher.write(&markov_corpus(7, 10_000, profile));
+            hasher.finish()
+        };
+        let hashes = Profile::ALL.map(hash);
+        assert_eq!(
+            hashes,
+            [
+                0xb2b82f5dcac60639,
+                0xbad00f9f36e2d230,
+                0x4c28e4ed99a59543,
+                0x94c2dde989fa5a65,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_profiles() {
+        let ratio = |profile| {
+            let corpus = markov_corpus(1, 100_000, profile);
+            compress_bytes(&corpus, 3).unwrap().len() as f64 / corpus.len() as f64
+        };
+        assert!(ratio(Profile::EnglishLike) < 0.6);
+        assert!(ratio(Profile::Repetitive) < 0.5);
+        assert!(ratio(Profile::Random) > 0.99);
+        let text = markov_corpus(1, 1000, Profile::EnglishLike);
+        assert!(text
+            .iter()
+            .all(|byte| byte.is_ascii_lowercase() || b" ,.".contains(byte)));
+    }
+}
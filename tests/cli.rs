@@ -0,0 +1,1131 @@
+//! Exit codes and error output of the command line tool.
+use assert_cmd::Command;
+use huffman_markov::{archive::read_archive, container::Header, Builder};
+use serde_json::{json, Value};
+use std::{fs, path::PathBuf};
+use tempfile::TempDir;
+
+fn command() -> Command {
+    Command::cargo_bin("huffman_markov").unwrap()
+}
+
+// writes `data` into a fresh directory, which has to outlive the returned path.
+fn file(data: &[u8]) -> (TempDir, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("input");
+    fs::write(&path, data).unwrap();
+    (dir, path)
+}
+
+#[test]
+fn test_success() {
+    let (_dir, path) = file(b"abracadabra");
+    command().arg("compress").arg(&path).assert().success();
+}
+
+#[test]
+fn test_compress_check() {
+    let (_dir, path) = file(&b"abracadabra ".repeat(100));
+    let output = command()
+        .args(["--json", "compress", "--check", "--depth", "3"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["input_bytes"], 1200);
+    assert_eq!(report["missed_bytes"], 0);
+    assert_eq!(report["windows"], 1198);
+    assert_eq!(report["contexts"], 9);
+}
+
+#[test]
+fn test_missing_file() {
+    command()
+        .args(["decompress", "does-not-exist"])
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn test_usage() {
+    command().arg("compress").assert().code(2);
+
+    let (_dir, path) = file(b"abracadabra");
+    command()
+        .args(["compress", "--depth", "0"])
+        .arg(&path)
+        .assert()
+        .code(2);
+    command()
+        .args(["compress", "--filter", "delta:0"])
+        .arg(&path)
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_format() {
+    let (_dir, path) = file(b"not compressed");
+    command().arg("decompress").arg(&path).assert().code(4);
+
+    let compressed = huffman_markov::compress_bytes(b"abracadabra", 3).unwrap();
+    let (_dir, path) = file(&compressed[..compressed.len() - 1]);
+    command().arg("decompress").arg(&path).assert().code(4);
+}
+
+#[test]
+fn test_checksum_mismatch() {
+    // incompressible blocks are stored, so damage is only caught by the checksum.
+    let data: Vec<u8> = (0..=255).collect();
+    let builder = Builder::new().depth(1).block_size(128).sync_interval(1);
+    let mut compressed = builder.compress(&data).unwrap();
+    *compressed.last_mut().unwrap() ^= 1;
+    let (_dir, path) = file(&compressed);
+    command().arg("decompress").arg(&path).assert().code(5);
+}
+
+#[test]
+fn test_json_errors() {
+    let mut compressed = huffman_markov::compress_bytes(b"abracadabra", 3).unwrap();
+    compressed[4] = 0xff;
+    let (_dir, path) = file(&compressed);
+    let output = command()
+        .args(["decompress", "--error-format", "json"])
+        .arg(&path)
+        .assert()
+        .code(4)
+        .get_output()
+        .clone();
+    let error: Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(
+        error,
+        json!({
+            "error": "unsupported format version 255, expected at most 1",
+            "kind": "unsupported_version",
+            "detail": {"found": 255, "supported": 1},
+        })
+    );
+
+    let output = command()
+        .args(["--error-format", "json", "decompress", "does-not-exist"])
+        .assert()
+        .code(1)
+        .get_output()
+        .clone();
+    let error: Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert!(error["error"].is_string());
+    assert_eq!(error["kind"], "io");
+}
+
+#[test]
+fn test_decode_failed() {
+    // the only block loses its last byte, with its size after the 22 bytes of the header, the
+    // block kind and its length.
+    let data = b"the quick brown fox jumps over the lazy dog. ".repeat(40);
+    let mut compressed = huffman_markov::compress_bytes(&data, 3).unwrap();
+    compressed.pop();
+    let size = u32::from_le_bytes(compressed[27..31].try_into().unwrap());
+    compressed[27..31].copy_from_slice(&(size - 1).to_le_bytes());
+    let (_dir, path) = file(&compressed);
+
+    let output = command()
+        .arg("decompress")
+        .arg(&path)
+        .assert()
+        .code(4)
+        .get_output()
+        .clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("Error: decode failed at output byte "));
+    assert!(stderr.contains("), context = ["));
+
+    let output = command()
+        .args(["--error-format", "json", "decompress"])
+        .arg(&path)
+        .assert()
+        .code(4)
+        .get_output()
+        .clone();
+    let error: Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["kind"], "truncated");
+    assert!(error["detail"]["bit_offset"].is_u64());
+    assert!(error["detail"]["bytes_produced"].is_u64());
+}
+
+#[test]
+fn test_external_model() {
+    let data = b"the quick brown fox jumps over the lazy dog. ".repeat(40);
+    let (dir, path) = file(&data);
+    let model = dir.path().join("model");
+    command()
+        .args(["train", "--depth", "3", "-m"])
+        .arg(&model)
+        .arg(&path)
+        .assert()
+        .success();
+    let compressed = dir.path().join("compressed");
+    command()
+        .args(["compress", "--model"])
+        .arg(&model)
+        .arg("-o")
+        .arg(&compressed)
+        .arg(&path)
+        .assert()
+        .success();
+    let header = Header::read(fs::File::open(&compressed).unwrap()).unwrap();
+    assert!(header.model_fingerprint.is_some());
+
+    // every way of decompressing takes the model.
+    for args in [&[][..], &["--recover"], &["--inspect=16"]] {
+        let output = command()
+            .arg("decompress")
+            .args(args)
+            .arg("--model")
+            .arg(&model)
+            .arg(&compressed)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{args:?}");
+        if args.is_empty() || args == ["--recover"] {
+            assert_eq!(output.stdout, data);
+        }
+
+        // and fails without it, or with other coder options.
+        command()
+            .arg("decompress")
+            .args(args)
+            .arg(&compressed)
+            .assert()
+            .code(4);
+        let output = command()
+            .args(["--error-format", "json", "decompress"])
+            .args(args)
+            .args(["--escape", "literal", "--model"])
+            .arg(&model)
+            .arg(&compressed)
+            .assert()
+            .code(6)
+            .get_output()
+            .clone();
+        let error: Value = serde_json::from_slice(&output.stderr).unwrap();
+        assert_eq!(error["kind"], "model_fingerprint_mismatch");
+        assert_eq!(
+            error["detail"]["expected"],
+            format!("{:016x}", header.model_fingerprint.unwrap())
+        );
+    }
+}
+
+#[test]
+fn test_decompress_inspect() {
+    // the only block loses its last byte, which a preview never decodes.
+    let data = b"the quick brown fox jumps over the lazy dog. ".repeat(40);
+    let mut compressed = huffman_markov::compress_bytes(&data, 3).unwrap();
+    compressed.pop();
+    let size = u32::from_le_bytes(compressed[27..31].try_into().unwrap());
+    compressed[27..31].copy_from_slice(&(size - 1).to_le_bytes());
+    let (_dir, path) = file(&compressed);
+
+    let output = command()
+        .args(["decompress", "--inspect=20"])
+        .arg(&path)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("compressed file, version "));
+    assert!(stdout.contains("\nlength: 1800 bytes\n"));
+    assert!(stdout.ends_with(
+        "first 20 of 1800 bytes:\n\
+        00000000  74 68 65 20 71 75 69 63  6b 20 62 72 6f 77 6e 20  |the quick brown |\n\
+        00000010  66 6f 78 20                                       |fox |\n"
+    ));
+
+    let output = command()
+        .args(["decompress", "--inspect"])
+        .arg(&path)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("first 256 of 1800 bytes:\n"));
+    assert_eq!(
+        stdout.lines().filter(|line| line.ends_with('|')).count(),
+        16
+    );
+
+    command()
+        .args(["decompress", "--inspect", "-o", "output"])
+        .arg(&path)
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_refuse_overwrite() {
+    let (dir, path) = file(b"abracadabra");
+    let output = dir.path().join("output");
+    fs::write(&output, b"existing").unwrap();
+    for command_name in ["compress", "decompress"] {
+        command()
+            .args([command_name, "-o"])
+            .arg(&output)
+            .arg(&path)
+            .assert()
+            .code(1);
+        assert_eq!(fs::read(&output).unwrap(), b"existing");
+    }
+
+    let model = dir.path().join("model");
+    fs::write(&model, b"existing").unwrap();
+    command()
+        .args(["train", "-m"])
+        .arg(&model)
+        .arg(&path)
+        .assert()
+        .code(1);
+    assert_eq!(fs::read(&model).unwrap(), b"existing");
+}
+
+#[test]
+fn test_force() {
+    let (dir, path) = file(b"abracadabra");
+    let output = dir.path().join("output");
+    let decompressed = dir.path().join("decompressed");
+    let model = dir.path().join("model");
+    for target in [&output, &decompressed, &model] {
+        fs::write(target, b"existing").unwrap();
+    }
+
+    command()
+        .args(["compress", "--force", "-o"])
+        .arg(&output)
+        .arg(&path)
+        .assert()
+        .success();
+    command()
+        .args(["decompress", "-f", "-o"])
+        .arg(&decompressed)
+        .arg(&output)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&decompressed).unwrap(), b"abracadabra");
+
+    command()
+        .args(["train", "-f", "-m"])
+        .arg(&model)
+        .arg(&path)
+        .assert()
+        .success();
+    assert_eq!(
+        huffman_markov::Markov::load(&fs::read(&model).unwrap()[..]).unwrap(),
+        Builder::new().train(b"abracadabra").unwrap()
+    );
+
+    let entries = fs::read_dir(dir.path()).unwrap().count();
+    assert_eq!(entries, 4);
+}
+
+#[test]
+fn test_repeated_byte() {
+    for depth in 1..=4 {
+        for len in [0, 1, depth - 1, depth, depth + 1, 100_000] {
+            let data = vec![b'x'; len];
+            let (dir, path) = file(&data);
+            let output = dir.path().join("output");
+            let decompressed = dir.path().join("decompressed");
+            let depth = depth.to_string();
+            command()
+                .args(["train", "--depth", &depth, "-m"])
+                .arg(dir.path().join("model"))
+                .arg(&path)
+                .assert()
+                .success();
+            command()
+                .args(["compress", "--depth", &depth, "-o"])
+                .arg(&output)
+                .arg(&path)
+                .assert()
+                .success();
+            command()
+                .args(["decompress", "-o"])
+                .arg(&decompressed)
+                .arg(&output)
+                .assert()
+                .success();
+            assert_eq!(fs::read(&decompressed).unwrap(), data);
+            assert!(fs::metadata(&output).unwrap().len() <= 22 + 32);
+        }
+    }
+}
+
+#[test]
+fn test_depth_auto() {
+    // every byte is one of two after the two bytes before it, which a depth of 3 captures.
+    let mut data = vec![0u8, 0];
+    let mut state = 1u32;
+    for _ in 0..100_000 {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let context = u32::from(data[data.len() - 2]) * 8 + u32::from(data[data.len() - 1]);
+        data.push((context.wrapping_mul(2_654_435_761) >> 29) as u8 ^ (state >> 30) as u8 & 1);
+    }
+    let (dir, path) = file(&data);
+    let output = dir.path().join("output");
+    let decompressed = dir.path().join("decompressed");
+    let assert = command()
+        .args(["compress", "--depth", "auto", "-v", "-o"])
+        .arg(&output)
+        .arg(&path)
+        .assert()
+        .success();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("chose depth 3\n"), "{stderr}");
+    let compressed = fs::read(&output).unwrap();
+    assert_eq!(Header::read(&compressed[..]).unwrap().depth, 3);
+    command()
+        .args(["decompress", "-o"])
+        .arg(&decompressed)
+        .arg(&output)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&decompressed).unwrap(), data);
+
+    // the depth is only picked when compressing.
+    let assert = command()
+        .args(["train", "--depth", "auto", "-m"])
+        .arg(dir.path().join("model"))
+        .arg(&path)
+        .assert()
+        .code(2);
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    assert!(
+        stderr.contains("depth auto is only supported by compress"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_max_depth() {
+    let (dir, path) = file(b"abracadabra abracadabra");
+    let output = dir.path().join("output");
+    command()
+        .args(["compress", "--depth", "16", "-o"])
+        .arg(&output)
+        .arg(&path)
+        .assert()
+        .success();
+    assert_eq!(
+        Header::read(&fs::read(&output).unwrap()[..]).unwrap().depth,
+        16
+    );
+
+    // deeper models are refused before reading any input.
+    let assert = command()
+        .args(["compress", "--depth", "17", "missing"])
+        .assert()
+        .code(2);
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    assert!(
+        stderr.contains("depth 17 exceeds the maximum of 16") && stderr.contains("tokens"),
+        "{stderr}"
+    );
+}
+
+#[cfg(feature = "compare")]
+#[test]
+fn test_compare() {
+    let data = b"the quick brown fox jumps over the lazy dog. ".repeat(200);
+    let (_dir, path) = file(&data);
+    let output = command()
+        .args([
+            "--json",
+            "compare",
+            "--depths",
+            "2,3",
+            "--against",
+            "zstd,gzip",
+        ])
+        .arg(&path)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["input_bytes"], data.len());
+    let comparisons = report["comparisons"].as_array().unwrap();
+    let codecs: Vec<_> = comparisons
+        .iter()
+        .map(|comparison| {
+            (
+                comparison["codec"].as_str().unwrap(),
+                comparison["depth"].as_u64(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        codecs,
+        [
+            ("huffman", Some(2)),
+            ("huffman", Some(3)),
+            ("zstd", None),
+            ("gzip", None)
+        ]
+    );
+    // the models are measured by what compress writes, tables included.
+    for comparison in &comparisons[..2] {
+        let depth = comparison["depth"].as_u64().unwrap() as usize;
+        let compressed = Builder::new().depth(depth).compress(&data).unwrap();
+        assert_eq!(comparison["output_bytes"], compressed.len());
+    }
+    for comparison in comparisons {
+        let ratio = comparison["ratio"].as_f64().unwrap();
+        assert!(ratio > 0.0 && ratio < 1.0, "{comparison}");
+    }
+
+    command()
+        .args(["compare", "--against", "brotli"])
+        .arg(&path)
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_model_interpolate() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name);
+    for (name, data) in [("a", &b"abababab"[..]), ("b", b"acacacac")] {
+        fs::write(path(name), data).unwrap();
+        command()
+            .args(["train", "--depth", "2", "-m"])
+            .arg(path(&format!("{name}.model")))
+            .arg(path(name))
+            .assert()
+            .success();
+    }
+
+    command()
+        .args(["model", "--lambda", "0.25", "--interpolate"])
+        .arg(path("b.model"))
+        .arg("-o")
+        .arg(path("blended.model"))
+        .arg(path("a.model"))
+        .assert()
+        .success();
+    let load = |name: &str| huffman_markov::Markov::load(&fs::read(path(name)).unwrap()[..]);
+    let blended = load("blended.model").unwrap();
+    let expected = huffman_markov::Markov::interpolate(
+        &load("a.model").unwrap(),
+        &load("b.model").unwrap(),
+        0.25,
+    )
+    .unwrap();
+    assert_eq!(blended, expected);
+
+    // the weight of the input model goes with the model to blend with.
+    command()
+        .args(["model", "--lambda", "0.5", "-o"])
+        .arg(path("other.model"))
+        .arg(path("a.model"))
+        .assert()
+        .code(2);
+    command()
+        .args(["model", "--lambda", "1.5", "--interpolate"])
+        .arg(path("b.model"))
+        .arg("-o")
+        .arg(path("other.model"))
+        .arg(path("a.model"))
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_model_augment() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name);
+    fs::write(path("a"), b"abababab").unwrap();
+    fs::write(path("sample"), b"acacacad").unwrap();
+    command()
+        .args(["train", "--depth", "2", "-m"])
+        .arg(path("a.model"))
+        .arg(path("a"))
+        .assert()
+        .success();
+
+    let output = command()
+        .args(["--json", "model", "--min", "3", "--augment"])
+        .arg(path("sample"))
+        .arg("-o")
+        .arg(path("augmented.model"))
+        .arg(path("a.model"))
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        report["augmented"],
+        json!({"added": 2, "boosted": 0, "skipped": 1})
+    );
+
+    let load = |name: &str| huffman_markov::Markov::load(&fs::read(path(name)).unwrap()[..]);
+    let mut expected = load("a.model").unwrap();
+    expected.augment_from(b"acacacad", 3).unwrap();
+    assert_eq!(load("augmented.model").unwrap(), expected);
+    assert_eq!(report["sequences"], expected.iter().count());
+}
+
+#[test]
+fn test_train_inputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name);
+    fs::write(path("curated"), b"abc").unwrap();
+    fs::write(path("scraped"), b"cab").unwrap();
+    let output = command()
+        .args(["--json", "train", "--depth", "2", "-m"])
+        .arg(path("model"))
+        .arg("--input")
+        .arg(format!("{}:10", path("curated").display()))
+        .arg("--input")
+        .arg(path("scraped"))
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["inputs"][0]["weight"], 10);
+    assert_eq!(report["inputs"][1]["bytes"], 3);
+    // "ab" of the scraped file repeats the curated one.
+    assert_eq!(
+        report["stats"],
+        json!({"bytes": 6, "windows": 4, "new_sequences": 3, "repeated_windows": 1})
+    );
+
+    // no window spans the two files, "c" is only followed by "a" in the scraped one.
+    let markov = huffman_markov::Markov::load(&fs::read(path("model")).unwrap()[..]).unwrap();
+    let sequences: Vec<_> = markov.iter().collect();
+    assert_eq!(
+        sequences,
+        [
+            (b"ab".to_vec(), 11),
+            (b"bc".to_vec(), 10),
+            (b"ca".to_vec(), 1)
+        ]
+    );
+
+    command()
+        .args(["train", "-m"])
+        .arg(path("other"))
+        .arg("--input")
+        .arg(format!("{}:0", path("curated").display()))
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_cleanup_on_failure() {
+    // decoding fails after the temporary file was created.
+    let compressed = huffman_markov::compress_bytes(b"abracadabra", 3).unwrap();
+    let (dir, path) = file(&compressed[..compressed.len() - 1]);
+    let output = dir.path().join("output");
+    command()
+        .args(["decompress", "-o"])
+        .arg(&output)
+        .arg(&path)
+        .assert()
+        .code(4);
+    assert!(!output.exists());
+    assert!(!dir.path().join("output.tmp").exists());
+}
+
+#[test]
+fn test_max_output() {
+    let compressed = huffman_markov::compress_bytes(b"abracadabra", 3).unwrap();
+    let (dir, path) = file(&compressed);
+    let output = dir.path().join("output");
+    let stderr = command()
+        .args(["decompress", "--max-output", "10", "-o"])
+        .arg(&output)
+        .arg(&path)
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(stderr)
+        .unwrap()
+        .starts_with("Error: output limit of 10 exceeded\n"));
+    assert!(!output.exists());
+
+    command()
+        .args(["decompress", "--max-output", "11", "-o"])
+        .arg(&output)
+        .arg(&path)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&output).unwrap(), b"abracadabra");
+}
+
+#[test]
+fn test_stats() {
+    // the next letter only depends on the one before it.
+    let (_dir, path) = file(&b"abcd".repeat(1000));
+    let output = command()
+        .args(["stats", "--depth", "2"])
+        .arg(&path)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    let mut lines = output.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        "order-0 entropy: 2.000 bpb, order-1 conditional entropy: 0.000 bpb, gain: 100.0%"
+    );
+    // the letters are in row 6, and each is followed by a single one.
+    assert!(lines.any(|line| line == "6_  ....           "));
+}
+
+#[test]
+fn test_stats_top_contexts() {
+    let (_dir, path) = file(&b"aab".repeat(1000));
+    let output = command()
+        .args(["stats", "--depth", "2", "--top-contexts", "5"])
+        .arg(&path)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(
+        output.ends_with("heaviest contexts:\n  61 2000\n  62 999\n"),
+        "{output}"
+    );
+
+    let output = command()
+        .args(["stats", "--json", "--depth", "2", "--top-contexts", "1"])
+        .arg(&path)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output: Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(
+        output["top_contexts"],
+        json!([{"context": "61", "weight": 2000}])
+    );
+}
+
+#[test]
+fn test_stats_json() {
+    let (_dir, path) = file(&b"abab".repeat(1000));
+    let output = command()
+        .args(["stats", "--json", "--depth", "2"])
+        .arg(&path)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output: Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(output["order"], 1);
+    assert_eq!(output["order0_entropy"], 1.0);
+    assert_eq!(output["conditional_entropy"], 0.0);
+    let bytes: Vec<&Value> = output["leading_bytes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|stats| &stats["byte"])
+        .collect();
+    assert_eq!(bytes, [97, 98]);
+}
+
+#[test]
+fn test_json_compress() {
+    let data = b"the quick brown fox jumps over the lazy dog. ".repeat(40);
+    let (dir, path) = file(&data);
+    let compressed = dir.path().join("compressed");
+    let train = |args: &[&str]| {
+        command()
+            .args(args)
+            .arg(&path)
+            .assert()
+            .success()
+            .get_output()
+            .clone()
+    };
+
+    // with the data in a file, the document goes to standard output.
+    let output = train(&[
+        "--json",
+        "compress",
+        "--depth",
+        "3",
+        "-o",
+        compressed.to_str().unwrap(),
+    ]);
+    assert!(output.stderr.is_empty());
+    let summary: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let output_bytes = fs::metadata(&compressed).unwrap().len();
+    assert_eq!(summary["input_bytes"], data.len());
+    assert_eq!(summary["output_bytes"], output_bytes);
+    assert_eq!(summary["depth"], 3);
+    assert_eq!(
+        summary["ratio"].as_f64().unwrap(),
+        output_bytes as f64 / data.len() as f64
+    );
+    assert!(summary["elapsed_ms"].is_u64());
+
+    // the fingerprint of a single block is the one of its model.
+    let model = dir.path().join("model");
+    let output = train(&[
+        "train",
+        "--json",
+        "--depth",
+        "3",
+        "-m",
+        model.to_str().unwrap(),
+    ]);
+    let trained: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(trained["depth"], 3);
+    assert_eq!(summary["model_fingerprint"], trained["fingerprint"]);
+
+    // with the data on standard output, the document goes to standard error.
+    let output = train(&["compress", "--json", "--depth", "3"]);
+    assert_eq!(output.stdout.len() as u64, output_bytes);
+    let summary: Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(summary["output_bytes"], output_bytes);
+
+    let output = command()
+        .args(["decompress", "--json", "-o"])
+        .arg(dir.path().join("decompressed"))
+        .arg(&compressed)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let summary: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["output_bytes"], data.len());
+    assert_eq!(summary["blocks"], 1);
+    assert_eq!(summary["checksum"], Value::Null);
+    assert_eq!(summary["verified_blocks"], 0);
+}
+
+#[test]
+fn test_stats_contexts() {
+    // "a" is always followed by "b", "b" by "a" or "c", and "c" by "a".
+    let (_dir, path) = file(&b"abcab".repeat(1000));
+    let output = command()
+        .args(["stats", "--depth", "2"])
+        .arg(&path)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines[1], "deterministic contexts: 2 (60.0% of weight)");
+    assert!(lines[2].starts_with("fan-out: min 1, max 2, mean 1.33"));
+
+    let output = command()
+        .args(["stats", "--json", "--depth", "2"])
+        .arg(&path)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output: Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(output["deterministic_contexts"], 2);
+    let fraction = output["deterministic_fraction"].as_f64().unwrap();
+    assert!((fraction - 0.6).abs() < 1e-3, "{fraction}");
+    assert_eq!(
+        output["fanout"],
+        json!([{"fanout": 1, "contexts": 2}, {"fanout": 2, "contexts": 1}])
+    );
+}
+
+#[test]
+fn test_compress_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let text = b"the quick brown fox jumps over the lazy dog. ".repeat(100);
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let random: Vec<u8> = (0..8192)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let paths: Vec<PathBuf> = [
+        ("a.txt", &text[..]),
+        ("b.bin", &random),
+        ("c.txt", &text[..900]),
+    ]
+    .into_iter()
+    .map(|(name, data)| {
+        let path = dir.path().join(name);
+        fs::write(&path, data).unwrap();
+        path
+    })
+    .collect();
+    let archive = dir.path().join("archive");
+    let compress = |args: &[&str]| {
+        command()
+            .args(["--json", "compress", "--depth", "3", "-f", "-o"])
+            .arg(&archive)
+            .args(args)
+            .args(&paths)
+            .assert()
+            .success()
+            .get_output()
+            .clone()
+    };
+
+    let output = compress(&[]);
+    let summary: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let entries = summary["entries"].as_array().unwrap();
+    let names: Vec<&str> = entries
+        .iter()
+        .map(|entry| entry["name"].as_str().unwrap())
+        .collect();
+    let name = |path: &PathBuf| path.display().to_string();
+    assert_eq!(names, paths.iter().map(name).collect::<Vec<_>>());
+    assert_eq!(entries[0]["original_bytes"], text.len());
+    assert!(entries[0]["ratio"].as_f64().unwrap() < 0.5);
+    assert_eq!(entries[0]["stored"], false);
+    assert!(entries[1]["ratio"].as_f64().unwrap() > 1.0);
+    assert_eq!(entries[1]["stored"], true);
+    assert_eq!(entries[1]["escapes"], 0);
+
+    // the totals are the sums of the entries, the archive adds its magic and end marker.
+    for field in ["original_bytes", "compressed_bytes", "escapes"] {
+        let sum: u64 = entries
+            .iter()
+            .map(|entry| entry[field].as_u64().unwrap())
+            .sum();
+        assert_eq!(summary[field], sum, "{field}");
+    }
+    let output_bytes = fs::metadata(&archive).unwrap().len();
+    assert_eq!(summary["output_bytes"], output_bytes);
+    assert_eq!(
+        summary["compressed_bytes"].as_u64().unwrap() + 6,
+        output_bytes
+    );
+    let entries = read_archive(&fs::read(&archive).unwrap(), None).unwrap();
+    assert_eq!(entries[1], (name(&paths[1]), random.clone()));
+
+    let order = |sort: &str| {
+        let output = compress(&["--sort", sort]);
+        let summary: Value = serde_json::from_slice(&output.stdout).unwrap();
+        let names: Vec<String> = summary["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["name"].as_str().unwrap().to_string())
+            .collect();
+        names
+    };
+    let names: Vec<String> = paths.iter().map(name).collect();
+    assert_eq!(order("ratio"), [&names[1][..], &names[2], &names[0]]);
+    assert_eq!(order("size"), [&names[1][..], &names[0], &names[2]]);
+    assert_eq!(order("name"), names);
+
+    // the table has a line per entry between the heading and the totals.
+    let output = command()
+        .args(["compress", "--depth", "3", "--sort", "size", "-f", "-o"])
+        .arg(&archive)
+        .args(&paths)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let table = String::from_utf8(output.stderr).unwrap();
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 5, "{table}");
+    assert!(lines[0].starts_with("name"));
+    assert!(lines[1].starts_with(&names[1]) && lines[1].ends_with("yes"));
+    assert!(lines[4].starts_with("total"));
+
+    command()
+        .args(["compress", "--check"])
+        .args(&paths)
+        .assert()
+        .code(2);
+    command()
+        .args(["compress", "--sort", "ratio"])
+        .arg(&paths[0])
+        .assert()
+        .code(2);
+    command()
+        .args(["compress", "--sort", "speed"])
+        .args(&paths)
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_completions() {
+    let output = command().args(["completions", "bash"]).output().unwrap();
+    assert!(output.status.success());
+    let script = String::from_utf8(output.stdout).unwrap();
+    for name in [
+        "markov",
+        "train",
+        "model",
+        "compress",
+        "decompress",
+        "info",
+        "completions",
+    ] {
+        assert!(script.contains(name), "{name} missing from completions");
+    }
+
+    let output = command().args(["completions", "--man"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains(".TH huffman_markov"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_interrupt() {
+    use std::{
+        io::Read,
+        process::{self, Stdio},
+    };
+
+    // takes long enough to compress that the signal arrives while it runs.
+    let data = include_bytes!("../src/markov.rs").repeat(300);
+    let (dir, path) = file(&data);
+    let output = dir.path().join("output");
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin("huffman_markov"))
+        .args(["compress", "--progress", "-o"])
+        .arg(&output)
+        .arg(&path)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // the first progress report shows that it is compressing.
+    let mut stderr = child.stderr.take().unwrap();
+    let mut byte = [0];
+    while byte != *b"%" {
+        stderr.read_exact(&mut byte).unwrap();
+    }
+    let signal = process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(signal.success());
+
+    stderr.read_to_end(&mut Vec::new()).unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+    let entries = fs::read_dir(dir.path()).unwrap().count();
+    assert_eq!(entries, 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_train_watch() {
+    use huffman_markov::Markov;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        process::{self, Stdio},
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input");
+    fs::create_dir(&input).unwrap();
+    fs::write(input.join("a"), b"abcd").unwrap();
+    // the model is in the watched directory, its writes are not trained on.
+    let model = input.join("model");
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin("huffman_markov"))
+        .args([
+            "--json",
+            "train",
+            "--watch",
+            "--debounce",
+            "50",
+            "--depth",
+            "2",
+            "-m",
+        ])
+        .arg(&model)
+        .arg(&input)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // the whole test fails instead of hanging once the deadline passes.
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let (sender, updates) = mpsc::channel();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    thread::spawn(move || {
+        for line in stdout.lines() {
+            if sender.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+    let mut next_update = || -> Value {
+        match updates.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(line) => serde_json::from_str(&line).unwrap(),
+            Err(error) => {
+                child.kill().unwrap();
+                panic!("no update before the deadline: {error}");
+            }
+        }
+    };
+
+    let update = next_update();
+    assert_eq!(
+        (update["files"].clone(), update["bytes"].clone()),
+        (json!(1), json!(4))
+    );
+    assert_eq!(update["model"]["sequences"], 3);
+
+    // appended bytes continue the file, new files start their own windows.
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(input.join("a"))
+        .unwrap();
+    file.write_all(b"e").unwrap();
+    fs::write(input.join("b"), b"xy").unwrap();
+    let mut bytes = 0;
+    while bytes < 3 {
+        bytes += next_update()["bytes"].as_u64().unwrap();
+    }
+    let markov = Markov::load(fs::File::open(&model).unwrap()).unwrap();
+    let sequences: Vec<_> = markov.iter().map(|(sequence, _)| sequence).collect();
+    assert_eq!(sequences, [b"ab", b"bc", b"cd", b"de", b"xy"]);
+
+    fs::remove_file(input.join("b")).unwrap();
+    let signal = process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(signal.success());
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            panic!("not stopped before the deadline");
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(status.code(), Some(130));
+}
@@ -0,0 +1,172 @@
+//! Differential tests of the decoders against a naive reference decoder.
+//!
+//! The reference turns the whole input into a `Vec<bool>` up front and walks the tree of the
+//! current context one bit at a time, without any of the streaming or buffering of the
+//! production [`Reader`](huffman_markov::huffman::Reader). Codes are read MSB-first, the only
+//! bit order the format has. Escaped bytes are read with the order-0 tree, whose own escape is
+//! a literal.
+use huffman_markov::{
+    huffman::{Coder, Symbol},
+    Builder, EscapeMode, Markov,
+};
+use proptest::prelude::*;
+use std::io::Read;
+use test_strategy::proptest;
+
+fn bits(data: &[u8]) -> Vec<bool> {
+    data.iter()
+        .flat_map(|byte| (0..8).rev().map(move |bit| byte >> bit & 1 == 1))
+        .collect()
+}
+
+struct BitCursor {
+    bits: Vec<bool>,
+    position: usize,
+}
+
+impl BitCursor {
+    fn bit(&mut self) -> Option<bool> {
+        let bit = *self.bits.get(self.position)?;
+        self.position += 1;
+        Some(bit)
+    }
+
+    fn literal(&mut self) -> Option<u8> {
+        (0..8).try_fold(0, |byte, _| Some(byte << 1 | u8::from(self.bit()?)))
+    }
+
+    fn backoff(&mut self, coder: &Coder) -> Option<u8> {
+        // at depth 1 the order-0 tree is the only context, its escapes are literals.
+        let mut node = match coder.order0() {
+            Some(node) if coder.depth() > 1 => node,
+            _ => return self.literal(),
+        };
+        loop {
+            match node.symbol() {
+                Some(Symbol::Byte(byte)) => return Some(byte),
+                Some(Symbol::Escape) => return self.literal(),
+                Some(Symbol::Eof) => return None,
+                None if self.bit()? => node = node.right()?,
+                None => node = node.left()?,
+            }
+        }
+    }
+}
+
+// decodes `len` bytes after `context`, or nothing if the input runs out or a context is missing.
+fn reference_decode(coder: &Coder, context: &[u8], data: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut cursor = BitCursor {
+        bits: bits(data),
+        position: 0,
+    };
+    let mut output = context.to_vec();
+    while output.len() < context.len() + len {
+        let prefix = &output[output.len() - context.len()..];
+        let byte = match coder.tree(prefix) {
+            Some(mut node) => loop {
+                match node.symbol() {
+                    Some(Symbol::Byte(byte)) => break byte,
+                    Some(Symbol::Escape) => break cursor.backoff(coder)?,
+                    // the stream ends before `len` bytes.
+                    Some(Symbol::Eof) => return None,
+                    None if cursor.bit()? => node = node.right()?,
+                    None => node = node.left()?,
+                }
+            },
+            None if coder.escape() == EscapeMode::Literal => cursor.backoff(coder)?,
+            None => return None,
+        };
+        output.push(byte);
+    }
+    Some(output.split_off(context.len()))
+}
+
+// reads all of a streaming reader, or nothing if it fails.
+fn streamed<R: Read>(mut reader: R) -> Option<Vec<u8>> {
+    let mut output = vec![];
+    reader.read_to_end(&mut output).ok().map(|_| output)
+}
+
+// every production decoder, all of which have to agree with the reference. the lazy decoder
+// builds the trees of `coder` from `markov` as it goes.
+fn production_decode(
+    builder: &Builder,
+    markov: &Markov,
+    coder: &Coder,
+    context: &[u8],
+    data: &[u8],
+    len: usize,
+) -> Vec<Option<Vec<u8>>> {
+    let decoder = coder.decoder();
+    let lazy = builder.build_lazy_decoder(markov).unwrap();
+    vec![
+        coder.decode_all(context, data, len).ok(),
+        streamed(coder.reader(data, context, len as u64)),
+        decoder.decode_all(context, data, len).ok(),
+        streamed(decoder.reader(data, context, len as u64)),
+        lazy.decode_all(context, data, len).ok(),
+        streamed(lazy.reader(data, context, len as u64)),
+    ]
+}
+
+fn escape() -> impl Strategy<Value = EscapeMode> {
+    prop_oneof![Just(EscapeMode::None), Just(EscapeMode::Literal)]
+}
+
+// small alphabets make for deep contexts that are actually shared.
+fn text(max: usize) -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        proptest::collection::vec(b'a'..=b'd', 0..max),
+        proptest::collection::vec(any::<u8>(), 0..max),
+    ]
+}
+
+#[proptest]
+fn test_encoded(
+    #[strategy(1usize..=5)] depth: usize,
+    #[strategy(escape())] escape: EscapeMode,
+    #[strategy(9u8..=15)] max_code_length: u8,
+    #[strategy(text(512))] training: Vec<u8>,
+    #[strategy(text(512))] input: Vec<u8>,
+) {
+    let builder = Builder::new()
+        .depth(depth)
+        .escape(escape)
+        .max_code_length(max_code_length);
+    // without escapes, only the training data itself can be encoded.
+    let input = match escape {
+        EscapeMode::None => training.clone(),
+        EscapeMode::Literal => input,
+    };
+    prop_assume!(input.len() >= depth);
+    let markov = builder.train(&training).unwrap();
+    let coder = builder.build_coder(&markov).unwrap();
+
+    let encoded = coder.encode_all(&input).unwrap();
+    let (context, rest) = input.split_at(depth - 1);
+    let expected = reference_decode(&coder, context, &encoded, rest.len());
+    prop_assert_eq!(expected.as_deref(), Some(rest));
+    for decoded in production_decode(&builder, &markov, &coder, context, &encoded, rest.len()) {
+        prop_assert_eq!(&decoded, &expected);
+    }
+}
+
+#[proptest]
+fn test_arbitrary(
+    #[strategy(1usize..=5)] depth: usize,
+    #[strategy(escape())] escape: EscapeMode,
+    #[strategy(text(256))] training: Vec<u8>,
+    #[strategy(proptest::collection::vec(any::<u8>(), #depth - 1))] context: Vec<u8>,
+    data: Vec<u8>,
+    #[strategy(0usize..256)] len: usize,
+) {
+    let builder = Builder::new().depth(depth).escape(escape);
+    let markov = builder.train(&training).unwrap();
+    let coder = builder.build_coder(&markov).unwrap();
+
+    // garbage either decodes the same everywhere or fails everywhere.
+    let expected = reference_decode(&coder, &context, &data, len);
+    for decoded in production_decode(&builder, &markov, &coder, &context, &data, len) {
+        prop_assert_eq!(&decoded, &expected);
+    }
+}
@@ -0,0 +1,200 @@
+//! Frozen test vectors for the compressed format.
+//!
+//! Every vector is compressed and compared byte-for-byte against the files checked into
+//! `tests/vectors/`, along with the content hash of the trained model. Changes to the format
+//! are made deliberately by regenerating the files:
+//!
+//! ```text
+//! cargo test --test vectors -- --bless
+//! ```
+use huffman_markov::{
+    compress_with,
+    container::Codec,
+    decompress_bytes,
+    util::synthetic::{markov_corpus, Profile},
+    Builder,
+};
+use std::{fs, path::PathBuf, process::ExitCode};
+
+struct Vector {
+    name: &'static str,
+    depth: usize,
+    codec: Codec,
+    input: fn() -> Vec<u8>,
+}
+
+const VECTORS: &[Vector] = &[
+    Vector {
+        name: "empty",
+        depth: 3,
+        codec: Codec::Huffman,
+        input: Vec::new,
+    },
+    Vector {
+        name: "short",
+        depth: 4,
+        codec: Codec::Huffman,
+        input: || b"abc".to_vec(),
+    },
+    Vector {
+        name: "single",
+        depth: 1,
+        codec: Codec::Huffman,
+        input: || b"aaaabbc".to_vec(),
+    },
+    Vector {
+        name: "abracadabra",
+        depth: 3,
+        codec: Codec::Huffman,
+        input: || b"abracadabra".to_vec(),
+    },
+    Vector {
+        name: "text",
+        depth: 4,
+        codec: Codec::Huffman,
+        input: || {
+            b"the quick brown fox jumps over the lazy dog, then the lazy dog sleeps \
+            while the quick brown fox jumps over it again and again."
+                .to_vec()
+        },
+    },
+    Vector {
+        name: "bytes",
+        depth: 2,
+        codec: Codec::Huffman,
+        input: || (0..=255u8).cycle().take(1024).collect(),
+    },
+    Vector {
+        name: "abracadabra-range",
+        depth: 3,
+        codec: Codec::Range,
+        input: || b"abracadabra".to_vec(),
+    },
+    Vector {
+        name: "text-range",
+        depth: 4,
+        codec: Codec::Range,
+        input: || {
+            b"the quick brown fox jumps over the lazy dog, then the lazy dog sleeps \
+            while the quick brown fox jumps over it again and again."
+                .to_vec()
+        },
+    },
+    Vector {
+        name: "repeated",
+        depth: 3,
+        codec: Codec::Huffman,
+        input: repeated,
+    },
+    Vector {
+        name: "repeated-range",
+        depth: 3,
+        codec: Codec::Range,
+        input: repeated,
+    },
+    Vector {
+        name: "synthetic-english",
+        depth: 3,
+        codec: Codec::Huffman,
+        input: || markov_corpus(1, 4096, Profile::EnglishLike),
+    },
+    Vector {
+        name: "synthetic-binary",
+        depth: 2,
+        codec: Codec::Range,
+        input: || markov_corpus(1, 4096, Profile::Binary),
+    },
+];
+
+// long enough for coding to beat storing the block.
+fn repeated() -> Vec<u8> {
+    b"the quick brown fox jumps over the lazy dog. ".repeat(16)
+}
+
+fn directory() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("vectors")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// describes the first mismatch between the two outputs, with some surrounding bytes.
+fn diff(expected: &[u8], actual: &[u8]) -> String {
+    let offset = expected
+        .iter()
+        .zip(actual)
+        .position(|(a, b)| a != b)
+        .unwrap_or(expected.len().min(actual.len()));
+    let start = offset.saturating_sub(8);
+    let window = |bytes: &[u8]| hex(&bytes[start.min(bytes.len())..(offset + 8).min(bytes.len())]);
+    format!(
+        "first mismatch at byte {offset} (expected {} bytes, got {})\n  expected @{start}: {}\n  actual   @{start}: {}",
+        expected.len(),
+        actual.len(),
+        window(expected),
+        window(actual),
+    )
+}
+
+fn check(vector: &Vector, bless: bool) -> Result<(), String> {
+    let input = (vector.input)();
+    let builder = Builder::new().depth(vector.depth).codec(vector.codec);
+    let compressed = compress_with(&input, &builder).map_err(|error| error.to_string())?;
+    let model = builder.train(&input).map_err(|error| error.to_string())?;
+    let hash = format!("{:016x}\n", model.content_hash());
+
+    let decompressed = decompress_bytes(&compressed).map_err(|error| error.to_string())?;
+    if decompressed != input {
+        return Err("roundtrip produced different output".into());
+    }
+
+    let data_path = directory().join(format!("{}.hmkv", vector.name));
+    let hash_path = directory().join(format!("{}.hash", vector.name));
+    if bless {
+        fs::create_dir_all(directory()).map_err(|error| error.to_string())?;
+        fs::write(&data_path, &compressed).map_err(|error| error.to_string())?;
+        fs::write(&hash_path, &hash).map_err(|error| error.to_string())?;
+        return Ok(());
+    }
+
+    let expected = fs::read(&data_path)
+        .map_err(|error| format!("reading {}: {error}", data_path.display()))?;
+    if expected != compressed {
+        return Err(diff(&expected, &compressed));
+    }
+
+    let expected = fs::read_to_string(&hash_path)
+        .map_err(|error| format!("reading {}: {error}", hash_path.display()))?;
+    if expected != hash {
+        return Err(format!(
+            "model hash mismatch: expected {}, got {}",
+            expected.trim(),
+            hash.trim()
+        ));
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let bless = std::env::args().any(|arg| arg == "--bless");
+    let mut failed = 0;
+    for vector in VECTORS {
+        match check(vector, bless) {
+            Ok(()) => println!("vector {} ... ok", vector.name),
+            Err(error) => {
+                println!("vector {} ... FAILED\n  {error}", vector.name);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        println!("{failed} of {} vectors failed", VECTORS.len());
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}